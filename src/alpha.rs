@@ -0,0 +1,302 @@
+//! Premultiplied alpha.
+//!
+//! Most formats store the colour of a semi-transparent pixel as it would be
+//! if opaque ("straight" alpha), which is also what the terminal expects.
+//! Some TIFFs, notably textures exported from 3D tools, store it already
+//! multiplied by the alpha, and shown as straight they get dark fringes
+//! wherever they fade out. Those are converted back after decoding. The file
+//! says which it is in TIFF's ExtraSamples field; `--alpha` overrides it.
+
+use std::path::Path;
+
+use image::DynamicImage;
+
+use crate::exif::{self, Tiff};
+
+/// ExtraSamples value for alpha premultiplied into the colour ("associated").
+const ASSOCIATED_ALPHA: u32 = 1;
+
+/// How the colour of semi-transparent pixels is stored (`--alpha`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum AlphaMode {
+    /// As the file declares; straight if it doesn't say
+    #[default]
+    Auto,
+    /// Colours are multiplied by alpha
+    Premultiplied,
+    /// Colours are independent of alpha
+    Straight,
+}
+
+impl AlphaMode {
+    /// Whether an image is to be treated as premultiplied, given what its
+    /// file `declared`, which is only asked in `Auto` mode.
+    pub fn is_premultiplied(self, declared: impl FnOnce() -> bool) -> bool {
+        match self {
+            AlphaMode::Auto => declared(),
+            AlphaMode::Premultiplied => true,
+            AlphaMode::Straight => false,
+        }
+    }
+}
+
+/// Whether the TIFF file at `path` declares premultiplied alpha.
+pub fn read_declared(path: &Path) -> bool {
+    let Some(head) = exif::read_head(path) else {
+        return false;
+    };
+    if !is_tiff(&head) {
+        return false;
+    }
+    match declared_in(&head) {
+        Some(declared) => declared,
+        // Many writers put the IFD after the pixel data, at the end of the file
+        None if head.len() as u64 == exif::SEARCH_BYTES => std::fs::read(path)
+            .ok()
+            .and_then(|bytes| declared_in(&bytes))
+            .unwrap_or(false),
+        None => false,
+    }
+}
+
+/// Whether `bytes`, a whole file, is a TIFF declaring premultiplied alpha.
+pub fn declared(bytes: &[u8]) -> bool {
+    is_tiff(bytes) && declared_in(bytes).unwrap_or(false)
+}
+
+/// Only a TIFF file itself counts: the TIFF structure inside a JPEG's EXIF
+/// block doesn't describe the JPEG's pixels.
+fn is_tiff(bytes: &[u8]) -> bool {
+    bytes.starts_with(b"II*\0") || bytes.starts_with(b"MM\0*")
+}
+
+/// What the first IFD of a TIFF says, or `None` if it isn't within `bytes`.
+fn declared_in(bytes: &[u8]) -> Option<bool> {
+    let tiff = Tiff::find(bytes)?;
+    let ifd = tiff.first_ifd()?;
+    tiff.ifd_size(ifd)?;
+    Some(tiff.number(ifd, exif::EXTRA_SAMPLES) == Some(ASSOCIATED_ALPHA))
+}
+
+/// Divide the colour of every pixel by its alpha, turning premultiplied
+/// pixels into straight ones. Images without alpha are returned unchanged.
+pub fn unpremultiply(img: DynamicImage) -> DynamicImage {
+    match img {
+        DynamicImage::ImageRgba8(mut buf) => {
+            unpremultiply_samples(&mut buf, 4, u8::MAX.into());
+            DynamicImage::ImageRgba8(buf)
+        }
+        DynamicImage::ImageLumaA8(mut buf) => {
+            unpremultiply_samples(&mut buf, 2, u8::MAX.into());
+            DynamicImage::ImageLumaA8(buf)
+        }
+        DynamicImage::ImageRgba16(mut buf) => {
+            unpremultiply_samples(&mut buf, 4, u16::MAX.into());
+            DynamicImage::ImageRgba16(buf)
+        }
+        DynamicImage::ImageLumaA16(mut buf) => {
+            unpremultiply_samples(&mut buf, 2, u16::MAX.into());
+            DynamicImage::ImageLumaA16(buf)
+        }
+        DynamicImage::ImageRgba32F(mut buf) => {
+            for px in buf.chunks_exact_mut(4) {
+                let a = px[3];
+                if a > 0.0 {
+                    px[..3].iter_mut().for_each(|c| *c /= a);
+                }
+            }
+            DynamicImage::ImageRgba32F(buf)
+        }
+        img => img,
+    }
+}
+
+/// Unpremultiply integer pixels of `channels` samples, the last being alpha,
+/// where `max` is full intensity. Results are rounded to nearest and clamped,
+/// since a premultiplied colour above its alpha is invalid but does occur.
+fn unpremultiply_samples<T>(samples: &mut [T], channels: usize, max: u64)
+where
+    T: Copy + Into<u64> + TryFrom<u64>,
+{
+    for px in samples.chunks_exact_mut(channels) {
+        let (colour, alpha) = px.split_at_mut(channels - 1);
+        let a: u64 = alpha[0].into();
+        // Transparent pixels have no colour to recover, and opaque ones keep theirs
+        if a == 0 || a == max {
+            continue;
+        }
+        for c in colour {
+            let straight = ((*c).into() * max + a / 2) / a;
+            if let Ok(straight) = T::try_from(straight.min(max)) {
+                *c = straight;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TestDir;
+    use image::{ImageBuffer, Luma, LumaA, Rgb, Rgba};
+
+    /// An uncompressed little-endian RGBA TIFF of `size`x`size` pixels of
+    /// `px`, with `extra` as its ExtraSamples. The IFD comes after the pixels.
+    fn tiff(size: u32, px: [u8; 4], extra: u32) -> Vec<u8> {
+        let pixels = (size * size * 4) as usize;
+        let ifd = 8 + pixels as u32;
+        let entries: [(u16, u16, u32, u32); 10] = [
+            (256, 4, 1, size),
+            (257, 4, 1, size),
+            (258, 3, 4, ifd + 2 + 10 * 12 + 4),
+            (259, 3, 1, 1),
+            (262, 3, 1, 2),
+            (273, 4, 1, 8),
+            (277, 3, 1, 4),
+            (278, 4, 1, size),
+            (279, 4, 1, pixels as u32),
+            (exif::EXTRA_SAMPLES, 3, 1, extra),
+        ];
+        let mut bytes = b"II*\0".to_vec();
+        bytes.extend(ifd.to_le_bytes());
+        bytes.extend(px.iter().cycle().take(pixels));
+        bytes.extend(10u16.to_le_bytes());
+        for (tag, kind, count, value) in entries {
+            bytes.extend(tag.to_le_bytes());
+            bytes.extend(kind.to_le_bytes());
+            bytes.extend(count.to_le_bytes());
+            bytes.extend(value.to_le_bytes());
+        }
+        bytes.extend(0u32.to_le_bytes());
+        bytes.extend([8u16; 4].iter().flat_map(|b| b.to_le_bytes()));
+        bytes
+    }
+
+    /// ExtraSamples value for straight ("unassociated") alpha.
+    const UNASSOCIATED: u32 = 2;
+    const HALF_RED: [u8; 4] = [100, 50, 25, 128];
+
+    #[test]
+    fn eight_bit_colours_are_divided_by_alpha_and_rounded() {
+        let img = ImageBuffer::from_fn(4, 1, |x, _| {
+            Rgba(match x {
+                0 => HALF_RED,
+                // Brighter than its alpha allows: clamped
+                1 => [200, 10, 0, 100],
+                2 => [37, 38, 39, 0],
+                _ => [37, 38, 39, 255],
+            })
+        });
+        let out = unpremultiply(DynamicImage::ImageRgba8(img)).into_rgba8();
+        let px: Vec<_> = out.pixels().map(|p| p.0).collect();
+        assert_eq!(
+            px,
+            [
+                [199, 100, 50, 128],
+                [255, 26, 0, 100],
+                [37, 38, 39, 0],
+                [37, 38, 39, 255],
+            ]
+        );
+
+        let grey = ImageBuffer::from_pixel(1, 1, LumaA([60u8, 120]));
+        let out = unpremultiply(DynamicImage::ImageLumaA8(grey)).into_luma_alpha8();
+        assert_eq!(out.get_pixel(0, 0).0, [128, 120]);
+    }
+
+    #[test]
+    fn wide_and_float_colours_are_divided_by_alpha() {
+        let wide = ImageBuffer::from_pixel(1, 1, Rgba([1000u16, 16384, 0, 32768]));
+        let out = unpremultiply(DynamicImage::ImageRgba16(wide)).into_rgba16();
+        assert_eq!(out.get_pixel(0, 0).0, [2000, 32768, 0, 32768]);
+
+        let grey = ImageBuffer::from_pixel(1, 1, LumaA([300u16, 600]));
+        let out = unpremultiply(DynamicImage::ImageLumaA16(grey)).into_luma_alpha16();
+        assert_eq!(out.get_pixel(0, 0).0, [32768, 600]);
+
+        let float = ImageBuffer::from_fn(2, 1, |x, _| {
+            Rgba(if x == 0 { [0.25f32, 0.5, 0.125, 0.5] } else { [0.25, 0.5, 0.125, 0.0] })
+        });
+        let out = unpremultiply(DynamicImage::ImageRgba32F(float)).into_rgba32f();
+        assert_eq!(out.get_pixel(0, 0).0, [0.5, 1.0, 0.25, 0.5]);
+        assert_eq!(out.get_pixel(1, 0).0, [0.25, 0.5, 0.125, 0.0]);
+    }
+
+    #[test]
+    fn images_without_alpha_are_unchanged() {
+        let rgb = ImageBuffer::from_pixel(2, 2, Rgb([10u8, 20, 30]));
+        let out = unpremultiply(DynamicImage::ImageRgb8(rgb.clone()));
+        assert_eq!(out.as_rgb8(), Some(&rgb));
+        let grey = ImageBuffer::from_pixel(2, 2, Luma([10u8]));
+        let out = unpremultiply(DynamicImage::ImageLuma8(grey.clone()));
+        assert_eq!(out.as_luma8(), Some(&grey));
+    }
+
+    #[test]
+    fn only_associated_alpha_in_a_tiff_counts_as_premultiplied() {
+        assert!(declared(&tiff(2, HALF_RED, ASSOCIATED_ALPHA)));
+        assert!(!declared(&tiff(2, HALF_RED, UNASSOCIATED)));
+        assert!(!declared(&tiff(2, HALF_RED, 0)));
+        // Truncated before the IFD
+        assert!(!declared(&tiff(2, HALF_RED, ASSOCIATED_ALPHA)[..20]));
+
+        // The same IFD inside a JPEG's EXIF block describes the EXIF data only
+        let inner = tiff(2, HALF_RED, ASSOCIATED_ALPHA);
+        let mut jpeg = vec![0xFF, 0xD8, 0xFF, 0xE1];
+        jpeg.extend((inner.len() as u16 + 8).to_be_bytes());
+        jpeg.extend(b"Exif\0\0");
+        jpeg.extend(&inner);
+        jpeg.extend([0xFF, 0xD9]);
+        assert!(Tiff::find(&jpeg).is_some());
+        assert!(!declared(&jpeg));
+    }
+
+    #[test]
+    fn declared_alpha_is_found_past_the_search_window() {
+        let dir = TestDir::new();
+        let small = dir.file("small.tif", &tiff(2, HALF_RED, ASSOCIATED_ALPHA));
+        assert!(read_declared(&small));
+        // 200x200 pixels push the IFD beyond the first 128 KiB
+        let big = tiff(200, HALF_RED, ASSOCIATED_ALPHA);
+        assert!(big.len() as u64 > exif::SEARCH_BYTES);
+        assert!(read_declared(&dir.file("big.tif", &big)));
+        assert!(!read_declared(&dir.file("plain.tif", &tiff(200, HALF_RED, UNASSOCIATED))));
+        assert!(!read_declared(&dir.path().join("missing.tif")));
+    }
+
+    #[test]
+    fn overrides_win_over_what_the_file_says() {
+        assert!(AlphaMode::Auto.is_premultiplied(|| true));
+        assert!(!AlphaMode::Auto.is_premultiplied(|| false));
+        assert!(AlphaMode::Premultiplied.is_premultiplied(|| unreachable!()));
+        assert!(!AlphaMode::Straight.is_premultiplied(|| unreachable!()));
+    }
+
+    /// What decoding `bytes` under `mode` gives for its first pixel.
+    fn decoded(bytes: &[u8], mode: AlphaMode) -> [u8; 4] {
+        match crate::decode_bytes(bytes, mode).unwrap() {
+            crate::Decoded::Still(img) => img.to_rgba8().get_pixel(0, 0).0,
+            crate::Decoded::Animated(_) => panic!("a still TIFF decoded as animated"),
+        }
+    }
+
+    #[test]
+    fn decoded_fixtures_match_reference_pixels() {
+        let premultiplied = tiff(4, HALF_RED, ASSOCIATED_ALPHA);
+        let straight = tiff(4, HALF_RED, UNASSOCIATED);
+        assert_eq!(decoded(&premultiplied, AlphaMode::Auto), [199, 100, 50, 128]);
+        assert_eq!(decoded(&straight, AlphaMode::Auto), HALF_RED);
+        assert_eq!(decoded(&premultiplied, AlphaMode::Straight), HALF_RED);
+        assert_eq!(decoded(&straight, AlphaMode::Premultiplied), [199, 100, 50, 128]);
+    }
+
+    #[test]
+    fn letterbox_blends_with_the_straightened_colour() {
+        let crate::Decoded::Still(img) =
+            crate::decode_bytes(&tiff(4, HALF_RED, ASSOCIATED_ALPHA), AlphaMode::Auto).unwrap()
+        else {
+            panic!("a still TIFF decoded as animated");
+        };
+        assert_eq!(crate::edge_color(&img, (0, 0, 4, 4)), Some((199, 100, 50)));
+    }
+}
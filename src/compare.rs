@@ -0,0 +1,196 @@
+//! `--compare-dirs A B`: step through two directories of identically named
+//! images (e.g. `render_v1/` and `render_v2/`) in lockstep, flipping between
+//! the two versions of each.
+//!
+//! Files are paired by name. A name found in only one directory still gets a
+//! slot in the list, so nothing is silently skipped; the status bar flags it.
+
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+/// Which of the two compared directories is on screen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    A,
+    B,
+}
+
+impl Side {
+    pub fn other(self) -> Side {
+        match self {
+            Side::A => Side::B,
+            Side::B => Side::A,
+        }
+    }
+}
+
+/// One filename and where it exists.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pair {
+    pub name: OsString,
+    pub a: Option<PathBuf>,
+    pub b: Option<PathBuf>,
+}
+
+impl Pair {
+    fn get(&self, side: Side) -> Option<&PathBuf> {
+        match side {
+            Side::A => self.a.as_ref(),
+            Side::B => self.b.as_ref(),
+        }
+    }
+}
+
+/// Match the images of two directories by filename. The result holds the
+/// union of names, ordered like a single directory listing.
+pub fn pair(a: Vec<PathBuf>, b: Vec<PathBuf>) -> Vec<Pair> {
+    let mut by_name: BTreeMap<(OsString, OsString), Pair> = BTreeMap::new();
+    for (side, images) in [(Side::A, a), (Side::B, b)] {
+        for path in images {
+            let name = path.file_name().unwrap_or_default().to_os_string();
+            let entry = by_name
+                .entry((name.to_ascii_lowercase(), name.clone()))
+                .or_insert_with(|| Pair {
+                    name,
+                    a: None,
+                    b: None,
+                });
+            match side {
+                Side::A => entry.a = Some(path),
+                Side::B => entry.b = Some(path),
+            }
+        }
+    }
+    by_name.into_values().collect()
+}
+
+/// State for `--compare-dirs`.
+#[derive(Debug)]
+pub struct Compare {
+    dirs: [PathBuf; 2],
+    pairs: Vec<Pair>,
+    pub side: Side,
+}
+
+impl Compare {
+    pub fn new(a: PathBuf, b: PathBuf, pairs: Vec<Pair>) -> Self {
+        Compare {
+            dirs: [a, b],
+            pairs,
+            side: Side::A,
+        }
+    }
+
+    pub fn dir(&self, side: Side) -> &Path {
+        match side {
+            Side::A => &self.dirs[0],
+            Side::B => &self.dirs[1],
+        }
+    }
+
+    /// The image list for the side on screen. Names missing from that side
+    /// are given the path they would have, so indices line up on both sides.
+    pub fn paths(&self) -> Vec<PathBuf> {
+        self.pairs
+            .iter()
+            .map(|p| match p.get(self.side) {
+                Some(path) => path.clone(),
+                None => self.dir(self.side).join(&p.name),
+            })
+            .collect()
+    }
+
+    pub fn flip(&mut self) {
+        self.side = self.side.other();
+    }
+
+    /// The side the image at `index` is missing from, if any.
+    pub fn missing_from(&self, index: usize) -> Option<Side> {
+        let pair = self.pairs.get(index)?;
+        match (&pair.a, &pair.b) {
+            (None, _) => Some(Side::A),
+            (_, None) => Some(Side::B),
+            _ => None,
+        }
+    }
+
+    /// Names found in only one directory, as `(only in A, only in B)` counts.
+    pub fn unmatched(&self) -> (usize, usize) {
+        let only_a = self.pairs.iter().filter(|p| p.b.is_none()).count();
+        let only_b = self.pairs.iter().filter(|p| p.a.is_none()).count();
+        (only_a, only_b)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::listing::ListOptions;
+    use crate::testdir::TestDir;
+
+    /// Two folders of renders: `v1` lacks `c.png`, `v2` lacks `b.png` and
+    /// has an extra `e.png`, and both have a file that isn't an image.
+    fn renders() -> (TestDir, Compare) {
+        let dir = TestDir::new();
+        for name in ["v1/a.png", "v1/b.png", "v1/D.png", "v1/notes.txt"] {
+            dir.file(name, b"");
+        }
+        for name in ["v2/a.png", "v2/c.png", "v2/D.png", "v2/e.png", "v2/notes.txt"] {
+            dir.file(name, b"");
+        }
+        let (a, b) = (dir.path().join("v1"), dir.path().join("v2"));
+        let listing = ListOptions::default();
+        let pairs = pair(listing.collect(&a), listing.collect(&b));
+        (dir, Compare::new(a, b, pairs))
+    }
+
+    fn names(compare: &Compare) -> Vec<String> {
+        let names = compare.pairs.iter().map(|p| p.name.to_string_lossy().into_owned());
+        names.collect()
+    }
+
+    #[test]
+    fn names_from_both_sides_are_listed_once_in_order() {
+        let (_dir, compare) = renders();
+        assert_eq!(names(&compare), ["a.png", "b.png", "c.png", "D.png", "e.png"]);
+        let sides: Vec<(bool, bool)> =
+            compare.pairs.iter().map(|p| (p.a.is_some(), p.b.is_some())).collect();
+        let both = (true, true);
+        assert_eq!(sides, [both, (true, false), (false, true), both, (false, true)]);
+        assert_eq!(compare.unmatched(), (1, 2));
+    }
+
+    #[test]
+    fn missing_images_are_flagged_on_their_side() {
+        let (_dir, compare) = renders();
+        let missing: Vec<Option<Side>> = (0..6).map(|i| compare.missing_from(i)).collect();
+        assert_eq!(missing, [None, Some(Side::B), Some(Side::A), None, Some(Side::A), None]);
+    }
+
+    #[test]
+    fn flipping_keeps_indices_lined_up() {
+        let (dir, mut compare) = renders();
+        let on = |side: &str, name: &str| dir.path().join(side).join(name);
+        let a = compare.paths();
+        compare.flip();
+        assert_eq!(compare.side, Side::B);
+        let b = compare.paths();
+        assert_eq!(a.len(), b.len());
+        for (i, name) in names(&compare).iter().enumerate() {
+            // Missing files get the path they would have
+            assert_eq!(a[i], on("v1", name));
+            assert_eq!(b[i], on("v2", name));
+        }
+        compare.flip();
+        assert_eq!(compare.paths(), a);
+    }
+
+    #[test]
+    fn one_side_empty_pairs_nothing() {
+        let pairs = pair(vec![PathBuf::from("x/a.png")], Vec::new());
+        assert_eq!(pairs.len(), 1);
+        assert_eq!(pairs[0].b, None);
+        assert!(pair(Vec::new(), Vec::new()).is_empty());
+    }
+}
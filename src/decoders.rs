@@ -0,0 +1,268 @@
+//! External decoder hooks.
+//!
+//! `--decoder dcm='dcmj2pnm {path} -'` registers a shell command for an
+//! extension. The command is expected to write a PNG or PNM image to stdout,
+//! which is then decoded with the built-in decoders. This covers niche formats
+//! (DICOM, FITS, camera RAW variants) for anyone with local tooling for them.
+
+use std::io::Read;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+use image::DynamicImage;
+
+/// Largest amount of decoder output accepted before giving up on it.
+const MAX_OUTPUT_BYTES: u64 = 512 * 1024 * 1024;
+
+/// A command that converts files with one extension into something we can decode.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Decoder {
+    /// Lowercase extension, without the dot.
+    pub ext: String,
+    /// Shell command; `{path}` is replaced with the quoted file path.
+    command: String,
+}
+
+impl std::str::FromStr for Decoder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (ext, command) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected EXT=COMMAND, got '{}'", s))?;
+        let ext = ext.trim().trim_start_matches('.').to_ascii_lowercase();
+        if ext.is_empty() || command.trim().is_empty() {
+            return Err(format!("expected EXT=COMMAND, got '{}'", s));
+        }
+        if !command.contains("{path}") {
+            return Err(format!("decoder command for '{}' must contain {{path}}", ext));
+        }
+        Ok(Decoder {
+            ext,
+            command: command.to_string(),
+        })
+    }
+}
+
+impl Decoder {
+    /// The program the command runs, for error messages.
+    fn program(&self) -> &str {
+        self.command.split_whitespace().next().unwrap_or("")
+    }
+
+    /// Run the command on `path` and decode what it writes to stdout. The
+    /// process is killed if it runs longer than `timeout`.
+    pub fn run(&self, path: &Path, timeout: Duration) -> Result<DynamicImage, String> {
+        self.run_limited(path, timeout, MAX_OUTPUT_BYTES)
+    }
+
+    /// `run`, accepting at most `max_output` bytes from the command.
+    fn run_limited(
+        &self,
+        path: &Path,
+        timeout: Duration,
+        max_output: u64,
+    ) -> Result<DynamicImage, String> {
+        let cmdline = self
+            .command
+            .replace("{path}", &shell_quote(&path.to_string_lossy()));
+
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(&cmdline)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("couldn't start decoder for .{}: {}", self.ext, e))?;
+
+        // Read output on another thread so a chatty decoder can't fill the pipe
+        // and stall while we wait for it to exit.
+        let mut stdout = child.stdout.take().expect("stdout is piped");
+        let reader = std::thread::spawn(move || {
+            let mut buf = Vec::new();
+            let result = (&mut stdout)
+                .take(max_output + 1)
+                .read_to_end(&mut buf);
+            result.map(|_| buf)
+        });
+        let mut stderr = child.stderr.take().expect("stderr is piped");
+        let err_reader = std::thread::spawn(move || {
+            let mut buf = String::new();
+            let _ = (&mut stderr).take(64 * 1024).read_to_string(&mut buf);
+            buf
+        });
+
+        let deadline = Instant::now() + timeout;
+        let status = loop {
+            match child.try_wait() {
+                Ok(Some(status)) => break status,
+                Ok(None) if Instant::now() >= deadline => {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    return Err(format!(
+                        "decoder '{}' timed out after {:.1}s",
+                        self.program(),
+                        timeout.as_secs_f64()
+                    ));
+                }
+                Ok(None) => std::thread::sleep(Duration::from_millis(10)),
+                Err(e) => return Err(format!("decoder '{}' failed: {}", self.program(), e)),
+            }
+        };
+
+        let output = reader
+            .join()
+            .map_err(|_| "decoder output reader panicked".to_string())?
+            .map_err(|e| format!("couldn't read decoder output: {}", e))?;
+        let errors = err_reader.join().unwrap_or_default();
+
+        // Reading stops at the limit, which usually kills the decoder with
+        // SIGPIPE, so this goes before its exit status
+        if output.len() as u64 > max_output {
+            return Err(format!(
+                "decoder '{}' wrote more than {} MiB",
+                self.program(),
+                max_output / (1024 * 1024)
+            ));
+        }
+
+        // sh reports a missing program with 127
+        if status.code() == Some(127) {
+            return Err(format!("decoder program not found: {}", self.program()));
+        }
+        if !status.success() {
+            let detail = errors.lines().last().unwrap_or("").trim();
+            return Err(format!("decoder '{}' failed ({}) {}", self.program(), status, detail));
+        }
+
+        image::load_from_memory(&output)
+            .map_err(|e| format!("couldn't decode output of '{}': {}", self.program(), e))
+    }
+}
+
+/// Find the decoder registered for `path`'s extension.
+pub fn find<'a>(decoders: &'a [Decoder], path: &Path) -> Option<&'a Decoder> {
+    let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+    decoders.iter().find(|d| d.ext == ext)
+}
+
+/// Quote `s` for POSIX sh.
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    use crate::listing::ListOptions;
+    use crate::testdir::TestDir;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    fn decoder(spec: &str) -> Decoder {
+        spec.parse().unwrap()
+    }
+
+    /// A folder with `scan.xyz`, really a 3x2 PNG, and a script that
+    /// converts such files by copying them to stdout.
+    fn fixture() -> (TestDir, PathBuf) {
+        let dir = TestDir::new();
+        let image = dir.path().join("scan.xyz");
+        DynamicImage::new_rgb8(3, 2).save_with_format(&image, image::ImageFormat::Png).unwrap();
+        dir.file("xyz2png", b"#!/bin/sh\necho converting >&2\nexec cat \"$1\"\n");
+        (dir, image)
+    }
+
+    #[test]
+    fn decoders_parse_from_ext_and_command() {
+        let parsed = decoder(".DCM=dcmj2pnm {path} -");
+        assert_eq!(parsed.ext, "dcm");
+        assert_eq!(parsed.command, "dcmj2pnm {path} -");
+        assert_eq!(parsed.program(), "dcmj2pnm");
+        // Only the first `=` separates the two
+        assert_eq!(decoder("fits=convert -define a=b {path} png:-").ext, "fits");
+
+        let errors = [
+            ("dcm", "expected EXT=COMMAND, got 'dcm'"),
+            ("=cat {path}", "expected EXT=COMMAND, got '=cat {path}'"),
+            ("dcm=  ", "expected EXT=COMMAND, got 'dcm=  '"),
+            ("dcm=dcmj2pnm -", "decoder command for 'dcm' must contain {path}"),
+        ];
+        for (spec, error) in errors {
+            assert_eq!(spec.parse::<Decoder>(), Err(error.to_string()));
+        }
+    }
+
+    #[test]
+    fn decoders_are_found_by_extension() {
+        let decoders = [decoder("dcm=a {path}"), decoder("fits=b {path}")];
+        assert_eq!(find(&decoders, Path::new("x/scan.DCM")), Some(&decoders[0]));
+        assert_eq!(find(&decoders, Path::new("m31.fits")), Some(&decoders[1]));
+        assert_eq!(find(&decoders, Path::new("scan.dcm.png")), None);
+        assert_eq!(find(&decoders, Path::new("dcm")), None);
+    }
+
+    #[test]
+    fn output_of_the_command_is_decoded() {
+        let (dir, image) = fixture();
+        let script = dir.path().join("xyz2png");
+        let hook = decoder(&format!("xyz=sh {} {{path}}", shell_quote(script.to_str().unwrap())));
+        let img = hook.run(&image, 5 * SECOND).unwrap();
+        assert_eq!((img.width(), img.height()), (3, 2));
+
+        // Names are quoted, so spaces and quotes reach the command intact
+        let awkward = dir.path().join("it's a $(scan).xyz");
+        std::fs::copy(&image, &awkward).unwrap();
+        assert!(hook.run(&awkward, 5 * SECOND).is_ok());
+    }
+
+    #[test]
+    fn failures_are_explained() {
+        let (_dir, image) = fixture();
+        let cases = [
+            ("xyz=no-such-tool {path}", "decoder program not found: no-such-tool"),
+            ("xyz=echo 'bad header' >&2; exit 3 # {path}", "failed (exit status: 3) bad header"),
+            ("xyz=echo not an image # {path}", "couldn't decode output of 'echo'"),
+        ];
+        for (spec, error) in cases {
+            let message = decoder(spec).run(&image, 5 * SECOND).unwrap_err();
+            assert!(message.contains(error), "{}: {}", spec, message);
+        }
+    }
+
+    #[test]
+    fn slow_decoders_are_stopped() {
+        let (_dir, image) = fixture();
+        let started = Instant::now();
+        let hook = decoder("xyz=sleep 5; cat {path}");
+        let message = hook.run(&image, Duration::from_millis(100)).unwrap_err();
+        assert_eq!(message, "decoder 'sleep' timed out after 0.1s");
+        assert!(started.elapsed() < 2 * SECOND);
+    }
+
+    #[test]
+    fn output_is_capped() {
+        let (_dir, image) = fixture();
+        let hook = decoder("xyz=head -c 3000000 /dev/zero # {path}");
+        let message = hook.run_limited(&image, 5 * SECOND, 1024 * 1024).unwrap_err();
+        assert_eq!(message, "decoder 'head' wrote more than 1 MiB");
+        // Even a decoder that would never stop writing
+        let hook = decoder("xyz=cat /dev/zero # {path}");
+        assert!(hook.run_limited(&image, SECOND, 1024 * 1024).is_err());
+    }
+
+    #[test]
+    fn hooked_extensions_are_listed() {
+        let (dir, image) = fixture();
+        assert!(ListOptions::default().collect(dir.path()).is_empty());
+        let listing = ListOptions {
+            decoders: vec![decoder("XYZ=cat {path}")],
+            ..ListOptions::default()
+        };
+        assert_eq!(listing.collect(dir.path()), [image]);
+    }
+}
@@ -0,0 +1,178 @@
+//! Per-directory defaults from a `.termview.toml` file.
+//!
+//! The file closest to the browsed directory is used, looking there first
+//! and then in each parent. Only top-level `key = value` pairs are read: the
+//! file is a flat list of settings, so tables, arrays and dates aren't
+//! supported and are reported like any other problem with the file. Which
+//! keys count, and how they combine with the command line, is up to the
+//! caller.
+
+use std::path::{Path, PathBuf};
+
+pub const CONFIG_FILE: &str = ".termview.toml";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(String),
+    /// An integer or float, kept as written.
+    Number(String),
+    Bool(bool),
+}
+
+pub struct DirConfig {
+    pub path: PathBuf,
+    /// Settings in file order.
+    pub entries: Vec<(String, Value)>,
+    /// Lines that couldn't be read, as messages for the session log.
+    pub problems: Vec<String>,
+}
+
+impl DirConfig {
+    /// Read the config file nearest to `dir`, if there is one.
+    pub fn find(dir: &Path) -> Option<DirConfig> {
+        let dir = std::fs::canonicalize(dir).ok()?;
+        let path = dir
+            .ancestors()
+            .map(|d| d.join(CONFIG_FILE))
+            .find(|p| p.is_file())?;
+        let text = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) => {
+                return Some(DirConfig {
+                    problems: vec![format!("can't read it: {}", e)],
+                    path,
+                    entries: Vec::new(),
+                })
+            }
+        };
+        let mut config = DirConfig::parse(&text);
+        config.path = path;
+        Some(config)
+    }
+
+    fn parse(text: &str) -> DirConfig {
+        let mut config = DirConfig {
+            path: PathBuf::new(),
+            entries: Vec::new(),
+            problems: Vec::new(),
+        };
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match parse_line(line) {
+                Ok((key, value)) => config.entries.push((key, value)),
+                Err(e) => config.problems.push(format!("line {}: {}", n + 1, e)),
+            }
+        }
+        config
+    }
+}
+
+fn parse_line(line: &str) -> Result<(String, Value), String> {
+    if line.starts_with('[') {
+        return Err("tables aren't supported".into());
+    }
+    let (key, value) = line
+        .split_once('=')
+        .ok_or_else(|| "expected key = value".to_string())?;
+    let key = key.trim();
+    let key = key
+        .strip_prefix('"')
+        .and_then(|k| k.strip_suffix('"'))
+        .unwrap_or(key);
+    if key.is_empty() {
+        return Err("missing key".into());
+    }
+    let (value, rest) = parse_value(value.trim())?;
+    let rest = rest.trim();
+    if !rest.is_empty() && !rest.starts_with('#') {
+        return Err(format!("unexpected '{}' after the value", rest));
+    }
+    Ok((key.to_string(), value))
+}
+
+/// Read one value from the start of `text`, returning it and what follows.
+fn parse_value(text: &str) -> Result<(Value, &str), String> {
+    if let Some(rest) = text.strip_prefix('"') {
+        let mut value = String::new();
+        let mut chars = rest.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => return Ok((Value::String(value), &rest[i + 1..])),
+                '\\' => match chars.next().map(|(_, c)| c) {
+                    Some('"') => value.push('"'),
+                    Some('\\') => value.push('\\'),
+                    Some('t') => value.push('\t'),
+                    Some('n') => value.push('\n'),
+                    Some(c) => return Err(format!("unsupported escape '\\{}'", c)),
+                    None => break,
+                },
+                c => value.push(c),
+            }
+        }
+        return Err("unterminated string".into());
+    }
+    if let Some(rest) = text.strip_prefix('\'') {
+        let end = rest.find('\'').ok_or("unterminated string")?;
+        return Ok((Value::String(rest[..end].to_string()), &rest[end + 1..]));
+    }
+
+    let end = text.find([' ', '\t', '#']).unwrap_or(text.len());
+    let (word, rest) = text.split_at(end);
+    let value = match word {
+        "true" => Value::Bool(true),
+        "false" => Value::Bool(false),
+        _ if word.replace('_', "").parse::<f64>().is_ok() => Value::Number(word.replace('_', "")),
+        "" => return Err("missing value".into()),
+        _ => return Err(format!("unsupported value '{}'", word)),
+    };
+    Ok((value, rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_values_parse_like_toml() {
+        let config = DirConfig::parse(
+            "# comment\n\
+             a = 'single'\n\
+             \"b\" = \"esc \\\"q\\\" \\t\" # trailing\n\
+             c = 1_000\n\
+             d = 2.5\n\
+             e = false\n\
+             [table]\n\
+             f\n\
+             g = \"open\n\
+             h = nope\n\
+             i = 1 2\n\
+             = 3\n",
+        );
+        let entries: Vec<(&str, &Value)> =
+            config.entries.iter().map(|(k, v)| (k.as_str(), v)).collect();
+        assert_eq!(
+            entries,
+            [
+                ("a", &Value::String("single".into())),
+                ("b", &Value::String("esc \"q\" \t".into())),
+                ("c", &Value::Number("1000".into())),
+                ("d", &Value::Number("2.5".into())),
+                ("e", &Value::Bool(false)),
+            ]
+        );
+        assert_eq!(
+            config.problems,
+            [
+                "line 7: tables aren't supported",
+                "line 8: expected key = value",
+                "line 9: unterminated string",
+                "line 10: unsupported value 'nope'",
+                "line 11: unexpected '2' after the value",
+                "line 12: missing key",
+            ]
+        );
+    }
+}
@@ -0,0 +1,323 @@
+//! Reading EXIF data from JPEG and TIFF files.
+//!
+//! Only the handful of fields termview uses are picked out: the orientation,
+//! the date taken for `--sort taken`, and the camera settings listed in the
+//! info panel (`i`). Anything malformed reads as absent rather than as an
+//! error.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How much of a file is searched for EXIF data. The APP1 segment holding it
+/// comes right after the JPEG header and can't exceed 64 KiB.
+pub const SEARCH_BYTES: u64 = 128 * 1024;
+
+pub const ORIENTATION: u16 = 0x0112;
+/// How the samples after the colour ones are used, e.g. as premultiplied alpha.
+pub const EXTRA_SAMPLES: u16 = 0x0152;
+const MAKE: u16 = 0x010F;
+const MODEL: u16 = 0x0110;
+/// Pointers to the sub-IFDs holding camera settings and location.
+const EXIF_IFD: u16 = 0x8769;
+const GPS_IFD: u16 = 0x8825;
+const EXPOSURE_TIME: u16 = 0x829A;
+const F_NUMBER: u16 = 0x829D;
+const ISO: u16 = 0x8827;
+const DATE_TAKEN: u16 = 0x9003;
+const FOCAL_LENGTH: u16 = 0x920A;
+const GPS_LATITUDE_REF: u16 = 1;
+const GPS_LATITUDE: u16 = 2;
+const GPS_LONGITUDE_REF: u16 = 3;
+const GPS_LONGITUDE: u16 = 4;
+
+/// The first `SEARCH_BYTES` of the file at `path`.
+pub fn read_head(path: &Path) -> Option<Vec<u8>> {
+    let mut head = Vec::new();
+    File::open(path)
+        .ok()?
+        .take(SEARCH_BYTES)
+        .read_to_end(&mut head)
+        .ok()?;
+    Some(head)
+}
+
+/// A TIFF structure: the file itself, or the EXIF block of a JPEG.
+pub struct Tiff<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+}
+
+impl<'a> Tiff<'a> {
+    /// Find the TIFF structure at the start of a JPEG or TIFF file.
+    pub fn find(bytes: &'a [u8]) -> Option<Self> {
+        if bytes.starts_with(b"II*\0") || bytes.starts_with(b"MM\0*") {
+            return Tiff::new(bytes);
+        }
+        if !bytes.starts_with(&[0xFF, 0xD8]) {
+            return None;
+        }
+
+        // Walk the JPEG segments up to the start of the image data
+        let mut pos = 2;
+        while pos + 4 <= bytes.len() {
+            if bytes[pos] != 0xFF {
+                return None;
+            }
+            let marker = bytes[pos + 1];
+            if marker == 0xDA || marker == 0xD9 {
+                return None;
+            }
+            let len = u16::from_be_bytes([bytes[pos + 2], bytes[pos + 3]]) as usize;
+            let segment = bytes.get(pos + 4..pos + 2 + len)?;
+            if marker == 0xE1 {
+                if let Some(tiff) = segment.strip_prefix(b"Exif\0\0") {
+                    return Tiff::new(tiff);
+                }
+            }
+            pos += 2 + len;
+        }
+        None
+    }
+
+    fn new(data: &'a [u8]) -> Option<Self> {
+        let big_endian = match data.get(..2)? {
+            b"II" => false,
+            b"MM" => true,
+            _ => return None,
+        };
+        Some(Tiff { data, big_endian })
+    }
+
+    fn u16_at(&self, at: usize) -> Option<u16> {
+        let b = self.data.get(at..at + 2)?;
+        Some(if self.big_endian {
+            u16::from_be_bytes([b[0], b[1]])
+        } else {
+            u16::from_le_bytes([b[0], b[1]])
+        })
+    }
+
+    fn u32_at(&self, at: usize) -> Option<u32> {
+        let b = self.data.get(at..at + 4)?;
+        Some(if self.big_endian {
+            u32::from_be_bytes([b[0], b[1], b[2], b[3]])
+        } else {
+            u32::from_le_bytes([b[0], b[1], b[2], b[3]])
+        })
+    }
+
+    /// Offset of the first IFD, which describes the main image.
+    pub fn first_ifd(&self) -> Option<usize> {
+        Some(self.u32_at(4)? as usize)
+    }
+
+    /// Size in bytes of `ifd`, or `None` if it runs past the end of the data.
+    pub fn ifd_size(&self, ifd: usize) -> Option<usize> {
+        let size = 2 + self.u16_at(ifd)? as usize * 12 + 4;
+        (ifd + size <= self.data.len()).then_some(size)
+    }
+
+    /// Offset of the sub-IFD that the `pointer` field of `ifd` leads to.
+    fn sub_ifd(&self, ifd: usize, pointer: u16) -> Option<usize> {
+        Some(self.number(ifd, pointer)? as usize)
+    }
+
+    /// Position of the 12-byte entry for `tag` in `ifd`.
+    fn entry(&self, ifd: usize, tag: u16) -> Option<usize> {
+        let count = self.u16_at(ifd)? as usize;
+        (0..count)
+            .map(|i| ifd + 2 + i * 12)
+            .find(|&entry| self.u16_at(entry) == Some(tag))
+    }
+
+    /// Type, count and offset of the value of field `tag` in `ifd`.
+    fn field(&self, ifd: usize, tag: u16) -> Option<(u16, usize, usize)> {
+        let entry = self.entry(ifd, tag)?;
+        let kind = self.u16_at(entry + 2)?;
+        let count = self.u32_at(entry + 4)? as usize;
+        let size = match kind {
+            3 => 2,
+            4 | 9 => 4,
+            5 | 10 => 8,
+            _ => 1,
+        };
+        // Values of up to four bytes are stored in the entry itself
+        let offset = if size * count <= 4 {
+            entry + 8
+        } else {
+            self.u32_at(entry + 8)? as usize
+        };
+        Some((kind, count, offset))
+    }
+
+    /// A SHORT or LONG field as a number.
+    pub fn number(&self, ifd: usize, tag: u16) -> Option<u32> {
+        let entry = self.entry(ifd, tag)?;
+        match self.u16_at(entry + 2)? {
+            3 => self.u16_at(entry + 8).map(u32::from),
+            4 => self.u32_at(entry + 8),
+            _ => None,
+        }
+    }
+
+    /// An ASCII field, without its terminating NUL and surrounding blanks.
+    fn text(&self, ifd: usize, tag: u16) -> Option<String> {
+        let (kind, count, offset) = self.field(ifd, tag)?;
+        if kind != 2 {
+            return None;
+        }
+        let bytes = self.data.get(offset..offset + count)?;
+        let bytes = &bytes[..bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len())];
+        let text = String::from_utf8_lossy(bytes).trim().to_string();
+        (!text.is_empty()).then_some(text)
+    }
+
+    /// Entry `index` of a RATIONAL field.
+    fn rational(&self, ifd: usize, tag: u16, index: usize) -> Option<f64> {
+        let (kind, count, offset) = self.field(ifd, tag)?;
+        if kind != 5 || index >= count {
+            return None;
+        }
+        let at = offset + index * 8;
+        let (num, den) = (self.u32_at(at)?, self.u32_at(at + 4)?);
+        (den != 0).then(|| num as f64 / den as f64)
+    }
+}
+
+/// How a photo was taken, as far as its EXIF data says. Each field is
+/// formatted for display.
+#[derive(Debug, Default, Clone)]
+pub struct Exif {
+    pub camera: Option<String>,
+    pub exposure: Option<String>,
+    pub aperture: Option<String>,
+    pub iso: Option<String>,
+    pub focal_length: Option<String>,
+    pub taken: Option<String>,
+    pub location: Option<String>,
+}
+
+impl Exif {
+    /// Read the fields from the start of a JPEG or TIFF file.
+    pub fn parse(bytes: &[u8]) -> Option<Exif> {
+        let tiff = Tiff::find(bytes)?;
+        let ifd = tiff.first_ifd()?;
+        let mut exif = Exif::default();
+
+        let make = tiff.text(ifd, MAKE);
+        exif.camera = match (make, tiff.text(ifd, MODEL)) {
+            // Models often repeat the make already ("Canon EOS R5")
+            (Some(make), Some(model)) if !model.starts_with(&make) => {
+                Some(format!("{} {}", make, model))
+            }
+            (make, model) => model.or(make),
+        };
+
+        if let Some(sub) = tiff.sub_ifd(ifd, EXIF_IFD) {
+            exif.exposure = tiff.rational(sub, EXPOSURE_TIME, 0).map(|t| {
+                if t > 0.0 && t < 0.5 {
+                    format!("1/{} s", (1.0 / t).round())
+                } else {
+                    format!("{} s", trim_number(t))
+                }
+            });
+            exif.aperture = tiff
+                .rational(sub, F_NUMBER, 0)
+                .map(|f| format!("f/{}", trim_number(f)));
+            exif.iso = tiff.number(sub, ISO).map(|iso| iso.to_string());
+            exif.focal_length = tiff
+                .rational(sub, FOCAL_LENGTH, 0)
+                .map(|mm| format!("{} mm", trim_number(mm)));
+            exif.taken = tiff.text(sub, DATE_TAKEN).map(|date| {
+                // Stored as "YYYY:MM:DD HH:MM:SS"
+                date.replacen(':', "-", 2)
+            });
+        }
+
+        if let Some(gps) = tiff.sub_ifd(ifd, GPS_IFD) {
+            let coordinate = |value: u16, reference: u16| -> Option<String> {
+                let degrees = tiff.rational(gps, value, 0)?
+                    + tiff.rational(gps, value, 1).unwrap_or(0.0) / 60.0
+                    + tiff.rational(gps, value, 2).unwrap_or(0.0) / 3600.0;
+                let hemisphere = tiff.text(gps, reference)?;
+                Some(format!("{:.5} {}", degrees, hemisphere))
+            };
+            exif.location = match (
+                coordinate(GPS_LATITUDE, GPS_LATITUDE_REF),
+                coordinate(GPS_LONGITUDE, GPS_LONGITUDE_REF),
+            ) {
+                (Some(lat), Some(lon)) => Some(format!("{}, {}", lat, lon)),
+                _ => None,
+            };
+        }
+        Some(exif)
+    }
+}
+
+/// When the photo at `path` was taken, from its EXIF date.
+pub fn taken_time(path: &Path) -> Option<SystemTime> {
+    let head = read_head(path)?;
+    let tiff = Tiff::find(&head)?;
+    let sub = tiff.sub_ifd(tiff.first_ifd()?, EXIF_IFD)?;
+    parse_date(&tiff.text(sub, DATE_TAKEN)?)
+}
+
+/// An EXIF date, "YYYY:MM:DD HH:MM:SS". It has no time zone, so it is read
+/// as local time, as the camera's clock most likely was.
+fn parse_date(date: &str) -> Option<SystemTime> {
+    let fields: Vec<i32> = date
+        .split([':', ' '])
+        .map(|f| f.parse().ok())
+        .collect::<Option<_>>()?;
+    let [year, month, day, hour, minute, second] = fields[..] else {
+        return None;
+    };
+    // Cameras without a set clock write zeros or blanks
+    if year == 0 || !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    let seconds = local_timestamp(year, month, day, hour, minute, second)?;
+    if seconds >= 0 {
+        UNIX_EPOCH.checked_add(Duration::from_secs(seconds as u64))
+    } else {
+        UNIX_EPOCH.checked_sub(Duration::from_secs(seconds.unsigned_abs()))
+    }
+}
+
+/// Seconds since the epoch of a local date and time.
+#[cfg(unix)]
+fn local_timestamp(year: i32, month: i32, day: i32, hour: i32, min: i32, sec: i32) -> Option<i64> {
+    // SAFETY: tm is plain data, and mktime only reads and normalises it
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    tm.tm_year = year - 1900;
+    tm.tm_mon = month - 1;
+    tm.tm_mday = day;
+    tm.tm_hour = hour;
+    tm.tm_min = min;
+    tm.tm_sec = sec;
+    tm.tm_isdst = -1;
+    let t = unsafe { libc::mktime(&mut tm) };
+    (t != -1).then_some(t as i64)
+}
+
+/// Seconds since the epoch of a date and time, taken as UTC where the local
+/// time zone isn't known.
+#[cfg(not(unix))]
+fn local_timestamp(year: i32, month: i32, day: i32, hour: i32, min: i32, sec: i32) -> Option<i64> {
+    // Days from civil, after Howard Hinnant
+    let (y, m) = if month <= 2 { (year - 1, month + 9) } else { (year, month - 3) };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * m + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era as i64 * 146097 + doe as i64 - 719468;
+    Some(days * 86400 + hour as i64 * 3600 + min as i64 * 60 + sec as i64)
+}
+
+/// `x` with at most one decimal place, and none if it is whole.
+fn trim_number(x: f64) -> String {
+    let s = format!("{:.1}", x);
+    s.strip_suffix(".0").map(str::to_string).unwrap_or(s)
+}
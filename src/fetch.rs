@@ -0,0 +1,373 @@
+//! Downloading images given as `http://` or `https://` arguments.
+//!
+//! The transfer is handed to `curl`, which handles TLS, redirects and proxy
+//! settings the way the user already has them configured. Downloads are
+//! kept in a `DownloadCache` when there is one: the next request for the
+//! same URL is conditional, and the kept copy is used when the server can't
+//! be reached.
+
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use crate::urlcache::{DownloadCache, Validators};
+
+/// Refuse downloads larger than this.
+const MAX_DOWNLOAD_BYTES: u64 = 256 * 1024 * 1024;
+
+/// Give up on a server that doesn't take the connection within this long.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Give up on a download that hasn't finished within this long, so a
+/// server that stalls halfway can't keep the viewer from starting.
+const MAX_TIME: Duration = Duration::from_secs(120);
+
+/// curl's exit status when one of its timeouts ran out.
+const CURL_TIMED_OUT: i32 = 28;
+
+/// curl's exit statuses for a server that couldn't be reached at all:
+/// names that don't resolve, refused connections, failed TLS handshakes
+/// and dropped connections.
+const CURL_UNREACHABLE: &[i32] = &[5, 6, 7, 35, 52, 55, 56, CURL_TIMED_OUT];
+
+/// Where the bytes of a download came from.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Origin {
+    /// Downloaded just now.
+    Network,
+    /// The kept copy, which the server said is still current.
+    Unchanged,
+    /// The kept copy, because the server couldn't be reached; why not.
+    Offline(String),
+}
+
+/// What the server answered.
+enum Response {
+    Body(Vec<u8>, Validators),
+    /// 304: the copy the request named is still current.
+    NotModified,
+}
+
+/// Why a request failed.
+enum Failure {
+    /// The server couldn't be reached, so a kept copy will do.
+    Unreachable(String),
+    /// It answered with an error, or curl couldn't run at all.
+    Refused(String),
+}
+
+/// Fetch `url` into memory, asking the server only whether it changed
+/// when `cache` has a copy from before.
+pub fn download(url: &str, cache: Option<&DownloadCache>) -> Result<(Vec<u8>, Origin), String> {
+    download_through(url, cache, |validators| {
+        request(url, validators, CONNECT_TIMEOUT, MAX_TIME)
+    })
+}
+
+/// `download`, making the request with `get`.
+fn download_through(
+    url: &str,
+    cache: Option<&DownloadCache>,
+    get: impl FnOnce(&Validators) -> Result<Response, Failure>,
+) -> Result<(Vec<u8>, Origin), String> {
+    let kept = cache.and_then(|c| c.get(url));
+    let validators = kept.as_ref().map(|k| k.validators.clone()).unwrap_or_default();
+    match (get(&validators), kept, cache) {
+        (Ok(Response::Body(bytes, validators)), _, Some(cache)) => {
+            // Not being able to keep it is no reason not to show it
+            let _ = cache.put(url, &validators, &bytes);
+            Ok((bytes, Origin::Network))
+        }
+        (Ok(Response::Body(bytes, _)), _, None) => Ok((bytes, Origin::Network)),
+        (Ok(Response::NotModified), Some(kept), Some(cache)) => {
+            cache.touch(url);
+            Ok((kept.bytes, Origin::Unchanged))
+        }
+        (Ok(Response::NotModified), _, _) => Err(format!(
+            "couldn't download {}: the server sent nothing",
+            url
+        )),
+        (Err(Failure::Unreachable(why)), Some(kept), Some(cache)) => {
+            cache.touch(url);
+            Ok((kept.bytes, Origin::Offline(why)))
+        }
+        (Err(Failure::Unreachable(why) | Failure::Refused(why)), _, _) => Err(why),
+    }
+}
+
+/// Ask for `url` with curl, conditionally on `validators` if it has any,
+/// giving up after `connect` without a connection or `total` in all.
+fn request(
+    url: &str,
+    validators: &Validators,
+    connect: Duration,
+    total: Duration,
+) -> Result<Response, Failure> {
+    let mut curl = Command::new("curl");
+    curl.args(["--fail", "--silent", "--show-error", "--location", "--include"])
+        .arg("--max-filesize")
+        .arg(MAX_DOWNLOAD_BYTES.to_string())
+        .arg("--connect-timeout")
+        .arg(seconds(connect))
+        .arg("--max-time")
+        .arg(seconds(total));
+    if let Some(ref etag) = validators.etag {
+        curl.arg("--header").arg(format!("If-None-Match: {}", etag));
+    }
+    if let Some(ref modified) = validators.last_modified {
+        curl.arg("--header").arg(format!("If-Modified-Since: {}", modified));
+    }
+    let output = curl
+        .arg("--url")
+        .arg(url)
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => "opening URLs needs curl to be installed".to_string(),
+            _ => format!("couldn't run curl: {}", e),
+        })
+        .map_err(Failure::Refused)?;
+
+    match output.status.code() {
+        Some(0) => {}
+        Some(CURL_TIMED_OUT) => {
+            return Err(Failure::Unreachable(format!(
+                "couldn't download {}: no answer within {}s",
+                url,
+                seconds(total)
+            )))
+        }
+        code => {
+            let detail = String::from_utf8_lossy(&output.stderr);
+            let why = format!("couldn't download {}: {}", url, detail.trim());
+            return Err(match code {
+                Some(code) if CURL_UNREACHABLE.contains(&code) => Failure::Unreachable(why),
+                _ => Failure::Refused(why),
+            });
+        }
+    }
+    let (status, headers, body) = split_response(&output.stdout)
+        .ok_or_else(|| Failure::Refused(format!("couldn't download {}: garbled reply", url)))?;
+    if status == 304 {
+        return Ok(Response::NotModified);
+    }
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.clone())
+    };
+    let validators = Validators {
+        etag: header("etag"),
+        last_modified: header("last-modified"),
+    };
+    Ok(Response::Body(body.to_vec(), validators))
+}
+
+/// Response headers by name, in the order sent.
+type Headers = Vec<(String, String)>;
+
+/// Split what `curl --include` wrote into the final response's status,
+/// headers and body. The headers of every response on the way (redirects,
+/// `100 Continue`, a proxy's tunnel) come first, each block ending in a
+/// blank line.
+fn split_response(out: &[u8]) -> Option<(u16, Headers, &[u8])> {
+    let mut rest = out;
+    let mut last = None;
+    while rest.starts_with(b"HTTP/") {
+        let end = rest.windows(4).position(|w| w == b"\r\n\r\n")?;
+        let block = String::from_utf8_lossy(&rest[..end]);
+        let mut lines = block.split("\r\n");
+        let status = lines.next()?.split(' ').nth(1)?.parse().ok()?;
+        let headers = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+            .collect();
+        last = Some((status, headers));
+        rest = &rest[end + 4..];
+    }
+    let (status, headers) = last?;
+    Some((status, headers, rest))
+}
+
+/// `d` as curl takes timeouts: decimal seconds.
+fn seconds(d: Duration) -> String {
+    format!("{}", d.as_secs_f64())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persistence::Store;
+    use crate::testdir::TestDir;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::time::Instant;
+
+    fn has_curl() -> bool {
+        Command::new("curl").arg("--version").output().is_ok()
+    }
+
+    fn cache_in(tmp: &TestDir) -> DownloadCache {
+        let store = Store {
+            path: Some(tmp.path().join("downloads")),
+            disabled: None,
+        };
+        DownloadCache::new(&store, 1 << 20).unwrap()
+    }
+
+    fn etag(tag: &str) -> Validators {
+        Validators {
+            etag: Some(tag.into()),
+            last_modified: None,
+        }
+    }
+
+    const URL: &str = "https://example.com/graph.png";
+
+    #[test]
+    fn responses_are_split_after_the_last_header_block() {
+        let out = b"HTTP/1.1 301 Moved Permanently\r\nLocation: /b.png\r\n\r\n\
+            HTTP/2 200\r\netag: \"v1\"\r\nLast-Modified: Wed, 21 Oct 2015 07:28:00 GMT\r\n\r\n\
+            \x89PNG\r\n\r\nrest";
+        let (status, headers, body) = split_response(out).unwrap();
+        assert_eq!(status, 200);
+        assert_eq!(headers[0], ("etag".into(), "\"v1\"".into()));
+        assert_eq!(headers[1].1, "Wed, 21 Oct 2015 07:28:00 GMT");
+        // A blank line inside the body is the body's own
+        assert_eq!(body, b"\x89PNG\r\n\r\nrest");
+
+        let (status, _, body) = split_response(b"HTTP/1.1 304 Not Modified\r\n\r\n").unwrap();
+        assert_eq!((status, body), (304, &b""[..]));
+        assert!(split_response(b"no headers").is_none());
+        assert!(split_response(b"HTTP/1.1 200 OK\r\nunfinished").is_none());
+    }
+
+    #[test]
+    fn downloads_are_kept_and_revalidated() {
+        let tmp = TestDir::new();
+        let cache = cache_in(&tmp);
+        let fresh = |_: &Validators| Ok(Response::Body(b"v1".to_vec(), etag("\"1\"")));
+        let got = download_through(URL, Some(&cache), fresh).unwrap();
+        assert_eq!(got, (b"v1".to_vec(), Origin::Network));
+
+        // The next request names the kept copy, and a 304 brings it back
+        let mut asked = None;
+        let unchanged = |v: &Validators| {
+            asked = Some(v.clone());
+            Ok(Response::NotModified)
+        };
+        let got = download_through(URL, Some(&cache), unchanged).unwrap();
+        assert_eq!(got, (b"v1".to_vec(), Origin::Unchanged));
+        assert_eq!(asked, Some(etag("\"1\"")));
+
+        // A changed image replaces it
+        let changed = |_: &Validators| Ok(Response::Body(b"v2".to_vec(), etag("\"2\"")));
+        download_through(URL, Some(&cache), changed).unwrap();
+        assert_eq!(cache.get(URL).unwrap().bytes, b"v2");
+    }
+
+    #[test]
+    fn the_kept_copy_is_used_offline() {
+        let tmp = TestDir::new();
+        let cache = cache_in(&tmp);
+        let offline = || Err(Failure::Unreachable("couldn't resolve host".into()));
+
+        // Nothing kept yet: the failure is the error
+        let e = download_through(URL, Some(&cache), |_| offline()).unwrap_err();
+        assert_eq!(e, "couldn't resolve host");
+
+        cache.put(URL, &etag("\"1\""), b"kept").unwrap();
+        let got = download_through(URL, Some(&cache), |_| offline()).unwrap();
+        let why = "couldn't resolve host".to_string();
+        assert_eq!(got, (b"kept".to_vec(), Origin::Offline(why)));
+
+        // An error from the server isn't being offline: the image is gone
+        let refused = |_: &Validators| Err(Failure::Refused("404".into()));
+        assert_eq!(download_through(URL, Some(&cache), refused).unwrap_err(), "404");
+    }
+
+    #[test]
+    fn no_cache_means_no_conditions() {
+        let tmp = TestDir::new();
+        let cache = cache_in(&tmp);
+        cache.put(URL, &etag("\"1\""), b"kept").unwrap();
+        let mut asked = None;
+        let fresh = |v: &Validators| {
+            asked = Some(v.clone());
+            Ok(Response::Body(b"new".to_vec(), etag("\"2\"")))
+        };
+        let got = download_through(URL, None, fresh).unwrap();
+        assert_eq!(got, (b"new".to_vec(), Origin::Network));
+        assert_eq!(asked, Some(Validators::default()));
+        let offline = |_: &Validators| Err(Failure::Unreachable("down".into()));
+        assert!(download_through(URL, None, offline).is_err());
+        // Left as it was
+        assert_eq!(cache.get(URL).unwrap().bytes, b"kept");
+    }
+
+    /// Answer `requests` requests on a local port: 304 when the request
+    /// names ETag `"1"`, the body with that ETag otherwise.
+    fn serve_with_etag(requests: usize) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/a.png", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for stream in listener.incoming().take(requests) {
+                let mut stream = stream.unwrap();
+                let mut conditional = false;
+                for line in BufReader::new(&stream).lines() {
+                    let line = line.unwrap();
+                    conditional |= line.eq_ignore_ascii_case("if-none-match: \"1\"");
+                    if line.is_empty() {
+                        break;
+                    }
+                }
+                let reply: &[u8] = if conditional {
+                    b"HTTP/1.1 304 Not Modified\r\nETag: \"1\"\r\n\r\n"
+                } else {
+                    b"HTTP/1.1 200 OK\r\nETag: \"1\"\r\nContent-Length: 4\r\n\r\nbody"
+                };
+                stream.write_all(reply).unwrap();
+            }
+        });
+        url
+    }
+
+    #[test]
+    fn curl_makes_conditional_requests() {
+        if !has_curl() {
+            return;
+        }
+        let tmp = TestDir::new();
+        let cache = cache_in(&tmp);
+        let url = serve_with_etag(2);
+        assert_eq!(download(&url, Some(&cache)).unwrap(), (b"body".to_vec(), Origin::Network));
+        assert_eq!(cache.get(&url).unwrap().validators, etag("\"1\""));
+        let again = download(&url, Some(&cache)).unwrap();
+        assert_eq!(again, (b"body".to_vec(), Origin::Unchanged));
+    }
+
+    #[test]
+    fn a_stalled_server_times_out() {
+        if !has_curl() {
+            return;
+        }
+        // Takes the connection, then never answers
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/a.png", listener.local_addr().unwrap());
+        let started = Instant::now();
+        let total = Duration::from_millis(500);
+        let result = request(&url, &Validators::default(), total, total);
+        assert!(started.elapsed() < Duration::from_secs(5));
+        let Err(Failure::Unreachable(e)) = result else {
+            panic!("not a timeout");
+        };
+        assert_eq!(e, format!("couldn't download {}: no answer within 0.5s", url));
+        drop(listener);
+    }
+
+    #[test]
+    fn timeouts_are_given_in_seconds() {
+        assert_eq!(seconds(CONNECT_TIMEOUT), "15");
+        assert_eq!(seconds(Duration::from_millis(250)), "0.25");
+    }
+}
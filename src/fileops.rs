@@ -0,0 +1,828 @@
+//! Deleting, moving and copying the displayed file (`x`, `m`, `c`).
+//!
+//! Deleting moves the file to the freedesktop.org trash, where file managers
+//! can restore it from, unless `--no-trash` asks for it to be removed
+//! outright. Files on the filesystem of the user's data directory go to the
+//! home trash there; files on other filesystems (USB sticks, other
+//! partitions) go to a trash at the top of their own filesystem, as the spec
+//! describes, rather than being copied across.
+//!
+//! The file may have changed since it was shown: rewritten by an editor,
+//! replaced by a download, or removed by another program. Each operation
+//! first checks that the file is still the one shown, and failures come
+//! back as a `Failure` that says what the user can do about it. Operations
+//! of several steps undo the ones already done if a later one fails, so
+//! nothing is left half moved.
+
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::persistence::{access_w, Store};
+use crate::session_log::format_time;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transfer {
+    Move,
+    Copy,
+}
+
+impl Transfer {
+    pub fn verb(self) -> &'static str {
+        match self {
+            Transfer::Move => "Move",
+            Transfer::Copy => "Copy",
+        }
+    }
+}
+
+/// Enough about a file to tell whether it is still the one that was shown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Identity {
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+impl Identity {
+    pub fn of(path: &Path) -> io::Result<Self> {
+        let meta = fs::metadata(path)?;
+        Ok(Identity {
+            len: meta.len(),
+            modified: meta.modified().ok(),
+        })
+    }
+}
+
+/// The file an operation is for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Target {
+    pub path: PathBuf,
+    /// What the file looked like when it was shown, or `None` to act on it
+    /// whatever it looks like now.
+    pub expected: Option<Identity>,
+}
+
+/// Why a file operation didn't happen.
+#[derive(Debug)]
+pub enum Failure {
+    /// The file is no longer there.
+    Gone,
+    /// The file isn't the one that was shown any more.
+    Changed,
+    /// Permission was refused, most likely by `folder`.
+    Denied { folder: PathBuf, owner: Option<String> },
+    /// Another program has the file open and won't let go of it.
+    Locked,
+    Other(io::Error),
+}
+
+impl Failure {
+    /// Make sense of `e`, from an operation on `path` that writes to
+    /// `folders`.
+    fn new(e: io::Error, path: &Path, folders: &[&Path]) -> Self {
+        match e.kind() {
+            // The target folder going missing is reported as it is
+            io::ErrorKind::NotFound if fs::symlink_metadata(path).is_err() => Failure::Gone,
+            io::ErrorKind::PermissionDenied => {
+                let folder = folders
+                    .iter()
+                    .find(|f| access_w(f).is_err())
+                    .copied()
+                    .unwrap_or(path);
+                Failure::Denied {
+                    folder: folder.to_path_buf(),
+                    owner: owner(folder),
+                }
+            }
+            _ if is_locked(&e) => Failure::Locked,
+            _ => Failure::Other(e),
+        }
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Failure::Gone => write!(f, "it is no longer there"),
+            Failure::Changed => write!(f, "it changed on disk since it was shown"),
+            Failure::Denied {
+                folder,
+                owner: Some(owner),
+            } => write!(
+                f,
+                "permission denied: {} belongs to {}",
+                folder.display(),
+                owner
+            ),
+            Failure::Denied { folder, owner: None } => {
+                write!(f, "permission denied: check who owns {}", folder.display())
+            }
+            Failure::Locked => write!(f, "it is in use by another program"),
+            Failure::Other(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Fail with `Failure::Changed` unless `file` still looks as it did when it
+/// was shown.
+fn check(file: &Target) -> Result<(), Failure> {
+    let now = Identity::of(&file.path).map_err(|e| Failure::new(e, &file.path, &[]))?;
+    match file.expected {
+        Some(expected) if expected != now => Err(Failure::Changed),
+        _ => Ok(()),
+    }
+}
+
+/// The folder `path` is in, for blaming a refused permission on.
+fn folder_of(path: &Path) -> &Path {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+}
+
+/// Files created by an operation so far, removed again unless the whole
+/// operation succeeds, so one that fails part way leaves no trace.
+struct Transaction {
+    created: Vec<PathBuf>,
+}
+
+impl Transaction {
+    fn new() -> Self {
+        Transaction { created: Vec::new() }
+    }
+
+    /// `path` was created, and should go if a later step fails.
+    fn created(&mut self, path: &Path) {
+        self.created.push(path.to_path_buf());
+    }
+
+    /// Everything succeeded: keep what was created.
+    fn commit(mut self) {
+        self.created.clear();
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        for path in self.created.drain(..).rev() {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// Move or copy `file` into `dir`, keeping its name. Refuses to overwrite.
+/// Returns the new path.
+pub fn transfer(op: Transfer, file: &Target, dir: &Path) -> Result<PathBuf, Failure> {
+    let path = &file.path;
+    check(file)?;
+    if !dir.is_dir() {
+        return Err(Failure::Other(io::Error::new(
+            io::ErrorKind::NotADirectory,
+            format!("{} is not a directory", dir.display()),
+        )));
+    }
+    let name = path
+        .file_name()
+        .ok_or_else(|| Failure::Other(io::ErrorKind::InvalidInput.into()))?;
+    let target = dir.join(name);
+    let result = match op {
+        Transfer::Copy => {
+            let mut tx = Transaction::new();
+            copy_new(path, &target, &mut tx).map(|()| tx.commit())
+        }
+        Transfer::Move => move_file(path, &target),
+    };
+    match result {
+        Ok(()) => Ok(target),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Err(Failure::Other(io::Error::new(
+            e.kind(),
+            format!("{} already exists", target.display()),
+        ))),
+        Err(e) => {
+            let folders: &[&Path] = match op {
+                Transfer::Copy => &[dir],
+                Transfer::Move => &[dir, folder_of(path)],
+            };
+            Err(Failure::new(e, path, folders))
+        }
+    }
+}
+
+/// Rename, falling back to copy and delete across filesystems. Refuses to
+/// replace a file at `to`.
+fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    match rename_new(from, to) {
+        Err(e) if e.raw_os_error() == Some(libc::EXDEV) => {
+            let mut tx = Transaction::new();
+            copy_new(from, to, &mut tx)?;
+            fs::remove_file(from)?;
+            tx.commit();
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+/// Rename `from` to `to` unless something is at `to` already.
+#[cfg(target_os = "linux")]
+fn rename_new(from: &Path, to: &Path) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = |p: &Path| {
+        CString::new(p.as_os_str().as_bytes())
+            .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))
+    };
+    let (c_from, c_to) = (c_path(from)?, c_path(to)?);
+    let renamed = unsafe {
+        libc::renameat2(
+            libc::AT_FDCWD,
+            c_from.as_ptr(),
+            libc::AT_FDCWD,
+            c_to.as_ptr(),
+            libc::RENAME_NOREPLACE,
+        )
+    };
+    if renamed == 0 {
+        return Ok(());
+    }
+    let e = io::Error::last_os_error();
+    match e.raw_os_error() {
+        // Filesystems that can't rename without replacing
+        Some(libc::EINVAL) | Some(libc::ENOSYS) => rename_checked(from, to),
+        _ => Err(e),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn rename_new(from: &Path, to: &Path) -> io::Result<()> {
+    rename_checked(from, to)
+}
+
+/// Rename `from` to `to` if nothing is at `to`. A file created there in
+/// between the check and the rename is replaced.
+fn rename_checked(from: &Path, to: &Path) -> io::Result<()> {
+    if fs::symlink_metadata(to).is_ok() {
+        return Err(io::ErrorKind::AlreadyExists.into());
+    }
+    fs::rename(from, to)
+}
+
+/// Copy `from` to the new file `to`, keeping its permissions and
+/// modification time. `to` is removed again if `tx` doesn't complete.
+fn copy_new(from: &Path, to: &Path, tx: &mut Transaction) -> io::Result<()> {
+    let mut source = File::open(from)?;
+    let meta = source.metadata()?;
+    let mut copy = OpenOptions::new().write(true).create_new(true).open(to)?;
+    tx.created(to);
+    io::copy(&mut source, &mut copy)?;
+    copy.set_permissions(meta.permissions())?;
+    if let Ok(modified) = meta.modified() {
+        copy.set_modified(modified)?;
+    }
+    // The original may be removed next, so the copy must be on disk first
+    copy.sync_all()
+}
+
+/// Delete `file`, to the trash unless `permanently`.
+pub fn delete(file: &Target, permanently: bool, home_trash: &Store) -> Result<(), Failure> {
+    let path = &file.path;
+    check(file)?;
+    if permanently {
+        return fs::remove_file(path).map_err(|e| Failure::new(e, path, &[folder_of(path)]));
+    }
+    let original = fs::canonicalize(path).map_err(|e| Failure::new(e, path, &[]))?;
+    let bin = trash_for(&original, home_trash).map_err(|e| {
+        Failure::Other(io::Error::new(e.kind(), format!("no trash: {}", e)))
+    })?;
+    trash(&original, &bin).map_err(|e| Failure::new(e, path, &[folder_of(path), &bin.dir]))
+}
+
+/// A trash directory, with `files` and `info` inside.
+#[derive(Debug, PartialEq, Eq)]
+struct Bin {
+    dir: PathBuf,
+    /// For a trash at the top of a filesystem, that top directory. Paths in
+    /// its info files are relative to it, so they still hold when the
+    /// filesystem is mounted somewhere else.
+    top: Option<PathBuf>,
+}
+
+/// The trash for `original`: the home trash if the file is on its
+/// filesystem, otherwise a trash at the top of the file's own.
+#[cfg(unix)]
+fn trash_for(original: &Path, home_trash: &Store) -> io::Result<Bin> {
+    use std::os::unix::fs::MetadataExt;
+
+    let dev = fs::symlink_metadata(original)?.dev();
+    let home = home_trash.require();
+    if let Ok(home) = home {
+        // The trash itself may not have been created yet
+        let existing = home.ancestors().find_map(|dir| fs::metadata(dir).ok());
+        if existing.is_some_and(|meta| meta.dev() == dev) {
+            return Ok(Bin {
+                dir: home.to_path_buf(),
+                top: None,
+            });
+        }
+    }
+    let top = top_dir(original, dev);
+    match top_trash(&top) {
+        Ok(dir) => Ok(Bin {
+            dir,
+            top: Some(top),
+        }),
+        Err(e) => match home {
+            Ok(_) => Err(io::Error::new(
+                e.kind(),
+                format!(
+                    "file is on another filesystem than the home trash, and {} has no trash of its own ({}); use --no-trash",
+                    top.display(),
+                    e
+                ),
+            )),
+            Err(no_home) => Err(no_home),
+        },
+    }
+}
+
+#[cfg(not(unix))]
+fn trash_for(_original: &Path, home_trash: &Store) -> io::Result<Bin> {
+    Ok(Bin {
+        dir: home_trash.require()?.to_path_buf(),
+        top: None,
+    })
+}
+
+/// The top directory of the filesystem `path` is on: its furthest ancestor
+/// on the device `dev`.
+#[cfg(unix)]
+fn top_dir(path: &Path, dev: u64) -> PathBuf {
+    use std::os::unix::fs::MetadataExt;
+
+    path.ancestors()
+        .skip(1)
+        .take_while(|dir| fs::metadata(dir).is_ok_and(|meta| meta.dev() == dev))
+        .last()
+        .unwrap_or(path)
+        .to_path_buf()
+}
+
+/// The user's trash at the top of a filesystem: `$top/.Trash/$uid` if an
+/// administrator set up a shared `.Trash` (a real directory with the
+/// sticky bit, so users can't touch each other's files), otherwise
+/// `$top/.Trash-$uid`. Either is created if missing.
+#[cfg(unix)]
+fn top_trash(top: &Path) -> io::Result<PathBuf> {
+    use std::os::unix::fs::MetadataExt;
+
+    let uid = unsafe { libc::getuid() };
+    let shared = top.join(".Trash");
+    let sticky = fs::symlink_metadata(&shared)
+        .is_ok_and(|meta| meta.is_dir() && meta.mode() & 0o1000 != 0);
+    if sticky {
+        let dir = shared.join(uid.to_string());
+        if private_dir(&dir, uid).is_ok() {
+            return Ok(dir);
+        }
+    }
+    let dir = top.join(format!(".Trash-{}", uid));
+    private_dir(&dir, uid)?;
+    Ok(dir)
+}
+
+/// Create `dir` readable only by `uid` if it's missing, and check that it is
+/// a real directory of theirs if it isn't.
+#[cfg(unix)]
+fn private_dir(dir: &Path, uid: u32) -> io::Result<()> {
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+
+    match fs::DirBuilder::new().mode(0o700).create(dir) {
+        Err(e) if e.kind() != io::ErrorKind::AlreadyExists => return Err(e),
+        _ => {}
+    }
+    let meta = fs::symlink_metadata(dir)?;
+    if !meta.is_dir() || meta.uid() != uid {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} isn't a trash directory of yours", dir.display()),
+        ));
+    }
+    Ok(())
+}
+
+/// Whether `e` says another program has the file locked or busy.
+fn is_locked(e: &io::Error) -> bool {
+    #[cfg(unix)]
+    const LOCKED: &[i32] = &[libc::EBUSY, libc::ETXTBSY];
+    // ERROR_SHARING_VIOLATION and ERROR_LOCK_VIOLATION
+    #[cfg(windows)]
+    const LOCKED: &[i32] = &[32, 33];
+    #[cfg(not(any(unix, windows)))]
+    const LOCKED: &[i32] = &[];
+    e.kind() == io::ErrorKind::ResourceBusy || e.raw_os_error().is_some_and(|c| LOCKED.contains(&c))
+}
+
+/// The name of the user owning `path`, or their uid if it has no name.
+#[cfg(unix)]
+fn owner(path: &Path) -> Option<String> {
+    use std::ffi::CStr;
+    use std::os::unix::fs::MetadataExt;
+
+    let uid = fs::metadata(path).ok()?.uid();
+    let mut entry: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buf = [0 as libc::c_char; 1024];
+    let mut found = std::ptr::null_mut();
+    let status =
+        unsafe { libc::getpwuid_r(uid, &mut entry, buf.as_mut_ptr(), buf.len(), &mut found) };
+    if status != 0 || found.is_null() {
+        return Some(format!("uid {}", uid));
+    }
+    let name = unsafe { CStr::from_ptr(entry.pw_name) };
+    Some(format!("{} (uid {})", name.to_string_lossy(), uid))
+}
+
+#[cfg(not(unix))]
+fn owner(_path: &Path) -> Option<String> {
+    None
+}
+
+/// Move `original`, a canonical path, to the trash `bin`, recording where it
+/// came from so it can be restored. Files on another filesystem than the
+/// trash are refused rather than copied, since that could take a while for
+/// large files.
+fn trash(original: &Path, bin: &Bin) -> io::Result<()> {
+    let files = bin.dir.join("files");
+    let info = bin.dir.join("info");
+    fs::create_dir_all(&files)?;
+    fs::create_dir_all(&info)?;
+
+    let name = original
+        .file_name()
+        .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+    let (stem, ext) = (Path::new(name).file_stem().unwrap_or(name), Path::new(name).extension());
+    let recorded = match &bin.top {
+        Some(top) => original.strip_prefix(top).unwrap_or(original),
+        None => original,
+    };
+
+    for n in 0.. {
+        let mut candidate = name.to_os_string();
+        if n > 0 {
+            candidate = stem.to_os_string();
+            candidate.push(format!(".{}", n));
+            if let Some(ext) = ext {
+                candidate.push(".");
+                candidate.push(ext);
+            }
+        }
+        if files.join(&candidate).exists() {
+            continue;
+        }
+        // Creating the info file first claims the name against other programs
+        let mut info_name = candidate.clone();
+        info_name.push(".trashinfo");
+        let info_path = info.join(info_name);
+        let mut info_file = match OpenOptions::new().write(true).create_new(true).open(&info_path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        };
+        let mut tx = Transaction::new();
+        tx.created(&info_path);
+        let written = write!(
+            info_file,
+            "[Trash Info]\nPath={}\nDeletionDate={}\n",
+            url_encode(recorded),
+            format_time(SystemTime::now(), true).replacen(' ', "T", 1)
+        );
+        let moved = written.and_then(|_| rename_new(original, &files.join(&candidate)));
+        if let Err(e) = moved {
+            if e.kind() == io::ErrorKind::AlreadyExists {
+                continue;
+            }
+            if e.raw_os_error() == Some(libc::EXDEV) {
+                return Err(io::Error::new(
+                    e.kind(),
+                    "file is on another filesystem than the trash (use --no-trash)",
+                ));
+            }
+            return Err(e);
+        }
+        tx.commit();
+        return Ok(());
+    }
+    unreachable!()
+}
+
+/// Percent-encode a path for a `.trashinfo` file or a `file://` URI. The
+/// characters left alone are the ones GLib leaves in paths, so URIs come out
+/// byte for byte as GNOME programs write them, which thumbnail names rely on.
+/// Names that aren't valid UTF-8 are encoded as the bytes they are on disk.
+pub fn url_encode(path: &Path) -> String {
+    #[cfg(unix)]
+    let bytes = std::os::unix::ffi::OsStrExt::as_bytes(path.as_os_str());
+    #[cfg(not(unix))]
+    let lossy = path.to_string_lossy();
+    #[cfg(not(unix))]
+    let bytes = lossy.as_bytes();
+
+    let mut encoded = String::new();
+    for &b in bytes {
+        match b {
+            // Letters, digits and unreserved marks, then the reserved
+            // characters allowed in paths
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' => encoded.push(b as char),
+            b'-' | b'_' | b'.' | b'!' | b'~' | b'*' | b'\'' | b'(' | b')' => encoded.push(b as char),
+            b'/' | b'&' | b'=' | b':' | b'@' | b'+' | b'$' | b',' => encoded.push(b as char),
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TestDir;
+    use std::time::Duration;
+
+    fn shown(path: &Path) -> Target {
+        Target {
+            path: path.to_path_buf(),
+            expected: Some(Identity::of(path).unwrap()),
+        }
+    }
+
+    fn trash_at(path: PathBuf) -> Store {
+        Store {
+            path: Some(path),
+            disabled: None,
+        }
+    }
+
+    #[test]
+    fn copy_keeps_contents_and_time() {
+        let tmp = TestDir::new();
+        let file = tmp.file("a.png", b"pixels");
+        let then = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        File::options().write(true).open(&file).unwrap().set_modified(then).unwrap();
+        let dir = tmp.dir("out");
+
+        let copy = transfer(Transfer::Copy, &shown(&file), &dir).unwrap();
+        assert_eq!(copy, dir.join("a.png"));
+        assert_eq!(fs::read(&copy).unwrap(), b"pixels");
+        assert_eq!(fs::metadata(&copy).unwrap().modified().unwrap(), then);
+        assert!(file.exists());
+    }
+
+    #[test]
+    fn move_refuses_to_overwrite() {
+        let tmp = TestDir::new();
+        let file = tmp.file("a.png", b"new");
+        let existing = tmp.file("out/a.png", b"old");
+
+        let result = transfer(Transfer::Move, &shown(&file), existing.parent().unwrap());
+        match result {
+            Err(Failure::Other(e)) => assert_eq!(e.kind(), io::ErrorKind::AlreadyExists),
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(fs::read(&existing).unwrap(), b"old");
+        assert_eq!(fs::read(&file).unwrap(), b"new");
+    }
+
+    #[test]
+    fn move_renames() {
+        let tmp = TestDir::new();
+        let file = tmp.file("a.png", b"pixels");
+        let dir = tmp.dir("out");
+
+        let moved = transfer(Transfer::Move, &shown(&file), &dir).unwrap();
+        assert!(!file.exists());
+        assert_eq!(fs::read(moved).unwrap(), b"pixels");
+    }
+
+    #[test]
+    fn changed_file_is_refused_until_confirmed() {
+        let tmp = TestDir::new();
+        let file = tmp.file("a.png", b"pixels");
+        let target = shown(&file);
+        fs::write(&file, b"rewritten by an editor").unwrap();
+        let dir = tmp.dir("out");
+
+        assert!(matches!(
+            transfer(Transfer::Move, &target, &dir),
+            Err(Failure::Changed)
+        ));
+        assert!(file.exists());
+        let anyway = Target {
+            expected: None,
+            ..target
+        };
+        assert!(transfer(Transfer::Move, &anyway, &dir).is_ok());
+    }
+
+    #[test]
+    fn missing_file_is_gone() {
+        let tmp = TestDir::new();
+        let file = tmp.file("a.png", b"pixels");
+        let target = shown(&file);
+        fs::remove_file(&file).unwrap();
+
+        let trash = trash_at(tmp.path().join("trash"));
+        assert!(matches!(delete(&target, true, &trash), Err(Failure::Gone)));
+        assert!(matches!(delete(&target, false, &trash), Err(Failure::Gone)));
+        assert!(matches!(
+            transfer(Transfer::Copy, &target, tmp.path()),
+            Err(Failure::Gone)
+        ));
+    }
+
+    #[test]
+    fn missing_destination_is_not_the_file_being_gone() {
+        let tmp = TestDir::new();
+        let file = tmp.file("a.png", b"pixels");
+
+        let result = transfer(Transfer::Move, &shown(&file), &tmp.path().join("nowhere"));
+        match result {
+            Err(Failure::Other(e)) => assert_eq!(e.kind(), io::ErrorKind::NotADirectory),
+            other => panic!("{:?}", other),
+        }
+        assert!(file.exists());
+    }
+
+    #[test]
+    fn failed_copy_leaves_nothing_behind() {
+        let tmp = TestDir::new();
+        // Opening a folder works, reading it doesn't
+        let folder = tmp.dir("folder");
+        let target = tmp.path().join("copy");
+
+        let mut tx = Transaction::new();
+        assert!(copy_new(&folder, &target, &mut tx).is_err());
+        drop(tx);
+        assert!(!target.exists());
+    }
+
+    #[test]
+    fn transaction_keeps_files_once_committed() {
+        let tmp = TestDir::new();
+        let kept = tmp.file("kept", b"");
+        let dropped = tmp.file("dropped", b"");
+
+        let mut tx = Transaction::new();
+        tx.created(&kept);
+        tx.commit();
+        let mut tx = Transaction::new();
+        tx.created(&dropped);
+        drop(tx);
+        assert!(kept.exists());
+        assert!(!dropped.exists());
+    }
+
+    #[test]
+    fn trash_records_where_the_file_was() {
+        let tmp = TestDir::new();
+        let file = tmp.file("a b.png", b"pixels");
+        let trash = trash_at(tmp.path().join("trash"));
+
+        delete(&shown(&file), false, &trash).unwrap();
+        let trash = tmp.path().join("trash");
+        assert!(!file.exists());
+        assert_eq!(fs::read(trash.join("files/a b.png")).unwrap(), b"pixels");
+        let info = fs::read_to_string(trash.join("info/a b.png.trashinfo")).unwrap();
+        let original = fs::canonicalize(tmp.path()).unwrap().join("a b.png");
+        let expected = format!("Path={}\n", url_encode(&original));
+        assert!(info.starts_with("[Trash Info]\n"));
+        assert!(info.contains(&expected), "{}", info);
+        assert!(info.contains("%20"));
+
+        // A second file of the same name gets a name of its own
+        let again = tmp.file("a b.png", b"more");
+        delete(&shown(&again), false, &trash_at(trash.clone())).unwrap();
+        assert_eq!(fs::read(trash.join("files/a b.1.png")).unwrap(), b"more");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn trash_keeps_names_that_are_not_utf8() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let tmp = TestDir::new();
+        let file = tmp.path().join(OsStr::from_bytes(b"caf\xe9.png"));
+        fs::write(&file, b"pixels").unwrap();
+        let trash = tmp.path().join("trash");
+
+        delete(&shown(&file), false, &trash_at(trash.clone())).unwrap();
+        let info = trash.join("info").join(OsStr::from_bytes(b"caf\xe9.png.trashinfo"));
+        let info = fs::read_to_string(info).unwrap();
+        assert!(info.contains("/caf%E9.png\n"), "{}", info);
+        assert!(trash.join("files").join(OsStr::from_bytes(b"caf\xe9.png")).exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn home_trash_on_the_same_filesystem() {
+        let tmp = TestDir::new();
+        let file = tmp.file("a.png", b"");
+        let home = tmp.path().join("home/.local/share/Trash");
+
+        let bin = trash_for(&fs::canonicalize(&file).unwrap(), &trash_at(home.clone())).unwrap();
+        assert_eq!(bin, Bin { dir: home, top: None });
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn top_trash_is_private_to_the_user() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp = TestDir::new();
+        let uid = unsafe { libc::getuid() };
+        let dir = top_trash(tmp.path()).unwrap();
+        assert_eq!(dir, tmp.path().join(format!(".Trash-{}", uid)));
+        assert_eq!(fs::metadata(&dir).unwrap().permissions().mode() & 0o777, 0o700);
+        // Found again the second time
+        assert_eq!(top_trash(tmp.path()).unwrap(), dir);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn shared_top_trash_needs_the_sticky_bit() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp = TestDir::new();
+        let uid = unsafe { libc::getuid() };
+        let shared = tmp.dir(".Trash");
+        assert_eq!(top_trash(tmp.path()).unwrap(), tmp.path().join(format!(".Trash-{}", uid)));
+
+        fs::set_permissions(&shared, fs::Permissions::from_mode(0o1777)).unwrap();
+        assert_eq!(top_trash(tmp.path()).unwrap(), shared.join(uid.to_string()));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinked_top_trash_is_not_used() {
+        let tmp = TestDir::new();
+        let uid = unsafe { libc::getuid() };
+        let elsewhere = tmp.dir("elsewhere");
+        std::os::unix::fs::symlink(&elsewhere, tmp.path().join(format!(".Trash-{}", uid))).unwrap();
+
+        assert!(top_trash(tmp.path()).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn top_trash_records_paths_from_the_top() {
+        let tmp = TestDir::new();
+        let file = tmp.file("photos/a.png", b"pixels");
+        let top = fs::canonicalize(tmp.path()).unwrap();
+        let bin = Bin {
+            dir: top_trash(&top).unwrap(),
+            top: Some(top),
+        };
+
+        trash(&fs::canonicalize(&file).unwrap(), &bin).unwrap();
+        let info = fs::read_to_string(bin.dir.join("info/a.png.trashinfo")).unwrap();
+        assert!(info.contains("\nPath=photos/a.png\n"), "{}", info);
+        assert!(bin.dir.join("files/a.png").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn top_dir_stops_at_the_filesystem_boundary() {
+        use std::os::unix::fs::MetadataExt;
+
+        let tmp = TestDir::new();
+        let file = fs::canonicalize(tmp.file("a/b/c.png", b"")).unwrap();
+        let dev = fs::metadata(&file).unwrap().dev();
+        let top = top_dir(&file, dev);
+        assert!(file.starts_with(&top) && top != file);
+        assert_eq!(fs::metadata(&top).unwrap().dev(), dev);
+        if let Some(above) = top.parent() {
+            assert_ne!(fs::metadata(above).unwrap().dev(), dev);
+        }
+    }
+
+    #[test]
+    fn errors_say_what_to_do() {
+        let tmp = TestDir::new();
+        let file = tmp.file("a.png", b"");
+
+        let denied = Failure::new(io::ErrorKind::PermissionDenied.into(), &file, &[tmp.path()]);
+        assert!(matches!(denied, Failure::Denied { .. }));
+        assert!(denied.to_string().starts_with("permission denied: "));
+        #[cfg(unix)]
+        assert!(matches!(
+            Failure::new(io::Error::from_raw_os_error(libc::EBUSY), &file, &[]),
+            Failure::Locked
+        ));
+        assert!(matches!(
+            Failure::new(io::ErrorKind::Other.into(), &file, &[]),
+            Failure::Other(_)
+        ));
+    }
+}
@@ -0,0 +1,146 @@
+//! Thumbnail grid (`t` / `--gallery`).
+//!
+//! The grid is laid out in fixed-size tiles: a one-cell frame around each
+//! thumbnail, with the filename on the row above the bottom edge. Moving the
+//! selection only repaints the two frames involved, so thumbnails already on
+//! screen are never cleared or retransmitted until the page scrolls.
+
+use std::collections::HashMap;
+use std::ops::Range;
+
+use image::DynamicImage;
+
+use crate::playlist::{EntryId, EntryMap, Playlist};
+use crate::CellRect;
+
+/// Size of one tile in cells, frame included.
+pub const TILE_COLS: u16 = 22;
+pub const TILE_ROWS: u16 = 12;
+
+/// Longest side of cached thumbnails, in pixels. Tiles are drawn from these,
+/// so paging back never decodes the original again.
+pub const THUMB_PX: u32 = 256;
+
+/// Shrink a decoded image, or a thumbnail from the shared cache, to the
+/// thumbnail kept for its tile.
+pub fn fit_thumb(img: &DynamicImage) -> DynamicImage {
+    img.thumbnail(THUMB_PX, THUMB_PX)
+}
+
+pub struct Gallery {
+    /// Whether the grid is on screen rather than the single-image view.
+    pub active: bool,
+    pub selected: usize,
+    /// Index of the first grid row on screen.
+    top_row: usize,
+    /// Tiles that fit on screen, as (columns, rows). Never zero.
+    grid: (usize, usize),
+    /// Column and row of the grid's top left corner, centering it in its area.
+    offset: (u16, u16),
+    /// Thumbnails of the images visited; `None` for files that failed to
+    /// decode.
+    thumbs: EntryMap<Option<DynamicImage>>,
+}
+
+impl Gallery {
+    pub fn new() -> Self {
+        Gallery {
+            active: false,
+            selected: 0,
+            top_row: 0,
+            grid: (1, 1),
+            offset: (0, 0),
+            thumbs: HashMap::new(),
+        }
+    }
+
+    /// Re-layout to fill the `area` cell rectangle.
+    pub fn resize(&mut self, (col, row, cols, rows): CellRect) {
+        let grid_cols = (cols / TILE_COLS).max(1);
+        let grid_rows = (rows / TILE_ROWS).max(1);
+        self.grid = (grid_cols as usize, grid_rows as usize);
+        self.offset = (
+            col + cols.saturating_sub(grid_cols * TILE_COLS) / 2,
+            row + rows.saturating_sub(grid_rows * TILE_ROWS) / 2,
+        );
+        self.scroll_into_view();
+    }
+
+    pub fn columns(&self) -> usize {
+        self.grid.0
+    }
+
+    pub fn per_page(&self) -> usize {
+        self.grid.0 * self.grid.1
+    }
+
+    /// Indices of the images on screen, for a list of `len` images.
+    pub fn visible(&self, len: usize) -> Range<usize> {
+        let first = (self.top_row * self.grid.0).min(len);
+        first..(first + self.per_page()).min(len)
+    }
+
+    /// Select `index` (clamped to the list), scrolling if it is off screen.
+    /// Returns whether the selection moved.
+    pub fn select(&mut self, index: usize, len: usize) -> bool {
+        let index = index.min(len.saturating_sub(1));
+        if index == self.selected {
+            return false;
+        }
+        self.selected = index;
+        self.scroll_into_view();
+        true
+    }
+
+    /// Move the selection by `delta` tiles, stopping at either end.
+    pub fn move_by(&mut self, delta: isize, len: usize) -> bool {
+        let index = (self.selected as isize + delta).max(0) as usize;
+        self.select(index, len)
+    }
+
+    fn scroll_into_view(&mut self) {
+        let row = self.selected / self.grid.0;
+        if row < self.top_row {
+            self.top_row = row;
+        } else if row >= self.top_row + self.grid.1 {
+            self.top_row = row + 1 - self.grid.1;
+        }
+    }
+
+    /// Cells covered by the tile of `index`, which must be visible.
+    pub fn tile_rect(&self, index: usize) -> CellRect {
+        let slot = index - self.top_row * self.grid.0;
+        let col = (slot % self.grid.0) as u16;
+        let row = (slot / self.grid.0) as u16;
+        (
+            self.offset.0 + col * TILE_COLS,
+            self.offset.1 + row * TILE_ROWS,
+            TILE_COLS,
+            TILE_ROWS,
+        )
+    }
+
+    pub fn thumb(&self, id: EntryId) -> Option<&Option<DynamicImage>> {
+        self.thumbs.get(&id)
+    }
+
+    pub fn insert_thumb(&mut self, id: EntryId, thumb: Option<DynamicImage>) {
+        self.thumbs.insert(id, thumb);
+    }
+
+    /// Drop the thumbnail of an image, so it is made again from the file.
+    pub fn forget_thumb(&mut self, id: EntryId) {
+        self.thumbs.remove(&id);
+    }
+
+    /// The first image on screen that has no thumbnail yet.
+    pub fn next_missing(&self, images: &Playlist) -> Option<usize> {
+        self.visible(images.len())
+            .find(|&i| images.id(i).is_some_and(|id| !self.thumbs.contains_key(&id)))
+    }
+}
+
+/// The part of a tile the thumbnail is drawn in.
+pub fn thumb_area((col, row, w, h): CellRect) -> CellRect {
+    (col + 1, row + 1, w - 2, h - 3)
+}
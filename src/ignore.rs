@@ -0,0 +1,278 @@
+//! Leaving files and folders out of the listing: `--exclude` patterns and a
+//! `.termviewignore` file at the browsed directory, both in the style of
+//! `.gitignore`.
+//!
+//! The supported syntax is the part of gitignore that makes sense for a
+//! photo library: `*`, `?` and `[...]` within a path component, `**` across
+//! components, a trailing `/` for directories only, a leading or inner `/`
+//! to anchor a pattern at the root, `!` to take an earlier match back, and
+//! `#` comments. Patterns are matched against paths relative to the root.
+
+use std::path::Path;
+
+/// Name of the pattern file read from the browsed directory.
+pub const IGNORE_FILE: &str = ".termviewignore";
+
+/// A folder holding a file of this name is skipped, as Android's media
+/// scanner does.
+pub const NOMEDIA_FILE: &str = ".nomedia";
+
+#[derive(Debug, Clone)]
+struct Rule {
+    /// Path components, with `**` standing for any number of them.
+    components: Vec<String>,
+    negated: bool,
+    dir_only: bool,
+}
+
+/// A list of ignore patterns; the last one matching a path decides.
+#[derive(Debug, Clone, Default)]
+pub struct IgnoreRules {
+    rules: Vec<Rule>,
+}
+
+impl IgnoreRules {
+    /// Rules from the lines of a pattern file.
+    pub fn parse(text: &str) -> Self {
+        let mut rules = IgnoreRules::default();
+        for line in text.lines() {
+            rules.add(line);
+        }
+        rules
+    }
+
+    /// The rules in `dir`'s `.termviewignore`, if it has one.
+    pub fn load(dir: &Path) -> Self {
+        std::fs::read_to_string(dir.join(IGNORE_FILE))
+            .map(|text| IgnoreRules::parse(&text))
+            .unwrap_or_default()
+    }
+
+    /// Add one pattern. Blank lines and comments are skipped.
+    pub fn add(&mut self, pattern: &str) {
+        let mut pattern = pattern.trim_end_matches(['\r', '\n']);
+        // Trailing spaces are dropped unless escaped, as git does
+        if !pattern.ends_with("\\ ") {
+            pattern = pattern.trim_end_matches(' ');
+        }
+        if pattern.is_empty() || pattern.starts_with('#') {
+            return;
+        }
+        let negated = pattern.starts_with('!');
+        if negated {
+            pattern = &pattern[1..];
+        }
+        // A leading backslash lets a pattern start with `#` or `!`
+        if pattern.starts_with("\\#") || pattern.starts_with("\\!") {
+            pattern = &pattern[1..];
+        }
+
+        let dir_only = pattern.ends_with('/');
+        let pattern = pattern.trim_end_matches('/');
+        if pattern.is_empty() {
+            return;
+        }
+        // A pattern without a slash matches a name at any depth
+        let anchored = pattern.contains('/');
+        let mut components: Vec<String> = Vec::new();
+        if !anchored {
+            components.push("**".into());
+        }
+        components.extend(
+            pattern
+                .trim_start_matches('/')
+                .split('/')
+                .filter(|c| !c.is_empty())
+                .map(String::from),
+        );
+        self.rules.push(Rule {
+            components,
+            negated,
+            dir_only,
+        });
+    }
+
+    /// Whether `relative`, a path below the root, is left out.
+    pub fn is_ignored(&self, relative: &Path, is_dir: bool) -> bool {
+        if self.rules.is_empty() {
+            return false;
+        }
+        let components: Vec<String> = relative
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy().into_owned())
+            .collect();
+        let components: Vec<&str> = components.iter().map(String::as_str).collect();
+        self.rules
+            .iter()
+            .rev()
+            .find(|rule| (is_dir || !rule.dir_only) && matches_path(&rule.components, &components))
+            .is_some_and(|rule| !rule.negated)
+    }
+}
+
+/// Match path components against pattern components, `**` taking any number.
+fn matches_path(pattern: &[String], path: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((first, rest)) if first == "**" => {
+            (0..=path.len()).any(|skip| matches_path(rest, &path[skip..]))
+        }
+        Some((first, rest)) => match path.split_first() {
+            Some((name, path)) => matches_name(first, name) && matches_path(rest, path),
+            None => false,
+        },
+    }
+}
+
+/// Match one file or folder name against a pattern with `*`, `?`, `[...]`
+/// and backslash escapes.
+fn matches_name(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    matches_chars(&pattern, &name)
+}
+
+fn matches_chars(pattern: &[char], name: &[char]) -> bool {
+    let Some((&p, rest)) = pattern.split_first() else {
+        return name.is_empty();
+    };
+    match p {
+        '*' => (0..=name.len()).any(|skip| matches_chars(rest, &name[skip..])),
+        '?' => !name.is_empty() && matches_chars(rest, &name[1..]),
+        '[' => {
+            let Some((&c, name_rest)) = name.split_first() else {
+                return false;
+            };
+            match match_class(rest, c) {
+                Some((matched, after)) => matched && matches_chars(after, name_rest),
+                // An unclosed bracket is an ordinary character
+                None => c == '[' && matches_chars(rest, name_rest),
+            }
+        }
+        '\\' if !rest.is_empty() => {
+            name.first() == Some(&rest[0]) && matches_chars(&rest[1..], &name[1..])
+        }
+        p => name.first() == Some(&p) && matches_chars(rest, &name[1..]),
+    }
+}
+
+/// Match `c` against the class starting just after a `[`. Returns whether it
+/// matched and the pattern after the closing `]`, or `None` if there is none.
+fn match_class(class: &[char], c: char) -> Option<(bool, &[char])> {
+    let (negated, mut i) = match class.first() {
+        Some('!') | Some('^') => (true, 1),
+        _ => (false, 0),
+    };
+    let mut matched = false;
+    let mut first = true;
+    loop {
+        let &start = class.get(i)?;
+        // A `]` straight after the opening bracket is part of the class
+        if start == ']' && !first {
+            return Some((matched != negated, &class[i + 1..]));
+        }
+        first = false;
+        if class.get(i + 1) == Some(&'-') && class.get(i + 2).is_some_and(|&end| end != ']') {
+            let end = class[i + 2];
+            matched |= (start..=end).contains(&c);
+            i += 3;
+        } else {
+            matched |= start == c;
+            i += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ignored(patterns: &str, path: &str) -> bool {
+        IgnoreRules::parse(patterns).is_ignored(Path::new(path), false)
+    }
+
+    fn ignored_dir(patterns: &str, path: &str) -> bool {
+        IgnoreRules::parse(patterns).is_ignored(Path::new(path), true)
+    }
+
+    #[test]
+    fn names_match_at_any_depth() {
+        assert!(ignored("*.tmp", "a.tmp"));
+        assert!(ignored("*.tmp", "deep/down/a.tmp"));
+        assert!(ignored("Thumbs.db", "x/Thumbs.db"));
+        assert!(!ignored("*.tmp", "a.tmp.png"));
+        assert!(!ignored("thumbs.db", "Thumbs.db"));
+        assert!(ignored_dir("cache", "photos/cache"));
+    }
+
+    #[test]
+    fn slashes_anchor_at_the_root() {
+        assert!(ignored("/raw.png", "raw.png"));
+        assert!(!ignored("/raw.png", "sub/raw.png"));
+        assert!(ignored("sub/*.png", "sub/a.png"));
+        assert!(!ignored("sub/*.png", "other/sub/a.png"));
+        assert!(!ignored("sub/*.png", "sub/deeper/a.png"));
+    }
+
+    #[test]
+    fn double_stars_cross_folders() {
+        assert!(ignored("**/drafts/*.png", "drafts/a.png"));
+        assert!(ignored("**/drafts/*.png", "x/y/drafts/a.png"));
+        assert!(ignored("a/**/b.png", "a/b.png"));
+        assert!(ignored("a/**/b.png", "a/x/y/b.png"));
+        assert!(!ignored("a/**/b.png", "c/a/x/b.png"));
+        assert!(ignored("export/**", "export/x/y.png"));
+    }
+
+    #[test]
+    fn trailing_slash_only_matches_folders() {
+        assert!(ignored_dir("cache/", "cache"));
+        assert!(ignored_dir("cache/", "a/b/cache"));
+        assert!(!ignored("cache/", "cache"));
+        assert!(ignored_dir("/out/", "out"));
+        assert!(!ignored_dir("/out/", "a/out"));
+    }
+
+    #[test]
+    fn later_negations_take_matches_back() {
+        let rules = "*.png\n!keep*.png\n";
+        assert!(ignored(rules, "a.png"));
+        assert!(!ignored(rules, "keep.png"));
+        assert!(!ignored(rules, "sub/keep-2.png"));
+        // The last matching rule wins, so a later pattern ignores it again
+        assert!(ignored("*.png\n!keep*.png\nkeep-old.png", "keep-old.png"));
+        assert!(!ignored("!a.png", "a.png"));
+    }
+
+    #[test]
+    fn wildcards_stay_within_a_name() {
+        assert!(ignored("img?.png", "img1.png"));
+        assert!(!ignored("img?.png", "img10.png"));
+        assert!(!ignored("a*b.png", "a/x/b.png"));
+        assert!(ignored("a*", "a"));
+        assert!(ignored("[ab]*.png", "b1.png"));
+        assert!(!ignored("[ab]*.png", "c1.png"));
+        assert!(ignored("img[0-9].png", "img7.png"));
+        assert!(!ignored("img[!0-9].png", "img7.png"));
+        assert!(ignored("img[^0-9].png", "imgx.png"));
+        assert!(ignored("[]]x", "]x"));
+        assert!(ignored("[a-]x", "-x"));
+        // An unclosed bracket is taken literally
+        assert!(ignored("[oops", "[oops"));
+    }
+
+    #[test]
+    fn comments_blanks_and_escapes() {
+        let rules = IgnoreRules::parse("# a comment\n\n   \n\r\n");
+        assert!(rules.rules.is_empty());
+        assert!(ignored("\\#hash.png", "#hash.png"));
+        assert!(ignored("\\!bang.png", "!bang.png"));
+        assert!(ignored("a\\*.png", "a*.png"));
+        assert!(!ignored("a\\*.png", "ab.png"));
+        assert!(ignored("spaced.png   ", "spaced.png"));
+        assert!(ignored("space\\ ", "space "));
+        assert!(ignored("crlf.png\r\n", "crlf.png"));
+        // Patterns that are nothing but slashes or a bang match nothing
+        assert!(IgnoreRules::parse("/\n!\n!/").rules.is_empty());
+    }
+}
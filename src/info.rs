@@ -0,0 +1,171 @@
+//! What the info panel (`i`) lists about the displayed image: facts about
+//! the file, its EXIF data, and a histogram of its brightness.
+
+use std::path::Path;
+use std::time::SystemTime;
+
+use image::{ColorType, DynamicImage, GenericImageView};
+
+use crate::exif::{self, Exif};
+use crate::session_log::format_time;
+
+/// Roughly how many pixels are sampled for the histogram, however large the
+/// image. Enough for its shape, and quick even on a 100 megapixel photo.
+const HISTOGRAM_SAMPLES: u64 = 64 * 1024;
+
+/// Block characters for an eighth to all of a cell's height, filled from the bottom.
+const BARS: [char; 9] = [' ', '▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// What can be told about an image without decoding it.
+#[derive(Debug, Default, Clone)]
+pub struct FileFacts {
+    size: Option<u64>,
+    format: Option<String>,
+    modified: Option<SystemTime>,
+    exif: Exif,
+}
+
+impl FileFacts {
+    pub fn read(path: &Path) -> FileFacts {
+        let meta = std::fs::metadata(path).ok();
+        let head = exif::read_head(path).unwrap_or_default();
+        let extension = path
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_uppercase());
+        FileFacts {
+            size: meta.as_ref().map(|m| m.len()),
+            format: format_name(&head).or(extension),
+            modified: meta.and_then(|m| m.modified().ok()),
+            exif: Exif::parse(&head).unwrap_or_default(),
+        }
+    }
+
+    /// Facts about an image held in memory, such as one read from stdin.
+    pub fn from_bytes(bytes: &[u8]) -> FileFacts {
+        FileFacts {
+            size: Some(bytes.len() as u64),
+            format: format_name(bytes),
+            modified: None,
+            exif: Exif::parse(bytes).unwrap_or_default(),
+        }
+    }
+}
+
+/// The name of the format `bytes` start with, e.g. "PNG".
+fn format_name(bytes: &[u8]) -> Option<String> {
+    let format = image::guess_format(bytes).ok()?;
+    let ext = format.extensions_str().first()?;
+    Some(ext.to_uppercase())
+}
+
+pub struct Info {
+    /// Label and value of each field known for the image, in display order.
+    /// Fields the file doesn't record are left out.
+    pub fields: Vec<(&'static str, String)>,
+    /// How many sampled pixels have each luminance, 0 to 255. Fully
+    /// transparent pixels aren't counted.
+    histogram: [u32; 256],
+}
+
+impl Info {
+    pub fn new(facts: FileFacts, img: &DynamicImage) -> Info {
+        let exif = facts.exif;
+        let fields = [
+            ("Format", facts.format),
+            ("Colour", Some(color_name(img.color()).to_string())),
+            ("Size", facts.size.map(format_size)),
+            ("Modified", facts.modified.map(|t| format_time(t, true))),
+            ("Camera", exif.camera),
+            ("Taken", exif.taken),
+            ("Exposure", exif.exposure),
+            ("Aperture", exif.aperture),
+            ("ISO", exif.iso),
+            ("Focal len", exif.focal_length),
+            ("GPS", exif.location),
+        ];
+        Info {
+            fields: fields
+                .into_iter()
+                .filter_map(|(label, value)| Some((label, value?)))
+                .collect(),
+            histogram: histogram(img),
+        }
+    }
+
+    /// The histogram drawn as `rows` lines of `width` bars, top line first.
+    pub fn histogram_lines(&self, width: usize, rows: usize) -> Vec<String> {
+        if width == 0 {
+            return Vec::new();
+        }
+        // Each bar sums the luminance levels that fall in its column
+        let mut bars = vec![0u64; width];
+        for (level, &count) in self.histogram.iter().enumerate() {
+            bars[level * width / 256] += count as u64;
+        }
+        let tallest = bars.iter().copied().max().unwrap_or(0).max(1);
+        let eighths: Vec<u64> = bars
+            .iter()
+            .map(|&bar| (bar * rows as u64 * 8).div_ceil(tallest))
+            .collect();
+        (0..rows)
+            .map(|row| {
+                let below = (rows - 1 - row) as u64 * 8;
+                eighths
+                    .iter()
+                    .map(|&e| BARS[e.saturating_sub(below).min(8) as usize])
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+/// Count the luminance of pixels on an even grid over `img`.
+fn histogram(img: &DynamicImage) -> [u32; 256] {
+    let mut counts = [0u32; 256];
+    let (w, h) = img.dimensions();
+    let pixels = w as u64 * h as u64;
+    let step = ((pixels as f64 / HISTOGRAM_SAMPLES as f64).sqrt().ceil() as u32).max(1);
+    for y in (0..h).step_by(step as usize) {
+        for x in (0..w).step_by(step as usize) {
+            let [r, g, b, a] = img.get_pixel(x, y).0;
+            if a == 0 {
+                continue;
+            }
+            // Rec. 709 weights, in 256ths
+            let luma = (54 * r as u32 + 183 * g as u32 + 19 * b as u32) >> 8;
+            counts[luma as usize] += 1;
+        }
+    }
+    counts
+}
+
+fn color_name(color: ColorType) -> &'static str {
+    match color {
+        ColorType::L8 => "grey, 8-bit",
+        ColorType::La8 => "grey + alpha, 8-bit",
+        ColorType::Rgb8 => "RGB, 8-bit",
+        ColorType::Rgba8 => "RGBA, 8-bit",
+        ColorType::L16 => "grey, 16-bit",
+        ColorType::La16 => "grey + alpha, 16-bit",
+        ColorType::Rgb16 => "RGB, 16-bit",
+        ColorType::Rgba16 => "RGBA, 16-bit",
+        ColorType::Rgb32F => "RGB, 32-bit float",
+        ColorType::Rgba32F => "RGBA, 32-bit float",
+        _ => "other",
+    }
+}
+
+/// A file size in bytes, KiB or MiB.
+fn format_size(bytes: u64) -> String {
+    const KIB: f64 = 1024.0;
+    let b = bytes as f64;
+    if b < KIB {
+        format!("{} bytes", bytes)
+    } else if b < KIB * KIB {
+        format!("{:.1} KiB", b / KIB)
+    } else if b < KIB * KIB * KIB {
+        format!("{:.1} MiB", b / (KIB * KIB))
+    } else {
+        format!("{:.2} GiB", b / (KIB * KIB * KIB))
+    }
+}
@@ -0,0 +1,568 @@
+//! Building the image list from directories: which files are included
+//! (`--recursive`, `--exclude`, `.termviewignore`, `.nomedia`) and the order
+//! they are shown in (`--sort`, `--reverse`, `--shuffle`, `--latest`).
+//!
+//! A watched directory is rescanned every few hundred milliseconds. Rather
+//! than building and sorting a new list each time, `rescan` works out which
+//! files came and went and patches the existing list, so the order of the
+//! files that stayed is not recomputed.
+
+use std::cmp::Ordering;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::decoders::Decoder;
+use crate::exif;
+use crate::ignore::{IgnoreRules, NOMEDIA_FILE};
+use crate::is_image_file;
+
+/// Order of the images within a directory (`--sort`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum SortOrder {
+    /// By name, ignoring case
+    #[default]
+    Name,
+    /// By modification time, oldest first
+    Mtime,
+    /// By file size, smallest first
+    Size,
+    /// By name, comparing runs of digits as numbers (img2 before img10)
+    Natural,
+    /// By the date taken from EXIF, files without one by modification time
+    Taken,
+}
+
+/// Which date makes an image the newest (`--latest`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Newest {
+    /// The file's modification time
+    Mtime,
+    /// The date taken from EXIF, falling back to the modification time
+    Exif,
+}
+
+impl Newest {
+    /// The order that puts the newest image last, before reversing.
+    pub fn sort_order(self) -> SortOrder {
+        match self {
+            Newest::Mtime => SortOrder::Mtime,
+            Newest::Exif => SortOrder::Taken,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ListOptions {
+    pub decoders: Vec<Decoder>,
+    pub sort: SortOrder,
+    pub reverse: bool,
+    /// Include images in subdirectories too.
+    pub recursive: bool,
+    /// Paths left out, relative to the browsed directory (`--exclude`).
+    pub exclude: IgnoreRules,
+    /// Don't read `.termviewignore` or skip folders marked `.nomedia`.
+    pub no_ignore: bool,
+    /// Shuffle instead of sorting, in the order this seed gives. Each file's
+    /// place depends only on the seed and its path, so files that appear
+    /// later slot in without reshuffling the others.
+    pub shuffle: Option<u64>,
+}
+
+impl ListOptions {
+    /// The images in `dir`, in display order.
+    pub fn collect(&self, dir: &Path) -> Vec<PathBuf> {
+        let mut images = Vec::new();
+        self.walk(dir, &mut images);
+        self.sort(dir, &mut images);
+        dedupe(&mut images);
+        images
+    }
+
+    /// Bring `images`, an earlier `collect(dir)`, up to date with what is in
+    /// `dir` now, returning what was added and removed. The result is the list
+    /// `collect` would give, except that of two names for the same file the
+    /// one already listed is kept.
+    pub fn rescan(&self, dir: &Path, images: &mut Vec<PathBuf>) -> ListDiff {
+        let mut found = Vec::new();
+        self.walk(dir, &mut found);
+        let mut changes = diff(images, &found);
+
+        // Links to files already listed are left out, as `collect` does
+        if changes.added.iter().any(|p| p.is_symlink()) {
+            let gone: HashSet<&PathBuf> = changes.removed.iter().collect();
+            let listed: HashSet<PathBuf> = images
+                .iter()
+                .filter(|p| !gone.contains(p))
+                .map(|p| canonical(p))
+                .collect();
+            changes.added.retain(|p| !listed.contains(&canonical(p)));
+        }
+        self.sort(dir, &mut changes.added);
+        dedupe(&mut changes.added);
+
+        match self.sort {
+            // A shuffled file's place depends only on its path, like a name's
+            _ if self.shuffle.is_some() => {
+                apply(images, &changes, |p| self.sort_key(dir, p), self.reverse)
+            }
+            SortOrder::Name | SortOrder::Natural => {
+                apply(images, &changes, |p| self.sort_key(dir, p), self.reverse)
+            }
+            // Times and sizes change while files are written, so every file's
+            // place has to be checked again
+            SortOrder::Mtime | SortOrder::Size | SortOrder::Taken => {
+                let gone: HashSet<&PathBuf> = changes.removed.iter().collect();
+                images.retain(|p| !gone.contains(p));
+                images.extend(changes.added.iter().cloned());
+                self.sort(dir, images);
+            }
+        }
+        changes
+    }
+
+    /// Find the images under `root`. The root's `.termviewignore` is read
+    /// afresh each time, so edits to it show up on the next rescan.
+    fn walk(&self, root: &Path, images: &mut Vec<PathBuf>) {
+        self.visit(root, &mut |path| {
+            if is_image_file(&path, &self.decoders) {
+                images.push(path);
+            }
+        });
+    }
+
+    /// Count the files `collect(dir)` looks at, images or not.
+    pub fn count_files(&self, dir: &Path) -> usize {
+        let mut count = 0;
+        self.visit(dir, &mut |_| count += 1);
+        count
+    }
+
+    /// Call `found` with every file under `root` that isn't ignored, in the
+    /// folders a listing descends into.
+    fn visit(&self, root: &Path, found: &mut dyn FnMut(PathBuf)) {
+        let rules = if self.no_ignore {
+            IgnoreRules::default()
+        } else {
+            IgnoreRules::load(root)
+        };
+        self.visit_dir(root, root, &rules, found);
+    }
+
+    fn visit_dir(
+        &self,
+        root: &Path,
+        dir: &Path,
+        rules: &IgnoreRules,
+        found: &mut dyn FnMut(PathBuf),
+    ) {
+        let entries = std::fs::read_dir(dir).ok().into_iter().flatten();
+        for entry in entries.filter_map(|e| e.ok()) {
+            let path = entry.path();
+            // Symlinked directories aren't descended into, so a link cycle can't recurse forever
+            let is_dir = entry.file_type().is_ok_and(|t| t.is_dir());
+            // Ignored folders are skipped whole rather than walked and filtered
+            let relative = path.strip_prefix(root).unwrap_or(&path);
+            if self.exclude.is_ignored(relative, is_dir) || rules.is_ignored(relative, is_dir) {
+                continue;
+            }
+            if is_dir {
+                let hidden = entry.file_name().to_string_lossy().starts_with('.');
+                let nomedia = !self.no_ignore && path.join(NOMEDIA_FILE).exists();
+                if self.recursive && !hidden && !nomedia {
+                    self.visit_dir(root, &path, rules, found);
+                }
+            } else if path.is_file() {
+                found(path);
+            }
+        }
+    }
+
+    /// Shuffle a list built some other way, e.g. from several paths, if
+    /// shuffling was asked for.
+    pub fn shuffle(&self, images: &mut [PathBuf]) {
+        if self.shuffle.is_some() {
+            self.sort(Path::new(""), images);
+        }
+    }
+
+    /// Sort images found under `root`.
+    fn sort(&self, root: &Path, images: &mut [PathBuf]) {
+        images.sort_by_cached_key(|p| self.sort_key(root, p));
+        if self.reverse {
+            images.reverse();
+        }
+    }
+
+    /// Where `path` goes in the list. Names are compared by their path below
+    /// `root`, so a recursive listing keeps each folder together. Ties are
+    /// broken on the exact path, so no two files compare equal and the order
+    /// never depends on the order the directory was read in.
+    fn sort_key(&self, root: &Path, path: &Path) -> SortKey {
+        if let Some(seed) = self.shuffle {
+            let mut hasher = DefaultHasher::new();
+            (seed, path).hash(&mut hasher);
+            return SortKey::Shuffled(hasher.finish(), path.as_os_str().to_owned());
+        }
+        let relative = path.strip_prefix(root).unwrap_or(path);
+        let name = (relative.as_os_str().to_ascii_lowercase(), path.as_os_str().to_owned());
+        match self.sort {
+            SortOrder::Name => SortKey::Name(name),
+            SortOrder::Natural => {
+                let natural = NaturalKey(relative.to_string_lossy().into_owned());
+                SortKey::Natural(natural, name.1)
+            }
+            SortOrder::Mtime => {
+                let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+                SortKey::Mtime(modified, name)
+            }
+            SortOrder::Taken => {
+                let taken = exif::taken_time(path)
+                    .or_else(|| std::fs::metadata(path).and_then(|m| m.modified()).ok());
+                SortKey::Mtime(taken, name)
+            }
+            SortOrder::Size => {
+                let size = std::fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+                SortKey::Size(size, name)
+            }
+        }
+    }
+}
+
+/// Sort key for one of the `SortOrder`s. A list only ever holds one kind.
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+enum SortKey {
+    Name((OsString, OsString)),
+    Natural(NaturalKey, OsString),
+    Mtime(Option<SystemTime>, (OsString, OsString)),
+    Size(u64, (OsString, OsString)),
+    Shuffled(u64, OsString),
+}
+
+/// Files that appeared in or disappeared from a directory between two scans.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ListDiff {
+    pub added: Vec<PathBuf>,
+    pub removed: Vec<PathBuf>,
+}
+
+impl ListDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+}
+
+/// What changed going from the listed images `old` to the images `found` on
+/// disk, which may be in any order. Both keep the order of their input.
+pub fn diff(old: &[PathBuf], found: &[PathBuf]) -> ListDiff {
+    let before: HashSet<&PathBuf> = old.iter().collect();
+    let after: HashSet<&PathBuf> = found.iter().collect();
+    ListDiff {
+        added: found.iter().filter(|p| !before.contains(p)).cloned().collect(),
+        removed: old.iter().filter(|p| !after.contains(p)).cloned().collect(),
+    }
+}
+
+/// Remove `changes.removed` from `images` and insert `changes.added` where
+/// they belong, given that `images` is sorted by `key` (largest first when
+/// `descending`). Entries that stay keep their relative order.
+pub fn apply<K: Ord>(
+    images: &mut Vec<PathBuf>,
+    changes: &ListDiff,
+    key: impl Fn(&Path) -> K,
+    descending: bool,
+) {
+    if !changes.removed.is_empty() {
+        let gone: HashSet<&PathBuf> = changes.removed.iter().collect();
+        images.retain(|p| !gone.contains(p));
+    }
+    for path in &changes.added {
+        let new = key(path);
+        let at = images.partition_point(|p| {
+            let ord = key(p).cmp(&new);
+            if descending {
+                ord == Ordering::Greater
+            } else {
+                ord == Ordering::Less
+            }
+        });
+        images.insert(at, path.clone());
+    }
+}
+
+fn canonical(path: &Path) -> PathBuf {
+    std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Drop later entries that are the same file as an earlier one, e.g. through
+/// a symlink or because it was named twice on the command line.
+pub fn dedupe(images: &mut Vec<PathBuf>) {
+    let mut seen = HashSet::new();
+    images.retain(|p| seen.insert(canonical(p)));
+}
+
+/// A name that sorts with runs of digits compared as numbers.
+#[derive(PartialEq, Eq)]
+struct NaturalKey(String);
+
+impl Ord for NaturalKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        natural_cmp(&self.0, &other.0).then_with(|| self.0.cmp(&other.0))
+    }
+}
+
+impl PartialOrd for NaturalKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Compare `a` and `b` ignoring case, with each run of ASCII digits compared
+/// by its numeric value (leading zeros ignored) rather than digit by digit.
+fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a.chars().peekable(), b.chars().peekable());
+    loop {
+        let ord = match (a.peek(), b.peek()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let x = take_number(&mut a);
+                let y = take_number(&mut b);
+                // Without leading zeros, a longer run is a bigger number
+                x.len().cmp(&y.len()).then_with(|| x.cmp(&y))
+            }
+            (Some(&x), Some(&y)) => {
+                a.next();
+                b.next();
+                x.to_lowercase().cmp(y.to_lowercase())
+            }
+        };
+        if ord != Ordering::Equal {
+            return ord;
+        }
+    }
+}
+
+/// Consume a run of digits, returning it without leading zeros.
+fn take_number(chars: &mut std::iter::Peekable<std::str::Chars>) -> String {
+    let mut digits = String::new();
+    while let Some(c) = chars.next_if(|c| c.is_ascii_digit()) {
+        if !(digits.is_empty() && c == '0') {
+            digits.push(c);
+        }
+    }
+    digits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ignore::IGNORE_FILE;
+    use crate::testdir::TestDir;
+
+    fn recursive() -> ListOptions {
+        ListOptions {
+            recursive: true,
+            ..ListOptions::default()
+        }
+    }
+
+    /// The listing of `dir`, as paths below it.
+    fn names(options: &ListOptions, dir: &TestDir) -> Vec<String> {
+        let images = options.collect(dir.path());
+        let relative = images.iter().map(|p| p.strip_prefix(dir.path()).unwrap());
+        relative.map(|p| p.to_string_lossy().into_owned()).collect()
+    }
+
+    #[test]
+    fn nomedia_folders_and_ignore_file_leave_images_out() {
+        let dir = TestDir::new();
+        dir.file("a.png", b"");
+        dir.file("cache/b.png", b"");
+        dir.file("phone/.nomedia", b"");
+        dir.file("phone/c.png", b"");
+        dir.file("phone/deeper/d.png", b"");
+        dir.file("trips/e.png", b"");
+        dir.file("trips/e.tmp.png", b"");
+        dir.file(IGNORE_FILE, b"cache/\n*.tmp.png\n");
+
+        assert_eq!(names(&recursive(), &dir), ["a.png", "trips/e.png"]);
+
+        let everything = ListOptions {
+            no_ignore: true,
+            ..recursive()
+        };
+        let all = names(&everything, &dir);
+        assert_eq!(all.len(), 6, "{:?}", all);
+    }
+
+    #[test]
+    fn exclude_patterns_add_to_the_ignore_file() {
+        let dir = TestDir::new();
+        dir.file("a.png", b"");
+        dir.file("b.png", b"");
+        dir.file("raw/c.png", b"");
+        dir.file(IGNORE_FILE, b"a.png\n");
+        let mut options = recursive();
+        options.exclude.add("raw/");
+        assert_eq!(names(&options, &dir), ["b.png"]);
+
+        // --no-ignore drops the file's patterns, not the command line's
+        options.no_ignore = true;
+        assert_eq!(names(&options, &dir), ["a.png", "b.png"]);
+    }
+
+    #[test]
+    fn ignored_folders_are_not_descended_into() {
+        let dir = TestDir::new();
+        dir.file("keep.png", b"");
+        dir.file("node_modules/keep.png", b"");
+        dir.file(IGNORE_FILE, b"node_modules/\n!keep.png\n");
+        // A deep tree under it, none of which is counted below
+        let mut deep = String::from("node_modules");
+        for level in 0..40 {
+            deep.push_str(&format!("/{}", level));
+            dir.file(&format!("{}/x.png", deep), b"");
+        }
+        // As in git, a file can't be taken back out of an ignored folder:
+        // the folder isn't looked in at all
+        assert_eq!(names(&recursive(), &dir), ["keep.png"]);
+        assert_eq!(recursive().count_files(dir.path()), 2);
+    }
+
+    #[test]
+    fn ignore_file_edits_show_up_on_the_next_rescan() {
+        let dir = TestDir::new();
+        dir.file("a.png", b"");
+        dir.file("b.png", b"");
+        let options = ListOptions::default();
+        let mut images = options.collect(dir.path());
+        assert_eq!(images.len(), 2);
+
+        dir.file(IGNORE_FILE, b"b.png\n");
+        let changes = options.rescan(dir.path(), &mut images);
+        assert_eq!(changes.removed, [dir.path().join("b.png")]);
+        assert_eq!(images, [dir.path().join("a.png")]);
+    }
+
+    /// xorshift, so failures can be reproduced from the seed.
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % n as u64) as usize
+        }
+
+        /// Some of `pool`, in a random order.
+        fn some_of(&mut self, pool: &[PathBuf]) -> Vec<PathBuf> {
+            let mut picked: Vec<PathBuf> =
+                pool.iter().filter(|_| self.below(3) > 0).cloned().collect();
+            for i in (1..picked.len()).rev() {
+                picked.swap(i, self.below(i + 1));
+            }
+            picked
+        }
+    }
+
+    #[test]
+    fn applying_the_diff_gives_the_fresh_list() {
+        // Names that differ only in case sort by their exact bytes
+        let pool: Vec<PathBuf> = (0..40)
+            .map(|i| format!("{}{}.png", if i % 2 == 0 { "IMG" } else { "img" }, i / 2))
+            .map(PathBuf::from)
+            .collect();
+        let key = |p: &Path| (p.as_os_str().to_ascii_lowercase(), p.as_os_str().to_owned());
+        for seed in 1..=3000u64 {
+            let mut rng = Rng(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15));
+            let descending = seed % 2 == 0;
+            let sorted = |mut list: Vec<PathBuf>| {
+                list.sort_by_key(|p| key(p));
+                if descending {
+                    list.reverse();
+                }
+                list
+            };
+            let mut images = sorted(rng.some_of(&pool));
+            let before = images.clone();
+            let found = rng.some_of(&pool);
+
+            let changes = diff(&images, &found);
+            assert!(changes.added.iter().all(|p| !before.contains(p)), "seed {}", seed);
+            assert!(changes.removed.iter().all(|p| !found.contains(p)), "seed {}", seed);
+            apply(&mut images, &changes, key, descending);
+            assert_eq!(images, sorted(found), "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn rescans_match_a_fresh_listing() {
+        let orders = [
+            (SortOrder::Name, false, None),
+            (SortOrder::Natural, true, None),
+            (SortOrder::Size, false, None),
+            (SortOrder::Mtime, true, None),
+            (SortOrder::Name, false, Some(7)),
+        ];
+        for (round, (sort, reverse, shuffle)) in orders.into_iter().enumerate() {
+            let dir = TestDir::new();
+            let options = ListOptions {
+                sort,
+                reverse,
+                shuffle,
+                ..recursive()
+            };
+            let folders = ["", "a/", "b/c/"];
+            let extensions = ["png", "jpg", "txt"];
+            let names: Vec<String> = (0..24)
+                .map(|i| format!("{}photo{}.{}", folders[i % 3], i, extensions[i / 3 % 3]))
+                .collect();
+            let mut rng = Rng(round as u64 + 1);
+            let mut images = options.collect(dir.path());
+            for _ in 0..30 {
+                for name in &names {
+                    match rng.below(4) {
+                        0 => {
+                            let _ = std::fs::remove_file(dir.path().join(name));
+                        }
+                        1 => {
+                            dir.file(name, &vec![0; rng.below(50)]);
+                        }
+                        _ => {}
+                    }
+                }
+                let before = images.clone();
+                let changes = options.rescan(dir.path(), &mut images);
+                assert_eq!(images, options.collect(dir.path()), "{:?}", sort);
+                assert_eq!(changes, diff(&before, &images));
+            }
+        }
+    }
+
+    #[test]
+    fn unchanged_files_keep_their_places() {
+        let dir = TestDir::new();
+        for name in ["a.png", "b.png", "d.png"] {
+            dir.file(name, b"");
+        }
+        let options = ListOptions::default();
+        let mut images = options.collect(dir.path());
+        let b = images[1].clone();
+
+        dir.file("c.png", b"");
+        std::fs::remove_file(dir.path().join("a.png")).unwrap();
+        let changes = options.rescan(dir.path(), &mut images);
+        assert_eq!(changes.added, [dir.path().join("c.png")]);
+        assert_eq!(changes.removed, [dir.path().join("a.png")]);
+        assert_eq!(images.iter().position(|p| *p == b), Some(0));
+        assert_eq!(images.len(), 3);
+
+        assert!(options.rescan(dir.path(), &mut images).is_empty());
+    }
+}
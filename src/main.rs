@@ -1,31 +1,539 @@
-use std::io::{self, stdout, Write};
+mod alpha;
+mod compare;
+mod decoders;
+mod dirconfig;
+mod exif;
+mod ignore;
+#[cfg(feature = "fetch")]
+mod fetch;
+mod fileops;
+mod gallery;
+mod info;
+mod listing;
+mod measure;
+mod orientation;
+mod persistence;
+mod playlist;
+mod preload;
+mod render;
+mod recording;
+mod reply;
+mod scheduler;
+mod session_log;
+mod slowfs;
+mod thumbcache;
+#[cfg(feature = "fetch")]
+mod urlcache;
+mod viewport;
+mod watch;
+#[cfg(feature = "server")]
+mod server;
+#[cfg(test)]
+mod testdir;
+
+use std::collections::{HashSet, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{self, stdout, IsTerminal, Read, Write};
 use std::path::{Path, PathBuf};
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, TryRecvError};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime};
 
-use base64::Engine;
-use clap::Parser;
+use clap::{CommandFactory, Parser};
 use crossterm::{
     cursor,
-    event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+    event::{
+        self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers, KeyboardEnhancementFlags,
+        PopKeyboardEnhancementFlags, PushKeyboardEnhancementFlags,
+    },
     execute, queue,
     style::{self, SetBackgroundColor, SetForegroundColor},
     terminal::{self, disable_raw_mode, enable_raw_mode, ClearType},
 };
-use image::imageops::FilterType;
 use image::{DynamicImage, GenericImageView};
+use alpha::AlphaMode;
+use compare::{Compare, Side};
+use decoders::Decoder;
+use dirconfig::{DirConfig, Value};
+use fileops::{Failure, Identity, Target, Transfer};
+use gallery::{Gallery, THUMB_PX};
+use ignore::IgnoreRules;
+use info::{FileFacts, Info};
+use listing::{ListDiff, ListOptions, Newest, SortOrder};
+use measure::Measure;
+use orientation::Orientation;
+use persistence::Persistence;
+use playlist::{EntryId, Playlist};
+use preload::{Preloaded, Preloader};
+use recording::{Input, Recorder, Recording, Replay};
+use render::{
+    fit_image, DrawnView, KittyCaps, KittyQuirks, LinkSpeed, Palette, PixelRect, Protocol, Renderer,
+    Scaling, View,
+};
+use scheduler::{Scheduler, Timer};
+use session_log::{SessionLog, Severity};
+use slowfs::{Progress, Task};
+use thumbcache::{Bucket, Cached, ThumbCache};
+use watch::{Change, Watcher};
 
 /// A terminal-based image viewer using the Kitty graphics protocol.
-/// Displays native pixels — works in foot, kitty, WezTerm, and Windows Terminal.
+/// Displays native pixels — works in foot, kitty, WezTerm, and Windows Terminal,
+/// with sixel and half-block fallbacks elsewhere.
 #[derive(Parser, Debug)]
-#[command(name = "termview", version, about)]
+#[command(
+    name = "termview",
+    version,
+    about,
+    subcommand_precedence_over_arg = true,
+    args_override_self = true
+)]
 struct Args {
-    /// Image file to open (defaults to first image in current directory)
-    #[arg()]
-    file: Option<PathBuf>,
+    /// Images and directories to open, browsed as one list in this order.
+    /// A single image opens its directory, starting on it (default: the
+    /// current directory). `-` reads one image from stdin; with the `fetch`
+    /// feature, an http:// or https:// URL downloads one
+    #[arg(value_name = "PATH")]
+    files: Vec<PathBuf>,
+
+    /// Directory to browse images from [default: .]
+    #[arg(short, long, conflicts_with = "files")]
+    directory: Option<PathBuf>,
+
+    /// Include images in subdirectories (hidden ones are skipped)
+    #[arg(short, long)]
+    recursive: bool,
+
+    /// Leave out files and folders matching PATTERN, relative to the browsed
+    /// directory, in .gitignore syntax. Repeatable, e.g. --exclude 'cache/'
+    #[arg(long, value_name = "PATTERN")]
+    exclude: Vec<String>,
+
+    /// Don't read .termviewignore, and descend into folders holding a
+    /// .nomedia file
+    #[arg(long)]
+    no_ignore: bool,
+
+    /// Order of the images in each directory
+    #[arg(long, value_enum, default_value_t = SortOrder::Name)]
+    sort: SortOrder,
+
+    /// Reverse the sort order
+    #[arg(long)]
+    reverse: bool,
+
+    /// Show the images in a random order instead of sorting them, shuffled
+    /// afresh each run
+    #[arg(long, conflicts_with = "compare_dirs")]
+    shuffle: bool,
+
+    /// Seed for `--shuffle`, picked once so every listing agrees on the order.
+    #[arg(skip = shuffle_seed())]
+    shuffle_seed: u64,
+
+    /// Keep the newest image in the directory on screen, switching to new
+    /// files as they appear (like `tail -f`). Navigating away pauses this;
+    /// press F to resume.
+    #[arg(long)]
+    follow: bool,
+
+    /// Open the newest image in the directory, with the list sorted newest
+    /// first. BY is mtime, or exif for the date taken (files without one by
+    /// their modification time). Overrides --sort and --shuffle.
+    #[arg(
+        long,
+        value_enum,
+        value_name = "BY",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "mtime",
+        conflicts_with = "compare_dirs"
+    )]
+    latest: Option<Newest>,
+
+    /// Step through two directories side by side, pairing images by filename.
+    /// Tab flips between the two versions of the current image.
+    #[arg(
+        long,
+        num_args = 2,
+        value_names = ["A", "B"],
+        conflicts_with_all = ["files", "directory", "follow", "wait_for_images", "recursive"]
+    )]
+    compare_dirs: Option<Vec<PathBuf>>,
+
+    /// Place the image within part of the screen, e.g. 'x=60%,y=0,w=40%,h=100%'.
+    /// Values are cells or percentages of the area above the status bar;
+    /// omitted keys default to x=0,y=0,w=100%,h=100%.
+    #[arg(long, value_name = "SPEC")]
+    region: Option<Region>,
+
+    /// Space to keep clear around the image and the thumbnail grid on every
+    /// side, in cells or as a percentage of the area, e.g. '2' or '5%'
+    #[arg(long, value_name = "SIZE")]
+    margin: Option<Extent>,
+
+    /// Left and right margin, overriding --margin
+    #[arg(long, value_name = "SIZE")]
+    margin_x: Option<Extent>,
+
+    /// Top and bottom margin, overriding --margin
+    #[arg(long, value_name = "SIZE")]
+    margin_y: Option<Extent>,
+
+    /// Give up on decoding an image after this many seconds
+    #[arg(long, value_name = "SECS", default_value = "10", value_parser = parse_seconds)]
+    decode_timeout: Duration,
+
+    /// How images are sized to the screen (cycle with `z`)
+    #[arg(long, value_enum, value_name = "MODE", default_value_t = FitMode::Fit)]
+    fit: FitMode,
+
+    /// Let `fit` and `fit-width` enlarge images smaller than the screen
+    #[arg(long)]
+    upscale: bool,
+
+    /// Outline each image pixel once it is shown this many screen pixels
+    /// wide, for inspecting pixel art (toggle with #)
+    #[arg(long, value_name = "SCALE", default_value_t = DEFAULT_PIXEL_GRID_AT, value_parser = parse_factor)]
+    pixel_grid_at: f64,
+
+    /// Colour for the area around the image: '#rrggbb', or 'auto' to match
+    /// the image's edges. When they are too varied to match, 'auto' leaves
+    /// the terminal's background and 'auto:#rrggbb' uses that colour
+    #[arg(long, value_name = "COLOR")]
+    letterbox_color: Option<Letterbox>,
+
+    /// How semi-transparent colours are stored. `auto` goes by the file,
+    /// which only TIFFs declare; the others override it for files that are
+    /// wrong, e.g. textures showing dark fringes around transparent edges
+    #[arg(long, value_enum, value_name = "MODE", default_value_t = AlphaMode::Auto)]
+    alpha: AlphaMode,
+
+    /// Draw on the normal screen instead of the alternate screen
+    ///
+    /// Useful for terminals that lose or misplace graphics when switching
+    /// screens, and for debugging. termview takes over the visible screen in
+    /// place: whatever was on it is cleared when it starts and not restored on
+    /// exit (scrollback above it is kept), and the cursor is left at the top of
+    /// the cleared screen.
+    #[arg(long)]
+    no_alt_screen: bool,
+
+    /// How to draw images. `auto` queries the terminal for kitty graphics,
+    /// then sixel, and falls back to unicode half-blocks
+    #[arg(long, value_enum, default_value_t = Protocol::Auto)]
+    protocol: Protocol,
+
+    /// The terminal's 16 colours for `--protocol blocks16`, as
+    /// '#rrggbb,...' (all 16, or the first 8). By default the terminal is
+    /// asked, falling back to the VGA colours
+    #[arg(long, value_name = "COLORS")]
+    palette: Option<Palette>,
+
+    /// Brighten (above 1) or darken (below 1) images drawn with half-blocks,
+    /// for terminals whose text colours look darker than their graphics
+    #[arg(long, value_name = "GAMMA", default_value_t = 1.0, value_parser = parse_factor)]
+    fallback_gamma: f64,
+
+    /// Which kitty graphics keys to send. `legacy` sticks to plain
+    /// transmit-and-display for terminals that print errors or raw data for
+    /// newer keys; `auto` decides from the terminal's reported version
+    #[arg(long, value_enum, default_value_t = KittyQuirks::Auto)]
+    kitty_quirks: KittyQuirks,
+
+    /// Connection speed to the terminal. Over a slow link, kitty images are
+    /// sent at half resolution and compressed; `auto` decides from how long
+    /// recent images took to write
+    #[arg(long, value_enum, default_value_t = LinkSpeed::Auto)]
+    link_speed: LinkSpeed,
+
+    /// Start in the thumbnail grid (toggle with t)
+    #[arg(long)]
+    gallery: bool,
+
+    /// Start a slideshow, moving to the next image every SECS seconds
+    /// (default 5). p starts and stops it, Space pauses it
+    #[arg(
+        long,
+        value_name = "SECS",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "5",
+        value_parser = parse_seconds
+    )]
+    slideshow: Option<Duration>,
+
+    /// End the slideshow after the last image and quit, instead of starting
+    /// again from the first
+    #[arg(long)]
+    once: bool,
+
+    /// Keep up to N decoded images in memory, decoding the next and previous
+    /// image in the background so moving to them is instant. 0 disables this
+    #[arg(long, value_name = "N", default_value_t = 4)]
+    cache_size: usize,
+
+    /// Open even if there are no images yet, and show the first one that appears
+    #[arg(long)]
+    wait_for_images: bool,
+
+    /// Decode files with extension EXT by running COMMAND, which must write a
+    /// PNG or PNM image to stdout. `{path}` in COMMAND is replaced with the
+    /// file path. Used when the built-in decoders fail or don't know EXT.
+    /// Repeatable, e.g. --decoder "dcm=dcmj2pnm {path} -"
+    #[arg(long, value_name = "EXT=COMMAND")]
+    decoder: Vec<Decoder>,
+
+    /// Directory `m` moves the displayed image to. Without it, `m` asks
+    #[arg(long, value_name = "DIR")]
+    move_to: Option<PathBuf>,
+
+    /// Directory `c` copies the displayed image to. Without it, `c` asks
+    #[arg(long, value_name = "DIR")]
+    copy_to: Option<PathBuf>,
+
+    /// Make `x` delete files permanently instead of moving them to the trash
+    #[arg(long)]
+    no_trash: bool,
+
+    /// Refuse every key that would change files: delete, move and copy.
+    /// 'strict' also refuses writing new files to paths typed in, and the
+    /// clipboard
+    #[arg(
+        long,
+        value_enum,
+        value_name = "LEVEL",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "normal"
+    )]
+    read_only: Option<ReadOnly>,
+
+    /// Size of a terminal cell in pixels, e.g. '10x20', instead of asking the
+    /// terminal. For terminals that report it wrongly or not at all
+    #[arg(long, value_name = "WxH", value_parser = parse_cell_size)]
+    cell_size: Option<(u16, u16)>,
+
+    /// Don't read or write the shared thumbnail cache in ~/.cache/thumbnails
+    #[arg(long)]
+    no_thumbnail_cache: bool,
+
+    /// Download URL arguments in full every time, without keeping them in
+    /// ~/.cache/termview/downloads or falling back to the kept copy offline
+    #[cfg(feature = "fetch")]
+    #[arg(long)]
+    no_cache: bool,
+
+    /// Most megabytes of downloads to keep; the least recently used go first
+    #[cfg(feature = "fetch")]
+    #[arg(long, value_name = "MB", default_value_t = 200)]
+    download_cache_size: u64,
+
+    /// Don't reload the displayed image or the image list when files change
+    #[arg(long)]
+    no_watch: bool,
+
+    /// Ignore .termview.toml files in the browsed directory and its parents
+    #[arg(long)]
+    no_dir_config: bool,
+
+    /// Append the session log (images that failed to load, directory
+    /// changes) to FILE as well as keeping it for the L overlay
+    #[arg(long, value_name = "FILE")]
+    log: Option<PathBuf>,
+
+    /// Write every key press, resize and clock-driven change (animation
+    /// frames, slideshow steps) to FILE with its timing, for --replay
+    #[arg(long, value_name = "FILE", conflicts_with = "replay")]
+    record_input: Option<PathBuf>,
+
+    /// Feed the input recorded in FILE back through the viewer instead of
+    /// reading the keyboard, on the same images. q stops the replay
+    #[arg(long, value_name = "FILE")]
+    replay: Option<PathBuf>,
+
+    /// Replay this many times faster than recorded, e.g. '4'
+    #[arg(
+        long,
+        value_name = "FACTOR",
+        default_value = "1",
+        value_parser = parse_factor,
+        requires = "replay"
+    )]
+    replay_speed: f64,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+impl Args {
+    fn list_options(&self) -> ListOptions {
+        let mut exclude = IgnoreRules::default();
+        for pattern in &self.exclude {
+            exclude.add(pattern);
+        }
+        // Newest first puts the image --latest opens at the start of the list
+        let (sort, reverse, shuffle) = match self.latest {
+            Some(newest) => (newest.sort_order(), true, None),
+            None => (self.sort, self.reverse, self.shuffle.then_some(self.shuffle_seed)),
+        };
+        ListOptions {
+            decoders: self.decoder.clone(),
+            sort,
+            reverse,
+            recursive: self.recursive,
+            exclude,
+            no_ignore: self.no_ignore,
+            shuffle,
+        }
+    }
+
+    fn margins(&self) -> Margins {
+        let margin = self.margin.unwrap_or(Extent::Cells(0));
+        Margins {
+            x: self.margin_x.unwrap_or(margin),
+            y: self.margin_y.unwrap_or(margin),
+        }
+    }
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Show where termview keeps its cache, trash and log, and which of them
+    /// can't be used here and why
+    Doctor,
+    /// Serve the image list read-only over HTTP instead of opening the viewer
+    ///
+    /// Endpoints: /list (JSON), /thumb/<index> (JPEG), /image/<index> (original file).
+    #[cfg(feature = "server")]
+    Serve {
+        /// Address to listen on
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        addr: String,
+    },
+}
+
+/// Settings a `.termview.toml` may give, by the flag each stands for. Only
+/// how images are ordered and shown: options that run commands, write files
+/// or say where files go are refused, so a folder from elsewhere can't make
+/// `m` or `x` do anything unexpected.
+const DIR_SETTINGS: &[&str] = &[
+    "sort",
+    "reverse",
+    "shuffle",
+    "latest",
+    "fit",
+    "upscale",
+    "letterbox-color",
+    "alpha",
+    "margin",
+    "slideshow",
+];
 
-    /// Directory to browse images from
-    #[arg(short, long, default_value = ".")]
-    directory: PathBuf,
+/// Where to look for a `.termview.toml`: the directory being browsed, if
+/// the command line names a single one.
+fn config_dir(args: &Args) -> Option<PathBuf> {
+    if args.no_dir_config || args.compare_dirs.is_some() || args.files.len() > 1 {
+        return None;
+    }
+    match (args.files.first(), &args.directory) {
+        (Some(path), _) if path == Path::new("-") || url_arg(path).is_some() => None,
+        (Some(path), _) if path.is_dir() => Some(path.clone()),
+        (Some(path), _) => Some(
+            path.parent()
+                .filter(|p| !p.as_os_str().is_empty())
+                .unwrap_or(Path::new("."))
+                .to_path_buf(),
+        ),
+        (None, Some(dir)) => Some(dir.clone()),
+        (None, None) => Some(PathBuf::from(".")),
+    }
+}
+
+/// Apply the nearest `.termview.toml` by reading the command line `argv`,
+/// which gave `args`, again with its settings in front, so flags given on
+/// the command line win. Settings that aren't allowed or don't parse are
+/// left out and listed in the config's `problems`.
+fn with_dir_config(
+    args: Args,
+    mut argv: impl Iterator<Item = std::ffi::OsString>,
+) -> (Args, Option<DirConfig>) {
+    let Some(mut config) = config_dir(&args).and_then(|dir| DirConfig::find(&dir)) else {
+        return (args, None);
+    };
+
+    let mut flags = Vec::new();
+    for (key, value) in &config.entries {
+        let key = key.replace('_', "-");
+        if !DIR_SETTINGS.contains(&key.as_str()) {
+            let known = Args::command()
+                .get_arguments()
+                .any(|arg| arg.get_long() == Some(key.as_str()));
+            config.problems.push(if known {
+                format!("{} can't be set per directory", key)
+            } else {
+                format!("unknown setting {}", key)
+            });
+            continue;
+        }
+        let flag = match value {
+            Value::Bool(true) => format!("--{}", key),
+            Value::Bool(false) => continue,
+            Value::String(v) | Value::Number(v) => format!("--{}={}", key, v),
+        };
+        // Each setting is checked on its own, so one bad value doesn't lose the rest
+        match Args::try_parse_from(["termview", flag.as_str()]) {
+            Ok(_) => flags.push(flag),
+            Err(e) => config.problems.push(clap_message(&e)),
+        }
+    }
+
+    let command_line = argv
+        .next()
+        .into_iter()
+        .chain(flags.into_iter().map(Into::into))
+        .chain(argv);
+    match Args::try_parse_from(command_line) {
+        Ok(args) => (args, Some(config)),
+        Err(e) => {
+            config
+                .problems
+                .push(format!("not applied: {}", clap_message(&e)));
+            (args, Some(config))
+        }
+    }
+}
+
+/// The first line of a command line error, without clap's `error:` prefix.
+fn clap_message(e: &clap::Error) -> String {
+    let message = e.to_string();
+    let first = message.lines().next().unwrap_or_default();
+    first.trim_start_matches("error: ").to_string()
+}
+
+/// Parse a positive factor, e.g. a speed-up or a magnification.
+fn parse_factor(s: &str) -> Result<f64, String> {
+    s.parse::<f64>()
+        .ok()
+        .filter(|f| f.is_finite() && *f > 0.0)
+        .ok_or_else(|| format!("expected a positive number, got '{}'", s))
+}
+
+/// A seed that differs from run to run.
+fn shuffle_seed() -> u64 {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |t| t.as_nanos() as u64);
+    nanos ^ ((std::process::id() as u64) << 32)
+}
+
+/// Parse a positive number of seconds, allowing fractions.
+fn parse_seconds(s: &str) -> Result<Duration, String> {
+    let secs: f64 = s.parse().map_err(|_| format!("invalid number of seconds '{}'", s))?;
+    Duration::try_from_secs_f64(secs)
+        .ok()
+        .filter(|d| !d.is_zero())
+        .ok_or_else(|| format!("expected a positive number of seconds, got '{}'", s))
 }
 
 const IMAGE_EXTENSIONS: &[&str] = &[
@@ -33,571 +541,6966 @@ const IMAGE_EXTENSIONS: &[&str] = &[
     "ppm", "qoi", "tga",
 ];
 
-fn is_image_file(path: &Path) -> bool {
+fn is_builtin_image_file(path: &Path) -> bool {
     path.extension()
         .and_then(|ext| ext.to_str())
         .map(|ext| IMAGE_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
         .unwrap_or(false)
 }
 
-fn collect_images(dir: &Path) -> Vec<PathBuf> {
-    let mut images: Vec<PathBuf> = std::fs::read_dir(dir)
-        .ok()
-        .into_iter()
-        .flatten()
-        .filter_map(|e| e.ok())
-        .map(|e| e.path())
-        .filter(|p| p.is_file() && is_image_file(p))
-        .collect();
+/// Whether `path` can be shown, either natively or through an external decoder.
+fn is_image_file(path: &Path, decoders: &[Decoder]) -> bool {
+    is_builtin_image_file(path) || decoders::find(decoders, path).is_some()
+}
 
-    images.sort_by(|a, b| {
-        a.file_name()
-            .unwrap_or_default()
-            .to_ascii_lowercase()
-            .cmp(&b.file_name().unwrap_or_default().to_ascii_lowercase())
+// ---------------------------------------------------------------------------
+// Decoding
+// ---------------------------------------------------------------------------
+
+/// Why an image couldn't be loaded.
+#[derive(Debug)]
+enum LoadError {
+    Decode(image::ImageError),
+    External(String),
+    TimedOut(Duration),
+    /// Too many earlier decodes are stuck to start another.
+    Stuck(usize),
+}
+
+impl std::fmt::Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoadError::Decode(e) => write!(f, "{}", e),
+            LoadError::External(e) => write!(f, "{}", e),
+            LoadError::TimedOut(t) => write!(f, "decode timed out after {:.1}s", t.as_secs_f64()),
+            LoadError::Stuck(n) => {
+                write!(f, "{} earlier decodes are stuck, not starting another", n)
+            }
+        }
+    }
+}
+
+/// One frame of an animation and how long it stays on screen.
+type Frame = (DynamicImage, Duration);
+
+/// Frames shorter than this are shown for `DEFAULT_FRAME_DELAY` instead. Many
+/// GIFs say 0ms meaning "as fast as possible", which browsers also slow down.
+const MIN_FRAME_DELAY: Duration = Duration::from_millis(20);
+const DEFAULT_FRAME_DELAY: Duration = Duration::from_millis(100);
+
+/// A decoded file.
+enum Decoded {
+    Still(DynamicImage),
+    Animated(Vec<Frame>),
+}
+
+impl Decoded {
+    /// Turn every frame by `orientation`.
+    fn oriented(self, orientation: Orientation) -> Decoded {
+        if orientation.is_identity() {
+            return self;
+        }
+        match self {
+            Decoded::Still(img) => Decoded::Still(orientation.apply(img)),
+            Decoded::Animated(frames) => Decoded::Animated(
+                frames
+                    .into_iter()
+                    .map(|(img, delay)| (orientation.apply(img), delay))
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Convert every frame to straight alpha if it is `premultiplied`.
+    fn straightened(self, premultiplied: bool) -> Decoded {
+        if !premultiplied {
+            return self;
+        }
+        match self {
+            Decoded::Still(img) => Decoded::Still(alpha::unpremultiply(img)),
+            Decoded::Animated(frames) => Decoded::Animated(
+                frames
+                    .into_iter()
+                    .map(|(img, delay)| (alpha::unpremultiply(img), delay))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+/// Decode `path` with the built-in decoders, keeping every frame of animated
+/// GIF, APNG and WebP files, turn it upright as its EXIF data says, and
+/// convert premultiplied alpha to straight.
+fn decode_builtin(path: &Path, alpha: AlphaMode) -> image::ImageResult<Decoded> {
+    let orientation = orientation::read_exif(path).unwrap_or_default();
+    let premultiplied = alpha.is_premultiplied(|| alpha::read_declared(path));
+    decode_builtin_as_stored(path).map(|d| d.oriented(orientation).straightened(premultiplied))
+}
+
+fn decode_builtin_as_stored(path: &Path) -> image::ImageResult<Decoded> {
+    // A broken animation falls through to `image::open`, which reports the error
+    if let Ok(format) = image::ImageFormat::from_path(path) {
+        if let Ok(file) = std::fs::File::open(path) {
+            if let Ok(Some(decoded)) = decode_frames(format, io::BufReader::new(file)) {
+                return Ok(decoded);
+            }
+        }
+    }
+    image::open(path).map(Decoded::Still)
+}
+
+/// Decode an image held in memory, sniffing its format from the contents.
+fn decode_bytes(bytes: &[u8], alpha: AlphaMode) -> image::ImageResult<Decoded> {
+    let orientation = orientation::exif_orientation(bytes).unwrap_or_default();
+    let premultiplied = alpha.is_premultiplied(|| alpha::declared(bytes));
+    decode_bytes_as_stored(bytes).map(|d| d.oriented(orientation).straightened(premultiplied))
+}
+
+fn decode_bytes_as_stored(bytes: &[u8]) -> image::ImageResult<Decoded> {
+    let format = image::guess_format(bytes)?;
+    if let Ok(Some(decoded)) = decode_frames(format, io::Cursor::new(bytes)) {
+        return Ok(decoded);
+    }
+    image::load_from_memory_with_format(bytes, format).map(Decoded::Still)
+}
+
+/// All frames of an image, or `None` if it isn't in a format that can animate.
+/// An image with a single frame is a still, decoded once.
+fn decode_frames(
+    format: image::ImageFormat,
+    reader: impl io::BufRead + io::Seek,
+) -> image::ImageResult<Option<Decoded>> {
+    use image::codecs::{gif::GifDecoder, png::PngDecoder, webp::WebPDecoder};
+    use image::{AnimationDecoder, ImageFormat};
+
+    let frames = match format {
+        ImageFormat::Gif => GifDecoder::new(reader)?.into_frames(),
+        ImageFormat::Png => {
+            let decoder = PngDecoder::new(reader)?;
+            if !decoder.is_apng() {
+                return Ok(None);
+            }
+            decoder.apng().into_frames()
+        }
+        ImageFormat::WebP => {
+            let decoder = WebPDecoder::new(reader)?;
+            if !decoder.has_animation() {
+                return Ok(None);
+            }
+            decoder.into_frames()
+        }
+        _ => return Ok(None),
+    };
+
+    let mut frames = frames.map(|frame| {
+        frame.map(|frame| {
+            let delay = Duration::from(frame.delay());
+            let delay = if delay < MIN_FRAME_DELAY {
+                DEFAULT_FRAME_DELAY
+            } else {
+                delay
+            };
+            (DynamicImage::ImageRgba8(frame.into_buffer()), delay)
+        })
     });
 
-    images
+    // Only an image with a second frame is worth decoding to the end
+    let Some(first) = frames.next().transpose()? else {
+        return Ok(None);
+    };
+    let Some(second) = frames.next().transpose()? else {
+        return Ok(Some(Decoded::Still(first.0)));
+    };
+    let mut all = vec![first, second];
+    for frame in frames {
+        all.push(frame?);
+    }
+    Ok(Some(Decoded::Animated(all)))
+}
+
+/// What an image file's header says, read without decoding the pixels.
+#[derive(Debug, Clone)]
+struct Probe {
+    width: u32,
+    height: u32,
+    format: Option<image::ImageFormat>,
+}
+
+/// Images with at least this many pixels get a placeholder while they decode.
+const PLACEHOLDER_MIN_PIXELS: u64 = 4_000_000;
+
+/// How long reading an image's header may take before going without a
+/// placeholder. A slow filesystem shouldn't hold up the key that was pressed.
+const PROBE_TIMEOUT: Duration = Duration::from_millis(250);
+
+/// Read dimensions and format from the header of `path`.
+fn probe_image(path: &Path) -> Option<Probe> {
+    let reader = image::io::Reader::open(path).ok()?.with_guessed_format().ok()?;
+    let format = reader.format();
+    let (width, height) = reader.into_dimensions().ok()?;
+    Some(Probe {
+        width,
+        height,
+        format,
+    })
+}
+
+/// An image read from stdin or downloaded, with the name it is shown under.
+#[derive(Debug)]
+struct InMemory {
+    name: PathBuf,
+    bytes: Vec<u8>,
+}
+
+/// Everything needed to turn a path into pixels.
+#[derive(Debug, Clone)]
+struct Loader {
+    timeout: Duration,
+    decoders: Vec<Decoder>,
+    /// Image that isn't a file; loading its `name` decodes it from memory.
+    memory: Option<Arc<InMemory>>,
+    alpha: AlphaMode,
+    /// Decodes that ran past `timeout` and are still running, shared by
+    /// every clone of the loader.
+    stuck: Arc<AtomicUsize>,
+}
+
+/// Most decodes left stuck in the background before no more are started.
+/// Each holds a thread, and whatever memory the decoder has taken.
+const MAX_STUCK_DECODES: usize = 4;
+
+impl Loader {
+    fn new(
+        timeout: Duration,
+        decoders: Vec<Decoder>,
+        memory: Option<InMemory>,
+        alpha: AlphaMode,
+    ) -> Self {
+        Loader {
+            timeout,
+            decoders,
+            memory: memory.map(Arc::new),
+            alpha,
+            stuck: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Start decoding `path` on a sacrificial thread, to be polled for the
+    /// result.
+    ///
+    /// Some malformed files send decoders into effectively unbounded loops. The
+    /// decoder can't be interrupted, so on timeout its thread is abandoned: it
+    /// keeps running in the background and its result is dropped if it ever
+    /// finishes. External decoders are killed at the same deadline.
+    fn start(&self, path: &Path) -> Decoding {
+        let path = path.to_path_buf();
+        let external = decoders::find(&self.decoders, &path).cloned();
+        let memory = self.memory.clone().filter(|m| m.name == path);
+        let timeout = self.timeout;
+        let alpha = self.alpha;
+        // External decoders write PNG or PNM, which are never premultiplied
+        let external_premultiplied = alpha.is_premultiplied(|| false);
+        Decoding::spawn(timeout, &self.stuck, move || match (memory, external) {
+            (Some(memory), _) => decode_bytes(&memory.bytes, alpha).map_err(LoadError::Decode),
+            (None, None) => decode_builtin(&path, alpha).map_err(LoadError::Decode),
+            (None, Some(decoder)) if is_builtin_image_file(&path) => {
+                decode_builtin(&path, alpha)
+                    .or_else(|_| {
+                        decoder.run(&path, timeout).map(|img| {
+                            Decoded::Still(img).straightened(external_premultiplied)
+                        })
+                    })
+                    .map_err(LoadError::External)
+            }
+            (None, Some(decoder)) => decoder
+                .run(&path, timeout)
+                .map(|img| Decoded::Still(img).straightened(external_premultiplied))
+                .map_err(LoadError::External),
+        })
+    }
+
+    /// Decode `path`, waiting for the result. Only for threads other than
+    /// the event loop's, like the preloader's.
+    fn load(&self, path: &Path) -> Result<Decoded, LoadError> {
+        self.start(path).wait()
+    }
+}
+
+/// A decode running on its own thread.
+struct Decoding {
+    rx: Receiver<Result<Decoded, LoadError>>,
+    deadline: Instant,
+    timeout: Duration,
+    /// `RUNNING` until either the thread finishes or the decode is given
+    /// up on, whichever comes first.
+    state: Arc<AtomicU8>,
+    stuck: Arc<AtomicUsize>,
+}
+
+/// A decode whose thread ended without a result.
+fn crashed() -> LoadError {
+    LoadError::External("the decoder crashed".into())
+}
+
+/// A decode of the current image the event loop is waiting on.
+struct Pending {
+    path: PathBuf,
+    job: Job,
+    /// For a reload of the file after it changed on disk, the changes that
+    /// led to it, to look at again if the reload fails.
+    reload: Option<HashSet<PathBuf>>,
+}
+
+enum Job {
+    Thread(Decoding),
+    /// The preloader was already decoding the file.
+    Preloader,
+}
+
+const RUNNING: u8 = 0;
+const FINISHED: u8 = 1;
+const ABANDONED: u8 = 2;
+
+impl Decoding {
+    fn spawn(
+        timeout: Duration,
+        stuck: &Arc<AtomicUsize>,
+        work: impl FnOnce() -> Result<Decoded, LoadError> + Send + 'static,
+    ) -> Self {
+        let (tx, rx) = mpsc::channel();
+        let state = Arc::new(AtomicU8::new(RUNNING));
+        let decoding = Decoding {
+            rx,
+            deadline: Instant::now() + timeout,
+            timeout,
+            state: Arc::clone(&state),
+            stuck: Arc::clone(stuck),
+        };
+        let stuck_now = stuck.load(Ordering::SeqCst);
+        if stuck_now >= MAX_STUCK_DECODES {
+            let _ = tx.send(Err(LoadError::Stuck(stuck_now)));
+            return decoding;
+        }
+        let stuck = Arc::clone(stuck);
+        std::thread::spawn(move || {
+            let result = work();
+            let finished =
+                state.compare_exchange(RUNNING, FINISHED, Ordering::SeqCst, Ordering::SeqCst);
+            if finished.is_err() {
+                // Given up on already: this one is no longer stuck
+                stuck.fetch_sub(1, Ordering::SeqCst);
+            }
+            // The receiver is gone if the decode was abandoned
+            let _ = tx.send(result);
+        });
+        decoding
+    }
+
+    /// The result, if it is in. Past the timeout the decode is abandoned and
+    /// reported as timed out.
+    fn poll(&self) -> Option<Result<Decoded, LoadError>> {
+        match self.rx.try_recv() {
+            Ok(result) => Some(result),
+            Err(TryRecvError::Disconnected) => Some(Err(crashed())),
+            Err(TryRecvError::Empty) if Instant::now() < self.deadline => None,
+            Err(TryRecvError::Empty) => Some(self.give_up()),
+        }
+    }
+
+    /// Wait for the result, up to the timeout.
+    fn wait(self) -> Result<Decoded, LoadError> {
+        let left = self.deadline.saturating_duration_since(Instant::now());
+        match self.rx.recv_timeout(left) {
+            Ok(result) => result,
+            Err(RecvTimeoutError::Disconnected) => Err(crashed()),
+            Err(RecvTimeoutError::Timeout) => self.give_up(),
+        }
+    }
+
+    fn give_up(&self) -> Result<Decoded, LoadError> {
+        let abandoned =
+            self.state.compare_exchange(RUNNING, ABANDONED, Ordering::SeqCst, Ordering::SeqCst);
+        if abandoned.is_ok() {
+            self.stuck.fetch_add(1, Ordering::SeqCst);
+        }
+        Err(LoadError::TimedOut(self.timeout))
+    }
 }
 
 // ---------------------------------------------------------------------------
-// Kitty graphics protocol
+// Follow mode
 // ---------------------------------------------------------------------------
 
-/// Delete all kitty graphics placements from the screen.
-fn kitty_clear(out: &mut impl Write) -> io::Result<()> {
-    // a=d (delete), d=A (all placements)
-    write!(out, "\x1b_Ga=d,d=A\x1b\\")?;
-    Ok(())
+/// How often a decode in progress is checked on. Decoding happens off the
+/// event loop, so keys are handled meanwhile; this is how late the image may
+/// appear once it is ready.
+const DECODE_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How often the directory is rescanned in follow mode.
+const FOLLOW_SCAN_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How often the directory watcher is read.
+const WATCH_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long a watched file has to go unchanged before it is reloaded, so a
+/// file being written isn't decoded half-way through.
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// Longest a change waits for the directory to go quiet. A sync tool
+/// copying in hundreds of files keeps it busy for a while, and the list
+/// catches up in batches meanwhile rather than all at the end.
+const WATCH_MAX_DELAY: Duration = Duration::from_secs(2);
+
+/// How long the status bar shows how the image list changed.
+const LIST_NOTICE_TIME: Duration = Duration::from_secs(4);
+
+/// How long each tick waits for a rescan before going back to the event loop.
+/// Scans that take longer are checked again on the next tick.
+const SCAN_WAIT: Duration = Duration::from_millis(50);
+
+/// The result of rescanning the browsed directory.
+struct Scan {
+    /// `App::list_version` when the scan started.
+    version: u64,
+    images: Vec<PathBuf>,
+    changes: ListDiff,
+    newest: Option<FileStamp>,
 }
 
-/// Display an image using the Kitty graphics protocol.
-///
-/// The image is transmitted as raw RGBA pixels, chunked into 4096-byte base64
-/// payloads. It is placed at the current cursor position and scaled to fit
-/// within `cols` x `rows` terminal cells.
-fn kitty_display(
-    out: &mut impl Write,
-    img: &DynamicImage,
-    cols: u16,
-    rows: u16,
-    cell_width_px: u16,
-    cell_height_px: u16,
-) -> io::Result<()> {
-    let avail_px_w = cols as u32 * cell_width_px as u32;
-    let avail_px_h = rows as u32 * cell_height_px as u32;
+/// Identity of a file's contents as far as follow mode cares.
+#[derive(Debug, Clone, PartialEq)]
+struct FileStamp {
+    path: PathBuf,
+    len: u64,
+    modified: SystemTime,
+}
 
-    let (img_w, img_h) = img.dimensions();
+/// Find the most recently modified image in `images`. Of files modified at
+/// the same time the one whose path sorts last wins, as it comes first in a
+/// `--latest` listing, whatever order `images` is in.
+fn newest_image<P: AsRef<Path>>(images: impl IntoIterator<Item = P>) -> Option<FileStamp> {
+    images
+        .into_iter()
+        .filter_map(|path| {
+            let path = path.as_ref();
+            let meta = std::fs::metadata(path).ok()?;
+            Some(FileStamp {
+                path: path.to_path_buf(),
+                len: meta.len(),
+                modified: meta.modified().ok()?,
+            })
+        })
+        .max_by(|a, b| (a.modified, &a.path).cmp(&(b.modified, &b.path)))
+}
 
-    // Scale to fit while preserving aspect ratio
-    let scale_x = avail_px_w as f64 / img_w as f64;
-    let scale_y = avail_px_h as f64 / img_h as f64;
-    let scale = scale_x.min(scale_y).min(1.0); // don't upscale
+/// A short note of a change to the list for the status bar, e.g. `+500 files`
+/// or `+2 -1 files`.
+fn list_notice(changes: &ListDiff) -> String {
+    let (added, removed) = (changes.added.len(), changes.removed.len());
+    let counts = match (added, removed) {
+        (_, 0) => format!("+{}", added),
+        (0, _) => format!("-{}", removed),
+        _ => format!("+{} -{}", added, removed),
+    };
+    let files = if added + removed == 1 { "file" } else { "files" };
+    format!("{} {}", counts, files)
+}
 
-    let disp_w = ((img_w as f64 * scale) as u32).max(1);
-    let disp_h = ((img_h as f64 * scale) as u32).max(1);
+/// State for `--follow`: keep the newest image in the directory on screen.
+struct Follow {
+    dir: PathBuf,
+    /// Whether new files are switched to automatically. Manual navigation
+    /// disengages it; `F` engages it again.
+    engaged: bool,
+    /// Newest file seen by the previous scan. A file is only switched to once
+    /// it looks the same on two consecutive scans, so files that are still
+    /// being written aren't decoded half-way through.
+    candidate: Option<FileStamp>,
+}
 
-    let resized = if disp_w != img_w || disp_h != img_h {
-        img.resize_exact(disp_w, disp_h, FilterType::Lanczos3)
-    } else {
-        img.clone()
-    };
-
-    let rgba = resized.to_rgba8();
-    let raw_pixels = rgba.as_raw();
-
-    // Center the image: compute the column/row offset
-    let img_cols = (disp_w + cell_width_px as u32 - 1) / cell_width_px as u32;
-    let img_rows = (disp_h + cell_height_px as u32 - 1) / cell_height_px as u32;
-    let col_offset = (cols as u32).saturating_sub(img_cols) / 2;
-    let row_offset = (rows as u32).saturating_sub(img_rows) / 2;
-
-    // Move cursor to centering position
-    queue!(out, cursor::MoveTo(col_offset as u16, row_offset as u16))?;
-
-    // Encode as base64 and send in chunks
-    let b64 = base64::engine::general_purpose::STANDARD.encode(raw_pixels);
-    let chunks: Vec<&str> = b64.as_bytes().chunks(4096).map(|c| {
-        std::str::from_utf8(c).unwrap()
-    }).collect();
-
-    for (i, chunk) in chunks.iter().enumerate() {
-        let is_first = i == 0;
-        let is_last = i == chunks.len() - 1;
-        let more = if is_last { 0 } else { 1 };
-
-        if is_first {
-            // a=T (transmit and display), f=32 (RGBA), s=width, v=height
-            write!(
-                out,
-                "\x1b_Ga=T,f=32,s={},v={},m={};{}\x1b\\",
-                disp_w, disp_h, more, chunk
-            )?;
-        } else {
-            write!(out, "\x1b_Gm={};{}\x1b\\", more, chunk)?;
+impl Follow {
+    fn new(dir: PathBuf, images: &Playlist) -> Self {
+        Follow {
+            dir,
+            engaged: true,
+            candidate: newest_image(images.iter()),
         }
     }
-
-    Ok(())
 }
 
 // ---------------------------------------------------------------------------
-// Terminal cell size detection
+// Layout
 // ---------------------------------------------------------------------------
 
-/// Try to detect the pixel dimensions of a terminal cell.
-/// Uses the TIOCGWINSZ ioctl on Linux to get pixel size.
-/// Falls back to reasonable defaults if unavailable.
-fn get_cell_size() -> (u16, u16) {
-    #[cfg(unix)]
-    {
-        use std::mem::MaybeUninit;
-
-        #[repr(C)]
-        struct Winsize {
-            ws_row: u16,
-            ws_col: u16,
-            ws_xpixel: u16,
-            ws_ypixel: u16,
-        }
-
-        unsafe {
-            let mut ws = MaybeUninit::<Winsize>::uninit();
-            // TIOCGWINSZ = 0x5413 on Linux
-            let ret = libc::ioctl(1, 0x5413, ws.as_mut_ptr());
-            if ret == 0 {
-                let ws = ws.assume_init();
-                if ws.ws_xpixel > 0 && ws.ws_ypixel > 0 && ws.ws_col > 0 && ws.ws_row > 0 {
-                    let cw = ws.ws_xpixel / ws.ws_col;
-                    let ch = ws.ws_ypixel / ws.ws_row;
-                    if cw > 0 && ch > 0 {
-                        return (cw, ch);
-                    }
-                }
+/// One coordinate of a `--region` spec: absolute cells or a share of the total.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Extent {
+    Cells(u16),
+    Percent(f64),
+}
+
+impl Extent {
+    fn resolve(self, total: u16) -> u16 {
+        match self {
+            Extent::Cells(n) => n,
+            Extent::Percent(p) => (total as f64 * p / 100.0).round() as u16,
+        }
+    }
+}
+
+impl std::str::FromStr for Extent {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Some(pct) = s.strip_suffix('%') {
+            let p: f64 = pct
+                .trim()
+                .parse()
+                .map_err(|_| format!("invalid percentage '{}'", s))?;
+            if !(0.0..=100.0).contains(&p) {
+                return Err(format!("percentage out of range '{}'", s));
             }
+            Ok(Extent::Percent(p))
+        } else {
+            s.parse()
+                .map(Extent::Cells)
+                .map_err(|_| format!("invalid cell count '{}'", s))
         }
     }
+}
 
-    // Fallback: assume ~8x16 px cells (common for most fonts)
-    (8, 16)
+/// A placement rectangle given as `x=..,y=..,w=..,h=..`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Region {
+    x: Extent,
+    y: Extent,
+    w: Extent,
+    h: Extent,
 }
 
-// ---------------------------------------------------------------------------
-// Status bar drawing (manual, no ratatui needed)
-// ---------------------------------------------------------------------------
+impl Region {
+    /// Resolve against `area`, clamping to its bounds. Returns `None` when
+    /// less than a single cell would be left to draw into.
+    fn resolve(&self, area: CellRect) -> Option<CellRect> {
+        let (area_col, area_row, cols, rows) = area;
+        let x = self.x.resolve(cols).min(cols);
+        let y = self.y.resolve(rows).min(rows);
+        let w = self.w.resolve(cols).min(cols - x);
+        let h = self.h.resolve(rows).min(rows - y);
+        if w == 0 || h == 0 {
+            return None;
+        }
+        Some((area_col + x, area_row + y, w, h))
+    }
+}
 
-fn draw_status_bar(
-    out: &mut impl Write,
-    row: u16,
-    cols: u16,
-    left: &str,
-    right: &str,
-) -> io::Result<()> {
-    queue!(out, cursor::MoveTo(0, row))?;
-    queue!(
-        out,
-        SetForegroundColor(style::Color::White),
-        SetBackgroundColor(style::Color::DarkGrey),
-    )?;
+impl std::str::FromStr for Region {
+    type Err = String;
 
-    let left_len = left.len().min(cols as usize);
-    let right_len = right.len().min(cols as usize);
-    let pad = (cols as usize).saturating_sub(left_len + right_len);
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut region = Region {
+            x: Extent::Cells(0),
+            y: Extent::Cells(0),
+            w: Extent::Percent(100.0),
+            h: Extent::Percent(100.0),
+        };
 
-    write!(out, "{}", &left[..left_len])?;
-    write!(out, "{}", " ".repeat(pad))?;
-    write!(out, "{}", &right[..right_len])?;
+        for part in s.split(',').filter(|p| !p.trim().is_empty()) {
+            let (key, value) = part
+                .split_once('=')
+                .ok_or_else(|| format!("expected key=value, got '{}'", part))?;
+            let value: Extent = value.parse()?;
+            match key.trim() {
+                "x" => region.x = value,
+                "y" => region.y = value,
+                "w" => region.w = value,
+                "h" => region.h = value,
+                other => return Err(format!("unknown region key '{}'", other)),
+            }
+        }
 
-    queue!(
-        out,
-        SetForegroundColor(style::Color::Reset),
-        SetBackgroundColor(style::Color::Reset),
-    )?;
+        Ok(region)
+    }
+}
+
+/// Space kept clear around what is drawn (`--margin`, `--margin-x`, `--margin-y`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Margins {
+    /// On the left and on the right.
+    x: Extent,
+    /// Above and below.
+    y: Extent,
+}
+
+impl Margins {
+    const NONE: Margins = Margins {
+        x: Extent::Cells(0),
+        y: Extent::Cells(0),
+    };
+
+    /// Shrink `area` by the margins on each side, percentages being of its
+    /// width and height. Returns `None` when no cell would be left.
+    fn apply(&self, area: CellRect) -> Option<CellRect> {
+        let (col, row, cols, rows) = area;
+        let x = self.x.resolve(cols);
+        let y = self.y.resolve(rows);
+        let w = cols.checked_sub(x.saturating_mul(2)).filter(|&w| w > 0)?;
+        let h = rows.checked_sub(y.saturating_mul(2)).filter(|&h| h > 0)?;
+        Some((col + x, row + y, w, h))
+    }
+}
+
+/// How an image is sized to the area it is shown in (`--fit`, `z`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+enum FitMode {
+    /// Whole image in view, shrunk if needed
+    #[default]
+    Fit,
+    /// Cover the whole area, cropping what overflows
+    Fill,
+    /// Fill the area exactly, ignoring the aspect ratio
+    Stretch,
+    /// One image pixel to one screen pixel
+    Actual,
+    /// Full width, panning up and down for the rest
+    FitWidth,
+}
+
+impl FitMode {
+    /// The mode `z` switches to from this one.
+    fn next(self) -> FitMode {
+        match self {
+            FitMode::Fit => FitMode::Fill,
+            FitMode::Fill => FitMode::Stretch,
+            FitMode::Stretch => FitMode::Actual,
+            FitMode::Actual => FitMode::FitWidth,
+            FitMode::FitWidth => FitMode::Fit,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            FitMode::Fit => "fit",
+            FitMode::Fill => "fill",
+            FitMode::Stretch => "stretch",
+            FitMode::Actual => "1:1",
+            FitMode::FitWidth => "fit width",
+        }
+    }
+}
+
+/// Watch `dir` and, for a recursive listing, the folders `images` are in.
+fn watch_dirs(dir: &Path, images: &Playlist) -> io::Result<Watcher> {
+    let mut watcher = Watcher::new()?;
+    let mut dirs: HashSet<&Path> = images.iter().filter_map(|p| p.parent()).collect();
+    dirs.insert(dir);
+    for dir in dirs {
+        watcher.watch(dir)?;
+    }
+    Ok(watcher)
+}
+
+/// Changes seen by the directory watcher that haven't been acted on yet.
+struct Watch {
+    watcher: Watcher,
+    dir: PathBuf,
+    /// Files written, created, removed or renamed.
+    changed: HashSet<PathBuf>,
+    /// Files came or went, so the list needs a rescan.
+    listing_changed: bool,
+    /// When the first of the changes not yet acted on was seen.
+    pending_since: Option<Instant>,
+    /// Reloading the displayed image failed once already; it may have been
+    /// caught mid-write, so it gets one more try before the error is shown.
+    retried: bool,
+}
+
+/// How to fill the part of the image area the image doesn't cover.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Letterbox {
+    Solid(u8, u8, u8),
+    /// Match the average colour of the outermost pixels in view, or use the
+    /// colour given where they are too varied to.
+    Auto(Option<(u8, u8, u8)>),
+}
+
+impl std::str::FromStr for Letterbox {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let expected = || format!("expected 'auto', 'auto:#rrggbb' or '#rrggbb', got '{}'", s);
+        let rgb = |hex: &str| {
+            let hex = hex.strip_prefix('#').unwrap_or(hex);
+            let channel = |i: usize| hex.get(i..i + 2).and_then(|c| u8::from_str_radix(c, 16).ok());
+            if hex.len() != 6 {
+                return None;
+            }
+            Some((channel(0)?, channel(2)?, channel(4)?))
+        };
+        let (kind, fallback) = match s.split_once(':') {
+            Some((kind, fallback)) => (kind, Some(fallback)),
+            None => (s, None),
+        };
+        if kind.eq_ignore_ascii_case("auto") {
+            return match fallback {
+                Some(fallback) => Ok(Letterbox::Auto(Some(rgb(fallback).ok_or_else(expected)?))),
+                None => Ok(Letterbox::Auto(None)),
+            };
+        }
+        let (r, g, b) = rgb(s).ok_or_else(expected)?;
+        Ok(Letterbox::Solid(r, g, b))
+    }
+}
+
+/// Edges whose per-channel standard deviation exceeds this don't read as a
+/// single colour, so `auto` letterboxing falls back rather than guessing.
+const EDGE_MAX_STDDEV: f64 = 24.0;
+
+/// How long the view has to hold still before the `auto` letterbox colour
+/// is sampled again, so panning across a busy image doesn't flicker it.
+const LETTERBOX_SETTLE: Duration = Duration::from_millis(150);
+
+/// Average colour of the outermost rows and columns of the `rect` of `img`
+/// in view, or `None` if the border is too varied to blend into. Pixels
+/// count by their alpha, so a transparent border's hidden colour doesn't
+/// decide it.
+fn edge_color(img: &DynamicImage, rect: PixelRect) -> Option<(u8, u8, u8)> {
+    let (x0, y0, w, h) = rect;
+    if w == 0 || h == 0 || x0 + w > img.width() || y0 + h > img.height() {
+        return None;
+    }
+
+    let mut edge = Vec::with_capacity(2 * (w + h) as usize);
+    for x in x0..x0 + w {
+        edge.push(img.get_pixel(x, y0));
+        if h > 1 {
+            edge.push(img.get_pixel(x, y0 + h - 1));
+        }
+    }
+    for y in y0 + 1..(y0 + h).saturating_sub(1) {
+        edge.push(img.get_pixel(x0, y));
+        if w > 1 {
+            edge.push(img.get_pixel(x0 + w - 1, y));
+        }
+    }
+
+    let weight = |px: &image::Rgba<u8>| px[3] as f64 / 255.0;
+    let n: f64 = edge.iter().map(weight).sum();
+    if n == 0.0 {
+        return None;
+    }
+    let mut mean = [0.0f64; 3];
+    for px in &edge {
+        for (c, m) in mean.iter_mut().enumerate() {
+            *m += px[c] as f64 * weight(px) / n;
+        }
+    }
+
+    let mut variance = [0.0f64; 3];
+    for px in &edge {
+        for (c, v) in variance.iter_mut().enumerate() {
+            let d = px[c] as f64 - mean[c];
+            *v += d * d * weight(px) / n;
+        }
+    }
+
+    if variance.iter().any(|v| v.sqrt() > EDGE_MAX_STDDEV) {
+        return None;
+    }
+
+    Some((
+        mean[0].round() as u8,
+        mean[1].round() as u8,
+        mean[2].round() as u8,
+    ))
+}
+
+// ---------------------------------------------------------------------------
+// Terminal cell size detection
+// ---------------------------------------------------------------------------
+
+/// Cell size assumed when nothing better is known: ~8x16 px, common for most fonts.
+const DEFAULT_CELL_SIZE: (u16, u16) = (8, 16);
+
+/// How long to wait for the terminal to report its 16 colours.
+const PALETTE_QUERY_TIMEOUT: Duration = Duration::from_millis(300);
+
+/// How long to wait for the terminal to say how big its cells are.
+const CELL_QUERY_TIMEOUT: Duration = Duration::from_millis(300);
+
+/// Where the size of a cell in pixels comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CellSizing {
+    /// `--cell-size`.
+    Fixed,
+    /// The tty's window size, or else asking the terminal.
+    Detect,
+    /// The tty's window size only: the terminal didn't answer when asked,
+    /// so asking again would just stall every resize.
+    TtyOnly,
+}
+
+/// Size of a terminal cell in pixels, from the tty's window size or, failing
+/// that and if `ask`, by asking the terminal. Over ssh and on some platforms
+/// the tty knows only rows and columns.
+fn detect_cell_size(ask: bool) -> Option<(u16, u16)> {
+    if let Ok(ws) = terminal::window_size() {
+        if ws.width > 0 && ws.height > 0 && ws.columns > 0 && ws.rows > 0 {
+            let cell = (ws.width / ws.columns, ws.height / ws.rows);
+            if cell.0 > 0 && cell.1 > 0 {
+                return Some(cell);
+            }
+        }
+    }
+    if !ask {
+        return None;
+    }
+    render::query_cell_size(terminal::size().ok()?, CELL_QUERY_TIMEOUT)
+}
+
+/// Parse `--cell-size` as `WxH` pixels.
+fn parse_cell_size(s: &str) -> Result<(u16, u16), String> {
+    let (w, h) = s.split_once('x').ok_or("expected WxH, e.g. 10x20")?;
+    let w: u16 = w.trim().parse().map_err(|_| format!("invalid width '{}'", w))?;
+    let h: u16 = h.trim().parse().map_err(|_| format!("invalid height '{}'", h))?;
+    if w == 0 || h == 0 {
+        return Err("width and height must be at least 1".into());
+    }
+    Ok((w, h))
+}
+
+// ---------------------------------------------------------------------------
+// Status bar drawing (manual, no ratatui needed)
+// ---------------------------------------------------------------------------
+
+fn draw_status_bar(
+    out: &mut impl Write,
+    row: u16,
+    cols: u16,
+    left: &str,
+    right: &str,
+) -> io::Result<()> {
+    queue!(out, cursor::MoveTo(0, row))?;
+    queue!(
+        out,
+        SetForegroundColor(style::Color::White),
+        SetBackgroundColor(style::Color::DarkGrey),
+    )?;
+
+    // Measured in columns, so wide characters in a filename don't push the
+    // right-hand side off the edge
+    let cols = cols as usize;
+    let right_width = text_width(right).min(cols.saturating_sub(text_width(left)));
+    write!(out, "{}", fit_width(left, cols - right_width))?;
+    write!(out, "{}", fit_width(right, right_width))?;
+
+    queue!(
+        out,
+        SetForegroundColor(style::Color::Reset),
+        SetBackgroundColor(style::Color::Reset),
+    )?;
+
+    Ok(())
+}
+
+/// Draw the help box centered on screen, returning the cells it covers.
+fn draw_help_overlay(out: &mut impl Write, cols: u16, rows: u16) -> io::Result<CellRect> {
+    let help_lines = [
+        "",
+        "  termview — Keyboard Shortcuts",
+        "",
+        "  ← / h       Previous image",
+        "  → / l       Next image",
+        "  Home / g    First image",
+        "  End / G     Last image",
+        "  t           Thumbnail grid",
+        "  Enter       Open selected thumbnail",
+        "  + / =       Zoom in",
+        "  - / _       Zoom out",
+        "  0           Reset zoom",
+        "  z           Cycle fit mode",
+        "  #           Pixel grid (zoomed in)",
+        "  M           Measure (Enter places points)",
+        "  Ctrl+r      Reload image from disk",
+        "  w/a/s/d     Pan (when zoomed)",
+        "  Ctrl+arrows Pan (when zoomed)",
+        "  F           Resume follow mode",
+        "  Tab         Flip compared directory",
+        "  p           Start/stop slideshow",
+        "  Space       Pause/play animation, slideshow",
+        "  . / ,       Next/previous frame",
+        "  x / Delete  Delete file (to trash)",
+        "  m / c       Move/copy file",
+        "  r / R       Rotate right/left",
+        "  f / v       Flip horizontally/vertically",
+        "  i           Image info",
+        "  L           Session log",
+        "  ?           Toggle help",
+        "  q / Esc     Quit",
+        "",
+    ];
+
+    let lines: Vec<_> = help_lines
+        .iter()
+        .map(|line| (style::Color::White, line.to_string()))
+        .collect();
+    let box_h = help_lines.len() as u16 + 2; // +2 for top/bottom border
+    draw_panel(out, cols, rows, (40, box_h), &lines)
+}
+
+/// Draw the box asking to confirm deleting `path`, returning the cells it covers.
+fn draw_delete_overlay(
+    out: &mut impl Write,
+    cols: u16,
+    rows: u16,
+    path: &Path,
+    permanently: bool,
+) -> io::Result<CellRect> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let how = if permanently {
+        "  It will be deleted permanently."
+    } else {
+        "  It will be moved to the trash."
+    };
+    let lines = [
+        (style::Color::White, String::new()),
+        (style::Color::White, format!("  Delete {}?", name)),
+        (style::Color::Grey, how.to_string()),
+        (style::Color::White, String::new()),
+        (style::Color::White, "  y: delete    any other key: cancel".to_string()),
+        (style::Color::White, String::new()),
+    ];
+    let box_w = (name.chars().count() as u16 + 14).max(40).min(cols);
+    draw_panel(out, cols, rows, (box_w, lines.len() as u16 + 2), &lines)
+}
+
+/// Widest the session log box gets on large terminals.
+const LOG_PANEL_MAX_WIDTH: u16 = 120;
+
+/// Entries moved by PageUp/PageDown in the session log.
+const LOG_PAGE_ENTRIES: usize = 10;
+
+/// Draw the session log box, newest entries at the bottom, returning the
+/// cells it covers. Long messages wrap rather than being cut off, so error
+/// text can be read in full.
+fn draw_log_overlay(
+    out: &mut impl Write,
+    cols: u16,
+    rows: u16,
+    log: &SessionLog,
+) -> io::Result<CellRect> {
+    let box_w = cols.saturating_sub(4).min(LOG_PANEL_MAX_WIDTH).max(cols.min(20));
+    // Leave the status bar and a row above and below the box uncovered
+    let box_h = rows.saturating_sub(3).max(rows.min(4));
+    let content_w = box_w.saturating_sub(2) as usize;
+    let body_h = (box_h as usize).saturating_sub(4); // borders, title, blank line
+
+    let mut body = Vec::new();
+    for entry in log.in_view().rev() {
+        let color = match entry.severity {
+            Severity::Info => style::Color::Grey,
+            Severity::Warning => style::Color::Yellow,
+            Severity::Error => style::Color::Red,
+        };
+        let prefix = format!(
+            " {} {:<5} ",
+            session_log::format_time(entry.time, false),
+            entry.severity.label()
+        );
+        let indent = prefix.len();
+        let chars: Vec<char> = entry.message.chars().collect();
+        let mut pieces: Vec<String> = chars
+            .chunks(content_w.saturating_sub(indent).max(1))
+            .map(|c| c.iter().collect())
+            .collect();
+        if pieces.is_empty() {
+            pieces.push(String::new());
+        }
+        // Collected bottom-up, so the last piece of the entry goes first
+        for (i, piece) in pieces.iter().enumerate().rev() {
+            let lead = if i == 0 { prefix.clone() } else { " ".repeat(indent) };
+            body.push((color, lead + piece));
+        }
+        if body.len() >= body_h {
+            break;
+        }
+    }
+    body.truncate(body_h);
+    body.reverse();
+
+    let mut title = format!("  Session log: {} entries", log.total());
+    if log.scroll() > 0 {
+        title += &format!(", {} newer below", log.scroll());
+    }
+    title += "  (j/k scroll, L close)";
+    let mut lines = vec![(style::Color::White, title), (style::Color::White, String::new())];
+    if log.is_empty() {
+        lines.push((style::Color::Grey, "  Nothing logged yet".into()));
+    }
+    lines.extend(body);
+    draw_panel(out, cols, rows, (box_w, box_h), &lines)
+}
+
+/// Draw a bordered `box_w` x `box_h` box centered on screen holding `lines`,
+/// each in its own colour, and return the cells it covers. Lines past the
+/// bottom or right edge of the box are cut off.
+fn draw_panel(
+    out: &mut impl Write,
+    cols: u16,
+    rows: u16,
+    (box_w, box_h): (u16, u16),
+    lines: &[(style::Color, String)],
+) -> io::Result<CellRect> {
+    let start_col = cols.saturating_sub(box_w) / 2;
+    let start_row = rows.saturating_sub(box_h) / 2;
+    draw_panel_at(out, (start_col, start_row), (box_w, box_h), lines)?;
+    Ok((start_col, start_row, box_w.min(cols), box_h.min(rows)))
+}
+
+/// Draw a bordered box with its top left corner at `(start_col, start_row)`,
+/// as `draw_panel` does. A box too small for its border isn't drawn.
+fn draw_panel_at(
+    out: &mut impl Write,
+    (start_col, start_row): (u16, u16),
+    (box_w, box_h): (u16, u16),
+    lines: &[(style::Color, String)],
+) -> io::Result<()> {
+    if box_w < 2 || box_h < 2 {
+        return Ok(());
+    }
+    let content_w = (box_w - 2) as usize;
+    queue!(
+        out,
+        SetForegroundColor(style::Color::White),
+        SetBackgroundColor(style::Color::Black),
+    )?;
+
+    // Top border
+    queue!(out, cursor::MoveTo(start_col, start_row))?;
+    write!(out, "┌{}┐", "─".repeat(content_w))?;
+
+    // Content lines
+    let blank = (style::Color::White, String::new());
+    for i in 0..box_h - 2 {
+        let (color, line) = lines.get(i as usize).unwrap_or(&blank);
+        let r = start_row + 1 + i;
+        queue!(out, cursor::MoveTo(start_col, r))?;
+        write!(out, "│")?;
+        queue!(out, SetForegroundColor(*color))?;
+        write!(out, "{}", fit_width(line, content_w))?;
+        queue!(out, SetForegroundColor(style::Color::White))?;
+        write!(out, "│")?;
+    }
+
+    // Bottom border
+    queue!(out, cursor::MoveTo(start_col, start_row + box_h - 1))?;
+    write!(out, "└{}┘", "─".repeat(content_w))?;
+
+    queue!(
+        out,
+        SetForegroundColor(style::Color::Reset),
+        SetBackgroundColor(style::Color::Reset),
+    )?;
+    Ok(())
+}
+
+/// `text` cut or padded with spaces to take exactly `width` columns. Wide
+/// characters count as two columns, and control characters are shown as `?`
+/// so they can't move the cursor.
+fn fit_width(text: &str, width: usize) -> String {
+    let mut fitted = String::new();
+    let mut used = 0;
+    for c in text.chars() {
+        let c = if c.is_control() { '?' } else { c };
+        let w = char_width(c);
+        if used + w > width {
+            break;
+        }
+        fitted.push(c);
+        used += w;
+    }
+    fitted.push_str(&" ".repeat(width - used));
+    fitted
+}
+
+/// Columns `text` takes in the terminal.
+fn text_width(text: &str) -> usize {
+    text.chars().map(char_width).sum()
+}
+
+/// Columns a character takes in the terminal: two for the East Asian wide
+/// ranges and emoji, one otherwise.
+fn char_width(c: char) -> usize {
+    match c as u32 {
+        0x1100..=0x115F
+        | 0x2E80..=0x303E
+        | 0x3041..=0x33FF
+        | 0x3400..=0x4DBF
+        | 0x4E00..=0x9FFF
+        | 0xA000..=0xA4CF
+        | 0xAC00..=0xD7A3
+        | 0xF900..=0xFAFF
+        | 0xFE30..=0xFE4F
+        | 0xFF00..=0xFF60
+        | 0xFFE0..=0xFFE6
+        | 0x1F300..=0x1F64F
+        | 0x1F900..=0x1F9FF
+        | 0x20000..=0x3FFFD => 2,
+        _ => 1,
+    }
+}
+
+/// Widest the info panel gets; it never takes more than half the screen.
+const INFO_PANEL_WIDTH: u16 = 38;
+
+/// Lines of the luminance histogram at the bottom of the info panel.
+const HISTOGRAM_ROWS: usize = 4;
+
+/// Width of the info panel on a screen `cols` wide, or 0 if there is no
+/// room for it.
+fn info_panel_width(cols: u16) -> u16 {
+    let width = INFO_PANEL_WIDTH.min(cols / 2);
+    if width < 12 {
+        0
+    } else {
+        width
+    }
+}
+
+/// Draw the info panel down the right-hand side of the image rows.
+fn draw_info_panel(out: &mut impl Write, cols: u16, image_rows: u16, app: &App) -> io::Result<()> {
+    let box_w = info_panel_width(cols);
+    if box_w == 0 || image_rows < 3 {
+        return Ok(());
+    }
+    let content_w = (box_w - 2) as usize;
+    let value_w = content_w.saturating_sub(12);
+    let field = |label: &str, value: &str| {
+        // Cut long values short with an ellipsis rather than at the border
+        let value = if value.chars().map(char_width).sum::<usize>() > value_w {
+            fit_width(value, value_w.saturating_sub(1)).trim_end().to_string() + "…"
+        } else {
+            value.to_string()
+        };
+        (style::Color::White, format!(" {:<10} {}", label, value))
+    };
+
+    let mut lines = vec![field("Name", &app.current_filename())];
+    if let Some(ref img) = app.current_image {
+        let (w, h) = img.dimensions();
+        lines.push(field("Dimensions", &format!("{}x{}", w, h)));
+    }
+    if let Some(ref info) = app.info {
+        lines.extend(info.fields.iter().map(|(label, value)| field(label, value)));
+        lines.push((style::Color::White, String::new()));
+        let bars = info.histogram_lines(content_w.saturating_sub(2), HISTOGRAM_ROWS);
+        lines.extend(bars.into_iter().map(|bar| (style::Color::Grey, format!(" {}", bar))));
+    }
+
+    let box_h = (lines.len() as u16 + 2).min(image_rows);
+    draw_panel_at(out, (cols - box_w, 0), (box_w, box_h), &lines)
+}
+
+// ---------------------------------------------------------------------------
+// Key bindings
+// ---------------------------------------------------------------------------
+
+/// Something the user can ask for from the keyboard.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Action {
+    Quit,
+    Next,
+    Prev,
+    First,
+    Last,
+    Up,
+    Down,
+    PageUp,
+    PageDown,
+    Open,
+    ToggleGallery,
+    Reload,
+    ZoomIn,
+    ZoomOut,
+    ZoomReset,
+    CycleFit,
+    TogglePixelGrid,
+    Measure,
+    /// `y` while measuring: the measurement onto the clipboard
+    CopyMeasurement,
+    PanUp,
+    PanDown,
+    PanLeft,
+    PanRight,
+    Follow,
+    FlipSide,
+    ToggleSlideshow,
+    TogglePlayback,
+    NextFrame,
+    PrevFrame,
+    RotateCw,
+    RotateCcw,
+    FlipHorizontal,
+    FlipVertical,
+    Delete,
+    Move,
+    Copy,
+    ToggleInfo,
+    ToggleHelp,
+    ToggleLog,
+}
+
+/// Reduce a key event to the form bindings are written in.
+///
+/// Legacy encoding reports Shift+g as `G` (sometimes with SHIFT set, sometimes
+/// not), while the kitty keyboard protocol may report it as `g` + SHIFT. For
+/// character keys the shift is folded into the character and dropped from the
+/// modifiers, so `Char('G')` with no modifiers matches under both encodings.
+fn normalize_key(key: &KeyEvent) -> (KeyCode, KeyModifiers) {
+    match key.code {
+        KeyCode::Char(c) => {
+            let c = if key.modifiers.contains(KeyModifiers::SHIFT) {
+                c.to_ascii_uppercase()
+            } else {
+                c
+            };
+            (KeyCode::Char(c), key.modifiers - KeyModifiers::SHIFT)
+        }
+        code => (code, key.modifiers),
+    }
+}
+
+/// What carrying out an action may do besides changing what is on screen.
+/// Ordered from harmless to destructive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Access {
+    /// Nothing: it only changes the view.
+    View,
+    /// Writes a new file at a path typed in for it, touching nothing else.
+    Export,
+    /// Changes, removes or adds files where they weren't explicitly asked for.
+    Modify,
+}
+
+impl Action {
+    /// The most this action may do. Every action is listed, so a new one
+    /// can't be added without deciding whether `--read-only` refuses it.
+    fn access(self) -> Access {
+        match self {
+            Action::Delete | Action::Move | Action::Copy => Access::Modify,
+            // The clipboard is written through the terminal, outside termview
+            Action::CopyMeasurement => Access::Export,
+            Action::Quit
+            | Action::Next
+            | Action::Prev
+            | Action::First
+            | Action::Last
+            | Action::Up
+            | Action::Down
+            | Action::PageUp
+            | Action::PageDown
+            | Action::Open
+            | Action::ToggleGallery
+            | Action::Reload
+            | Action::ZoomIn
+            | Action::ZoomOut
+            | Action::ZoomReset
+            | Action::CycleFit
+            | Action::TogglePixelGrid
+            | Action::Measure
+            | Action::PanUp
+            | Action::PanDown
+            | Action::PanLeft
+            | Action::PanRight
+            | Action::Follow
+            | Action::FlipSide
+            | Action::ToggleSlideshow
+            | Action::TogglePlayback
+            | Action::NextFrame
+            | Action::PrevFrame
+            | Action::RotateCw
+            | Action::RotateCcw
+            | Action::FlipHorizontal
+            | Action::FlipVertical
+            | Action::ToggleInfo
+            | Action::ToggleHelp
+            | Action::ToggleLog => Access::View,
+        }
+    }
+}
+
+/// How much `--read-only` refuses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum ReadOnly {
+    /// Refuse anything that changes existing files or writes unasked
+    Normal,
+    /// Also refuse writing new files to typed-in paths, and the clipboard
+    Strict,
+}
+
+impl ReadOnly {
+    /// The most an action may do in this mode.
+    fn allows(self) -> Access {
+        match self {
+            ReadOnly::Normal => Access::Export,
+            ReadOnly::Strict => Access::View,
+        }
+    }
+}
+
+/// Map a key event to the action bound to it, if any.
+fn action_for(key: &KeyEvent) -> Option<Action> {
+    let (code, mods) = normalize_key(key);
+    let ctrl = mods.contains(KeyModifiers::CONTROL);
+
+    let action = match code {
+        KeyCode::Char('c') if ctrl => Action::Quit,
+        KeyCode::Char('r') if ctrl => Action::Reload,
+        KeyCode::Left if ctrl => Action::PanLeft,
+        KeyCode::Right if ctrl => Action::PanRight,
+        KeyCode::Up if ctrl => Action::PanUp,
+        KeyCode::Down if ctrl => Action::PanDown,
+        _ if !(mods - KeyModifiers::SHIFT).is_empty() => return None,
+
+        KeyCode::Char('q') | KeyCode::Esc => Action::Quit,
+
+        KeyCode::Right | KeyCode::Char('l') => Action::Next,
+        KeyCode::Left | KeyCode::Char('h') => Action::Prev,
+        KeyCode::Home | KeyCode::Char('g') => Action::First,
+        KeyCode::End | KeyCode::Char('G') => Action::Last,
+        KeyCode::Up | KeyCode::Char('k') => Action::Up,
+        KeyCode::Down | KeyCode::Char('j') => Action::Down,
+        KeyCode::PageUp => Action::PageUp,
+        KeyCode::PageDown => Action::PageDown,
+        KeyCode::Enter => Action::Open,
+        KeyCode::Char('t') => Action::ToggleGallery,
+
+        KeyCode::Char('+') | KeyCode::Char('=') => Action::ZoomIn,
+        KeyCode::Char('-') | KeyCode::Char('_') => Action::ZoomOut,
+        KeyCode::Char('0') => Action::ZoomReset,
+        KeyCode::Char('z') => Action::CycleFit,
+        KeyCode::Char('#') => Action::TogglePixelGrid,
+        KeyCode::Char('M') => Action::Measure,
+
+        KeyCode::Char('w') => Action::PanUp,
+        KeyCode::Char('s') => Action::PanDown,
+        KeyCode::Char('a') => Action::PanLeft,
+        KeyCode::Char('d') => Action::PanRight,
+
+        KeyCode::Char('F') => Action::Follow,
+        KeyCode::Tab => Action::FlipSide,
+        KeyCode::Char('p') => Action::ToggleSlideshow,
+        KeyCode::Char(' ') => Action::TogglePlayback,
+        KeyCode::Char('.') => Action::NextFrame,
+        KeyCode::Char(',') => Action::PrevFrame,
+        KeyCode::Char('x') | KeyCode::Delete => Action::Delete,
+        KeyCode::Char('m') => Action::Move,
+        KeyCode::Char('c') => Action::Copy,
+        KeyCode::Char('r') => Action::RotateCw,
+        KeyCode::Char('R') => Action::RotateCcw,
+        KeyCode::Char('f') => Action::FlipHorizontal,
+        KeyCode::Char('v') => Action::FlipVertical,
+        KeyCode::Char('i') => Action::ToggleInfo,
+        KeyCode::Char('?') => Action::ToggleHelp,
+        KeyCode::Char('L') => Action::ToggleLog,
+
+        _ => return None,
+    };
+    Some(action)
+}
+
+// ---------------------------------------------------------------------------
+// App state
+// ---------------------------------------------------------------------------
+
+/// How much of the screen a background update (e.g. a directory rescan) touched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Refresh {
+    Nothing,
+    Status,
+    Image,
+}
+
+/// A question on screen that takes the keyboard until it is answered. Each
+/// is about the file that was current when it was asked, whatever a
+/// slideshow or rescan has shown since.
+enum Prompt {
+    /// Waiting for `y` to delete `file`.
+    ConfirmDelete { file: Target },
+    /// Typing the directory to move or copy `file` to, on the status bar.
+    Destination {
+        op: Transfer,
+        input: String,
+        file: Target,
+    },
+    /// Waiting for `y` to try `op` again after it failed because the file
+    /// changed since it was shown, or was in use.
+    Retry { op: FileOp, failure: Failure },
+}
+
+impl Prompt {
+    fn file(&self) -> &Target {
+        match self {
+            Prompt::ConfirmDelete { file } | Prompt::Destination { file, .. } => file,
+            Prompt::Retry { op, .. } => op.target(),
+        }
+    }
+}
+
+/// Deleting, moving or copying a file, once confirmed.
+enum FileOp {
+    Delete(Target),
+    Transfer(Transfer, Target, PathBuf),
+}
+
+impl FileOp {
+    fn target(&self) -> &Target {
+        match self {
+            FileOp::Delete(file) | FileOp::Transfer(_, file, _) => file,
+        }
+    }
+
+    fn verb(&self) -> &'static str {
+        match self {
+            FileOp::Delete(_) => "delete",
+            FileOp::Transfer(Transfer::Move, ..) => "move",
+            FileOp::Transfer(Transfer::Copy, ..) => "copy",
+        }
+    }
+
+    /// The same operation, going ahead whatever the file looks like now.
+    fn unchecked(mut self) -> Self {
+        match &mut self {
+            FileOp::Delete(file) | FileOp::Transfer(_, file, _) => file.expected = None,
+        }
+        self
+    }
+}
+
+/// Playback state for a multi-frame image. The frame on screen is also held
+/// in `App::current_image`, so zoom and pan work on it like any other image.
+struct Animation {
+    frames: Vec<Frame>,
+    current: usize,
+    paused: bool,
+    /// The `image_key` it was shown under, telling its frames from those of
+    /// another decode.
+    key: u64,
+}
+
+/// One frame of one decoded animation, turned one way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FrameId {
+    animation: u64,
+    frame: usize,
+    orientation: Orientation,
+}
+
+/// How long each image is shown by a slideshow started with `p`.
+const DEFAULT_SLIDESHOW_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A running slideshow (`--slideshow`, `p`).
+struct Slideshow {
+    interval: Duration,
+    /// When to move to the next image. Counted from when the last move was
+    /// due rather than from when its image appeared, so the time spent
+    /// decoding doesn't make the show drift.
+    due: Instant,
+    /// Time that was left when the show was paused with Space.
+    paused: Option<Duration>,
+}
+
+impl Slideshow {
+    fn new(interval: Duration) -> Self {
+        Slideshow {
+            interval,
+            due: Instant::now() + interval,
+            paused: None,
+        }
+    }
+
+    /// Pause, or carry on with the time that was left. Returns whether the
+    /// show is now paused.
+    fn toggle_pause(&mut self) -> bool {
+        let now = Instant::now();
+        match self.paused.take() {
+            Some(left) => self.due = now + left,
+            None => self.paused = Some(self.due.saturating_duration_since(now)),
+        }
+        self.paused.is_some()
+    }
+
+    /// Set the next move's time once the one due has happened at `now`.
+    fn advance(&mut self, now: Instant) {
+        self.due += self.interval;
+        // An image that took longer than a whole interval to decode gets one of its own
+        if self.due <= now {
+            self.due = now + self.interval;
+        }
+    }
+
+    /// Start the countdown to the next image over.
+    fn restart(&mut self) {
+        self.due = Instant::now() + self.interval;
+        if self.paused.is_some() {
+            self.paused = Some(self.interval);
+        }
+    }
+}
+
+/// Magnification at which the pixel grid turns on unless `--pixel-grid-at`
+/// says otherwise.
+const DEFAULT_PIXEL_GRID_AT: f64 = 8.0;
+
+/// Below this many screen pixels per image pixel the grid lines would cover
+/// most of the image, so the grid isn't drawn even when turned on.
+const MIN_PIXEL_GRID_SCALE: f64 = 4.0;
+
+struct App {
+    images: Playlist,
+    index: usize,
+    current_image: Option<DynamicImage>,
+    /// Changes whenever the pixels of `current_image` do, so the renderer can
+    /// tell when an image it already sent is still good.
+    image_key: u64,
+    animation: Option<Animation>,
+    /// Rotation and flips from the keyboard, already applied to
+    /// `current_image` (but not to the frames of `animation`).
+    orientation: Orientation,
+    /// The current image still has to be decoded. This happens once input
+    /// has settled, so holding an arrow key skips past images undecoded.
+    decode_pending: bool,
+    /// Header of a large image that is about to be decoded. While set, a
+    /// placeholder of the right size is drawn in place of the image.
+    loading: Option<Probe>,
+    /// Decode of the current image in progress, polled from the event loop.
+    decoding: Option<Pending>,
+    /// Decode of a gallery thumbnail in progress, and whose it is.
+    thumb_decoding: Option<(EntryId, Decoding)>,
+    /// File whose pixels are in `current_image`.
+    shown: Option<PathBuf>,
+    /// The current file as it was on disk when it was displayed, so a
+    /// delete or move can tell whether it has changed since.
+    displayed: Option<Target>,
+    error_message: Option<String>,
+    /// The info panel is open beside the image.
+    show_info: bool,
+    /// What the info panel lists about the current image, gathered when the
+    /// panel is open.
+    info: Option<Info>,
+    show_help: bool,
+    log: SessionLog,
+    prompt: Option<Prompt>,
+    /// Rescan of the followed or awaited directory in progress.
+    scan: Option<Task<Scan>>,
+    /// Bumped whenever the list is edited here rather than by a scan, so a
+    /// scan that started from the older list isn't applied over the edit.
+    list_version: u64,
+    /// How the last scan changed the list (e.g. `+500 files`), and until when
+    /// the status bar shows it.
+    list_notice: Option<(String, Instant)>,
+    /// A filesystem operation has been taking longer than `slowfs::SLOW_AFTER`.
+    fs_slow: bool,
+    /// Watches the browsed directory (not with `--no-watch`).
+    watcher: Option<Watch>,
+    /// Preset targets for `m` and `c` (`--move-to`, `--copy-to`).
+    move_to: Option<PathBuf>,
+    copy_to: Option<PathBuf>,
+    /// Actions that change files are refused (`--read-only`).
+    read_only: Option<ReadOnly>,
+    /// Delete without going through the trash (`--no-trash`).
+    delete_permanently: bool,
+    /// Where the trash is, and `$HOME` for `~` in typed paths.
+    persistence: Persistence,
+    zoom: f64,
+    /// Kept from image to image.
+    fit_mode: FitMode,
+    /// Space kept clear around the image and the grid.
+    margins: Margins,
+    /// Size of a terminal cell in pixels.
+    cell_size: (u16, u16),
+    cell_sizing: CellSizing,
+    /// Let `Fit` and `FitWidth` enlarge small images (`--upscale`).
+    upscale: bool,
+    /// Pixel grid turned on or off with `#`; `None` leaves it to the zoom.
+    pixel_grid: Option<bool>,
+    /// Magnification at which the grid turns on by itself (`--pixel-grid-at`).
+    pixel_grid_at: f64,
+    /// Measurement mode (`M`) and the measurements drawn over the image.
+    measure: Option<Measure>,
+    /// Text to put on the clipboard through the terminal on the next pass.
+    clipboard: Option<String>,
+    /// Ring the terminal's bell on the next pass: a key had nothing to do.
+    bell: bool,
+    pan_x: f64,
+    pan_y: f64,
+    follow: Option<Follow>,
+    /// Directory being watched for a first image (`--wait-for-images`).
+    waiting_for: Option<PathBuf>,
+    compare: Option<Compare>,
+    slideshow: Option<Slideshow>,
+    /// Interval `p` starts a slideshow with.
+    slideshow_interval: Duration,
+    /// Stop the slideshow after the last image rather than wrapping (`--once`).
+    slideshow_once: bool,
+    gallery: Gallery,
+    /// Thumbnails shared with other programs (not with `--no-thumbnail-cache`).
+    thumb_cache: Option<ThumbCache>,
+    /// The `.termview.toml` whose settings are in effect.
+    dir_config: Option<PathBuf>,
+    /// Where input is being recorded (`--record-input`).
+    recorder: Option<Recorder>,
+    /// How directories are rescanned in follow mode and while waiting.
+    listing: ListOptions,
+    loader: Loader,
+    preloader: Option<Preloader>,
+}
+
+impl App {
+    /// Set up the viewer on `start_index`. Nothing is loaded until
+    /// `load_current` is called, so the caller can finish configuring first.
+    fn new(images: Vec<PathBuf>, start_index: usize, loader: Loader) -> Self {
+        App {
+            images: Playlist::new(images),
+            index: start_index,
+            current_image: None,
+            image_key: 0,
+            animation: None,
+            orientation: Orientation::default(),
+            decode_pending: false,
+            decoding: None,
+            thumb_decoding: None,
+            loading: None,
+            shown: None,
+            displayed: None,
+            error_message: None,
+            show_info: false,
+            info: None,
+            show_help: false,
+            log: SessionLog::new(None),
+            prompt: None,
+            scan: None,
+            list_version: 0,
+            list_notice: None,
+            fs_slow: false,
+            watcher: None,
+            move_to: None,
+            copy_to: None,
+            read_only: None,
+            delete_permanently: false,
+            persistence: Persistence::in_memory(),
+            zoom: 1.0,
+            fit_mode: FitMode::Fit,
+            margins: Margins::NONE,
+            cell_size: DEFAULT_CELL_SIZE,
+            cell_sizing: CellSizing::Detect,
+            upscale: false,
+            pixel_grid: None,
+            pixel_grid_at: DEFAULT_PIXEL_GRID_AT,
+            measure: None,
+            clipboard: None,
+            bell: false,
+            pan_x: 0.0,
+            pan_y: 0.0,
+            follow: None,
+            waiting_for: None,
+            compare: None,
+            slideshow: None,
+            slideshow_interval: DEFAULT_SLIDESHOW_INTERVAL,
+            slideshow_once: false,
+            gallery: Gallery::new(),
+            thumb_cache: None,
+            dir_config: None,
+            recorder: None,
+            listing: ListOptions::default(),
+            loader,
+            preloader: None,
+        }
+    }
+
+    fn load_current(&mut self) {
+        self.stash_current();
+        self.decoding = None;
+        self.displayed = None;
+        self.error_message = None;
+        self.info = None;
+        self.animation = None;
+        self.orientation = Orientation::default();
+        self.decode_pending = false;
+        self.zoom = 1.0;
+        self.pan_x = 0.0;
+        self.pan_y = 0.0;
+
+        if self.images.is_empty() {
+            self.current_image = None;
+            self.loading = None;
+            self.error_message = Some(match self.waiting_for {
+                Some(ref dir) => format!("Waiting for images in {}…", dir.display()),
+                None => "No images found in directory".into(),
+            });
+            return;
+        }
+
+        let path = self.images[self.index].to_path_buf();
+        self.current_image = None;
+        self.loading = None;
+
+        if let Some(ref compare) = self.compare {
+            if compare.missing_from(self.index) == Some(compare.side) {
+                self.error_message = Some(format!(
+                    "{} is only in {}",
+                    self.current_filename(),
+                    compare.dir(compare.side.other()).display()
+                ));
+                return;
+            }
+        }
+
+        // Decoding happens on the next pass through the event loop; large
+        // images get a placeholder drawn in the meantime.
+        self.decode_pending = true;
+        self.loading = slowfs::within(PROBE_TIMEOUT, move || probe_image(&path))
+            .flatten()
+            .filter(|p| p.width as u64 * p.height as u64 >= PLACEHOLDER_MIN_PIXELS);
+    }
+
+    /// Decode the image from disk again, bypassing the cache.
+    fn reload(&mut self) {
+        self.shown = None;
+        self.load_current();
+    }
+
+    /// Start the decode deferred by `load_current`. Returns whether the
+    /// image could be shown straight away, ready in the preloader's cache.
+    fn finish_load(&mut self) -> bool {
+        if !self.decode_pending {
+            return false;
+        }
+        self.decode_pending = false;
+        let path = self.images[self.index].to_path_buf();
+        let preloaded = self.preloader.as_ref().map_or(Preloaded::Missing, |p| p.take(&path));
+        let job = match preloaded {
+            Preloaded::Ready(result) => {
+                self.loading = None;
+                self.show_decoded(path, result);
+                return true;
+            }
+            Preloaded::Decoding => Job::Preloader,
+            Preloaded::Missing => Job::Thread(self.loader.start(&path)),
+        };
+        self.decoding = Some(Pending {
+            path,
+            job,
+            reload: None,
+        });
+        false
+    }
+
+    /// Whether a decode is in progress that the event loop should poll for.
+    fn is_decoding(&self) -> bool {
+        self.decoding.is_some() || (self.gallery.active && self.thumb_decoding.is_some())
+    }
+
+    /// Show the current image once its decode is in. Also returns whether
+    /// to look at watched changes again, after a reload failed the first time.
+    fn poll_decode(&mut self) -> (Refresh, bool) {
+        let Some(pending) = self.decoding.as_mut() else {
+            return (Refresh::Nothing, false);
+        };
+        let result = match &pending.job {
+            Job::Thread(decoding) => decoding.poll(),
+            Job::Preloader => {
+                let preloaded = match self.preloader.as_ref() {
+                    Some(preloader) => preloader.take(&pending.path),
+                    None => Preloaded::Missing,
+                };
+                match preloaded {
+                    Preloaded::Ready(result) => Some(result),
+                    Preloaded::Decoding => None,
+                    // The worker moved on to another request without it
+                    Preloaded::Missing => {
+                        pending.job = Job::Thread(self.loader.start(&pending.path));
+                        None
+                    }
+                }
+            }
+        };
+        let Some(result) = result else {
+            return (Refresh::Nothing, false);
+        };
+        let pending = self.decoding.take().expect("checked above");
+        let Some(changed) = pending.reload else {
+            self.loading = None;
+            self.show_decoded(pending.path, result);
+            return (Refresh::Image, false);
+        };
+        if let Some(watch) = self.watcher.as_mut() {
+            if result.is_err() && !watch.retried {
+                watch.retried = true;
+                watch.changed.extend(changed);
+                return (Refresh::Nothing, true);
+            }
+            watch.retried = false;
+        }
+        self.reload_in_place(pending.path, result);
+        (Refresh::Image, false)
+    }
+
+    /// Display the result of decoding `path`, or the error.
+    fn show_decoded(&mut self, path: PathBuf, result: Result<Decoded, LoadError>) {
+        self.image_key += 1;
+        // Measurements survive a reload of the same image, not another one
+        if self.measure.as_ref().is_some_and(|m| m.path != path) {
+            self.measure = None;
+        }
+        match result {
+            Ok(Decoded::Still(img)) => self.current_image = Some(img),
+            Ok(Decoded::Animated(frames)) => {
+                self.current_image = Some(frames[0].0.clone());
+                self.animation = Some(Animation {
+                    frames,
+                    current: 0,
+                    paused: false,
+                    key: self.image_key,
+                });
+            }
+            Err(e) => {
+                self.current_image = None;
+                let message = format!("Failed to load {}: {}", path.display(), e);
+                self.log.push(Severity::Error, message.clone());
+                self.error_message = Some(message);
+            }
+        }
+        if self.loader.memory.is_none() {
+            let stat = path.clone();
+            let expected = slowfs::within(PROBE_TIMEOUT, move || Identity::of(&stat).ok()).flatten();
+            self.displayed = Some(Target {
+                path: path.clone(),
+                expected,
+            });
+        }
+        if self.current_image.is_some() {
+            self.shown = Some(path);
+        }
+        self.info = None;
+        self.gather_info();
+        self.prefetch_neighbours();
+    }
+
+    /// Give the displayed image to the preloader's cache before switching
+    /// away from it, so coming back is instant.
+    fn stash_current(&mut self) {
+        let (Some(path), Some(preloader)) = (self.shown.take(), self.preloader.as_ref()) else {
+            return;
+        };
+        let decoded = match (self.animation.take(), self.current_image.take()) {
+            (Some(anim), _) => Decoded::Animated(anim.frames),
+            // The cache holds images as they were decoded
+            (None, Some(img)) => Decoded::Still(self.orientation.inverse().apply(img)),
+            (None, None) => return,
+        };
+        preloader.put(path, decoded);
+    }
+
+    /// Start decoding the images either side of the current one.
+    fn prefetch_neighbours(&self) {
+        let Some(ref preloader) = self.preloader else {
+            return;
+        };
+        let len = self.images.len();
+        if len < 2 {
+            return;
+        }
+        let next = (self.index + 1) % len;
+        let prev = (self.index + len - 1) % len;
+        let mut paths = vec![self.images[next].to_path_buf()];
+        if prev != next {
+            paths.push(self.images[prev].to_path_buf());
+        }
+        preloader.prefetch(paths);
+    }
+
+    /// Move to `index`, loading it unless it is already the displayed image.
+    /// Returns whether anything changed.
+    fn goto(&mut self, index: usize) -> bool {
+        if self.images.is_empty() {
+            return false;
+        }
+        let index = index.min(self.images.len() - 1);
+        if index == self.index {
+            return false;
+        }
+        self.index = index;
+        self.load_current();
+        true
+    }
+
+    /// Carry out a navigation key. One that lands on the displayed image,
+    /// as any does with a single image, rings the bell instead.
+    fn navigate(&mut self, action: Action) -> bool {
+        let moved = match action {
+            Action::Next => self.next(),
+            Action::Prev => self.prev(),
+            Action::First => self.first(),
+            Action::Last => self.last(),
+            _ => false,
+        };
+        self.bell |= !moved;
+        moved
+    }
+
+    fn next(&mut self) -> bool {
+        if self.images.is_empty() {
+            return false;
+        }
+        self.goto((self.index + 1) % self.images.len())
+    }
+
+    fn prev(&mut self) -> bool {
+        if self.images.is_empty() {
+            return false;
+        }
+        let index = if self.index == 0 {
+            self.images.len() - 1
+        } else {
+            self.index - 1
+        };
+        self.goto(index)
+    }
+
+    fn first(&mut self) -> bool {
+        self.goto(0)
+    }
+
+    fn last(&mut self) -> bool {
+        self.goto(self.images.len().saturating_sub(1))
+    }
+
+    /// Read what the directory watcher has seen. Returns whether there was
+    /// anything, in which case `settle_watch` should follow once it stops.
+    fn drain_watch(&mut self) -> Option<Instant> {
+        let watch = self.watcher.as_mut()?;
+        let changes = watch.watcher.drain();
+        if changes.is_empty() {
+            return None;
+        }
+        for (path, change) in &changes {
+            if *change == Change::Listing {
+                watch.listing_changed = true;
+            }
+            watch.changed.insert(path.clone());
+        }
+        let now = Instant::now();
+        let since = *watch.pending_since.get_or_insert(now);
+        Some((now + WATCH_DEBOUNCE).min(since + WATCH_MAX_DELAY))
+    }
+
+    /// Act on what the watcher saw: rescan the list if files came or went,
+    /// and reload the displayed image if it was rewritten. Also returns
+    /// whether to try again shortly, when a rescan is still running or a
+    /// reload failed the first time.
+    fn settle_watch(&mut self) -> (Refresh, bool) {
+        let Some(watch) = self.watcher.as_mut() else {
+            return (Refresh::Nothing, false);
+        };
+        let dir = watch.dir.clone();
+        let mut refresh = Refresh::Nothing;
+
+        if watch.listing_changed {
+            match self.poll_scan(&dir) {
+                (Some(scan), scan_refresh) => {
+                    refresh = scan_refresh.max(self.apply_scan(scan.images, &scan.changes));
+                }
+                (None, scan_refresh) => return (scan_refresh, true),
+            }
+        }
+        let Some(watch) = self.watcher.as_mut() else {
+            return (refresh, false);
+        };
+        watch.listing_changed = false;
+        watch.pending_since = None;
+        let changed = std::mem::take(&mut watch.changed);
+        for id in changed.iter().filter_map(|path| self.images.id_of(path)) {
+            self.gallery.forget_thumb(id);
+        }
+        if self.gallery.active && changed.iter().any(|p| self.images.contains(p)) {
+            refresh = Refresh::Image;
+        }
+
+        // A rescan that switched images has loaded the new one already
+        let current = self.images.get(self.index).map(Path::to_path_buf);
+        let rewritten = current.filter(|p| {
+            changed.contains(p) || p.parent().is_some_and(|d| changed.contains(d))
+        });
+        let Some(path) = rewritten else {
+            return (refresh, false);
+        };
+        // A file still to be shown for the first time is read as it is now
+        if self.decode_pending || self.decoding.as_ref().is_some_and(|p| p.reload.is_none()) {
+            return (refresh, false);
+        }
+
+        self.decoding = Some(Pending {
+            job: Job::Thread(self.loader.start(&path)),
+            path,
+            reload: Some(changed),
+        });
+        (refresh, false)
+    }
+
+    /// Show a new decode of the displayed image, keeping zoom, pan and
+    /// rotation as they were.
+    fn reload_in_place(&mut self, path: PathBuf, result: Result<Decoded, LoadError>) {
+        self.shown = None;
+        self.animation = None;
+        self.error_message = None;
+        self.show_decoded(path, result);
+        if let Some(img) = self.current_image.take() {
+            self.current_image = Some(self.orientation.apply(img));
+        }
+    }
+
+    /// Rescan `dir` on another thread, returning the result once it is in.
+    /// Only one scan runs at a time, so a hung filesystem ties up a single
+    /// thread rather than one per tick.
+    fn background_scan(&mut self, dir: &Path) -> Option<Scan> {
+        let task = self.scan.get_or_insert_with(|| {
+            let dir = dir.to_path_buf();
+            let listing = self.listing.clone();
+            let mut images = self.images.to_vec();
+            let version = self.list_version;
+            Task::spawn(move || {
+                let changes = listing.rescan(&dir, &mut images);
+                let newest = newest_image(&images);
+                Scan {
+                    version,
+                    images,
+                    changes,
+                    newest,
+                }
+            })
+        });
+        match task.wait(SCAN_WAIT) {
+            Progress::Done(scan) => {
+                self.scan = None;
+                self.fs_slow = false;
+                // A file deleted or moved meanwhile may be in it still; the
+                // next tick scans again
+                if scan.version == self.list_version {
+                    return Some(scan);
+                }
+            }
+            Progress::Running => {}
+            Progress::Slow => {
+                if !self.fs_slow {
+                    self.log.push(
+                        Severity::Warning,
+                        format!("Scanning {} is taking a while", dir.display()),
+                    );
+                }
+                self.fs_slow = true;
+            }
+            Progress::Abandoned => {
+                self.scan = None;
+                self.fs_slow = true;
+                self.log.push(
+                    Severity::Error,
+                    format!("Gave up scanning {}; trying again", dir.display()),
+                );
+            }
+        }
+        None
+    }
+
+    /// Rescan `dir` in the background. The refresh covers the slow filesystem
+    /// indicator coming or going; the scan is returned once it is in.
+    fn poll_scan(&mut self, dir: &Path) -> (Option<Scan>, Refresh) {
+        let was_slow = self.fs_slow;
+        let scan = self.background_scan(dir);
+        let refresh = if self.fs_slow != was_slow {
+            Refresh::Status
+        } else {
+            Refresh::Nothing
+        };
+        (scan, refresh)
+    }
+
+    /// Take the list from a finished scan, keeping the displayed file and
+    /// the selected thumbnail on the same files if they are still present.
+    /// However many files a scan turns up, this is one update to the list,
+    /// made between keys.
+    fn apply_scan(&mut self, images: Vec<PathBuf>, changes: &ListDiff) -> Refresh {
+        let current = self.images.id(self.index);
+        let selected = self.images.id(self.gallery.selected);
+        self.images.replace(images);
+        if !changes.is_empty() {
+            self.log.push(
+                Severity::Info,
+                format!(
+                    "Image list changed: {} added, {} removed",
+                    changes.added.len(),
+                    changes.removed.len()
+                ),
+            );
+            self.list_notice = Some((list_notice(changes), Instant::now() + LIST_NOTICE_TIME));
+        }
+        let selected = selected.and_then(|sel| self.images.index_of(sel));
+        let before = self.gallery.selected;
+        self.gallery.select(selected.unwrap_or(before), self.images.len());
+        let refresh = if self.gallery.active && self.gallery.selected != before {
+            Refresh::Image
+        } else if changes.is_empty() {
+            Refresh::Nothing
+        } else {
+            Refresh::Status
+        };
+        if current == self.images.id(self.index) {
+            // Nothing moved, but the counter may still have changed
+            return refresh;
+        }
+
+        match current.and_then(|cur| self.images.index_of(cur)) {
+            Some(i) => {
+                self.index = i;
+                refresh.max(Refresh::Status)
+            }
+            None => {
+                self.index = self.index.min(self.images.len().saturating_sub(1));
+                self.load_current();
+                Refresh::Image
+            }
+        }
+    }
+
+    /// When the note on the last list change leaves the status bar.
+    fn list_notice_due(&self) -> Option<Instant> {
+        self.list_notice.as_ref().map(|(_, until)| *until)
+    }
+
+    /// Rescan the followed directory, switching to a newly arrived image
+    /// once it has finished being written.
+    fn poll_follow(&mut self) -> Refresh {
+        let Some(follow) = self.follow.as_ref() else {
+            return Refresh::Nothing;
+        };
+        let dir = follow.dir.clone();
+
+        let (scan, refresh) = self.poll_scan(&dir);
+        let Some(scan) = scan else {
+            return refresh;
+        };
+        let mut refresh = refresh.max(self.apply_scan(scan.images, &scan.changes));
+
+        let newest = scan.newest;
+        let Some(follow) = self.follow.as_mut() else {
+            return refresh;
+        };
+        let settled = newest.is_some() && newest == follow.candidate;
+        follow.candidate = newest;
+
+        // Switching is left until a question about this file is answered
+        if settled && follow.engaged && self.prompt.is_none() {
+            if let Some(target) = follow.candidate.as_ref().map(|c| c.path.clone()) {
+                if let Some(i) = self.images.position(&target) {
+                    if self.goto(i) {
+                        self.log.push(
+                            Severity::Info,
+                            format!("Following: switched to {}", target.display()),
+                        );
+                        refresh = Refresh::Image;
+                    }
+                }
+            }
+        }
+
+        refresh
+    }
+
+    /// Rescan the directory while there is nothing to show yet.
+    fn poll_waiting(&mut self) -> Refresh {
+        match self.waiting_for.clone() {
+            Some(dir) => match self.poll_scan(&dir) {
+                (Some(scan), refresh) => refresh.max(self.apply_scan(scan.images, &scan.changes)),
+                (None, refresh) => refresh,
+            },
+            None => Refresh::Nothing,
+        }
+    }
+
+    /// Resume follow mode, jumping to the newest image.
+    fn engage_follow(&mut self) -> bool {
+        let Some(follow) = self.follow.as_mut() else {
+            return false;
+        };
+        follow.engaged = true;
+        let target = follow.candidate.as_ref().map(|c| c.path.clone());
+        if let Some(i) = target.and_then(|t| self.images.position(&t)) {
+            self.goto(i);
+        }
+        true
+    }
+
+    /// Switch to the thumbnail grid for a `cols` x `rows` terminal, with the
+    /// displayed image selected.
+    fn open_gallery(&mut self, size: (u16, u16)) -> bool {
+        if self.images.is_empty() {
+            return false;
+        }
+        self.gallery.active = true;
+        self.gallery.selected = self.index;
+        self.layout_gallery(size);
+        true
+    }
+
+    /// Fit the thumbnail grid to a `cols` x `rows` terminal, inside the margins.
+    fn layout_gallery(&mut self, (cols, rows): (u16, u16)) {
+        let full = (0, 0, cols, rows.saturating_sub(1));
+        self.gallery.resize(self.margins.apply(full).unwrap_or(full));
+    }
+
+    /// Leave the grid for the single-image view of the selected image.
+    fn open_selected(&mut self) -> bool {
+        self.gallery.active = false;
+        let unloaded =
+            self.current_image.is_none() && !self.decode_pending && self.decoding.is_none();
+        if !self.goto(self.gallery.selected) && unloaded {
+            self.load_current();
+        }
+        true
+    }
+
+    /// Move the gallery selection. Returns whether it moved.
+    fn gallery_move(&mut self, action: Action) -> bool {
+        let len = self.images.len();
+        let g = &mut self.gallery;
+        match action {
+            Action::Next => g.move_by(1, len),
+            Action::Prev => g.move_by(-1, len),
+            Action::Down => g.move_by(g.columns() as isize, len),
+            Action::Up => g.move_by(-(g.columns() as isize), len),
+            Action::PageDown => g.move_by(g.per_page() as isize, len),
+            Action::PageUp => g.move_by(-(g.per_page() as isize), len),
+            Action::First => g.select(0, len),
+            Action::Last => g.select(len.saturating_sub(1), len),
+            _ => false,
+        }
+    }
+
+    /// Make the thumbnail for the first tile on screen that lacks one,
+    /// starting its decode if it isn't in the cache or taking the decode
+    /// once it is in. Returns the index a thumbnail was made for.
+    fn load_next_thumbnail(&mut self) -> Option<usize> {
+        if !self.gallery.active {
+            return None;
+        }
+        if let Some((id, decoding)) = self.thumb_decoding.as_ref() {
+            let result = decoding.poll()?;
+            let id = *id;
+            self.thumb_decoding = None;
+            let path = self.images.path(id).to_path_buf();
+            let thumb = self.thumbnail_decoded(&path, result);
+            self.insert_thumbnail(id, thumb);
+            return self.images.index_of(id);
+        }
+        let i = self.gallery.next_missing(&self.images)?;
+        let id = self.images.id(i)?;
+        let path = self.images.path(id).to_path_buf();
+        let cached = match self.thumb_cache_for(&path) {
+            Some(cache) => cache.get(&path, Bucket::for_size(THUMB_PX)),
+            None => Cached::Missing,
+        };
+        let thumb = match cached {
+            Cached::Thumb(img) => Some(img),
+            Cached::Failed => None,
+            Cached::Missing => {
+                self.thumb_decoding = Some((id, self.loader.start(&path)));
+                return None;
+            }
+        };
+        self.insert_thumbnail(id, thumb);
+        Some(i)
+    }
+
+    /// The shared thumbnail cache, if `path` can have thumbnails in it.
+    fn thumb_cache_for(&self, path: &Path) -> Option<&ThumbCache> {
+        // Images from stdin or a URL have no file to share thumbnails of
+        self.thumb_cache
+            .as_ref()
+            .filter(|_| self.loader.memory.as_ref().is_none_or(|m| m.name != path))
+    }
+
+    /// Make a thumbnail of a decoded image, saving it in the shared cache.
+    fn thumbnail_decoded(
+        &mut self,
+        path: &Path,
+        result: Result<Decoded, LoadError>,
+    ) -> Option<DynamicImage> {
+        let (thumb, saved) = keep_thumbnail(self.thumb_cache_for(path), path, result);
+        let thumb = thumb.unwrap_or_else(|e| {
+            self.log.push(
+                Severity::Warning,
+                format!("No thumbnail for {}: {}", path.display(), e),
+            );
+            None
+        });
+        // Said once: the next file would most likely fail the same way
+        if let Err(e) = saved {
+            self.log.push(
+                Severity::Warning,
+                format!("Thumbnail cache turned off, can't save to it: {}", e),
+            );
+            self.thumb_cache = None;
+        }
+        thumb
+    }
+
+    fn insert_thumbnail(&mut self, id: EntryId, thumb: Option<DynamicImage>) {
+        let thumb = thumb.as_ref().map(gallery::fit_thumb);
+        self.gallery.insert_thumb(id, thumb);
+    }
+
+    /// The file on screen, if it is one termview may delete or move: not an
+    /// image from stdin or a URL, nor a name missing from a compared directory.
+    fn current_file(&self) -> Result<&Path, String> {
+        let path = self.images.get(self.index).ok_or("no image")?;
+        if self.loader.memory.is_some() {
+            return Err("not a file on disk".into());
+        }
+        if self.compare.is_some() {
+            return Err("not available when comparing directories".into());
+        }
+        Ok(path)
+    }
+
+    /// Whether `action` may be carried out, saying why not on the status
+    /// bar if it can't.
+    fn permits(&mut self, action: Action) -> bool {
+        match self.read_only {
+            Some(mode) if action.access() > mode.allows() => {
+                self.error_message = Some("Not allowed in read-only mode".into());
+                false
+            }
+            _ => true,
+        }
+    }
+
+    /// Ask to confirm deleting the current file.
+    fn start_delete(&mut self) -> bool {
+        match self.current_file() {
+            Ok(_) => {
+                self.prompt = Some(Prompt::ConfirmDelete {
+                    file: self.current_target(),
+                })
+            }
+            Err(e) => self.error_message = Some(format!("Can't delete: {}", e)),
+        }
+        true
+    }
+
+    /// Move or copy the current file to the preset directory, or ask for one.
+    fn start_transfer(&mut self, op: Transfer) -> bool {
+        if let Err(e) = self.current_file() {
+            self.error_message = Some(format!("Can't {}: {}", op.verb().to_lowercase(), e));
+            return true;
+        }
+        let preset = match op {
+            Transfer::Move => self.move_to.clone(),
+            Transfer::Copy => self.copy_to.clone(),
+        };
+        let file = self.current_target();
+        match preset {
+            Some(dir) => {
+                self.run_file_op(FileOp::Transfer(op, file, dir));
+            }
+            None => {
+                self.prompt = Some(Prompt::Destination {
+                    op,
+                    input: String::new(),
+                    file,
+                })
+            }
+        }
+        true
+    }
+
+    /// Answer the open prompt with `key`.
+    fn prompt_key(&mut self, key: &KeyEvent) -> Refresh {
+        let refresh = self.answer_prompt(key);
+        // The slideshow stood still while the question was open
+        if self.prompt.is_none() {
+            self.restart_slideshow();
+        }
+        refresh
+    }
+
+    fn answer_prompt(&mut self, key: &KeyEvent) -> Refresh {
+        let Some(prompt) = self.prompt.take() else {
+            return Refresh::Nothing;
+        };
+        match prompt {
+            Prompt::ConfirmDelete { file } => match key.code {
+                KeyCode::Char('y') | KeyCode::Char('Y') => self.run_file_op(FileOp::Delete(file)),
+                _ => Refresh::Status,
+            },
+            Prompt::Retry { op, .. } => match key.code {
+                KeyCode::Char('y') | KeyCode::Char('Y') => self.run_file_op(op),
+                _ => Refresh::Status,
+            },
+            Prompt::Destination { op, mut input, file } => match key.code {
+                KeyCode::Enter if !input.is_empty() => {
+                    let dir = match input.strip_prefix("~/") {
+                        Some(rest) => match &self.persistence.home {
+                            Some(home) => home.join(rest),
+                            None => {
+                                self.error_message = Some("$HOME isn't set, so ~ can't be used".into());
+                                return Refresh::Status;
+                            }
+                        },
+                        None => PathBuf::from(&input),
+                    };
+                    self.run_file_op(FileOp::Transfer(op, file, dir))
+                }
+                KeyCode::Esc => Refresh::Status,
+                KeyCode::Backspace => {
+                    input.pop();
+                    self.prompt = Some(Prompt::Destination { op, input, file });
+                    Refresh::Status
+                }
+                KeyCode::Char(c) if !key.modifiers.contains(KeyModifiers::CONTROL) => {
+                    input.push(c);
+                    self.prompt = Some(Prompt::Destination { op, input, file });
+                    Refresh::Status
+                }
+                _ => {
+                    self.prompt = Some(Prompt::Destination { op, input, file });
+                    Refresh::Nothing
+                }
+            },
+        }
+    }
+
+    /// The current file, as it was when displayed if it has been.
+    fn current_target(&self) -> Target {
+        let path = &self.images[self.index];
+        match &self.displayed {
+            Some(file) if file.path == *path => file.clone(),
+            _ => Target {
+                path: path.to_path_buf(),
+                expected: None,
+            },
+        }
+    }
+
+    /// Delete, move or copy a file. A file found to be gone already is
+    /// dropped from the list; one that changed since it was shown, or is in
+    /// use by another program, is asked about again.
+    fn run_file_op(&mut self, op: FileOp) -> Refresh {
+        let path = op.target().path.clone();
+        let result = match &op {
+            FileOp::Delete(file) => {
+                fileops::delete(file, self.delete_permanently, &self.persistence.trash).map(|()| None)
+            }
+            FileOp::Transfer(how, file, dir) => fileops::transfer(*how, file, dir).map(Some),
+        };
+        match result {
+            Ok(moved_to) => {
+                let done = match (&op, moved_to) {
+                    (FileOp::Delete(_), _) if self.delete_permanently => {
+                        format!("Deleted {}", path.display())
+                    }
+                    (FileOp::Delete(_), _) => format!("Trashed {}", path.display()),
+                    (FileOp::Transfer(how, ..), target) => format!(
+                        "{} {} to {}",
+                        match how {
+                            Transfer::Move => "Moved",
+                            Transfer::Copy => "Copied",
+                        },
+                        path.display(),
+                        target.unwrap_or_default().display()
+                    ),
+                };
+                self.log.push(Severity::Info, done);
+                if let FileOp::Transfer(Transfer::Copy, ..) = op {
+                    return Refresh::Status;
+                }
+                self.remove_path(&path);
+                Refresh::Image
+            }
+            Err(Failure::Gone) => {
+                let message = format!("{} is no longer there", path.display());
+                self.log.push(Severity::Warning, message.clone());
+                self.remove_path(&path);
+                self.error_message = Some(message);
+                Refresh::Image
+            }
+            Err(failure @ (Failure::Changed | Failure::Locked)) => {
+                let op = match failure {
+                    Failure::Changed => op.unchecked(),
+                    _ => op,
+                };
+                self.prompt = Some(Prompt::Retry { op, failure });
+                Refresh::Status
+            }
+            Err(failure) => {
+                let message = format!("Couldn't {} {}: {}", op.verb(), path.display(), failure);
+                self.log.push(Severity::Error, message.clone());
+                self.error_message = Some(message);
+                Refresh::Status
+            }
+        }
+    }
+
+    /// Close the open prompt if its file has left the list, e.g. removed by
+    /// another program and dropped by a rescan. Returns whether it did.
+    fn drop_stale_prompt(&mut self) -> bool {
+        let Some(prompt) = self.prompt.as_ref() else {
+            return false;
+        };
+        let path = &prompt.file().path;
+        if self.images.contains(path) {
+            return false;
+        }
+        self.error_message = Some(format!("{} is no longer in the list", path.display()));
+        self.prompt = None;
+        self.restart_slideshow();
+        true
+    }
+
+    /// Drop `path` from the list after it was deleted or moved away. If it
+    /// was the current file, the one that took its place is shown.
+    fn remove_path(&mut self, path: &Path) {
+        let Some(removed) = self.images.iter().position(|p| p == path) else {
+            return;
+        };
+        let current = removed == self.index;
+        self.images.remove(removed);
+        self.list_version += 1;
+        if removed < self.index {
+            self.index -= 1;
+        }
+        self.index = self.index.min(self.images.len().saturating_sub(1));
+        self.gallery.select(self.gallery.selected, self.images.len());
+        if current {
+            // The file is gone, so there is nothing to keep in the cache
+            self.shown = None;
+            self.load_current();
+        }
+    }
+
+    fn toggle_info(&mut self) {
+        self.show_info = !self.show_info;
+        self.gather_info();
+    }
+
+    /// Fill in `info` for the current image if the panel needs it.
+    fn gather_info(&mut self) {
+        let (true, None, Some(img), Some(path)) = (
+            self.show_info,
+            &self.info,
+            &self.current_image,
+            self.images.get(self.index),
+        ) else {
+            return;
+        };
+        let facts = match self.loader.memory {
+            Some(ref memory) if memory.name == *path => FileFacts::from_bytes(&memory.bytes),
+            _ => {
+                let path = path.to_path_buf();
+                slowfs::within(PROBE_TIMEOUT, move || FileFacts::read(&path)).unwrap_or_default()
+            }
+        };
+        self.info = Some(Info::new(facts, img));
+    }
+
+    /// Find out the size of a terminal cell again, e.g. after the font was
+    /// zoomed. Keeps the old size if nothing says otherwise.
+    fn detect_cell_size(&mut self) {
+        let ask = match self.cell_sizing {
+            CellSizing::Fixed => return,
+            CellSizing::Detect => true,
+            CellSizing::TtyOnly => false,
+        };
+        match detect_cell_size(ask) {
+            Some(size) => self.cell_size = size,
+            None if ask => self.cell_sizing = CellSizing::TtyOnly,
+            None => {}
+        }
+    }
+
+    /// Show or hide the help box. Only one overlay is open at a time.
+    fn toggle_help(&mut self) {
+        self.show_help = !self.show_help;
+        self.log.visible = false;
+    }
+
+    fn toggle_log(&mut self) {
+        self.log.visible = !self.log.visible;
+        self.show_help = false;
+    }
+
+    /// Scroll the open session log for a navigation key. Returns whether it moved.
+    fn scroll_log(&mut self, action: Action) -> bool {
+        let page = LOG_PAGE_ENTRIES as isize;
+        let delta = match action {
+            Action::Up => 1,
+            Action::Down => -1,
+            Action::PageUp => page,
+            Action::PageDown => -page,
+            Action::First => isize::MAX / 2,
+            Action::Last => -(isize::MAX / 2),
+            _ => 0,
+        };
+        self.log.scroll_by(delta)
+    }
+
+    /// Show the other directory's version of the current image, keeping the
+    /// zoom and pan so the same detail stays in view.
+    fn flip_side(&mut self) -> bool {
+        let Some(compare) = self.compare.as_mut() else {
+            return false;
+        };
+        compare.flip();
+        self.images.replace(compare.paths());
+
+        let (zoom, pan_x, pan_y) = (self.zoom, self.pan_x, self.pan_y);
+        self.load_current();
+        self.zoom = zoom;
+        self.pan_x = pan_x;
+        self.pan_y = pan_y;
+        true
+    }
+
+    /// Add `input` to the recording, if one is being made. A recording that
+    /// can't be written is given up on, leaving a note in the log.
+    fn record(&mut self, input: Input) {
+        let Some(recorder) = self.recorder.as_mut() else {
+            return;
+        };
+        if let Err(e) = recorder.record(input) {
+            self.recorder = None;
+            self.log
+                .push(Severity::Error, format!("Stopped recording input: {}", e));
+        }
+    }
+
+    /// Stop follow mode from switching images until it is engaged again.
+    fn disengage_follow(&mut self) {
+        if let Some(follow) = self.follow.as_mut() {
+            follow.engaged = false;
+        }
+    }
+
+    /// Choosing an image by hand with `action` takes over from follow mode.
+    fn chose_by_hand(&mut self, action: Action) {
+        if matches!(action, Action::Next | Action::Prev | Action::First | Action::Last) {
+            self.disengage_follow();
+        }
+    }
+
+    /// How long the frame on screen should stay there, while an animation is playing.
+    fn frame_delay(&self) -> Option<Duration> {
+        if self.gallery.active {
+            return None;
+        }
+        let anim = self.animation.as_ref().filter(|a| !a.paused)?;
+        Some(anim.frames[anim.current].1)
+    }
+
+    /// Show the frame `offset` frames from the current one, wrapping around.
+    fn show_frame(&mut self, offset: isize) {
+        if let Some(anim) = self.animation.as_mut() {
+            let len = anim.frames.len() as isize;
+            anim.current = (anim.current as isize + offset).rem_euclid(len) as usize;
+            self.current_image = Some(self.orientation.apply(anim.frames[anim.current].0.clone()));
+            self.image_key += 1;
+        }
+    }
+
+    /// The animation frame on screen.
+    fn frame_id(&self) -> Option<FrameId> {
+        let anim = self.animation.as_ref()?;
+        Some(FrameId {
+            animation: anim.key,
+            frame: anim.current,
+            orientation: self.orientation,
+        })
+    }
+
+    /// The frame a playing animation moves on to next, as decoded, to be
+    /// drawn ahead of time while the current one is on screen.
+    fn upcoming_frame(&self) -> Option<(FrameId, DynamicImage)> {
+        self.frame_delay()?;
+        let anim = self.animation.as_ref()?;
+        let next = (anim.current + 1) % anim.frames.len();
+        let id = FrameId {
+            animation: anim.key,
+            frame: next,
+            orientation: self.orientation,
+        };
+        Some((id, anim.frames[next].0.clone()))
+    }
+
+    /// Advance a playing animation when its frame timer fires.
+    fn advance_frame(&mut self) -> Refresh {
+        if self.frame_delay().is_none() {
+            return Refresh::Nothing;
+        }
+        self.show_frame(1);
+        Refresh::Image
+    }
+
+    fn toggle_slideshow(&mut self) {
+        self.slideshow = match self.slideshow {
+            Some(_) => None,
+            None => Some(Slideshow::new(self.slideshow_interval)),
+        };
+    }
+
+    /// When the slideshow moves on next, if it is running. It stands still
+    /// while paused and while the thumbnail grid is open.
+    fn slideshow_due(&self) -> Option<Instant> {
+        let show = self.slideshow.as_ref()?;
+        if self.gallery.active || self.prompt.is_some() || show.paused.is_some() {
+            return None;
+        }
+        Some(show.due)
+    }
+
+    /// Move to the next image when the slideshow timer fires. Returns `None`
+    /// once the last image has had its turn with `--once`.
+    fn advance_slideshow(&mut self) -> Option<Refresh> {
+        if self.slideshow_due().is_none() || self.images.is_empty() {
+            return Some(Refresh::Nothing);
+        }
+        self.slideshow.as_mut()?.advance(Instant::now());
+
+        let last = self.index + 1 == self.images.len();
+        if last && self.slideshow_once {
+            return None;
+        }
+        Some(if self.next() { Refresh::Image } else { Refresh::Nothing })
+    }
+
+    /// Start the countdown over after an image was chosen by hand.
+    fn restart_slideshow(&mut self) {
+        if let Some(show) = self.slideshow.as_mut() {
+            show.restart();
+        }
+    }
+
+    /// Space pauses the slideshow while one is running, along with any
+    /// animation on screen, and otherwise the animation alone.
+    fn toggle_playback(&mut self) -> bool {
+        if let Some(show) = self.slideshow.as_mut() {
+            let paused = show.toggle_pause();
+            if let Some(anim) = self.animation.as_mut() {
+                anim.paused = paused;
+            }
+            return true;
+        }
+        match self.animation.as_mut() {
+            Some(anim) => {
+                anim.paused = !anim.paused;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Pause and step by one frame in either direction.
+    fn step_frame(&mut self, offset: isize) -> bool {
+        let Some(anim) = self.animation.as_mut() else {
+            return false;
+        };
+        anim.paused = true;
+        self.show_frame(offset);
+        true
+    }
+
+    /// Turn the pixel grid off if it is `shown`, and on otherwise. The choice
+    /// holds at any zoom until it is toggled again.
+    fn toggle_pixel_grid(&mut self, shown: bool) {
+        self.pixel_grid = Some(!shown);
+    }
+
+    /// Start or leave measurement mode, starting with the crosshair in the
+    /// middle of `view`, the part of the image last drawn.
+    fn toggle_measure(&mut self, view: Option<PixelRect>) -> bool {
+        let Some(img) = &self.current_image else {
+            return false;
+        };
+        let size = img.dimensions();
+        let (x, y, w, h) = view.unwrap_or((0, 0, size.0, size.1));
+        let center = (x + w / 2, y + h / 2);
+        match self.measure.as_mut() {
+            Some(measure) if measure.active => {
+                measure.active = false;
+                measure.anchor = None;
+                if measure.done.is_empty() {
+                    self.measure = None;
+                }
+            }
+            Some(measure) => {
+                measure.active = true;
+                measure.cursor = center;
+            }
+            None => {
+                let path = self.images.get(self.index).map(Path::to_path_buf);
+                let path = path.unwrap_or_default();
+                self.measure = Some(Measure::new(path, size, center));
+            }
+        }
+        true
+    }
+
+    /// Handle a key in measurement mode. Returns `None` for keys it leaves
+    /// to their usual bindings, such as zooming and panning. `view` is the
+    /// part of the image last drawn, which is panned to keep the crosshair
+    /// in sight.
+    fn measure_key(&mut self, key: &KeyEvent, view: Option<PixelRect>) -> Option<Refresh> {
+        let measure = self.measure.as_mut().filter(|m| m.active)?;
+        let (code, mods) = normalize_key(key);
+        if !(mods - KeyModifiers::SHIFT).is_empty() {
+            return None;
+        }
+        let step = if mods.contains(KeyModifiers::SHIFT) { 10 } else { 1 };
+        measure.copied = false;
+        match code {
+            KeyCode::Left | KeyCode::Char('h') => measure.move_cursor(-step, 0),
+            KeyCode::Right | KeyCode::Char('l') => measure.move_cursor(step, 0),
+            KeyCode::Up | KeyCode::Char('k') => measure.move_cursor(0, -step),
+            KeyCode::Down | KeyCode::Char('j') => measure.move_cursor(0, step),
+            KeyCode::Char('H') => measure.move_cursor(-10, 0),
+            KeyCode::Char('L') => measure.move_cursor(10, 0),
+            KeyCode::Char('K') => measure.move_cursor(0, -10),
+            KeyCode::Char('J') => measure.move_cursor(0, 10),
+            KeyCode::Enter | KeyCode::Char(' ') => measure.place(),
+            KeyCode::Backspace => measure.undo(),
+            KeyCode::Delete => {
+                measure.anchor = None;
+                measure.done.clear();
+            }
+            KeyCode::Char('y') => {
+                if !self.permits(Action::CopyMeasurement) {
+                    return Some(Refresh::Status);
+                }
+                let measure = self.measure.as_mut()?;
+                if let Some(current) = measure.current() {
+                    self.clipboard = Some(current.summary());
+                    measure.copied = true;
+                }
+                return Some(Refresh::Status);
+            }
+            KeyCode::Esc | KeyCode::Char('M') => {
+                self.toggle_measure(view);
+            }
+            _ => return None,
+        }
+
+        // Pan just far enough to bring the crosshair back into view
+        if let (Some(measure), Some((x, y, w, h))) = (&self.measure, view) {
+            let (cx, cy) = measure.cursor;
+            let (img_w, img_h) = measure.size;
+            let center = |c: u32, start: u32, len: u32| {
+                if c < start {
+                    Some(c as f64 + len as f64 / 2.0)
+                } else if c >= start + len {
+                    Some(c as f64 + 1.0 - len as f64 / 2.0)
+                } else {
+                    None
+                }
+            };
+            if let Some(center_x) = center(cx, x, w) {
+                self.pan_x = center_x / img_w as f64 - 0.5;
+            }
+            if let Some(center_y) = center(cy, y, h) {
+                self.pan_y = center_y / img_h as f64 - 0.5;
+            }
+        }
+        Some(Refresh::Image)
+    }
+
+    fn zoom_in(&mut self) {
+        self.zoom = (self.zoom * 1.25).min(10.0);
+    }
+
+    fn zoom_out(&mut self) {
+        self.zoom = (self.zoom / 1.25).max(0.1);
+    }
+
+    fn zoom_reset(&mut self) {
+        self.zoom = 1.0;
+        self.pan_x = 0.0;
+        self.pan_y = 0.0;
+    }
+
+    fn pan(&mut self, dx: f64, dy: f64) {
+        self.pan_x += dx;
+        self.pan_y += dy;
+    }
+
+    /// Rotate or flip the displayed image by `change`. The pixels of
+    /// `current_image` are transformed, so zoom, pan and fitting see the image
+    /// the right way round and width and height swap for quarter turns. The
+    /// pan moves with the image, keeping the same detail in view.
+    fn reorient(&mut self, change: Orientation) -> bool {
+        let Some(img) = self.current_image.take() else {
+            return false;
+        };
+        self.current_image = Some(change.apply(img));
+        self.orientation = self.orientation.then(change);
+        self.image_key += 1;
+
+        (self.pan_x, self.pan_y) = change.apply_to_point((self.pan_x, self.pan_y));
+        if let (Some(measure), Some(img)) = (self.measure.as_mut(), &self.current_image) {
+            measure.reset(img.dimensions());
+        }
+        true
+    }
+
+    fn current_filename(&self) -> String {
+        if self.images.is_empty() {
+            return "(none)".into();
+        }
+        let path = &self.images[self.index];
+        // Stdin and URLs are shown under the name they were given
+        if self.loader.memory.as_ref().is_some_and(|m| m.name == *path) {
+            return path.to_string_lossy().into();
+        }
+        path.file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into()
+    }
+
+    fn image_info(&self) -> String {
+        if let Some(ref img) = self.current_image {
+            let (w, h) = img.dimensions();
+            format!("{}x{}", w, h)
+        } else {
+            String::new()
+        }
+    }
+
+    /// The part of the current image in view when it is shown in `area`
+    /// sized by `fit_mode`, then zoomed and panned, and how to scale it. The
+    /// crop follows the contract in `viewport`.
+    fn view(&self, area: CellRect, (cell_w, cell_h): (u16, u16)) -> Option<View> {
+        let img = self.current_image.as_ref()?;
+        let (w, h) = img.dimensions();
+        let area_w = (area.2 as f64 * cell_w as f64).max(1.0);
+        let area_h = (area.3 as f64 * cell_h as f64).max(1.0);
+
+        // Screen pixels per image pixel before zooming
+        let fit_x = area_w / w as f64;
+        let fit_y = area_h / h as f64;
+        let limit = if self.upscale { f64::INFINITY } else { 1.0 };
+        let (scale_x, scale_y) = match self.fit_mode {
+            FitMode::Fit => {
+                let scale = fit_x.min(fit_y).min(limit);
+                (scale, scale)
+            }
+            FitMode::FitWidth => (fit_x.min(limit), fit_x.min(limit)),
+            FitMode::Fill => (fit_x.max(fit_y), fit_x.max(fit_y)),
+            FitMode::Actual => (1.0, 1.0),
+            FitMode::Stretch => (fit_x, fit_y),
+        };
+        let (scale_x, scale_y) = (scale_x * self.zoom, scale_y * self.zoom);
+        let scaling = Scaling {
+            max: scale_x.max(scale_y),
+            stretch: self.fit_mode == FitMode::Stretch,
+        };
+        let grid = self.grid_shown(scale_x.min(scale_y));
+
+        Some(View {
+            rect: viewport::crop(
+                (w, h),
+                (area_w, area_h),
+                (scale_x, scale_y),
+                (self.pan_x, self.pan_y),
+            ),
+            scaling,
+            grid,
+        })
+    }
+
+    /// Whether the pixel grid is drawn with each image pixel `scale` screen
+    /// pixels wide.
+    fn grid_shown(&self, scale: f64) -> bool {
+        scale >= MIN_PIXEL_GRID_SCALE && self.pixel_grid.unwrap_or(scale >= self.pixel_grid_at)
+    }
+}
+
+/// Keep what decoding `path` for its thumbnail came to in the shared cache:
+/// the image, or a marker that it doesn't decode so it isn't tried on every
+/// run. Returns the image (`None` for an animation without frames), and the
+/// error saving it if that failed.
+fn keep_thumbnail(
+    cache: Option<&ThumbCache>,
+    path: &Path,
+    result: Result<Decoded, LoadError>,
+) -> (Result<Option<DynamicImage>, LoadError>, io::Result<()>) {
+    let thumb = result.map(|decoded| match decoded {
+        Decoded::Still(img) => Some(img),
+        Decoded::Animated(frames) => frames.into_iter().next().map(|(img, _)| img),
+    });
+    let Some(cache) = cache else {
+        return (thumb, Ok(()));
+    };
+    let saved = match thumb {
+        Ok(Some(ref img)) => cache.put(path, Bucket::for_size(THUMB_PX), img),
+        // A slow decode may yet succeed another time
+        Ok(None) | Err(LoadError::TimedOut(_) | LoadError::Stuck(_)) => Ok(()),
+        Err(_) => {
+            let _ = cache.mark_failed(path);
+            Ok(())
+        }
+    };
+    (thumb, saved)
+}
+
+/// The thumbnail the grid would show of `path`, for `serve`: taken from the
+/// shared cache when it has one, otherwise decoded in place and kept there
+/// as `load_next_thumbnail` would. A cache that can't be saved to is turned
+/// off, once `warned` about.
+#[cfg(feature = "server")]
+fn serve_thumbnail(
+    cache: Option<&ThumbCache>,
+    loader: &Loader,
+    path: &Path,
+    warned: &AtomicBool,
+) -> Result<DynamicImage, String> {
+    let cache = cache.filter(|_| !warned.load(Ordering::SeqCst));
+    let cached = match cache {
+        Some(cache) => cache.get(path, Bucket::for_size(THUMB_PX)),
+        None => Cached::Missing,
+    };
+    let thumb = match cached {
+        Cached::Thumb(img) => img,
+        Cached::Failed => return Err(format!("{} doesn't decode", path.display())),
+        Cached::Missing => {
+            let (thumb, saved) = keep_thumbnail(cache, path, loader.load(path));
+            if let Err(e) = saved {
+                if !warned.swap(true, Ordering::SeqCst) {
+                    eprintln!("termview: thumbnail cache turned off, can't save to it: {}", e);
+                }
+            }
+            thumb.map_err(|e| e.to_string())?.ok_or("no frames")?
+        }
+    };
+    Ok(gallery::fit_thumb(&thumb))
+}
+
+// ---------------------------------------------------------------------------
+// Drawing
+// ---------------------------------------------------------------------------
+
+/// A rectangle of terminal cells: (col, row, width, height).
+type CellRect = (u16, u16, u16, u16);
+
+/// The `auto` letterbox colour, and the image and view it was sampled for.
+#[derive(Debug, Clone, Copy)]
+struct SampledEdge {
+    image_key: u64,
+    view: View,
+    color: Option<(u8, u8, u8)>,
+}
+
+/// Tracks which layers of the screen are out of date and repaints only those.
+///
+/// Layers, bottom to top:
+/// - image: the kitty placement (or error text) filling the rows above the status bar,
+///   or the thumbnail grid, whose selection frame and tiles can be repainted on their own
+/// - status: the bottom row
+/// - overlay: the help box or session log drawn over the image (and the info
+///   panel, which is drawn with the image)
+///
+/// Call one of the `invalidate_*` helpers after changing state, then `render`.
+/// Rendering an image layer is the only thing that emits kitty graphics data.
+struct Screen {
+    full: bool,
+    image: bool,
+    status: bool,
+    overlay: bool,
+    /// The gallery selection moved.
+    selection: bool,
+    /// Gallery tiles whose thumbnail arrived since the last render.
+    tiles: Vec<usize>,
+    /// Visible range and selection of the gallery page on screen.
+    gallery_drawn: Option<(std::ops::Range<usize>, usize)>,
+    /// Status bar contents last written, keyed with the geometry they were drawn for.
+    last_status: Option<(u16, u16, String, String)>,
+    /// Cells covered by the overlay last time it was drawn, blanked when it is hidden.
+    overlay_rect: Option<CellRect>,
+    /// Entry count and scroll position of the session log on screen, so it
+    /// is redrawn when either changes.
+    log_drawn: Option<(usize, usize)>,
+    /// Image pixel at the top left of the view while the pixel grid is drawn,
+    /// shown in the status bar.
+    grid_origin: Option<(u32, u32)>,
+    /// Part of the image last drawn, where measuring starts its crosshair.
+    view_rect: Option<PixelRect>,
+    /// The view last scaled to draw measurements over, and what it was
+    /// scaled for, so moving the crosshair doesn't scale it again.
+    measure_base: Option<(DrawnView, image::RgbaImage)>,
+    /// Input arrived while the image was being sent, and the rest was
+    /// abandoned. It is sent again on the next render.
+    image_interrupted: bool,
+    /// Gallery tiles whose thumbnail was cut short the same way.
+    tiles_interrupted: Vec<usize>,
+    /// The terminal changed size while the last render was being written, so
+    /// what it drew may be placed for the old size.
+    resized_while_drawing: bool,
+    /// What the renderer last said about the detail it sends images with.
+    quality: Option<&'static str>,
+    /// Part of the image area the image is placed in (`--region`), resolved
+    /// against the terminal size on every render so it stays proportional.
+    region: Option<Region>,
+    letterbox: Option<Letterbox>,
+    edge: Option<SampledEdge>,
+    /// The view moved off the one `edge` was sampled for, which keeps its
+    /// colour until the view settles.
+    edge_moved: bool,
+    /// The view has settled, so `edge` is sampled again on the next render.
+    edge_due: bool,
+    /// The next frame of a playing animation, being drawn ahead of time.
+    next_frame: Option<NextFrame>,
+    renderer: Box<dyn Renderer>,
+}
+
+/// An animation frame drawn into bytes on another thread while the frame
+/// before it is on screen, so that showing it only takes writing them out.
+struct NextFrame {
+    frame: FrameId,
+    /// The view, area and cell size it is drawn for. Zooming, panning or
+    /// resizing in the meantime leaves it unused.
+    drawn_for: (View, CellRect, (u16, u16)),
+    bytes: Receiver<io::Result<Vec<u8>>>,
+}
+
+impl Screen {
+    fn new(renderer: Box<dyn Renderer>) -> Self {
+        Screen {
+            full: true,
+            image: true,
+            status: true,
+            overlay: true,
+            selection: false,
+            tiles: Vec::new(),
+            gallery_drawn: None,
+            last_status: None,
+            overlay_rect: None,
+            log_drawn: None,
+            grid_origin: None,
+            view_rect: None,
+            measure_base: None,
+            image_interrupted: false,
+            tiles_interrupted: Vec::new(),
+            resized_while_drawing: false,
+            quality: None,
+            region: None,
+            letterbox: None,
+            edge: None,
+            edge_moved: false,
+            edge_due: false,
+            next_frame: None,
+            renderer,
+        }
+    }
+
+    /// Repaint everything from scratch (e.g. after a resize).
+    fn invalidate_all(&mut self) {
+        self.full = true;
+    }
+
+    /// The terminal was handed back and taken over again, so nothing the
+    /// renderer left on it is there any more.
+    fn invalidate_terminal(&mut self) {
+        self.renderer.forget();
+        self.full = true;
+    }
+
+    /// The displayed image, its zoom, or its pan changed.
+    fn invalidate_image(&mut self) {
+        self.image = true;
+    }
+
+    /// Something shown in the status bar may have changed. The bar is only
+    /// rewritten if its text actually differs from what is on screen.
+    fn invalidate_status(&mut self) {
+        self.status = true;
+    }
+
+    /// The gallery selection moved; only the old and new frames are redrawn.
+    fn invalidate_selection(&mut self) {
+        self.selection = true;
+    }
+
+    /// The thumbnail for gallery tile `index` is ready to draw.
+    fn invalidate_tile(&mut self, index: usize) {
+        self.tiles.push(index);
+    }
+
+    /// An overlay was shown, hidden, or changed.
+    fn invalidate_overlay(&mut self) {
+        self.overlay = true;
+        // Blanking a hidden overlay would punch a hole in a coloured letterbox
+        if self.letterbox.is_some() {
+            self.image = true;
+        }
+    }
+
+    fn is_dirty(&self) -> bool {
+        self.full
+            || self.image
+            || self.status
+            || self.overlay
+            || self.selection
+            || !self.tiles.is_empty()
+    }
+
+    /// Emit output for the dirty layers inside one synchronized update.
+    fn render(&mut self, out: &mut impl Write, app: &App) -> io::Result<()> {
+        self.render_sized(out, app, terminal::size()?)
+    }
+
+    /// `render` for a terminal of `cols` x `rows`.
+    fn render_sized(
+        &mut self,
+        out: &mut impl Write,
+        app: &App,
+        (cols, rows): (u16, u16),
+    ) -> io::Result<()> {
+        let log_state = app.log.visible.then(|| (app.log.total(), app.log.scroll()));
+        if app.log.visible && log_state != self.log_drawn {
+            self.overlay = true;
+        }
+        if !self.is_dirty() {
+            return Ok(());
+        }
+
+        let image_rows = rows.saturating_sub(1); // reserve 1 row for status bar
+
+        queue!(out, terminal::BeginSynchronizedUpdate)?;
+
+        if self.full {
+            queue!(out, terminal::Clear(ClearType::All))?;
+            self.last_status = None;
+            self.overlay_rect = None;
+            self.image = true;
+            self.status = true;
+            self.overlay = true;
+        }
+
+        // Scrolling the gallery replaces the whole page
+        if app.gallery.active
+            && self.gallery_drawn.as_ref().map(|(range, _)| range)
+                != Some(&app.gallery.visible(app.images.len()))
+        {
+            self.image = true;
+        }
+
+        // Blanking a hidden overlay would also punch a hole in the info panel
+        if self.overlay && self.overlay_rect.is_some() && app.show_info && !app.gallery.active {
+            self.image = true;
+        }
+
+        if self.image {
+            let grid_origin = self.grid_origin.take();
+            if app.gallery.active {
+                self.render_gallery(out, app, image_rows)?;
+            } else {
+                self.render_image(out, app, cols, image_rows)?;
+                self.gallery_drawn = None;
+            }
+            // Panning moves the origin without otherwise touching the status bar
+            if self.grid_origin != grid_origin {
+                self.status = true;
+            }
+            // The image rows were blanked, taking any overlay with them.
+            self.overlay_rect = None;
+            self.overlay = true;
+        } else if app.gallery.active {
+            self.update_gallery(out, app)?;
+        }
+
+        if self.overlay {
+            if let Some((col, row, w, h)) = self.overlay_rect.take() {
+                for r in row..row + h {
+                    queue!(out, cursor::MoveTo(col, r))?;
+                    write!(out, "{}", " ".repeat(w as usize))?;
+                }
+            }
+            if let Some(Prompt::ConfirmDelete { file }) = &app.prompt {
+                self.overlay_rect = Some(draw_delete_overlay(
+                    out,
+                    cols,
+                    rows,
+                    &file.path,
+                    app.delete_permanently,
+                )?);
+            } else if app.show_help {
+                self.overlay_rect = Some(draw_help_overlay(out, cols, rows)?);
+            } else if app.log.visible {
+                self.overlay_rect = Some(draw_log_overlay(out, cols, rows, &app.log)?);
+            }
+            self.log_drawn = log_state;
+        }
+
+        if self.status && rows > 0 {
+            let (left, right) = status_text(app, self.grid_origin);
+            let current = (cols, rows - 1, left, right);
+            if self.last_status.as_ref() != Some(&current) {
+                draw_status_bar(out, current.1, cols, &current.2, &current.3)?;
+                self.last_status = Some(current);
+            }
+        }
+
+        self.full = false;
+        self.image = false;
+        self.status = false;
+        self.overlay = false;
+        self.selection = false;
+        self.tiles.clear();
+        // Whatever was cut short by input goes out again next time
+        self.image = std::mem::take(&mut self.image_interrupted);
+        self.tiles = std::mem::take(&mut self.tiles_interrupted);
+
+        queue!(out, cursor::Hide, terminal::EndSynchronizedUpdate)?;
+        out.flush()?;
+
+        // Sending a large image can take seconds, long enough to be resized under
+        if terminal::size()? != (cols, rows) {
+            self.resized_while_drawing = true;
+        }
+        Ok(())
+    }
+
+    /// The `auto` letterbox colour for `view` of the image `key` stands for.
+    /// While the view is being panned or zoomed the last colour sampled for
+    /// the image is kept, and `take_edge_moved` says to sample it again later.
+    fn edge_color(&mut self, img: &DynamicImage, key: u64, view: View) -> Option<(u8, u8, u8)> {
+        match self.edge {
+            Some(edge) if edge.image_key == key && edge.view == view => return edge.color,
+            Some(edge) if edge.image_key == key && !self.edge_due => {
+                self.edge_moved = true;
+                return edge.color;
+            }
+            _ => {}
+        }
+        let color = edge_color(img, view.rect);
+        self.edge = Some(SampledEdge {
+            image_key: key,
+            view,
+            color,
+        });
+        self.edge_due = false;
+        color
+    }
+
+    /// Whether the letterbox colour is waiting on the view since the last call.
+    fn take_edge_moved(&mut self) -> bool {
+        std::mem::take(&mut self.edge_moved)
+    }
+
+    /// The view has held still: sample the letterbox colour again.
+    fn settle_edge(&mut self) {
+        if self.edge.is_some() {
+            self.edge_due = true;
+            self.invalidate_image();
+        }
+    }
+
+    /// Whether the terminal was resized during a render since the last call.
+    fn take_resized_while_drawing(&mut self) -> bool {
+        std::mem::take(&mut self.resized_while_drawing)
+    }
+
+    /// The detail the renderer sends images with, if it changed since the
+    /// last call, e.g. once the link has been measured as slow.
+    fn take_quality_change(&mut self) -> Option<&'static str> {
+        let quality = self.renderer.quality();
+        if quality == self.quality {
+            return None;
+        }
+        self.quality = quality;
+        quality
+    }
+
+    fn render_image(
+        &mut self,
+        out: &mut impl Write,
+        app: &App,
+        cols: u16,
+        image_rows: u16,
+    ) -> io::Result<()> {
+        let (cell_w, cell_h) = app.cell_size;
+        self.view_rect = None;
+
+        // Delete old kitty images and blank the image rows, leaving the status bar
+        self.renderer.clear(out)?;
+        for r in 0..image_rows {
+            queue!(out, cursor::MoveTo(0, r), terminal::Clear(ClearType::CurrentLine))?;
+        }
+
+        // The info panel takes the right-hand columns
+        let panel_w = if app.show_info { info_panel_width(cols) } else { 0 };
+        if panel_w > 0 {
+            draw_info_panel(out, cols, image_rows, app)?;
+        }
+
+        let full = (0, 0, cols - panel_w, image_rows);
+        let area = match self.region {
+            Some(region) => region.resolve(full),
+            None => Some(full),
+        };
+
+        let Some(area) = area else {
+            draw_centered_error(out, full, "Region too small for this terminal")?;
+            return Ok(());
+        };
+        let Some(area) = app.margins.apply(area) else {
+            draw_centered_error(out, full, "Margins leave no room for the image")?;
+            return Ok(());
+        };
+
+        if let Some(ref probe) = app.loading {
+            draw_placeholder(out, probe, area, cell_w, cell_h)?;
+        } else if let (Some(img), Some(view)) =
+            (&app.current_image, app.view(area, (cell_w, cell_h)))
+        {
+            let fill = match self.letterbox {
+                Some(Letterbox::Solid(r, g, b)) => Some((r, g, b)),
+                Some(Letterbox::Auto(fallback)) => {
+                    self.edge_color(img, app.image_key, view).or(fallback)
+                }
+                None => None,
+            };
+            if let Some((r, g, b)) = fill {
+                fill_rect(out, area, style::Color::Rgb { r, g, b })?;
+            }
+            if view.grid {
+                self.grid_origin = Some((view.rect.0, view.rect.1));
+            }
+            self.view_rect = Some(view.rect);
+            let shown = match app.measure.as_ref().filter(|m| m.is_shown()) {
+                // Measurements are drawn into the pixels, so the terminal can't scale them
+                Some(measure) => {
+                    let drawn_for = (app.image_key, view, area, (cell_w, cell_h));
+                    let mut pixels = match self.measure_base.take() {
+                        Some((key, pixels)) if key == drawn_for => pixels,
+                        _ => render::scaled_view(img, view, area, (cell_w, cell_h)).into_rgba8(),
+                    };
+                    self.measure_base = Some((drawn_for, pixels.clone()));
+                    measure.draw(&mut pixels, view.rect);
+                    let pixels = DynamicImage::ImageRgba8(pixels);
+                    self.renderer.display(out, &pixels, area, (cell_w, cell_h))
+                }
+                None => {
+                    self.measure_base = None;
+                    match self.take_next_frame(app, (view, area, (cell_w, cell_h))) {
+                        Some(bytes) => out.write_all(&bytes),
+                        None => self.renderer.display_view(
+                            out,
+                            img,
+                            view,
+                            app.image_key,
+                            area,
+                            (cell_w, cell_h),
+                        ),
+                    }
+                }
+            };
+            match shown {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => self.image_interrupted = true,
+                shown => shown?,
+            }
+            if !app.measure.as_ref().is_some_and(|m| m.is_shown()) {
+                self.prepare_next_frame(app, (view, area, (cell_w, cell_h)));
+            }
+        } else if let Some(ref err) = app.error_message {
+            draw_centered_error(out, area, err)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Screen {
+    /// The bytes drawn ahead of time for the frame now on screen, if they
+    /// were drawn for `drawn_for`. One still being drawn is waited for: it
+    /// is further along than drawing the frame again would be.
+    fn take_next_frame(
+        &mut self,
+        app: &App,
+        drawn_for: (View, CellRect, (u16, u16)),
+    ) -> Option<Vec<u8>> {
+        let next = self.next_frame.take()?;
+        if Some(next.frame) != app.frame_id() || next.drawn_for != drawn_for {
+            return None;
+        }
+        next.bytes.recv().ok()?.ok()
+    }
+
+    /// Start drawing the frame a playing animation shows next, for the same
+    /// view as the one on screen.
+    fn prepare_next_frame(&mut self, app: &App, drawn_for: (View, CellRect, (u16, u16))) {
+        let Some((frame, img)) = app.upcoming_frame() else {
+            self.next_frame = None;
+            return;
+        };
+        let same = |next: &NextFrame| next.frame == frame && next.drawn_for == drawn_for;
+        if self.next_frame.as_ref().is_some_and(same) {
+            return;
+        }
+
+        let mut encoder = self.renderer.frame_encoder();
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            let (view, area, cell_size) = drawn_for;
+            let img = frame.orientation.apply(img);
+            let mut bytes = Vec::new();
+            let drawn = encoder.display_view(&mut bytes, &img, view, 0, area, cell_size);
+            // Nobody is waiting if the animation moved on meanwhile
+            let _ = tx.send(drawn.map(|()| bytes));
+        });
+        self.next_frame = Some(NextFrame {
+            frame,
+            drawn_for,
+            bytes: rx,
+        });
+    }
+
+    /// Draw every tile on the current gallery page.
+    fn render_gallery(
+        &mut self,
+        out: &mut impl Write,
+        app: &App,
+        image_rows: u16,
+    ) -> io::Result<()> {
+        self.renderer.clear(out)?;
+        for r in 0..image_rows {
+            queue!(out, cursor::MoveTo(0, r), terminal::Clear(ClearType::CurrentLine))?;
+        }
+
+        let (cols, _) = terminal::size()?;
+        let full = (0, 0, cols, image_rows);
+        if app.margins.apply(full).is_none() {
+            draw_centered_error(out, full, "Margins leave no room for the grid")?;
+            self.gallery_drawn = None;
+            return Ok(());
+        }
+
+        let visible = app.gallery.visible(app.images.len());
+        for i in visible.clone() {
+            let rect = app.gallery.tile_rect(i);
+            draw_tile_frame(out, rect, i == app.gallery.selected)?;
+            draw_tile_label(out, rect, &app.images[i])?;
+            self.draw_thumbnail(out, app, i)?;
+        }
+        self.gallery_drawn = Some((visible, app.gallery.selected));
+        Ok(())
+    }
+
+    /// Repaint the parts of the gallery page that changed: the selection
+    /// frames and any thumbnails that have just been made.
+    fn update_gallery(&mut self, out: &mut impl Write, app: &App) -> io::Result<()> {
+        let Some((visible, drawn_selected)) = self.gallery_drawn.clone() else {
+            return Ok(());
+        };
+
+        if self.selection && drawn_selected != app.gallery.selected {
+            if visible.contains(&drawn_selected) {
+                draw_tile_frame(out, app.gallery.tile_rect(drawn_selected), false)?;
+            }
+            draw_tile_frame(out, app.gallery.tile_rect(app.gallery.selected), true)?;
+            self.gallery_drawn = Some((visible.clone(), app.gallery.selected));
+        }
+
+        for i in std::mem::take(&mut self.tiles) {
+            if visible.contains(&i) {
+                self.draw_thumbnail(out, app, i)?;
+            }
+        }
+
+        // Thumbnails may have been drawn over the help box or log
+        if app.show_help || app.log.visible {
+            self.overlay = true;
+        }
+        Ok(())
+    }
+
+    fn draw_thumbnail(&mut self, out: &mut impl Write, app: &App, index: usize) -> io::Result<()> {
+        let area = gallery::thumb_area(app.gallery.tile_rect(index));
+        match app.images.id(index).and_then(|id| app.gallery.thumb(id)) {
+            Some(Some(thumb)) => match self.renderer.display(out, thumb, area, app.cell_size) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {
+                    self.tiles_interrupted.push(index);
+                    Ok(())
+                }
+                shown => shown,
+            },
+            Some(None) => draw_centered_error(out, area, "can't load"),
+            None => {
+                let (col, row, w, h) = area;
+                queue!(
+                    out,
+                    cursor::MoveTo(col + w / 2, row + h / 2),
+                    SetForegroundColor(style::Color::DarkGrey),
+                )?;
+                write!(out, "…")?;
+                queue!(out, SetForegroundColor(style::Color::Reset))
+            }
+        }
+    }
+}
+
+/// Draw the one-cell frame around a gallery tile, highlighted if selected.
+fn draw_tile_frame(out: &mut impl Write, rect: CellRect, selected: bool) -> io::Result<()> {
+    let (col, row, w, h) = rect;
+    if w < 2 || h < 2 {
+        return Ok(());
+    }
+    let color = if selected {
+        style::Color::Yellow
+    } else {
+        style::Color::DarkGrey
+    };
+    let inner = "─".repeat((w - 2) as usize);
+
+    queue!(out, SetForegroundColor(color), cursor::MoveTo(col, row))?;
+    write!(out, "┌{}┐", inner)?;
+    for r in row + 1..row + h - 1 {
+        queue!(out, cursor::MoveTo(col, r))?;
+        write!(out, "│")?;
+        queue!(out, cursor::MoveTo(col + w - 1, r))?;
+        write!(out, "│")?;
+    }
+    queue!(out, cursor::MoveTo(col, row + h - 1))?;
+    write!(out, "└{}┘", inner)?;
+    queue!(out, SetForegroundColor(style::Color::Reset))
+}
+
+/// Write the filename of a gallery tile, centered above its bottom edge and
+/// cut short with an ellipsis if it doesn't fit.
+fn draw_tile_label(out: &mut impl Write, rect: CellRect, path: &Path) -> io::Result<()> {
+    let (col, row, w, h) = rect;
+    // Inside the frame, there must be a cell for it at least
+    if w < 3 || h < 2 {
+        return Ok(());
+    }
+    let width = (w - 2) as usize;
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let label: String = if name.chars().count() > width {
+        name.chars()
+            .take(width.saturating_sub(1))
+            .chain(std::iter::once('…'))
+            .collect()
+    } else {
+        name.into_owned()
+    };
+    let label_col = col + 1 + (width - label.chars().count()) as u16 / 2;
+    queue!(out, cursor::MoveTo(label_col, row + h - 2))?;
+    write!(out, "{}", label)
+}
+
+/// Draw a dim box where an image described by `probe` will be placed, with
+/// its size and format in the middle.
+fn draw_placeholder(
+    out: &mut impl Write,
+    probe: &Probe,
+    area: CellRect,
+    cell_w: u16,
+    cell_h: u16,
+) -> io::Result<()> {
+    let (_, rect) = fit_image(probe.width, probe.height, area, cell_w, cell_h);
+    let (col, row, w, h) = rect;
+    fill_rect(out, rect, style::Color::DarkGrey)?;
+
+    let format = probe
+        .format
+        .map(|f| format!(" {:?}", f).to_uppercase())
+        .unwrap_or_default();
+    let label = format!("{}×{}{} — decoding…", probe.width, probe.height, format);
+    let label: String = label.chars().take(w as usize).collect();
+    let label_col = col + w.saturating_sub(label.chars().count() as u16) / 2;
+
+    queue!(
+        out,
+        cursor::MoveTo(label_col, row + h / 2),
+        SetForegroundColor(style::Color::White),
+        SetBackgroundColor(style::Color::DarkGrey),
+    )?;
+    write!(out, "{}", label)?;
+    queue!(
+        out,
+        SetForegroundColor(style::Color::Reset),
+        SetBackgroundColor(style::Color::Reset),
+    )?;
+    Ok(())
+}
+
+/// Paint every cell of `area` with background `color`.
+fn fill_rect(out: &mut impl Write, area: CellRect, color: style::Color) -> io::Result<()> {
+    let (col, row, w, h) = area;
+    queue!(out, SetBackgroundColor(color))?;
+    for r in row..row + h {
+        queue!(out, cursor::MoveTo(col, r))?;
+        write!(out, "{}", " ".repeat(w as usize))?;
+    }
+    queue!(out, SetBackgroundColor(style::Color::Reset))?;
+    Ok(())
+}
+
+/// Write `msg` in red, centered within `area`.
+fn draw_centered_error(out: &mut impl Write, area: CellRect, msg: &str) -> io::Result<()> {
+    let (col, row, w, h) = area;
+    let err_row = row + h / 2;
+    let err_col = col + w.saturating_sub(msg.len() as u16) / 2;
+    queue!(
+        out,
+        cursor::MoveTo(err_col, err_row),
+        SetForegroundColor(style::Color::Red),
+    )?;
+    write!(out, "{}", msg)?;
+    queue!(out, SetForegroundColor(style::Color::Reset))?;
+    Ok(())
+}
+
+/// Build the left and right halves of the status bar.
+fn status_text(app: &App, grid_origin: Option<(u32, u32)>) -> (String, String) {
+    if app.gallery.active {
+        let selected = app
+            .images
+            .get(app.gallery.selected)
+            .and_then(|p| p.file_name())
+            .unwrap_or_default()
+            .to_string_lossy();
+        let left = format!(" gallery  {}", selected);
+        let right = format!(
+            "{}/{} | Enter:open q:quit ?:help ",
+            app.gallery.selected + 1,
+            app.images.len()
+        );
+        return (left, right);
+    }
+
+    if let Some(Prompt::Destination { op, ref input, .. }) = app.prompt {
+        let left = format!(" {} to: {}_", op.verb(), input);
+        return (left, "Enter:ok Esc:cancel ".into());
+    }
+
+    if let Some(Prompt::Retry { ref op, ref failure }) = app.prompt {
+        let name = op.target().path.file_name().unwrap_or_default().to_string_lossy();
+        let left = match failure {
+            Failure::Changed => format!(" {}: {}, {} anyway?", name, failure, op.verb()),
+            _ => format!(" {}: {}, try again?", name, failure),
+        };
+        return (left, "y:yes n:no ".into());
+    }
+
+    if let Some(measure) = app.measure.as_ref().filter(|m| m.active) {
+        let (x, y) = measure.cursor;
+        let mut left = format!(" measure @{},{}", x, y);
+        if let Some(current) = measure.current() {
+            left.push_str(&format!("  {}", current.summary()));
+        }
+        if measure.copied {
+            left.push_str(" (copied)");
+        }
+        let right = match measure.anchor {
+            Some(_) => "Enter:end Bksp:undo Esc:done ",
+            None => "Enter:start y:copy Del:clear Esc:done ",
+        };
+        return (left, right.into());
+    }
+
+    let filename = app.current_filename();
+    let counter = if app.images.is_empty() {
+        "0/0".into()
+    } else {
+        format!("{}/{}", app.index + 1, app.images.len())
+    };
+    let info = app.image_info();
+    let orientation_str = match app.orientation.label() {
+        label if label.is_empty() => label,
+        label => format!(" {}", label),
+    };
+    let zoom_str = if (app.zoom - 1.0).abs() > 0.01 {
+        format!(" {:.0}%", app.zoom * 100.0)
+    } else {
+        String::new()
+    };
+
+    let fit_str = format!(" {}", app.fit_mode.label());
+
+    let grid_str = match grid_origin {
+        Some((x, y)) => format!(" grid @{},{}", x, y),
+        None => String::new(),
+    };
+
+    let follow_str = match app.follow {
+        Some(Follow { engaged: true, .. }) => "follow | ",
+        Some(Follow { engaged: false, .. }) => "follow paused (F) | ",
+        None => "",
+    };
+
+    let slideshow_str = match app.slideshow {
+        Some(ref show) => format!(
+            "{} {}s | ",
+            if show.paused.is_some() { "⏸" } else { "▶" },
+            show.interval.as_secs_f64()
+        ),
+        None => String::new(),
+    };
+
+    let config_str = if app.dir_config.is_some() { "dir config | " } else { "" };
+
+    let slow_str = if app.fs_slow { "filesystem slow | " } else { "" };
+
+    let list_str = match app.list_notice {
+        Some((ref notice, _)) => format!("{} | ", notice),
+        None => String::new(),
+    };
+
+    let failed_str = match app.log.errors() {
+        0 => String::new(),
+        n => format!("{} failed (L) | ", n),
+    };
+
+    let (side_str, missing_str) = match app.compare {
+        Some(ref compare) => {
+            let side = match compare.side {
+                Side::A => "[A] ",
+                Side::B => "[B] ",
+            };
+            let missing = match compare.missing_from(app.index) {
+                Some(Side::A) => "missing in A | ",
+                Some(Side::B) => "missing in B | ",
+                None => "",
+            };
+            (side, missing)
+        }
+        None => ("", ""),
+    };
+
+    let frame_str = match app.animation {
+        Some(ref anim) => format!(
+            " frame {}/{}{}",
+            anim.current + 1,
+            anim.frames.len(),
+            if anim.paused { " paused" } else { "" }
+        ),
+        None => String::new(),
+    };
+
+    let left = match app.error_message {
+        // With no image the error is shown in its place instead
+        Some(ref err) if app.current_image.is_some() => format!(" {}", err),
+        _ => format!(
+            " {}{} {}{}{}{} {}{}",
+            side_str, filename, info, orientation_str, frame_str, fit_str, zoom_str, grid_str
+        ),
+    };
+    let right = format!(
+        "{}{}{}{}{}{}{}{} | q:quit ?:help ",
+        list_str,
+        slow_str,
+        failed_str,
+        missing_str,
+        config_str,
+        follow_str,
+        slideshow_str,
+        counter
+    );
+    (left, right)
+}
+
+// ---------------------------------------------------------------------------
+// Argument resolution
+// ---------------------------------------------------------------------------
+
+/// What to browse, as worked out from the command line.
+#[derive(Debug)]
+struct Sources {
+    dir: PathBuf,
+    images: Vec<PathBuf>,
+    start_index: usize,
+    compare: Option<Compare>,
+    /// The one image to show when it came from stdin or a URL.
+    memory: Option<InMemory>,
+}
+
+/// `resolve_sources`, saying so on stderr if the filesystem is slow to answer.
+/// There is no screen to show it on yet, and Ctrl-C still works.
+fn resolve_sources_patiently(args: &Args, persistence: &Persistence) -> Result<Sources, String> {
+    std::thread::scope(|scope| {
+        let (tx, rx) = std::sync::mpsc::channel();
+        scope.spawn(move || {
+            let _ = tx.send(resolve_sources(args, persistence));
+        });
+        if let Ok(result) = rx.recv_timeout(slowfs::SLOW_AFTER) {
+            return result;
+        }
+        // Waiting on stdin or a download isn't the filesystem's fault
+        let streamed = args
+            .files
+            .iter()
+            .any(|f| f.as_os_str() == "-" || url_arg(f).is_some());
+        if !streamed {
+            eprintln!("termview: still reading the image list; the filesystem is slow to respond");
+        }
+        rx.recv()
+            .unwrap_or_else(|_| Err("couldn't read the image list".into()))
+    })
+}
+
+/// Turn the positional arguments and `-d` into a directory, its images, and
+/// the image to start on.
+///
+/// A single positional argument may be an image (browse its siblings,
+/// starting on it) or a directory (browse it, like `-d`). Several are
+/// browsed as one list; see `resolve_paths`. Errors are messages meant for
+/// stderr, e.g. when the path doesn't exist or there is nothing to show.
+fn resolve_sources(args: &Args, persistence: &Persistence) -> Result<Sources, String> {
+    let listing = args.list_options();
+    if let Some(ref dirs) = args.compare_dirs {
+        return resolve_compare(&dirs[0], &dirs[1], &listing);
+    }
+
+    if args.files.len() > 1 {
+        return resolve_paths(&args.files, args, &listing);
+    }
+    let file = args.files.first();
+
+    let memory = file.map(|f| read_into_memory(f, args, persistence));
+    if let Some(memory) = memory.transpose()?.flatten() {
+        if args.follow || args.wait_for_images || args.latest.is_some() {
+            return Err(format!(
+                "--follow, --wait-for-images and --latest need a directory, not {}",
+                memory.name.display()
+            ));
+        }
+        return Ok(Sources {
+            dir: PathBuf::from("."),
+            images: vec![memory.name.clone()],
+            start_index: 0,
+            compare: None,
+            memory: Some(memory),
+        });
+    }
+
+    let (dir, start_file) = match (file, &args.directory) {
+        (Some(file), _) if file.is_dir() => (file.clone(), None),
+        (Some(file), _) if file.is_file() => {
+            let parent = file
+                .parent()
+                .filter(|p| !p.as_os_str().is_empty())
+                .unwrap_or(Path::new("."));
+            (parent.to_path_buf(), Some(file.clone()))
+        }
+        (Some(file), _) => return Err(format!("no such file or directory: {}", file.display())),
+        (None, Some(dir)) if !dir.is_dir() => {
+            return Err(format!("not a directory: {}", dir.display()))
+        }
+        (None, Some(dir)) => (dir.clone(), None),
+        (None, None) => (PathBuf::from("."), None),
+    };
+
+    let dir = std::fs::canonicalize(&dir).unwrap_or(dir);
+    let images = listing.collect(&dir);
+
+    // An empty directory is only worth opening if we're waiting for files to appear
+    if images.is_empty() && !args.wait_for_images {
+        let subfolders = if args.recursive {
+            ""
+        } else {
+            "\n  try `termview -r` (`--recursive`) to include images in subfolders,"
+        };
+        return Err(format!(
+            "no images found in {}\n\
+             \x20 searched {} files, 0 matched\n\
+             \x20 recognised extensions: {}{}\n\
+             \x20 try `termview -d <dir>` to browse another directory, or\n\
+             \x20 --wait-for-images to open anyway and wait for one to appear",
+            dir.display(),
+            listing.count_files(&dir),
+            IMAGE_EXTENSIONS
+                .iter()
+                .copied()
+                .chain(args.decoder.iter().map(|d| d.ext.as_str()))
+                .collect::<Vec<_>>()
+                .join(", "),
+            subfolders
+        ));
+    }
+
+    // The list is sorted newest first, whatever file was named
+    let start_index = if args.latest.is_some() {
+        0
+    } else if let Some(file) = start_file {
+        let canonical = std::fs::canonicalize(&file).unwrap_or(file);
+        images
+            .iter()
+            .position(|p| std::fs::canonicalize(p).unwrap_or(p.clone()) == canonical)
+            .unwrap_or(0)
+    } else if args.follow {
+        newest_image(&images)
+            .and_then(|newest| images.iter().position(|p| *p == newest.path))
+            .unwrap_or(0)
+    } else {
+        0
+    };
+
+    Ok(Sources {
+        dir,
+        images,
+        start_index,
+        compare: None,
+        memory: None,
+    })
+}
+
+/// Several paths on the command line: images are taken as given and
+/// directories expanded, in argument order, with each file listed once.
+fn resolve_paths(paths: &[PathBuf], args: &Args, listing: &ListOptions) -> Result<Sources, String> {
+    if args.follow || args.wait_for_images || args.latest.is_some() {
+        return Err("--follow, --wait-for-images and --latest need a single directory".into());
+    }
+    let mut images = Vec::new();
+    for path in paths {
+        if path == Path::new("-") || url_arg(path).is_some() {
+            return Err(format!("{} can only be opened on its own", path.display()));
+        } else if path.is_dir() {
+            images.extend(listing.collect(path));
+        } else if path.is_file() {
+            images.push(path.clone());
+        } else {
+            return Err(format!("no such file or directory: {}", path.display()));
+        }
+    }
+    listing::dedupe(&mut images);
+    listing.shuffle(&mut images);
+    if images.is_empty() {
+        return Err("no images found in the given directories".into());
+    }
+
+    Ok(Sources {
+        dir: PathBuf::from("."),
+        images,
+        start_index: 0,
+        compare: None,
+        memory: None,
+    })
+}
+
+/// The URL in `arg`, if it is one.
+fn url_arg(arg: &Path) -> Option<&str> {
+    arg.to_str()
+        .filter(|s| s.starts_with("http://") || s.starts_with("https://"))
+}
+
+/// Name an image read from stdin is shown under.
+const STDIN_NAME: &str = "(stdin)";
+
+/// Read the image named by `arg` into memory if it is `-` (stdin) or a URL.
+/// Returns `Ok(None)` for anything else, which is taken to be a path.
+#[cfg_attr(not(feature = "fetch"), allow(unused_variables))]
+fn read_into_memory(
+    arg: &Path,
+    args: &Args,
+    persistence: &Persistence,
+) -> Result<Option<InMemory>, String> {
+    if arg == Path::new("-") {
+        let mut stdin = io::stdin();
+        if stdin.is_terminal() {
+            return Err("nothing piped to stdin, e.g. `curl -s URL | termview -`".into());
+        }
+        let mut bytes = Vec::new();
+        stdin
+            .read_to_end(&mut bytes)
+            .map_err(|e| format!("couldn't read stdin: {}", e))?;
+        return Ok(Some(InMemory {
+            name: PathBuf::from(STDIN_NAME),
+            bytes,
+        }));
+    }
+
+    let Some(url) = url_arg(arg) else {
+        return Ok(None);
+    };
+
+    #[cfg(feature = "fetch")]
+    {
+        let max_bytes = args.download_cache_size << 20;
+        let cache = urlcache::DownloadCache::new(&persistence.downloads, max_bytes);
+        let (bytes, origin) = fetch::download(url, cache.as_ref())?;
+        if let fetch::Origin::Offline(why) = origin {
+            eprintln!("termview: {} (cached, offline): {}", url, why);
+        }
+        Ok(Some(InMemory {
+            name: PathBuf::from(url),
+            bytes,
+        }))
+    }
+    #[cfg(not(feature = "fetch"))]
+    Err(format!(
+        "can't open {}: URLs need termview built with `--features fetch`",
+        url
+    ))
+}
+
+/// Pair up the images of two directories for `--compare-dirs`.
+fn resolve_compare(a: &Path, b: &Path, listing: &ListOptions) -> Result<Sources, String> {
+    for dir in [a, b] {
+        if !dir.is_dir() {
+            return Err(format!("not a directory: {}", dir.display()));
+        }
+    }
+    let a = std::fs::canonicalize(a).unwrap_or(a.to_path_buf());
+    let b = std::fs::canonicalize(b).unwrap_or(b.to_path_buf());
+
+    let pairs = compare::pair(listing.collect(&a), listing.collect(&b));
+    if pairs.is_empty() {
+        return Err(format!(
+            "no images found in {} or {}",
+            a.display(),
+            b.display()
+        ));
+    }
+
+    let compare = Compare::new(a.clone(), b, pairs);
+    let (only_a, only_b) = compare.unmatched();
+    if only_a + only_b > 0 {
+        eprintln!(
+            "termview: {} images only in {}, {} only in {}",
+            only_a,
+            compare.dir(Side::A).display(),
+            only_b,
+            compare.dir(Side::B).display()
+        );
+    }
+
+    Ok(Sources {
+        dir: a,
+        images: compare.paths(),
+        start_index: 0,
+        compare: Some(compare),
+        memory: None,
+    })
+}
+
+// ---------------------------------------------------------------------------
+// Terminal setup
+// ---------------------------------------------------------------------------
+
+/// How the terminal was set up, so it can be put back the same way.
+#[derive(Debug, Clone, Copy)]
+struct TerminalMode {
+    alt_screen: bool,
+    keyboard_enhanced: bool,
+    /// Image backend in use; never `Auto`.
+    protocol: Protocol,
+}
+
+/// Switch to raw mode and take over the screen.
+fn setup_terminal(
+    out: &mut impl Write,
+    alt_screen: bool,
+    protocol: Protocol,
+) -> io::Result<TerminalMode> {
+    enable_raw_mode()?;
+
+    let protocol = match protocol {
+        Protocol::Auto => render::detect(),
+        p => p,
+    };
+
+    // Prefer the kitty keyboard protocol where available: it reports modifiers
+    // on keys (Ctrl+arrows, Esc vs Alt) that legacy encoding can't express.
+    let keyboard_enhanced = terminal::supports_keyboard_enhancement().unwrap_or(false);
+
+    let mode = TerminalMode {
+        alt_screen,
+        keyboard_enhanced,
+        protocol,
+    };
+    take_over(out, mode)?;
+    Ok(mode)
+}
+
+/// Enter raw mode, take over the screen and turn on the keyboard protocol,
+/// as `setup_terminal` worked out. Done again when resuming from a suspend.
+fn take_over(out: &mut impl Write, mode: TerminalMode) -> io::Result<()> {
+    enable_raw_mode()?;
+    if mode.alt_screen {
+        execute!(out, terminal::EnterAlternateScreen, cursor::Hide)?;
+    } else {
+        // Without an alternate screen, a write to the bottom-right cell must
+        // not wrap and scroll our drawing into the user's scrollback.
+        execute!(
+            out,
+            terminal::Clear(ClearType::All),
+            terminal::DisableLineWrap,
+            cursor::Hide,
+        )?;
+    }
+    if mode.keyboard_enhanced {
+        execute!(
+            out,
+            PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::DISAMBIGUATE_ESCAPE_CODES)
+        )?;
+    }
+    Ok(())
+}
+
+/// Longest to wait for the terminal to confirm our images are deleted.
+const EXIT_SYNC_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// Undo `setup_terminal`: delete our images and hand the terminal back.
+///
+/// This is the only way out, whether by quitting, an error, panicking, or a
+/// signal, and it only acts on the first call. The order matters: images are
+/// deleted and the terminal is made to confirm it has processed that before
+/// the alternate screen is left, so terminals that keep graphics across the
+/// switch can't leave a stale image over the shell. Cursor and raw mode come
+/// back last.
+fn restore_terminal(out: &mut impl Write, mode: TerminalMode) -> io::Result<()> {
+    static RESTORED: AtomicBool = AtomicBool::new(false);
+    if RESTORED.swap(true, Ordering::SeqCst) {
+        return Ok(());
+    }
+    hand_back(out, mode)
+}
+
+/// Delete our images and put the terminal back as it was before
+/// `take_over`, in the order `restore_terminal` describes.
+fn hand_back(out: &mut impl Write, mode: TerminalMode) -> io::Result<()> {
+    if mode.protocol == Protocol::Kitty {
+        render::kitty_clear(out)?;
+        out.flush()?;
+        render::wait_for_terminal(EXIT_SYNC_TIMEOUT);
+    }
+    if mode.keyboard_enhanced {
+        execute!(out, PopKeyboardEnhancementFlags)?;
+    }
+    let rows = terminal::size().map_or(1, |(_, rows)| rows);
+    leave_screen(out, mode.alt_screen, rows)?;
+    execute!(out, cursor::Show)?;
+    disable_raw_mode()
+}
+
+/// Leave the alternate screen, or without one leave what was drawn on the
+/// `rows`-high normal screen in place and put the shell's prompt below it.
+fn leave_screen(out: &mut impl Write, alt_screen: bool, rows: u16) -> io::Result<()> {
+    if alt_screen {
+        return execute!(out, terminal::LeaveAlternateScreen);
+    }
+    execute!(
+        out,
+        terminal::EnableLineWrap,
+        cursor::MoveTo(0, rows.saturating_sub(1)),
+        style::Print("\r\n"),
+    )
+}
+
+/// Restores the terminal when dropped, so an error returned from the event
+/// loop hands the terminal back before it is printed.
+struct RestoreOnDrop(TerminalMode);
+
+impl Drop for RestoreOnDrop {
+    fn drop(&mut self) {
+        let _ = restore_terminal(&mut stdout(), self.0);
+    }
+}
+
+/// Held by the event loop while it may write to the terminal, which is all
+/// the time except while it waits for input. A signal restores the terminal
+/// only once it has this, so never in the middle of an escape sequence.
+static TERMINAL: Mutex<()> = Mutex::new(());
+
+fn hold_terminal() -> MutexGuard<'static, ()> {
+    // A panic while holding it leaves nothing half done that matters here
+    TERMINAL.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Set when the terminal was taken over again after a suspend, for the
+/// event loop to redraw everything it had on it.
+static RESUMED: AtomicBool = AtomicBool::new(false);
+
+/// Restore the terminal and exit when asked to terminate by a signal. An
+/// image transfer in progress is cut short first, and the terminal restored
+/// once the event loop next waits for input.
+///
+/// A stop signal (`kill -TSTP`; in raw mode Ctrl-Z is only a key) hands the
+/// terminal back to the shell the same way before stopping, and it is taken
+/// over again on continuing.
+fn restore_on_signals(mode: TerminalMode) -> io::Result<()> {
+    use signal_hook::consts::{SIGCONT, SIGHUP, SIGINT, SIGQUIT, SIGTERM, SIGTSTP, SIGWINCH};
+    use signal_hook::low_level::{emulate_default_handler, raise};
+
+    let signals = [SIGHUP, SIGINT, SIGQUIT, SIGTERM, SIGTSTP, SIGCONT];
+    let mut signals = signal_hook::iterator::Signals::new(signals)?;
+    std::thread::spawn(move || {
+        let mut suspended = false;
+        for signal in signals.forever() {
+            match signal {
+                SIGTSTP => {
+                    let _held = hold_terminal();
+                    suspended = hand_back(&mut stdout(), mode).is_ok();
+                    // Stops the process as the default action would, until SIGCONT
+                    let _ = emulate_default_handler(SIGTSTP);
+                }
+                SIGCONT => {
+                    let _held = hold_terminal();
+                    // Stopped some other way, the terminal may still be ours
+                    if std::mem::take(&mut suspended) {
+                        let _ = take_over(&mut stdout(), mode);
+                    }
+                    RESUMED.store(true, Ordering::SeqCst);
+                    // Wakes the event loop with a resize, as the size may have
+                    // changed while stopped anyway
+                    let _ = raise(SIGWINCH);
+                }
+                signal => {
+                    render::TERMINATING.store(true, Ordering::SeqCst);
+                    let _held = hold_terminal();
+                    let _ = restore_terminal(&mut stdout(), mode);
+                    std::process::exit(128 + signal);
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+// ---------------------------------------------------------------------------
+// Main
+// ---------------------------------------------------------------------------
+
+/// `termview doctor`: where each store resolved to in this environment, and
+/// why any that can't be used is off.
+fn doctor(persistence: &Persistence, dir_config: Option<&DirConfig>) {
+    let mut lines = persistence.report();
+    lines.push((
+        "directory settings",
+        match dir_config {
+            Some(config) => config.path.display().to_string(),
+            None => "none".into(),
+        },
+    ));
+    let width = lines.iter().map(|(name, _)| name.len()).max().unwrap_or(0) + 1;
+    println!("termview {}", env!("CARGO_PKG_VERSION"));
+    for (name, value) in lines {
+        println!("  {:width$} {}", format!("{}:", name), value, width = width);
+    }
+    for problem in dir_config.iter().flat_map(|config| &config.problems) {
+        println!("  {:width$} {}", "", problem, width = width);
+    }
+}
+
+/// Longest the event loop sleeps when no timer is pending. Nothing needs the
+/// loop to wake without input or a timer, so this only bounds how stale the
+/// loop can get if a future feature forgets to schedule itself.
+const MAX_POLL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Whether `action` scrolls the session log: moving up and down does while
+/// it is open.
+fn scrolls_log(app: &App, action: Action) -> bool {
+    app.log.visible
+        && matches!(
+            action,
+            Action::Up
+                | Action::Down
+                | Action::PageUp
+                | Action::PageDown
+                | Action::First
+                | Action::Last
+        )
+}
+
+/// Carry out the key action `action` in a terminal of `size`: whether it
+/// changed anything, or `None` if it quits. Everything a key does goes
+/// through here, keys replayed from a recording included.
+fn perform(app: &mut App, action: Action, screen: &Screen, size: (u16, u16)) -> Option<bool> {
+    let in_gallery = app.gallery.active;
+    let scrolls_log = scrolls_log(app, action);
+    let changed = if scrolls_log {
+        app.scroll_log(action)
+    } else if app.gallery.active {
+        match action {
+            Action::Quit => return None,
+            Action::Open | Action::ToggleGallery => app.open_selected(),
+            Action::ToggleHelp => {
+                app.toggle_help();
+                true
+            }
+            Action::ToggleLog => {
+                app.toggle_log();
+                true
+            }
+            _ => app.gallery_move(action),
+        }
+    } else {
+        match action {
+            Action::Quit => return None,
+
+            // Navigation is a no-op when it lands on the displayed image
+            Action::Next | Action::Prev | Action::First | Action::Last => {
+                app.navigate(action)
+            }
+            Action::Reload => {
+                app.reload();
+                true
+            }
+
+            Action::ZoomIn => {
+                app.zoom_in();
+                true
+            }
+            Action::ZoomOut => {
+                app.zoom_out();
+                true
+            }
+            Action::ZoomReset => {
+                app.zoom_reset();
+                true
+            }
+            Action::CycleFit => {
+                app.fit_mode = app.fit_mode.next();
+                true
+            }
+            Action::TogglePixelGrid => {
+                app.toggle_pixel_grid(screen.grid_origin.is_some());
+                true
+            }
+            Action::Measure => app.toggle_measure(screen.view_rect),
+            // Only ever taken by measuring, which isn't on here
+            Action::CopyMeasurement => false,
+
+            Action::PanUp => {
+                app.pan(0.0, -0.05);
+                true
+            }
+            Action::PanDown => {
+                app.pan(0.0, 0.05);
+                true
+            }
+            Action::PanLeft => {
+                app.pan(-0.05, 0.0);
+                true
+            }
+            Action::PanRight => {
+                app.pan(0.05, 0.0);
+                true
+            }
+
+            Action::Follow => app.engage_follow(),
+            Action::FlipSide => app.flip_side(),
+            Action::ToggleSlideshow => {
+                app.toggle_slideshow();
+                true
+            }
+            Action::TogglePlayback => app.toggle_playback(),
+            Action::NextFrame => app.step_frame(1),
+            Action::PrevFrame => app.step_frame(-1),
+            Action::RotateCw => app.reorient(Orientation::ROTATE_CW),
+            Action::RotateCcw => app.reorient(Orientation::ROTATE_CCW),
+            Action::FlipHorizontal => app.reorient(Orientation::FLIP_HORIZONTAL),
+            Action::FlipVertical => app.reorient(Orientation::FLIP_VERTICAL),
+            Action::ToggleGallery => app.open_gallery(size),
+            Action::Delete => app.start_delete(),
+            Action::Move => app.start_transfer(Transfer::Move),
+            Action::Copy => app.start_transfer(Transfer::Copy),
+            Action::ToggleInfo => {
+                app.toggle_info();
+                true
+            }
+            Action::ToggleHelp => {
+                app.toggle_help();
+                true
+            }
+            Action::ToggleLog => {
+                app.toggle_log();
+                true
+            }
+            Action::Up
+            | Action::Down
+            | Action::PageUp
+            | Action::PageDown
+            | Action::Open => false,
+        }
+    };
+    if !changed {
+        return Some(false);
+    }
+
+    if !in_gallery && !scrolls_log {
+        app.chose_by_hand(action);
+    }
+    // Whatever is chosen by hand gets a full turn in the slideshow
+    if !scrolls_log
+        && matches!(
+            action,
+            Action::Next
+                | Action::Prev
+                | Action::First
+                | Action::Last
+                | Action::Open
+                | Action::ToggleGallery
+        )
+    {
+        app.restart_slideshow();
+    }
+    Some(true)
+}
+
+/// How long the terminal has to keep its size before the screen is redrawn
+/// for it. Dragging a window edge sends a stream of resizes, and each full
+/// redraw may resend a large image.
+const RESIZE_DEBOUNCE: Duration = Duration::from_millis(100);
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let (args, dir_config) = with_dir_config(Args::parse(), std::env::args_os());
+    #[cfg(feature = "fetch")]
+    let downloads = !args.no_cache;
+    #[cfg(not(feature = "fetch"))]
+    let downloads = false;
+    let persistence =
+        Persistence::resolve(!args.no_thumbnail_cache, downloads, args.log.as_deref());
+    if let Some(Command::Doctor) = args.command {
+        doctor(&persistence, dir_config.as_ref());
+        return Ok(());
+    }
+
+    let sources = match resolve_sources_patiently(&args, &persistence) {
+        Ok(sources) => sources,
+        Err(msg) => {
+            eprintln!("termview: {}", msg);
+            std::process::exit(1);
+        }
+    };
+    let Sources {
+        dir: browse_dir,
+        images,
+        start_index,
+        compare,
+        memory,
+    } = sources;
+
+    #[cfg(feature = "server")]
+    if let Some(Command::Serve { ref addr }) = args.command {
+        if memory.is_some() {
+            eprintln!("termview: serve needs image files, not stdin or a URL");
+            std::process::exit(1);
+        }
+        // Thumbnails are the grid's: from the shared cache, or made through
+        // the same decoders as the viewer, so they come out upright and
+        // hooked-up formats are served too
+        let loader = Loader::new(args.decode_timeout, args.decoder.clone(), None, args.alpha);
+        let cache = ThumbCache::new(&persistence.thumbnails);
+        let warned = AtomicBool::new(false);
+        let thumbnail = move |path: &Path| serve_thumbnail(cache.as_ref(), &loader, path, &warned);
+        server::serve(addr, images, Box::new(thumbnail))?;
+        return Ok(());
+    }
+
+    // A log file that can't be written is left out; the `L` overlay still has it all
+    let log_file = persistence.log.as_ref().map(|log| {
+        let path = log.require()?;
+        OpenOptions::new().create(true).append(true).open(path)
+    });
+    let (log_file, log_error) = match log_file {
+        Some(Ok(file)) => (Some(file), None),
+        Some(Err(e)) => (None, Some(e)),
+        None => (None, None),
+    };
+    let record_file = match args.record_input {
+        Some(ref path) => match File::create(path) {
+            Ok(file) => Some(file),
+            Err(e) => {
+                eprintln!("termview: can't create {}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let recording = match args.replay {
+        Some(ref path) => match Recording::load(path) {
+            Ok(recording) => Some(recording),
+            Err(e) => {
+                eprintln!("termview: can't replay {}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    // Setup terminal
+    let mut out = stdout();
+    let mode = setup_terminal(&mut out, !args.no_alt_screen, args.protocol)?;
+    let _restore = RestoreOnDrop(mode);
+    let mut held = hold_terminal();
+
+    // Put the terminal back even if we panic, so the shell isn't left in raw mode
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        let _ = restore_terminal(&mut stdout(), mode);
+        default_hook(info);
+    }));
+    restore_on_signals(mode)?;
+
+    let loader = Loader::new(args.decode_timeout, args.decoder.clone(), memory, args.alpha);
+    let preloader = (args.cache_size > 0).then(|| Preloader::new(loader.clone(), args.cache_size));
+    let mut app = App::new(images, start_index, loader);
+    app.preloader = preloader;
+    app.log = SessionLog::new(log_file);
+    if let (Some(path), Some(e)) = (&args.log, log_error) {
+        app.log.push(
+            Severity::Warning,
+            format!("Not writing the log to {}: {}", path.display(), e),
+        );
+    }
+    if let Some(config) = dir_config {
+        app.log.push(
+            Severity::Info,
+            format!("Using settings from {}", config.path.display()),
+        );
+        for problem in &config.problems {
+            app.log.push(
+                Severity::Warning,
+                format!("{}: {}", config.path.display(), problem),
+            );
+        }
+        app.dir_config = Some(config.path);
+    }
+    app.listing = args.list_options();
+    app.compare = compare;
+    app.slideshow_interval = args.slideshow.unwrap_or(DEFAULT_SLIDESHOW_INTERVAL);
+    app.slideshow = args.slideshow.map(Slideshow::new);
+    app.slideshow_once = args.once;
+    app.fit_mode = args.fit;
+    app.margins = args.margins();
+    match args.cell_size {
+        Some(size) => {
+            app.cell_size = size;
+            app.cell_sizing = CellSizing::Fixed;
+        }
+        None => app.detect_cell_size(),
+    }
+    app.upscale = args.upscale;
+    app.pixel_grid_at = args.pixel_grid_at;
+    app.move_to = args.move_to.clone();
+    app.copy_to = args.copy_to.clone();
+    app.read_only = args.read_only;
+    app.thumb_cache = ThumbCache::new(&persistence.thumbnails);
+    app.delete_permanently = args.no_trash;
+    app.persistence = persistence;
+    let mut scheduler = Scheduler::new();
+    if args.follow {
+        app.follow = Some(Follow::new(browse_dir.clone(), &app.images));
+        scheduler.every(Timer::FollowScan, FOLLOW_SCAN_INTERVAL);
+    }
+    if app.images.is_empty() {
+        // Only reachable with --wait-for-images
+        app.waiting_for = Some(browse_dir.clone());
+        if !args.follow {
+            scheduler.every(Timer::WaitScan, FOLLOW_SCAN_INTERVAL);
+        }
+    }
+    // A list built from several paths or two directories isn't a directory listing to watch
+    let watchable = args.files.len() <= 1 && app.compare.is_none() && app.loader.memory.is_none();
+    if watchable && !args.no_watch {
+        match watch_dirs(&browse_dir, &app.images) {
+            Ok(watcher) => {
+                app.watcher = Some(Watch {
+                    watcher,
+                    dir: browse_dir.clone(),
+                    changed: HashSet::new(),
+                    listing_changed: false,
+                    pending_since: None,
+                    retried: false,
+                });
+                scheduler.every(Timer::Watch, WATCH_INTERVAL);
+            }
+            Err(e) => app.log.push(
+                Severity::Warning,
+                format!("Not watching {} for changes: {}", browse_dir.display(), e),
+            ),
+        }
+    }
+    if args.gallery && !app.images.is_empty() {
+        app.open_gallery(terminal::size()?);
+    } else {
+        app.load_current();
+    }
+    let kitty_caps = if mode.protocol == Protocol::Kitty {
+        let terminal = match args.kitty_quirks {
+            KittyQuirks::Auto => render::terminal_version(),
+            _ => None,
+        };
+        let caps = render::kitty_caps(args.kitty_quirks, terminal.as_deref());
+        app.log.push(
+            Severity::Info,
+            format!(
+                "Kitty graphics, {} of keys (terminal: {})",
+                caps.name(),
+                terminal.as_deref().unwrap_or("unknown")
+            ),
+        );
+        caps
+    } else {
+        KittyCaps::MODERN
+    };
+    let palette = match args.palette {
+        Some(palette) => palette,
+        None if mode.protocol == Protocol::Blocks16 => {
+            let reported = render::query_palette(PALETTE_QUERY_TIMEOUT);
+            let source = if reported.is_some() { "as the terminal reports them" } else { "VGA defaults" };
+            app.log.push(Severity::Info, format!("16 colours: {}", source));
+            reported.unwrap_or(Palette::VGA)
+        }
+        None => Palette::VGA,
+    };
+    let mut screen = Screen::new(render::renderer_for(
+        mode.protocol,
+        args.link_speed,
+        kitty_caps,
+        palette,
+        args.fallback_gamma,
+    ));
+    screen.region = args.region;
+    screen.letterbox = args.letterbox_color;
+
+    // Recording and replay both time inputs from here
+    let size = terminal::size()?;
+    if let Some(file) = record_file {
+        match Recorder::new(file, size, &app.images) {
+            Ok(recorder) => app.recorder = Some(recorder),
+            Err(e) => app
+                .log
+                .push(Severity::Error, format!("Not recording input: {}", e)),
+        }
+    }
+    let mut replay = recording.map(|recording| {
+        let problems = recording.problems(&app.images, size);
+        if !problems.is_empty() {
+            let message = format!("Replay may differ: {}", problems.join("; "));
+            app.log.push(Severity::Warning, message.clone());
+            app.error_message = Some(message);
+        }
+        Replay::new(recording, args.replay_speed)
+    });
+
+    // Initial draw
+    screen.render(&mut out, &app)?;
+
+    // Keys read while the terminal was being asked something
+    let mut typed_ahead: VecDeque<Event> = VecDeque::new();
+
+    // Event loop
+    loop {
+        typed_ahead.extend(render::typed_ahead().into_iter().map(Event::Key));
+
+        // Decode the image navigated to once no more keys are waiting
+        if typed_ahead.is_empty() && !event::poll(Duration::ZERO)? && app.finish_load() {
+            screen.invalidate_image();
+            screen.invalidate_status();
+            screen.render(&mut out, &app)?;
+        }
+
+        // Fill in gallery thumbnails one per pass, so keys are handled in between
+        let thumbnail = app.load_next_thumbnail();
+        if let Some(i) = thumbnail {
+            screen.invalidate_tile(i);
+            screen.render(&mut out, &app)?;
+        }
+
+        // Keep the frame timer in step with playback
+        match app.frame_delay() {
+            Some(delay) if !scheduler.is_pending(Timer::Frame) => {
+                scheduler.after(Timer::Frame, delay)
+            }
+            Some(_) => {}
+            None => scheduler.cancel(Timer::Frame),
+        }
+
+        // A render cut short by input finishes once the input has been handled
+        if screen.is_dirty() {
+            screen.render(&mut out, &app)?;
+        }
+
+        // Each move of the view pushes this back, so the colour changes once it stops
+        if screen.take_edge_moved() {
+            scheduler.after(Timer::Letterbox, LETTERBOX_SETTLE);
+        }
+
+        // An image sent while the terminal was resized was placed for the old size
+        if screen.take_resized_while_drawing() {
+            app.log.push(
+                Severity::Info,
+                "Terminal resized while drawing; redrawing for the new size".into(),
+            );
+            scheduler.after(Timer::Resize, RESIZE_DEBOUNCE);
+        }
+
+        if let Some(quality) = screen.take_quality_change() {
+            app.log.push(Severity::Info, format!("Images sent at {}", quality));
+        }
+
+        // Check on decodes in progress until they are all in
+        match app.is_decoding() {
+            true if !scheduler.is_pending(Timer::Decode) => {
+                scheduler.every(Timer::Decode, DECODE_POLL_INTERVAL)
+            }
+            true => {}
+            false => scheduler.cancel(Timer::Decode),
+        }
+
+        // Keep the slideshow timer on the next image's due time
+        match app.slideshow_due() {
+            Some(due) => scheduler.at(Timer::Slideshow, due),
+            None => scheduler.cancel(Timer::Slideshow),
+        }
+        match app.list_notice_due() {
+            Some(due) => scheduler.at(Timer::ListNotice, due),
+            None => scheduler.cancel(Timer::ListNotice),
+        }
+
+        let mut timeout = if thumbnail.is_some() || !typed_ahead.is_empty() {
+            Duration::ZERO
+        } else {
+            scheduler.timeout(Instant::now(), MAX_POLL_TIMEOUT)
+        };
+        if let Some(ref replay) = replay {
+            timeout = timeout.min(replay.timeout(Instant::now()).unwrap_or(timeout));
+        }
+        // A signal may take the terminal over while nothing is being drawn
+        drop(held);
+        let ready = event::poll(timeout);
+        held = hold_terminal();
+        let ready = ready?;
+        if RESUMED.swap(false, Ordering::SeqCst) {
+            screen.invalidate_terminal();
+        }
+        // They were typed before anything still waiting to be read
+        let mut event = match typed_ahead.pop_front() {
+            Some(event) => Some(event),
+            None if ready => Some(event::read()?),
+            None => None,
+        };
+        if let Some(ref mut replay) = replay {
+            // The recording stands in for the keyboard, but a quit key still stops it
+            if let Some(Event::Key(ref key)) = event {
+                if key.kind == KeyEventKind::Press && action_for(key) == Some(Action::Quit) {
+                    break;
+                }
+            }
+            // A real resize is handled as usual, and the recording waits a pass
+            if !matches!(event, Some(Event::Resize(..))) {
+                event = replay.next_event(Instant::now());
+            }
+        }
+        if let Some(event) = event {
+            match event {
+                Event::Key(key) => {
+                    if key.kind != KeyEventKind::Press {
+                        continue;
+                    }
+                    let (code, mods) = normalize_key(&key);
+                    app.record(Input::Key(code, mods));
+
+                    // An open prompt gets every key until it is answered
+                    if app.prompt.is_some() {
+                        match app.prompt_key(&key) {
+                            Refresh::Nothing => {}
+                            Refresh::Status => {
+                                screen.invalidate_overlay();
+                                screen.invalidate_status();
+                            }
+                            Refresh::Image => {
+                                scheduler.cancel(Timer::Frame);
+                                screen.invalidate_image();
+                                screen.invalidate_status();
+                            }
+                        }
+                        screen.render(&mut out, &app)?;
+                        continue;
+                    }
+
+                    // Measuring takes the keys it uses; the rest work as usual
+                    if !app.gallery.active {
+                        if let Some(refresh) = app.measure_key(&key, screen.view_rect) {
+                            if let Some(text) = app.clipboard.take() {
+                                render::copy_to_clipboard(&mut out, &text)?;
+                            }
+                            if refresh == Refresh::Image {
+                                screen.invalidate_image();
+                            }
+                            screen.invalidate_status();
+                            screen.render(&mut out, &app)?;
+                            continue;
+                        }
+                    }
+
+                    let Some(action) = action_for(&key) else {
+                        continue;
+                    };
+                    if !app.permits(action) {
+                        screen.invalidate_status();
+                        // Without an image, messages are shown in its place
+                        if app.current_image.is_none() {
+                            screen.invalidate_image();
+                        }
+                        screen.render(&mut out, &app)?;
+                        continue;
+                    }
+
+                    let in_gallery = app.gallery.active;
+                    let scrolls_log = scrolls_log(&app, action);
+                    let Some(changed) = perform(&mut app, action, &screen, terminal::size()?) else {
+                        break;
+                    };
+
+                    // BEL: terminals set to a visual bell flash the window
+                    if std::mem::take(&mut app.bell) {
+                        out.write_all(b"\x07")?;
+                        out.flush()?;
+                    }
+                    if !changed {
+                        continue;
+                    }
+
+                    if matches!(
+                        action,
+                        Action::Next
+                            | Action::Prev
+                            | Action::First
+                            | Action::Last
+                            | Action::Reload
+                            | Action::FlipSide
+                            | Action::Open
+                            | Action::ToggleGallery
+                    ) {
+                        scheduler.cancel(Timer::Frame);
+                    }
+
+                    match action {
+                        // The log is redrawn once the render sees it has moved
+                        _ if scrolls_log => {}
+                        Action::ToggleHelp | Action::ToggleLog => {
+                            screen.invalidate_overlay();
+                            // Blanking the help box leaves a hole in the grid
+                            if in_gallery {
+                                screen.invalidate_image();
+                            }
+                        }
+                        // Only the prompt appears; nothing has happened yet
+                        Action::Delete | Action::Move | Action::Copy if app.prompt.is_some() => {
+                            screen.invalidate_overlay();
+                            screen.invalidate_status();
+                        }
+                        _ if in_gallery && app.gallery.active => {
+                            screen.invalidate_selection();
+                            screen.invalidate_status();
+                        }
+                        Action::PanUp | Action::PanDown | Action::PanLeft | Action::PanRight => {
+                            screen.invalidate_image()
+                        }
+                        _ => {
+                            screen.invalidate_image();
+                            screen.invalidate_status();
+                        }
+                    }
+                }
+                Event::Resize(cols, rows) => {
+                    if replay.is_none() {
+                        app.record(Input::Resize(cols, rows));
+                    }
+                    // Each resize pushes this back, so it fires once resizing stops
+                    scheduler.after(Timer::Resize, RESIZE_DEBOUNCE);
+                }
+                _ => {}
+            }
+        }
+
+        let mut refresh = Refresh::Nothing;
+        let mut slideshow_over = false;
+        let now = Instant::now();
+        let mut timers = scheduler.expired(now);
+        if let Some(ref mut replay) = replay {
+            timers.retain(|&timer| !recording::replayed(timer));
+            timers.extend(replay.timers(now));
+        }
+        if replay.as_ref().is_some_and(Replay::is_finished) {
+            app.log.push(Severity::Info, "Replay finished".into());
+            replay = None;
+        }
+        for &timer in timers.iter().filter(|&&timer| recording::replayed(timer)) {
+            app.record(Input::Timer(timer));
+        }
+        for timer in timers {
+            refresh = refresh.max(match timer {
+                Timer::FollowScan => app.poll_follow(),
+                Timer::WaitScan => app.poll_waiting(),
+                Timer::Frame => app.advance_frame(),
+                // Thumbnails are taken at the top of the loop
+                Timer::Decode => {
+                    let (refresh, again) = app.poll_decode();
+                    if again {
+                        scheduler.after(Timer::WatchSettle, WATCH_DEBOUNCE);
+                    }
+                    refresh
+                }
+                Timer::Slideshow => app.advance_slideshow().unwrap_or_else(|| {
+                    slideshow_over = true;
+                    Refresh::Nothing
+                }),
+                Timer::Watch => {
+                    // Each change pushes this back, so it fires once writes stop
+                    // or the first change has waited long enough
+                    if let Some(at) = app.drain_watch() {
+                        scheduler.at(Timer::WatchSettle, at);
+                    }
+                    Refresh::Nothing
+                }
+                Timer::ListNotice => {
+                    app.list_notice = None;
+                    Refresh::Status
+                }
+                Timer::Letterbox => {
+                    screen.settle_edge();
+                    Refresh::Nothing
+                }
+                Timer::Resize => {
+                    // Zooming the font resizes the cells as well as the grid
+                    app.detect_cell_size();
+                    app.layout_gallery(terminal::size()?);
+                    screen.invalidate_all();
+                    Refresh::Nothing
+                }
+                Timer::WatchSettle => {
+                    let (refresh, again) = app.settle_watch();
+                    if again {
+                        scheduler.after(Timer::WatchSettle, WATCH_DEBOUNCE);
+                    }
+                    refresh
+                }
+            });
+        }
+        if slideshow_over {
+            break;
+        }
+        if app.drop_stale_prompt() {
+            screen.invalidate_overlay();
+            refresh = refresh.max(Refresh::Status);
+        }
+        if !app.images.is_empty() {
+            scheduler.cancel(Timer::WaitScan);
+        }
+        if refresh == Refresh::Image {
+            // A rescan may have switched files; don't carry over its frame timer
+            scheduler.cancel(Timer::Frame);
+        }
+
+        match refresh {
+            Refresh::Nothing => {}
+            Refresh::Status => screen.invalidate_status(),
+            Refresh::Image => {
+                screen.invalidate_image();
+                screen.invalidate_status();
+            }
+        }
+        screen.render(&mut out, &app)?;
+    }
+
+    // Cleanup: delete kitty images, restore terminal
+    restore_terminal(&mut out, mode)?;
+
+    Ok(())
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::listing::apply;
+    use crate::testdir::TestDir;
+
+    fn pixel() -> Result<Decoded, LoadError> {
+        Ok(Decoded::Still(DynamicImage::new_rgb8(1, 1)))
+    }
+
+    /// Poll `decoding` until its result is in.
+    fn settle(decoding: &Decoding) -> Result<Decoded, LoadError> {
+        loop {
+            if let Some(result) = decoding.poll() {
+                return result;
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    fn wait_until(mut done: impl FnMut() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done() {
+            assert!(Instant::now() < deadline, "gave up waiting");
+            std::thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn slow_decode_is_polled_without_waiting() {
+        let stuck = Arc::new(AtomicUsize::new(0));
+        let started = Instant::now();
+        let decoding = Decoding::spawn(Duration::from_secs(5), &stuck, || {
+            std::thread::sleep(Duration::from_millis(300));
+            pixel()
+        });
+        assert!(decoding.poll().is_none());
+        assert!(started.elapsed() < Duration::from_millis(100));
+        assert!(settle(&decoding).is_ok());
+        assert_eq!(stuck.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn timed_out_decode_counts_as_stuck_until_it_ends() {
+        let stuck = Arc::new(AtomicUsize::new(0));
+        let decoding = Decoding::spawn(Duration::from_millis(20), &stuck, || {
+            std::thread::sleep(Duration::from_millis(300));
+            pixel()
+        });
+        assert!(matches!(settle(&decoding), Err(LoadError::TimedOut(_))));
+        assert_eq!(stuck.load(Ordering::SeqCst), 1);
+        wait_until(|| stuck.load(Ordering::SeqCst) == 0);
+    }
+
+    #[test]
+    fn stuck_decodes_are_capped() {
+        let stuck = Arc::new(AtomicUsize::new(0));
+        let mut releases = Vec::new();
+        for _ in 0..MAX_STUCK_DECODES {
+            let (release, wait) = mpsc::channel::<()>();
+            releases.push(release);
+            let decoding = Decoding::spawn(Duration::from_millis(10), &stuck, move || {
+                let _ = wait.recv();
+                pixel()
+            });
+            assert!(matches!(settle(&decoding), Err(LoadError::TimedOut(_))));
+        }
+        assert_eq!(stuck.load(Ordering::SeqCst), MAX_STUCK_DECODES);
+
+        // No thread for this one: it fails at once
+        let refused = Decoding::spawn(Duration::from_secs(5), &stuck, || unreachable!());
+        assert!(matches!(refused.poll(), Some(Err(LoadError::Stuck(_)))));
+
+        drop(releases);
+        wait_until(|| stuck.load(Ordering::SeqCst) == 0);
+        let again = Decoding::spawn(Duration::from_secs(5), &stuck, pixel);
+        assert!(settle(&again).is_ok());
+    }
+
+    #[test]
+    fn event_loop_keeps_going_while_an_image_decodes() {
+        let tmp = TestDir::new();
+        let path = tmp.path().join("slow.xyz");
+        DynamicImage::new_rgb8(4, 3).save_with_format(&path, image::ImageFormat::Png).unwrap();
+        let decoder: Decoder = "xyz=sleep 0.5; cat {path}".parse().unwrap();
+        let loader = Loader::new(Duration::from_secs(5), vec![decoder], None, AlphaMode::Auto);
+        let mut app = App::new(vec![path], 0, loader);
+        app.load_current();
+
+        // Every pass through the loop returns at once, until the image is in
+        let started = Instant::now();
+        assert!(!app.finish_load());
+        let mut passes = 0;
+        loop {
+            let pass = Instant::now();
+            let (refresh, _) = app.poll_decode();
+            assert!(pass.elapsed() < Duration::from_millis(50), "a pass took {:?}", pass.elapsed());
+            passes += 1;
+            if refresh == Refresh::Image {
+                break;
+            }
+            assert!(started.elapsed() < Duration::from_secs(5), "never decoded");
+            std::thread::sleep(DECODE_POLL_INTERVAL);
+        }
+        assert!(passes > 10);
+        assert_eq!(app.current_image.as_ref().map(|img| img.dimensions()), Some((4, 3)));
+    }
+
+    /// An app over `count` images, showing `index` zoomed in, so that
+    /// reloading it shows as the zoom being reset.
+    fn browsing(count: usize, index: usize) -> App {
+        let images = (0..count).map(|i| PathBuf::from(format!("{}.png", i))).collect();
+        let loader = Loader::new(Duration::from_secs(5), Vec::new(), None, AlphaMode::Auto);
+        let mut app = App::new(images, index, loader);
+        app.current_image = Some(DynamicImage::new_rgb8(1, 1));
+        app.zoom = 2.0;
+        app
+    }
+
+    fn reloaded(app: &App) -> bool {
+        app.zoom == 1.0
+    }
+
+    #[test]
+    fn navigating_a_single_image_rings_the_bell_without_reloading() {
+        for action in [Action::Next, Action::Prev, Action::First, Action::Last] {
+            let mut app = browsing(1, 0);
+            assert!(!app.navigate(action), "{:?}", action);
+            assert!(app.bell, "{:?}", action);
+            assert!(!reloaded(&app), "{:?}", action);
+        }
+    }
+
+    #[test]
+    fn navigation_wraps_around() {
+        let mut app = browsing(3, 2);
+        assert!(app.navigate(Action::Next));
+        assert_eq!(app.index, 0);
+        assert!(reloaded(&app));
+        assert!(!app.bell);
+
+        let mut app = browsing(3, 0);
+        assert!(app.navigate(Action::Prev));
+        assert_eq!(app.index, 2);
+
+        let mut app = browsing(2, 1);
+        assert!(app.navigate(Action::Next));
+        assert!(app.navigate(Action::Next));
+        assert_eq!(app.index, 1);
+    }
+
+    #[test]
+    fn going_to_the_current_image_is_a_no_op() {
+        let mut app = browsing(3, 0);
+        assert!(!app.navigate(Action::First));
+        assert!(app.bell);
+        assert!(!reloaded(&app));
+
+        let mut app = browsing(3, 2);
+        assert!(!app.navigate(Action::Last));
+        assert!(!app.goto(2));
+        // Past the end is clamped to the last image, which is shown already
+        assert!(!app.goto(7));
+        assert_eq!(app.index, 2);
+        assert!(!reloaded(&app));
+
+        let mut app = browsing(3, 1);
+        assert!(app.goto(7));
+        assert_eq!(app.index, 2);
+        assert!(reloaded(&app));
+
+        let mut app = browsing(0, 0);
+        assert!(!app.navigate(Action::Next));
+        assert!(app.bell);
+    }
+
+    fn args(line: &[&str]) -> Args {
+        Args::try_parse_from(std::iter::once("termview").chain(line.iter().copied())).unwrap()
+    }
+
+    #[test]
+    fn empty_directory_is_explained() {
+        let dir = TestDir::new();
+        dir.file("notes.txt", b"");
+        dir.file("sub/readme.txt", b"");
+        let path = dir.path().to_str().unwrap();
+
+        let no_stores = Persistence::in_memory();
+        let Err(message) = resolve_sources(&args(&["-d", path]), &no_stores) else {
+            panic!("opened an empty directory");
+        };
+        assert!(message.starts_with("no images found in "), "{}", message);
+        assert!(message.contains("searched 1 files, 0 matched"), "{}", message);
+        assert!(message.contains("png, jpg"), "{}", message);
+        assert!(message.contains("`termview -r`"), "{}", message);
+        assert!(message.contains("--wait-for-images"), "{}", message);
+
+        let Err(message) = resolve_sources(&args(&["-r", "-d", path]), &no_stores) else {
+            panic!("opened an empty directory");
+        };
+        assert!(message.contains("searched 2 files, 0 matched"), "{}", message);
+        assert!(!message.contains("`termview -r`"), "{}", message);
+    }
+
+    #[test]
+    fn waiting_shows_the_first_image_to_appear() {
+        let dir = TestDir::new();
+        let args = args(&["--wait-for-images", "-d", dir.path().to_str().unwrap()]);
+        let sources = resolve_sources(&args, &Persistence::in_memory()).unwrap();
+        assert!(sources.images.is_empty());
+
+        let loader = Loader::new(Duration::from_secs(5), Vec::new(), None, AlphaMode::Auto);
+        let mut app = App::new(sources.images, sources.start_index, loader);
+        app.listing = args.list_options();
+        app.waiting_for = Some(sources.dir.clone());
+        app.load_current();
+        let waiting = app.error_message.clone().unwrap();
+        assert!(waiting.starts_with("Waiting for images in "), "{}", waiting);
+        assert_eq!(app.poll_waiting(), Refresh::Nothing);
+
+        let path = sources.dir.join("first.png");
+        DynamicImage::new_rgb8(2, 2).save(&path).unwrap();
+        wait_until(|| app.poll_waiting() != Refresh::Nothing);
+        assert_eq!(app.images.to_vec(), vec![path]);
+        assert_eq!(app.index, 0);
+        assert!(app.decode_pending);
+        assert!(app.error_message.is_none());
+    }
+
+    /// An image at `name` in `dir`, last modified `age` seconds before `now`.
+    fn aged(dir: &TestDir, name: &str, now: SystemTime, age: u64) -> PathBuf {
+        let path = dir.file(name, b"");
+        DynamicImage::new_rgb8(1, 1).save_with_format(&path, image::ImageFormat::Png).unwrap();
+        let file = std::fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(now - Duration::from_secs(age)).unwrap();
+        path
+    }
+
+    /// Following `dir`, showing its newest image.
+    fn following(dir: &TestDir) -> App {
+        let mut images: Vec<PathBuf> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .collect();
+        images.sort();
+        let newest = newest_image(&images).unwrap().path;
+        let index = images.iter().position(|p| *p == newest).unwrap();
+        let mut app = browsing(0, 0);
+        app.images = Playlist::new(images);
+        app.index = index;
+        app.follow = Some(Follow::new(dir.path().to_path_buf(), &app.images));
+        app
+    }
+
+    /// Poll follow mode until a scan of the folder has been taken in.
+    fn rescanned(app: &mut App) -> Refresh {
+        loop {
+            let refresh = app.poll_follow();
+            if app.scan.is_none() {
+                return refresh;
+            }
+        }
+    }
+
+    fn shown(app: &App) -> &str {
+        app.images[app.index].file_name().unwrap().to_str().unwrap()
+    }
+
+    fn engaged(app: &App) -> bool {
+        app.follow.as_ref().unwrap().engaged
+    }
+
+    /// Make `name` in `dir` a named pipe: opening it to read hangs until
+    /// something opens it to write, like a stat on a dead network mount.
+    #[cfg(unix)]
+    fn hang(dir: &TestDir, name: &str) -> PathBuf {
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = dir.path().join(name);
+        let c_path = std::ffi::CString::new(path.as_os_str().as_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) }, 0);
+        path
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn hung_scans_leave_the_viewer_responsive() {
+        let dir = TestDir::new();
+        let now = SystemTime::now();
+        aged(&dir, "a.png", now, 300);
+        aged(&dir, "b.png", now, 200);
+        let mut app = following(&dir);
+        // Rescanning reads the ignore file, and now hangs there
+        let pipe = hang(&dir, crate::ignore::IGNORE_FILE);
+
+        let started = Instant::now();
+        let mut longest = Duration::ZERO;
+        while !app.fs_slow {
+            assert!(started.elapsed() < Duration::from_secs(10), "never reported slow");
+            let before = Instant::now();
+            app.poll_follow();
+            longest = longest.max(before.elapsed());
+            // Keys are still taken while the scan is stuck
+            assert!(app.navigate(Action::Prev) && app.navigate(Action::Next));
+        }
+        assert!(started.elapsed() >= slowfs::SLOW_AFTER);
+        assert!(longest < SCAN_WAIT * 4, "a poll took {:?}", longest);
+        let (_, right) = status_text(&app, None);
+        assert!(right.starts_with("filesystem slow | "), "{}", right);
+        assert_eq!(shown(&app), "b.png");
+
+        // Once the filesystem answers, the scan finishes and the warning goes
+        drop(std::fs::File::options().write(true).open(&pipe).unwrap());
+        assert_eq!(rescanned(&mut app), Refresh::Status);
+        assert!(!app.fs_slow);
+        assert!(app.scan.is_none());
+    }
+
+    #[test]
+    fn follow_switches_to_new_files_once_they_are_written() {
+        let dir = TestDir::new();
+        let now = SystemTime::now();
+        aged(&dir, "a.png", now, 300);
+        aged(&dir, "c.png", now, 200);
+        let mut app = following(&dir);
+        assert_eq!(shown(&app), "c.png");
+
+        // Seen once: it may still be being written
+        aged(&dir, "b.png", now, 100);
+        assert_eq!(rescanned(&mut app), Refresh::Status);
+        assert_eq!(shown(&app), "c.png");
+        assert_eq!(rescanned(&mut app), Refresh::Image);
+        assert_eq!(shown(&app), "b.png");
+
+        // Growing between scans holds the switch back
+        let d = aged(&dir, "d.png", now, 50);
+        rescanned(&mut app);
+        let mut file = std::fs::File::options().append(true).open(&d).unwrap();
+        file.write_all(b"more").unwrap();
+        file.set_modified(now - Duration::from_secs(50)).unwrap();
+        rescanned(&mut app);
+        assert_eq!(shown(&app), "b.png");
+        rescanned(&mut app);
+        assert_eq!(shown(&app), "d.png");
+        assert!(app.log.in_view().any(|e| e.message.starts_with("Following: switched to")));
+    }
+
+    #[test]
+    fn follow_gives_way_to_choosing_by_hand_until_engaged_again() {
+        let dir = TestDir::new();
+        let now = SystemTime::now();
+        aged(&dir, "a.png", now, 300);
+        aged(&dir, "b.png", now, 200);
+        let mut app = following(&dir);
+
+        // Only picking another image takes over
+        for action in [Action::ZoomIn, Action::PanLeft, Action::Reload, Action::ToggleInfo] {
+            app.chose_by_hand(action);
+            assert!(engaged(&app), "{:?}", action);
+        }
+        for action in [Action::Prev, Action::Next, Action::First, Action::Last] {
+            app.follow.as_mut().unwrap().engaged = true;
+            app.chose_by_hand(action);
+            assert!(!engaged(&app), "{:?}", action);
+        }
+        app.navigate(Action::First);
+        let (_, right) = status_text(&app, None);
+        assert!(right.starts_with("follow paused (F) | "), "{}", right);
+
+        // New files don't pull the view away while disengaged
+        aged(&dir, "c.png", now, 100);
+        rescanned(&mut app);
+        rescanned(&mut app);
+        assert_eq!(shown(&app), "a.png");
+
+        // F catches up with the newest
+        assert!(app.engage_follow());
+        assert!(engaged(&app));
+        assert_eq!(shown(&app), "c.png");
+        let (_, right) = status_text(&app, None);
+        assert!(right.contains(" file | follow | 3/3 |"), "{}", right);
+    }
+
+    #[test]
+    fn follow_waits_for_open_questions_and_wins_over_the_slideshow() {
+        let dir = TestDir::new();
+        let now = SystemTime::now();
+        aged(&dir, "a.png", now, 300);
+        aged(&dir, "b.png", now, 200);
+        let mut app = following(&dir);
+        app.toggle_slideshow();
+        assert!(app.advance_slideshow().is_some());
+        assert_eq!(shown(&app), "a.png");
+
+        aged(&dir, "c.png", now, 100);
+        app.prompt = Some(Prompt::ConfirmDelete { file: app.current_target() });
+        rescanned(&mut app);
+        rescanned(&mut app);
+        assert_eq!(shown(&app), "a.png");
+        app.prompt = None;
+        assert_eq!(rescanned(&mut app), Refresh::Image);
+        assert_eq!(shown(&app), "c.png");
+        assert!(engaged(&app));
+    }
+
+    #[test]
+    fn slideshow_moves_are_timed_from_the_last_due_time_not_the_clock() {
+        let start = Instant::now();
+        let interval = Duration::from_secs(5);
+        let mut show = Slideshow {
+            interval,
+            due: start,
+            paused: None,
+        };
+        show.advance(start + Duration::from_millis(300));
+        assert_eq!(show.due, start + interval);
+
+        // Each move handled late (decoding, a busy loop) doesn't push the next
+        for _ in 0..99 {
+            show.advance(show.due + Duration::from_millis(700));
+        }
+        assert_eq!(show.due, start + interval * 100);
+    }
+
+    #[test]
+    fn a_slideshow_a_whole_interval_behind_waits_from_now() {
+        let start = Instant::now();
+        let interval = Duration::from_secs(5);
+        let mut show = Slideshow {
+            interval,
+            due: start,
+            paused: None,
+        };
+        // Rather than skipping through images to catch up
+        let now = start + Duration::from_secs(12);
+        show.advance(now);
+        assert_eq!(show.due, now + interval);
+    }
+
+    #[test]
+    fn advancing_the_slideshow_keeps_to_its_schedule() {
+        let mut app = browsing(3, 0);
+        app.toggle_slideshow();
+        let show = app.slideshow.as_mut().unwrap();
+        let due = Instant::now() - Duration::from_millis(100);
+        show.due = due;
+        assert_eq!(app.advance_slideshow(), Some(Refresh::Image));
+        assert_eq!(app.index, 1);
+        assert_eq!(app.slideshow_due(), Some(due + DEFAULT_SLIDESHOW_INTERVAL));
+    }
+
+    fn latest(line: &[&str]) -> Result<Sources, String> {
+        resolve_sources(&args(&[&["--latest"], line].concat()), &Persistence::in_memory())
+    }
+
+    #[test]
+    fn latest_opens_the_newest_image_with_the_rest_newest_first() {
+        let dir = TestDir::new();
+        let now = SystemTime::now();
+        let old = aged(&dir, "a.png", now, 300);
+        let new = aged(&dir, "b.png", now, 10);
+        let mid = aged(&dir, "c.png", now, 60);
+        let path = dir.path().to_str().unwrap();
+
+        let sources = latest(&["-d", path]).unwrap();
+        assert_eq!(sources.images, [new.clone(), mid, old.clone()]);
+        assert_eq!(sources.start_index, 0);
+
+        // Naming a file doesn't change which one opens
+        let sources = latest(&[old.to_str().unwrap()]).unwrap();
+        assert_eq!(sources.images[sources.start_index], new);
+    }
+
+    #[test]
+    fn latest_breaks_ties_the_same_way_follow_does() {
+        let dir = TestDir::new();
+        let now = SystemTime::now();
+        let older = aged(&dir, "0.png", now, 60);
+        let tied = ["b.png", "c.png", "a.png"].map(|name| aged(&dir, name, now, 5));
+        let path = dir.path().to_str().unwrap();
+
+        let sources = latest(&["-d", path]).unwrap();
+        let order = [&tied[1], &tied[0], &tied[2], &older];
+        assert_eq!(sources.images, order.map(PathBuf::clone));
+        for _ in 0..3 {
+            assert_eq!(latest(&["-d", path]).unwrap().images, sources.images);
+        }
+        assert_eq!(newest_image(&sources.images).unwrap().path, tied[1]);
+        let shuffled = [older.clone(), tied[2].clone(), tied[1].clone(), tied[0].clone()];
+        assert_eq!(newest_image(&shuffled).unwrap().path, tied[1]);
+    }
+
+    #[test]
+    fn latest_skips_images_left_out_of_the_list() {
+        let dir = TestDir::new();
+        let now = SystemTime::now();
+        let kept = aged(&dir, "shots/kept.png", now, 60);
+        aged(&dir, "shots/drafts/newest.png", now, 1);
+        aged(&dir, "shots/skip.png", now, 2);
+        dir.file("shots/notes.txt", b"newer still, but not an image");
+        let path = dir.path().join("shots");
+        let path = path.to_str().unwrap();
+
+        let sources = latest(&["-r", "--exclude", "drafts/", "--exclude", "skip*", "-d", path]);
+        assert_eq!(sources.unwrap().images, [kept]);
+
+        // Images in a subfolder only count when it's listed
+        let sources = latest(&["-d", path]).unwrap();
+        assert_eq!(sources.images[0], dir.path().join("shots/skip.png"));
+        assert!(!sources.images.iter().any(|p| p.ends_with("newest.png")));
+    }
+
+    #[test]
+    fn latest_in_an_empty_directory_is_explained() {
+        let dir = TestDir::new();
+        let now = SystemTime::now();
+        aged(&dir, "only.png", now, 1);
+        let path = dir.path().to_str().unwrap();
+
+        let Err(message) = latest(&["--exclude", "*.png", "-d", path]) else {
+            panic!("opened a directory with nothing left to show");
+        };
+        assert!(message.starts_with("no images found in "), "{}", message);
+
+        let waiting = latest(&["--wait-for-images", "--exclude", "*.png", "-d", path]).unwrap();
+        assert!(waiting.images.is_empty());
+        assert_eq!(waiting.start_index, 0);
+    }
+
+    /// `line` read as the command line, with the nearest `.termview.toml`.
+    fn configured(line: &[&str]) -> (Args, Option<DirConfig>) {
+        let argv = std::iter::once("termview").chain(line.iter().copied());
+        with_dir_config(args(line), argv.map(Into::into))
+    }
+
+    #[test]
+    fn command_line_beats_directory_beats_defaults() {
+        let dir = TestDir::new();
+        dir.file(dirconfig::CONFIG_FILE, b"sort = \"mtime\"\nfit = \"fill\"\nupscale = true\n");
+        let path = dir.path().to_str().unwrap();
+
+        let (args, config) = configured(&["-d", path]);
+        assert!(config.unwrap().problems.is_empty());
+        assert_eq!(args.sort, SortOrder::Mtime);
+        assert_eq!(args.fit, FitMode::Fill);
+        assert!(args.upscale);
+        assert!(args.slideshow.is_none());
+
+        let (args, _) = configured(&["--sort", "size", "-d", path]);
+        assert_eq!(args.sort, SortOrder::Size);
+        assert_eq!(args.fit, FitMode::Fill);
+
+        let (args, config) = configured(&["--no-dir-config", "-d", path]);
+        assert!(config.is_none());
+        assert_eq!((args.sort, args.fit, args.upscale), (SortOrder::Name, FitMode::Fit, false));
+    }
+
+    #[test]
+    fn nearest_config_file_wins() {
+        let dir = TestDir::new();
+        dir.file(dirconfig::CONFIG_FILE, b"sort = \"size\"\nreverse = true\n");
+        dir.file("sprites/.termview.toml", b"fit = \"actual\"\n");
+        let sprites = dir.path().join("sprites");
+        let image = dir.file("sprites/walk/a.png", b"");
+
+        let (args, config) = configured(&[sprites.to_str().unwrap()]);
+        let nearest = sprites.canonicalize().unwrap().join(dirconfig::CONFIG_FILE);
+        assert_eq!(config.unwrap().path, nearest);
+        assert_eq!(args.fit, FitMode::Actual);
+        // Files don't combine: the parent's settings aren't used
+        assert_eq!(args.sort, SortOrder::Name);
+        assert!(!args.reverse);
+
+        // An image is looked up from its own folder
+        let (args, _) = configured(&[image.to_str().unwrap()]);
+        assert_eq!(args.fit, FitMode::Actual);
+
+        let (args, _) = configured(&["-d", dir.path().to_str().unwrap()]);
+        assert_eq!((args.sort, args.reverse), (SortOrder::Size, true));
+    }
+
+    #[test]
+    fn directory_config_cannot_run_commands_or_move_files() {
+        let dir = TestDir::new();
+        let config = "\
+            decoder = \"png=rm -rf {path}\"\n\
+            move_to = \"/tmp\"\n\
+            copy-to = \"/tmp\"\n\
+            log = \"/tmp/termview.log\"\n\
+            colour = \"red\"\n\
+            sort = \"sideways\"\n\
+            slideshow = 3\n";
+        dir.file(dirconfig::CONFIG_FILE, config.as_bytes());
+
+        let (args, config) = configured(&["-d", dir.path().to_str().unwrap()]);
+        let problems = config.unwrap().problems;
+        assert!(args.decoder.is_empty());
+        assert!(args.move_to.is_none() && args.copy_to.is_none() && args.log.is_none());
+        assert_eq!(args.sort, SortOrder::Name);
+        assert_eq!(args.slideshow, Some(Duration::from_secs(3)));
+
+        assert_eq!(problems.len(), 6, "{:?}", problems);
+        assert_eq!(problems[0], "decoder can't be set per directory");
+        assert_eq!(problems[1], "move-to can't be set per directory");
+        assert_eq!(problems[4], "unknown setting colour");
+        assert!(problems[5].contains("sideways"), "{}", problems[5]);
+    }
+
+    #[test]
+    fn cell_size_flag_parses_width_by_height() {
+        assert_eq!(parse_cell_size("10x20"), Ok((10, 20)));
+        assert_eq!(parse_cell_size(" 8 x 16 "), Ok((8, 16)));
+        assert_eq!(parse_cell_size("10"), Err("expected WxH, e.g. 10x20".into()));
+        assert_eq!(parse_cell_size("10X20"), Err("expected WxH, e.g. 10x20".into()));
+        assert_eq!(parse_cell_size("ax20"), Err("invalid width 'a'".into()));
+        assert_eq!(parse_cell_size("10x"), Err("invalid height ''".into()));
+        assert_eq!(parse_cell_size("10x20x30"), Err("invalid height '20x30'".into()));
+        assert_eq!(parse_cell_size("-1x20"), Err("invalid width '-1'".into()));
+        assert_eq!(parse_cell_size("70000x20"), Err("invalid width '70000'".into()));
+        assert_eq!(parse_cell_size("0x20"), Err("width and height must be at least 1".into()));
+        assert!(Args::try_parse_from(["termview", "--cell-size", "9x18", "a.png"]).is_ok());
+        assert!(Args::try_parse_from(["termview", "--cell-size", "9", "a.png"]).is_err());
+    }
+
+    #[test]
+    fn extents_parse_as_cells_or_percentages() {
+        let cases = [
+            ("0", Ok(Extent::Cells(0))),
+            ("12", Ok(Extent::Cells(12))),
+            (" 7 ", Ok(Extent::Cells(7))),
+            ("40%", Ok(Extent::Percent(40.0))),
+            ("12.5 %", Ok(Extent::Percent(12.5))),
+            ("0%", Ok(Extent::Percent(0.0))),
+            ("100%", Ok(Extent::Percent(100.0))),
+            ("", Err("invalid cell count ''")),
+            ("-1", Err("invalid cell count '-1'")),
+            ("2.5", Err("invalid cell count '2.5'")),
+            ("70000", Err("invalid cell count '70000'")),
+            ("%", Err("invalid percentage '%'")),
+            ("half%", Err("invalid percentage 'half%'")),
+            ("101%", Err("percentage out of range '101%'")),
+            ("-5%", Err("percentage out of range '-5%'")),
+            ("NaN%", Err("percentage out of range 'NaN%'")),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(text.parse::<Extent>(), expected.map_err(String::from), "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn regions_parse_with_defaults_for_what_is_left_out() {
+        use Extent::{Cells, Percent};
+        const ALL: Extent = Percent(100.0);
+        let region = |x, y, w, h| Ok(Region { x, y, w, h });
+        let cases = [
+            ("x=60%,y=0,w=40%,h=100%", region(Percent(60.0), Cells(0), Percent(40.0), ALL)),
+            ("x=60%,w=40%", region(Percent(60.0), Cells(0), Percent(40.0), ALL)),
+            (" y = 2 , h = 50% ,", region(Cells(0), Cells(2), ALL, Percent(50.0))),
+            ("", region(Cells(0), Cells(0), ALL, ALL)),
+            ("w=10,w=20", region(Cells(0), Cells(0), Cells(20), ALL)),
+            ("x", Err("expected key=value, got 'x'")),
+            ("x=1;y=2", Err("invalid cell count '1;y=2'")),
+            ("z=1", Err("unknown region key 'z'")),
+            ("w=150%", Err("percentage out of range '150%'")),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(text.parse::<Region>(), expected.map_err(String::from), "{:?}", text);
+        }
+
+        let args = args(&["--region", "x=60%,w=40%"]);
+        assert_eq!(args.region, "x=60%,w=40%".parse().ok());
+        assert!(Args::try_parse_from(["termview", "--region", "x=1,q=2"]).is_err());
+    }
+
+    #[test]
+    fn regions_resolve_against_the_area() {
+        let cases = [
+            // Right 40%, top aligned, of a 100x30 area that starts on row 1
+            ("x=60%,w=40%", (0, 1, 100, 30), Some((60, 1, 40, 30))),
+            ("x=60%,y=0,w=40%,h=100%", (0, 1, 100, 30), Some((60, 1, 40, 30))),
+            // Mixed: absolute position, relative size
+            ("x=10,y=5,w=50%,h=50%", (2, 0, 80, 24), Some((12, 5, 40, 12))),
+            // Rounded to the nearest cell
+            ("w=50%,h=50%", (0, 0, 81, 25), Some((0, 0, 41, 13))),
+            // Clamped to what is left of the area
+            ("x=70,w=20", (0, 0, 80, 24), Some((70, 0, 10, 24))),
+            ("x=50%,w=100%", (0, 0, 80, 24), Some((40, 0, 40, 24))),
+            ("x=1000,y=1000,w=1,h=1", (0, 0, 80, 24), None),
+            // Nothing left to draw into
+            ("w=0", (0, 0, 80, 24), None),
+            ("h=0%", (0, 0, 80, 24), None),
+            ("w=1%", (0, 0, 40, 24), None),
+            ("x=100%", (0, 0, 80, 24), None),
+            ("", (0, 0, 0, 0), None),
+            ("w=1,h=1", (3, 4, 80, 24), Some((3, 4, 1, 1))),
+        ];
+        for (spec, area, expected) in cases {
+            let region: Region = spec.parse().unwrap();
+            assert_eq!(region.resolve(area), expected, "{:?} in {:?}", spec, area);
+        }
+    }
+
+    #[test]
+    fn regions_stay_proportional_when_the_terminal_resizes() {
+        let region: Region = "x=25%,y=10%,w=50%,h=80%".parse().unwrap();
+        for (cols, rows) in [(40, 10), (80, 24), (120, 40), (200, 60), (333, 77)] {
+            let (x, y, w, h) = region.resolve((0, 0, cols, rows)).unwrap();
+            let near = |got: u16, share: f64, of: u16| {
+                (got as f64 - share * of as f64).abs() <= 0.5
+            };
+            assert!(near(x, 0.25, cols) && near(w, 0.5, cols), "{} cols: {} {}", cols, x, w);
+            assert!(near(y, 0.1, rows) && near(h, 0.8, rows), "{} rows: {} {}", rows, y, h);
+        }
+    }
+
+    #[test]
+    fn margins_shrink_the_area_on_every_side() {
+        use Extent::{Cells, Percent};
+        let margins = |x, y| Margins { x, y };
+        let cases = [
+            (Margins::NONE, (0, 1, 80, 24), Some((0, 1, 80, 24))),
+            (margins(Cells(2), Cells(2)), (0, 1, 80, 24), Some((2, 3, 76, 20))),
+            (margins(Cells(5), Cells(0)), (10, 0, 40, 20), Some((15, 0, 30, 20))),
+            // Percentages are of each side's own length
+            (margins(Percent(5.0), Percent(5.0)), (0, 0, 100, 40), Some((5, 2, 90, 36))),
+            (margins(Percent(10.0), Cells(1)), (0, 0, 81, 24), Some((8, 1, 65, 22))),
+            // One cell left is still somewhere to draw
+            (margins(Cells(39), Cells(11)), (0, 0, 79, 23), Some((39, 11, 1, 1))),
+            // Margins that meet or cross leave nothing
+            (margins(Cells(40), Cells(0)), (0, 0, 80, 24), None),
+            (margins(Cells(0), Cells(12)), (0, 0, 80, 24), None),
+            (margins(Percent(50.0), Cells(0)), (0, 0, 80, 24), None),
+            (margins(Cells(u16::MAX), Cells(0)), (0, 0, 80, 24), None),
+            (Margins::NONE, (0, 0, 0, 24), None),
+        ];
+        for (margins, area, expected) in cases {
+            assert_eq!(margins.apply(area), expected, "{:?} in {:?}", margins, area);
+        }
+    }
+
+    #[test]
+    fn margin_flags_override_each_axis() {
+        use Extent::{Cells, Percent};
+        assert_eq!(args(&[]).margins(), Margins::NONE);
+        let both = args(&["--margin", "2"]).margins();
+        assert_eq!(both, Margins { x: Cells(2), y: Cells(2) });
+        let mixed = args(&["--margin", "5%", "--margin-y", "1"]).margins();
+        assert_eq!(mixed, Margins { x: Percent(5.0), y: Cells(1) });
+        let only_x = args(&["--margin-x", "3"]).margins();
+        assert_eq!(only_x, Margins { x: Cells(3), y: Cells(0) });
+        assert!(Args::try_parse_from(["termview", "--margin", "120%"]).is_err());
+    }
+
+    #[test]
+    fn thumbnail_grid_stays_inside_the_margins() {
+        let mut app = browsing(40, 0);
+        app.margins = Margins { x: Extent::Cells(4), y: Extent::Cells(2) };
+        app.layout_gallery((80, 25));
+        let tiles = app.gallery.visible(app.images.len());
+        assert!(!tiles.is_empty());
+        for i in tiles {
+            let (col, row, w, h) = app.gallery.tile_rect(i);
+            assert!(col >= 4 && col + w <= 76, "tile {} at {:?}", i, (col, w));
+            assert!(row >= 2 && row + h <= 22, "tile {} at {:?}", i, (row, h));
+        }
+
+        // Margins that leave no room fall back to the whole area for layout;
+        // drawing the grid reports them instead
+        app.margins = Margins { x: Extent::Cells(40), y: Extent::Cells(0) };
+        app.layout_gallery((80, 25));
+        assert!(!app.gallery.visible(app.images.len()).is_empty());
+    }
+
+    fn thumb_cache_in(dir: &TestDir) -> ThumbCache {
+        let store = persistence::Store {
+            path: Some(dir.path().join("thumbnails")),
+            disabled: None,
+        };
+        ThumbCache::new(&store).unwrap()
+    }
+
+    #[test]
+    fn kept_thumbnails_are_saved_and_lasting_failures_marked() {
+        let dir = TestDir::new();
+        let cache = thumb_cache_in(&dir);
+        let bucket = Bucket::for_size(THUMB_PX);
+        let shot = dir.file("shot.png", b"png");
+        let decoded = Decoded::Still(DynamicImage::new_rgb8(600, 300));
+        let (thumb, saved) = keep_thumbnail(Some(&cache), &shot, Ok(decoded));
+        assert_eq!(thumb.unwrap().unwrap().dimensions(), (600, 300));
+        assert!(saved.is_ok());
+        assert!(matches!(cache.get(&shot, bucket), Cached::Thumb(img) if img.width() == 256));
+
+        // A slow decode is tried again next time, a broken file isn't
+        let slow = dir.file("slow.png", b"png");
+        let timed_out = Err(LoadError::TimedOut(Duration::ZERO));
+        let (thumb, _) = keep_thumbnail(Some(&cache), &slow, timed_out);
+        assert!(thumb.is_err());
+        assert!(matches!(cache.get(&slow, bucket), Cached::Missing));
+        let broken = dir.file("broken.png", b"png");
+        let failed = Err(LoadError::External("no decoder".into()));
+        let (thumb, _) = keep_thumbnail(Some(&cache), &broken, failed);
+        assert!(thumb.is_err());
+        assert!(matches!(cache.get(&broken, bucket), Cached::Failed));
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn served_thumbnails_are_the_grids() {
+        let dir = TestDir::new();
+        let cache = thumb_cache_in(&dir);
+        let loader = Loader::new(Duration::from_secs(5), Vec::new(), None, AlphaMode::Auto);
+        let warned = AtomicBool::new(false);
+        let path = dir.path().join("shot.png");
+        image::RgbImage::from_pixel(600, 300, image::Rgb([0, 0, 255])).save(&path).unwrap();
+
+        // Made as the grid makes them, and kept for the grid
+        let thumb = serve_thumbnail(Some(&cache), &loader, &path, &warned).unwrap();
+        let grid = gallery::fit_thumb(&DynamicImage::new_rgb8(600, 300));
+        assert_eq!(thumb.dimensions(), grid.dimensions());
+        let bucket = Bucket::for_size(THUMB_PX);
+        assert!(matches!(cache.get(&path, bucket), Cached::Thumb(_)));
+
+        // Taken from the cache rather than decoded again
+        cache.put(&path, bucket, &DynamicImage::new_rgb8(600, 300)).unwrap();
+        let thumb = serve_thumbnail(Some(&cache), &loader, &path, &warned).unwrap();
+        assert_eq!(thumb.to_rgb8().get_pixel(0, 0).0, [0, 0, 0]);
+
+        // Broken files are marked, and not decoded again
+        let broken = dir.file("broken.png", b"not a png");
+        assert!(serve_thumbnail(Some(&cache), &loader, &broken, &warned).is_err());
+        assert!(matches!(cache.get(&broken, bucket), Cached::Failed));
+        let again = serve_thumbnail(Some(&cache), &loader, &broken, &warned);
+        assert!(again.unwrap_err().contains("doesn't decode"));
+        assert!(!warned.load(Ordering::SeqCst));
+    }
+
+    /// Writes where it was asked to put the image.
+    struct Placed;
+
+    impl Renderer for Placed {
+        fn display(
+            &mut self,
+            out: &mut dyn Write,
+            _img: &DynamicImage,
+            area: CellRect,
+            _cell_size: (u16, u16),
+        ) -> io::Result<()> {
+            write!(out, "[placed {:?}]", area)
+        }
+
+        fn clear(&mut self, _out: &mut dyn Write) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn frame_encoder(&self) -> Box<dyn Renderer + Send> {
+            Box::new(Placed)
+        }
+    }
+
+    #[test]
+    fn image_is_placed_inside_the_margins_or_not_at_all() {
+        let mut app = browsing(1, 0);
+        app.zoom = 1.0;
+        app.cell_size = (10, 20);
+        app.current_image = Some(DynamicImage::new_rgb8(400, 300));
+        let mut screen = Screen::new(Box::new(Placed));
+
+        let (drawn, _) = draw(&mut screen, &app);
+        assert!(drawn.contains("[placed (0, 0, 80, 24)]"), "{}", drawn);
+
+        app.margins = Margins { x: Extent::Cells(10), y: Extent::Percent(25.0) };
+        let (drawn, _) = draw(&mut screen, &app);
+        assert!(drawn.contains("[placed (10, 6, 60, 12)]"), "{}", drawn);
+
+        // With the region, margins come off what it leaves
+        screen.region = "x=50%".parse().ok();
+        let (drawn, _) = draw(&mut screen, &app);
+        assert!(drawn.contains("[placed (50, 6, 20, 12)]"), "{}", drawn);
+
+        app.margins = Margins { x: Extent::Cells(20), y: Extent::Cells(0) };
+        let (drawn, _) = draw(&mut screen, &app);
+        assert!(!drawn.contains("[placed"), "{}", drawn);
+        assert!(drawn.contains("Margins leave no room for the image"), "{}", drawn);
+    }
+
+    /// What `screen` writes for `app` on an 80x24 terminal.
+    fn rendered(screen: &mut Screen, app: &App) -> String {
+        let mut out = Vec::new();
+        screen.render_sized(&mut out, app, (80, 24)).unwrap();
+        String::from_utf8_lossy(&out).into_owned()
+    }
+
+    const KITTY: &str = "\x1b_G";
+
+    fn kitty_screen() -> (Screen, App) {
+        let mut app = browsing(1, 0);
+        app.cell_size = (10, 20);
+        app.current_image = Some(DynamicImage::new_rgb8(400, 300));
+        let screen = Screen::new(Box::new(render::Kitty::for_tests(render::KittyCaps::MODERN)));
+        (screen, app)
+    }
+
+    #[test]
+    fn status_changes_send_no_image() {
+        let (mut screen, mut app) = kitty_screen();
+        app.list_notice = Some(("+1 file".into(), Instant::now()));
+        let first = rendered(&mut screen, &app);
+        assert!(first.contains(KITTY) && first.contains("+1 file"));
+
+        // The notice running out only touches the status bar
+        app.list_notice = None;
+        screen.invalidate_status();
+        let expired = rendered(&mut screen, &app);
+        assert!(!expired.contains(KITTY), "{:?}", expired);
+        assert!(!expired.contains("+1 file") && expired.contains("0.png"), "{:?}", expired);
 
-    Ok(())
-}
+        // Nor is the bar written again when it reads the same
+        screen.invalidate_status();
+        let same = rendered(&mut screen, &app);
+        assert!(!same.contains(KITTY) && !same.contains("0.png"), "{:?}", same);
+        assert_eq!(rendered(&mut screen, &app), "");
+    }
 
-fn draw_help_overlay(out: &mut impl Write, cols: u16, rows: u16) -> io::Result<()> {
-    let help_lines = [
-        "",
-        "  termview — Keyboard Shortcuts",
-        "",
-        "  ← / h       Previous image",
-        "  → / l       Next image",
-        "  Home / g    First image",
-        "  End / G     Last image",
-        "  + / =       Zoom in",
-        "  - / _       Zoom out",
-        "  0           Reset zoom",
-        "  w/a/s/d     Pan (when zoomed)",
-        "  ?           Toggle help",
-        "  q / Esc     Quit",
-        "",
-    ];
+    #[test]
+    fn panning_leaves_the_status_bar_alone_unless_it_changes() {
+        let (mut screen, mut app) = kitty_screen();
+        rendered(&mut screen, &app);
 
-    let box_w: u16 = 40;
-    let box_h = help_lines.len() as u16 + 2; // +2 for top/bottom border
-    let start_col = cols.saturating_sub(box_w) / 2;
-    let start_row = rows.saturating_sub(box_h) / 2;
+        app.pan(0.05, 0.0);
+        screen.invalidate_image();
+        let panned = rendered(&mut screen, &app);
+        assert!(panned.contains(KITTY));
+        assert!(!panned.contains("0.png"), "{:?}", panned);
 
-    queue!(
-        out,
-        SetForegroundColor(style::Color::White),
-        SetBackgroundColor(style::Color::Black),
-    )?;
+        // Zooming changes the zoom shown, so the bar is rewritten
+        app.zoom = 3.0;
+        screen.invalidate_image();
+        screen.invalidate_status();
+        let zoomed = rendered(&mut screen, &app);
+        assert!(zoomed.contains(KITTY) && zoomed.contains("300%"), "{:?}", zoomed);
 
-    // Top border
-    queue!(out, cursor::MoveTo(start_col, start_row))?;
-    write!(out, "┌{}┐", "─".repeat((box_w - 2) as usize))?;
+        // With the pixel grid, the origin it shows moves with the pan
+        app.zoom = 20.0;
+        app.pixel_grid = Some(true);
+        screen.invalidate_image();
+        screen.invalidate_status();
+        let gridded = rendered(&mut screen, &app);
+        assert!(gridded.contains("grid @"), "{:?}", gridded);
+        app.pan(0.05, 0.0);
+        screen.invalidate_image();
+        let moved = rendered(&mut screen, &app);
+        assert!(moved.contains("grid @") && moved.contains("0.png"), "{:?}", moved);
+    }
 
-    // Content lines
-    for (i, line) in help_lines.iter().enumerate() {
-        let r = start_row + 1 + i as u16;
-        queue!(out, cursor::MoveTo(start_col, r))?;
-        let content_w = (box_w - 2) as usize;
-        let padded = format!("{:<width$}", line, width = content_w);
-        // Truncate if needed
-        let display: String = padded.chars().take(content_w).collect();
-        write!(out, "│{}│", display)?;
+    /// A 200x150 checkerboard of two light colours, so each image pixel can
+    /// be told from its neighbours and from a grid line.
+    fn checkered() -> image::RgbaImage {
+        image::RgbaImage::from_fn(200, 150, checker)
     }
 
-    // Bottom border
-    queue!(out, cursor::MoveTo(start_col, start_row + box_h - 1))?;
-    write!(out, "└{}┘", "─".repeat((box_w - 2) as usize))?;
+    fn checker(x: u32, y: u32) -> image::Rgba<u8> {
+        let even = (x + y).is_multiple_of(2);
+        image::Rgba(if even { [220, 220, 220, 255] } else { [180, 200, 240, 255] })
+    }
 
-    queue!(
-        out,
-        SetForegroundColor(style::Color::Reset),
-        SetBackgroundColor(style::Color::Reset),
-    )?;
+    /// Positions of the grid lines in a line of `pixels`.
+    fn grid_lines(pixels: impl Iterator<Item = image::Rgba<u8>>) -> Vec<usize> {
+        let plain = [checker(0, 0), checker(1, 0)];
+        pixels
+            .enumerate()
+            .filter(|(_, px)| !plain.contains(px))
+            .map(|(i, _)| i)
+            .collect()
+    }
 
-    Ok(())
-}
+    #[test]
+    fn pixel_grid_stays_on_image_pixels_when_zoomed_and_panned() {
+        const AREA: CellRect = (0, 0, 80, 24);
+        const CELL: (u16, u16) = (10, 20);
+        let img = checkered();
+        let mut app = browsing(1, 0);
+        app.current_image = Some(DynamicImage::ImageRgba8(img.clone()));
+        for zoom in [8.0, 9.5, 13.25, 31.0] {
+            for pan in [(0.0, 0.0), (0.13, -0.21), (-0.4, 0.33), (0.5, 0.5)] {
+                (app.zoom, (app.pan_x, app.pan_y)) = (zoom, pan);
+                let view = app.view(AREA, CELL).unwrap();
+                assert!(view.grid, "at {}", zoom);
+                let (x0, y0, w, h) = view.rect;
+                let shown = render::scaled_view(&img.clone().into(), view, AREA, CELL).to_rgba8();
+                let (shown_w, shown_h) = shown.dimensions();
 
-// ---------------------------------------------------------------------------
-// App state
-// ---------------------------------------------------------------------------
+                let cols = grid_lines(shown.rows().nth(1).unwrap().copied());
+                let rows = grid_lines((0..shown_h).map(|y| *shown.get_pixel(1, y)));
+                let counts = (cols.len() as u32, rows.len() as u32);
+                assert_eq!(counts, (w, h), "{} {:?}", zoom, pan);
+                for (lines, len) in [(&cols, shown_w), (&rows, shown_h)] {
+                    assert_eq!(lines[0], 0);
+                    let ends = lines[1..].iter().copied().chain([len as usize]);
+                    for (start, end) in lines.iter().zip(ends) {
+                        let span = (end - start) as f64;
+                        assert!((span - zoom).abs() < 1.0, "{} wide at {}", span, zoom);
+                    }
+                }
+                // Each span shows the image pixel it should: no drift along the way
+                for (i, &c) in cols.iter().enumerate() {
+                    assert_eq!(*shown.get_pixel(c as u32 + 1, 1), checker(x0 + i as u32, y0));
+                }
+                for (i, &r) in rows.iter().enumerate() {
+                    assert_eq!(*shown.get_pixel(1, r as u32 + 1), checker(x0, y0 + i as u32));
+                }
+            }
+        }
+    }
 
-struct App {
-    images: Vec<PathBuf>,
-    index: usize,
-    current_image: Option<DynamicImage>,
-    error_message: Option<String>,
-    show_help: bool,
-    zoom: f64,
-    pan_x: f64,
-    pan_y: f64,
-}
+    #[test]
+    fn pixel_grid_origin_is_in_the_status_bar() {
+        let mut app = browsing(1, 0);
+        app.cell_size = (10, 20);
+        app.current_image = Some(DynamicImage::ImageRgba8(checkered()));
+        app.zoom = 10.0;
+        app.pan_x = 0.25;
+        let mut screen = Screen::new(Box::new(Placed));
+        draw(&mut screen, &app);
+        let (x, y, _, _) = screen.view_rect.unwrap();
+        assert!(x > 0 && y > 0);
+        assert_eq!(screen.grid_origin, Some((x, y)));
+        let (left, _) = status_text(&app, screen.grid_origin);
+        assert!(left.contains(&format!("grid @{},{}", x, y)), "{}", left);
 
-impl App {
-    fn new(images: Vec<PathBuf>, start_index: usize) -> Self {
-        let mut app = App {
-            images,
-            index: start_index,
-            current_image: None,
-            error_message: None,
-            show_help: false,
-            zoom: 1.0,
-            pan_x: 0.0,
-            pan_y: 0.0,
-        };
-        app.load_current();
-        app
+        // Turned off by hand, and never at a scale too small to see. `render`
+        // clears the origin before each image
+        app.pixel_grid = Some(false);
+        screen.grid_origin = None;
+        draw(&mut screen, &app);
+        assert_eq!(screen.grid_origin, None);
+        app.pixel_grid = Some(true);
+        app.zoom = 3.0;
+        draw(&mut screen, &app);
+        assert_eq!(screen.grid_origin, None);
     }
 
-    fn load_current(&mut self) {
-        self.error_message = None;
-        self.zoom = 1.0;
-        self.pan_x = 0.0;
-        self.pan_y = 0.0;
+    #[test]
+    fn widths_count_terminal_columns() {
+        assert_eq!(text_width("photo.png"), 9);
+        assert_eq!(text_width("写真.png"), 8);
+        assert_eq!(text_width("사진 📷"), 7);
+        assert_eq!(text_width("▶ 3s"), 4);
+        assert_eq!(text_width(""), 0);
 
-        if self.images.is_empty() {
-            self.current_image = None;
-            self.error_message = Some("No images found in directory".into());
-            return;
-        }
+        assert_eq!(fit_width("photo.png", 12), "photo.png   ");
+        assert_eq!(fit_width("photo.png", 5), "photo");
+        // A wide character that doesn't fit whole is left out, and its place padded
+        assert_eq!(fit_width("ab写真", 3), "ab ");
+        assert_eq!(fit_width("ab写真", 4), "ab写");
+        assert_eq!(fit_width("写", 1), " ");
+        assert_eq!(fit_width("a\tb\x1bc", 5), "a?b?c");
+        assert_eq!(fit_width("anything", 0), "");
+    }
 
-        let path = &self.images[self.index];
-        match image::open(path) {
-            Ok(img) => self.current_image = Some(img),
-            Err(e) => {
-                self.current_image = None;
-                self.error_message = Some(format!("Failed to load {}: {}", path.display(), e));
+    /// The text of a drawn status bar, without the escape sequences.
+    fn status_bar(cols: u16, left: &str, right: &str) -> String {
+        let mut out = Vec::new();
+        draw_status_bar(&mut out, 23, cols, left, right).unwrap();
+        let drawn = String::from_utf8(out).unwrap();
+        let mut text = String::new();
+        let mut chars = drawn.chars();
+        while let Some(c) = chars.next() {
+            if c == '\x1b' {
+                // CSI sequences end at their first letter
+                chars.by_ref().find(|c| c.is_ascii_alphabetic());
+            } else {
+                text.push(c);
             }
         }
+        text
     }
 
-    fn next(&mut self) {
-        if !self.images.is_empty() {
-            self.index = (self.index + 1) % self.images.len();
-            self.load_current();
+    #[test]
+    fn status_bar_fills_the_width_with_wide_text() {
+        let left = " [2/10] 東京タワー.jpg  800x600";
+        let right = "▶ 3s  100% ";
+        for cols in [0, 1, 5, 11, 20, 30, 31, 32, 43, 60, 120] {
+            let bar = status_bar(cols, left, right);
+            assert_eq!(text_width(&bar), cols as usize, "{} cols: {:?}", cols, bar);
         }
+
+        // Room for both: the right side ends at the edge
+        let bar = status_bar(60, left, right);
+        assert!(bar.starts_with(left) && bar.ends_with(right), "{:?}", bar);
+
+        // Short of room, the left side keeps what it can and the right gets the rest
+        let bar = status_bar(35, left, right);
+        assert!(bar.starts_with(left), "{:?}", bar);
+        assert_eq!(text_width(&bar[left.len()..]), 35 - text_width(left));
+
+        // Too narrow for the left side: it is cut at a whole character
+        assert_eq!(status_bar(13, left, right), " [2/10] 東京 ");
+        assert_eq!(status_bar(14, left, right), " [2/10] 東京タ");
     }
 
-    fn prev(&mut self) {
-        if !self.images.is_empty() {
-            self.index = if self.index == 0 {
-                self.images.len() - 1
-            } else {
-                self.index - 1
+    #[test]
+    fn rescans_keep_the_current_image_selected() {
+        let mut app = browsing(5, 2);
+        let current = app.images[2].to_path_buf();
+        let mut images = app.images.to_vec();
+        let changes = ListDiff {
+            added: vec![PathBuf::from("00.png")],
+            removed: vec![PathBuf::from("0.png"), PathBuf::from("4.png")],
+        };
+        apply(&mut images, &changes, |p| p.to_path_buf(), false);
+        assert_eq!(app.apply_scan(images, &changes), Refresh::Status);
+        assert_eq!(app.images[app.index], current);
+        assert!(!reloaded(&app));
+
+        // When it is gone, its place goes to the image that took it
+        let mut images = app.images.to_vec();
+        let changes = ListDiff {
+            added: Vec::new(),
+            removed: vec![current],
+        };
+        apply(&mut images, &changes, |p| p.to_path_buf(), false);
+        assert_eq!(app.apply_scan(images, &changes), Refresh::Image);
+        assert_eq!(app.images[app.index], PathBuf::from("3.png"));
+        assert!(reloaded(&app));
+    }
+
+    #[test]
+    fn batched_changes_never_swap_the_image_underneath() {
+        let name = |n: usize| PathBuf::from(format!("{:03}.png", n));
+        for seed in 1..=300u64 {
+            let mut rng = seed.wrapping_mul(0x9e37_79b9_7f4a_7c15);
+            let mut below = |n: usize| {
+                rng ^= rng << 13;
+                rng ^= rng >> 7;
+                rng ^= rng << 17;
+                (rng % n.max(1) as u64) as usize
             };
-            self.load_current();
+            let count = below(12);
+            let mut app = browsing(count, below(count));
+            app.images = Playlist::new((0..count).map(|i| name(i * 10)).collect());
+            // What is on disk: the list catches up with it a batch at a time
+            let mut disk = app.images.to_vec();
+
+            for _ in 0..40 {
+                match below(6) {
+                    0 | 1 => disk.push(name(below(200))),
+                    2 if !disk.is_empty() => {
+                        let i = below(disk.len());
+                        disk.remove(i);
+                    }
+                    3 => {
+                        app.navigate([Action::Next, Action::Prev, Action::Last][below(3)]);
+                    }
+                    4 => {
+                        app.gallery.select(below(app.images.len()), app.images.len());
+                    }
+                    _ => {
+                        disk.sort();
+                        disk.dedup();
+                        let current = app.images.get(app.index).map(Path::to_path_buf);
+                        let selected = app.images.get(app.gallery.selected).map(Path::to_path_buf);
+                        let mut images = app.images.to_vec();
+                        let changes = listing::diff(&images, &disk);
+                        apply(&mut images, &changes, |p| p.to_path_buf(), false);
+                        app.apply_scan(images, &changes);
+
+                        assert_eq!(app.images.to_vec(), disk, "seed {}", seed);
+                        let len = app.images.len();
+                        assert!(app.index < len.max(1), "seed {}", seed);
+                        assert!(app.gallery.selected < len.max(1), "seed {}", seed);
+                        if let Some(current) = current.filter(|c| disk.contains(c)) {
+                            assert_eq!(app.images[app.index], current, "seed {}", seed);
+                        }
+                        if let Some(selected) = selected.filter(|s| disk.contains(s)) {
+                            assert_eq!(app.images[app.gallery.selected], selected, "seed {}", seed);
+                        }
+                    }
+                }
+            }
         }
     }
 
-    fn first(&mut self) {
-        if !self.images.is_empty() {
-            self.index = 0;
-            self.load_current();
-        }
+    #[test]
+    fn list_changes_are_summed_up() {
+        let files = |n: usize| vec![PathBuf::new(); n];
+        let notice = |added, removed| {
+            list_notice(&ListDiff {
+                added: files(added),
+                removed: files(removed),
+            })
+        };
+        assert_eq!(notice(500, 0), "+500 files");
+        assert_eq!(notice(1, 0), "+1 file");
+        assert_eq!(notice(0, 1), "-1 file");
+        assert_eq!(notice(0, 3), "-3 files");
+        assert_eq!(notice(2, 1), "+2 -1 files");
     }
 
-    fn last(&mut self) {
-        if !self.images.is_empty() {
-            self.index = self.images.len() - 1;
-            self.load_current();
+    #[test]
+    fn sources_resolve_from_the_command_line() {
+        const A: &str = "top/a.png";
+        const B: &str = "top/b.jpg";
+        const D: &str = "top/sub/d.png";
+        const E: &str = "other/e.png";
+        let dir = TestDir::new();
+        for name in [A, B, "top/c.txt", D, E] {
+            dir.file(name, b"");
+        }
+        let root = dir.path().canonicalize().unwrap();
+        let at = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+
+        // Arguments, then the images with the one opened first, or the error
+        type Expected<'a> = Result<(&'a [&'a str], &'a str), &'a str>;
+        let cases: Vec<(Vec<String>, Expected)> = vec![
+            (vec![at("top")], Ok((&[A, B], A))),
+            (vec!["-d".into(), at("top")], Ok((&[A, B], A))),
+            (vec!["-r".into(), at("top")], Ok((&[A, B, D], A))),
+            (vec!["--reverse".into(), at("top")], Ok((&[B, A], B))),
+            (vec!["--exclude=*.jpg".into(), at("top")], Ok((&[A], A))),
+            (vec![at(B)], Ok((&[A, B], B))),
+            (vec![at("top/sub/../b.jpg")], Ok((&[A, B], B))),
+            (vec![at("other"), at(A)], Ok((&[E, A], E))),
+            (vec![at(A), at(A)], Ok((&[A], A))),
+            (vec![at("top/sub"), at("top/c.txt")], Ok((&[D, "top/c.txt"], D))),
+            (vec![at("top/missing.png")], Err("no such file or directory: ")),
+            (vec!["-d".into(), at(A)], Err("not a directory: ")),
+            (vec!["--exclude=*".into(), at("top"), at("other")], Err("no images found in the")),
+            (vec!["--follow".into(), at("top"), at("other")], Err("need a single directory")),
+            (vec!["--latest".into(), at("top"), at("other")], Err("need a single directory")),
+            (vec![at("top"), "https://example.com/a.png".into()], Err("opened on its own")),
+            (vec![at("top/c.txt"), "-".into()], Err("- can only be opened on its own")),
+        ];
+        let relative = |p: &PathBuf| {
+            let p = p.canonicalize().unwrap();
+            p.strip_prefix(&root).unwrap().to_string_lossy().into_owned()
+        };
+        for (line, expected) in cases {
+            let line: Vec<&str> = line.iter().map(String::as_str).collect();
+            let no_stores = Persistence::in_memory();
+            let result = resolve_sources(&args(&line), &no_stores).map(|sources| {
+                let start = relative(&sources.images[sources.start_index]);
+                (sources.images.iter().map(relative).collect::<Vec<_>>(), start)
+            });
+            match (result, expected) {
+                (Ok((images, start)), Ok((want, want_start))) => {
+                    assert!(images == want && start == want_start, "{:?}: {:?}", line, images);
+                }
+                (Err(message), Err(want)) => {
+                    assert!(message.contains(want), "{:?}: {}", line, message);
+                }
+                (result, _) => panic!("{:?} gave {:?}", line, result),
+            }
         }
     }
 
-    fn zoom_in(&mut self) {
-        self.zoom = (self.zoom * 1.25).min(10.0);
+    #[test]
+    fn a_directory_can_be_named_only_once() {
+        let error = Args::try_parse_from(["termview", "-d", "photos", "other"]).unwrap_err();
+        assert_eq!(error.kind(), clap::error::ErrorKind::ArgumentConflict);
+        let error = Args::try_parse_from(["termview", "--compare-dirs", "a", "b", "c"]);
+        assert!(error.is_err());
     }
 
-    fn zoom_out(&mut self) {
-        self.zoom = (self.zoom / 1.25).max(0.1);
+    #[test]
+    fn compared_directories_step_and_zoom_together() {
+        let dir = TestDir::new();
+        for name in ["v1/a.png", "v1/b.png", "v2/a.png", "v2/c.png"] {
+            dir.file(name, b"");
+        }
+        let (v1, v2) = (dir.path().join("v1"), dir.path().join("v2"));
+        let line = ["--compare-dirs", v1.to_str().unwrap(), v2.to_str().unwrap()];
+        let sources = resolve_sources(&args(&line), &Persistence::in_memory()).unwrap();
+        let loader = Loader::new(Duration::from_secs(5), Vec::new(), None, AlphaMode::Auto);
+        let mut app = App::new(sources.images, sources.start_index, loader);
+        app.compare = sources.compare;
+        let v1 = v1.canonicalize().unwrap();
+        let v2 = v2.canonicalize().unwrap();
+        let listed = [v1.join("a.png"), v1.join("b.png"), v1.join("c.png")];
+        assert_eq!(app.images.to_vec(), listed);
+
+        app.zoom = 3.0;
+        app.pan_x = 0.25;
+        assert!(app.flip_side());
+        assert_eq!(app.images[app.index], v2.join("a.png"));
+        assert_eq!((app.zoom, app.pan_x), (3.0, 0.25));
+
+        // Navigating stays on the flipped side, and says what is missing there
+        assert!(app.navigate(Action::Next));
+        assert_eq!(app.images[app.index], v2.join("b.png"));
+        let missing = app.error_message.clone().unwrap();
+        assert_eq!(missing, format!("b.png is only in {}", v1.display()));
+        assert!(app.flip_side());
+        assert_eq!(app.images[app.index], v1.join("b.png"));
+        assert!(app.error_message.is_none());
     }
 
-    fn zoom_reset(&mut self) {
-        self.zoom = 1.0;
-        self.pan_x = 0.0;
-        self.pan_y = 0.0;
+    /// Every action, with what read-only mode does to it.
+    const ACTIONS: [(Action, Access); 39] = [
+        (Action::Quit, Access::View),
+        (Action::Next, Access::View),
+        (Action::Prev, Access::View),
+        (Action::First, Access::View),
+        (Action::Last, Access::View),
+        (Action::Up, Access::View),
+        (Action::Down, Access::View),
+        (Action::PageUp, Access::View),
+        (Action::PageDown, Access::View),
+        (Action::Open, Access::View),
+        (Action::ToggleGallery, Access::View),
+        (Action::Reload, Access::View),
+        (Action::ZoomIn, Access::View),
+        (Action::ZoomOut, Access::View),
+        (Action::ZoomReset, Access::View),
+        (Action::CycleFit, Access::View),
+        (Action::TogglePixelGrid, Access::View),
+        (Action::Measure, Access::View),
+        (Action::CopyMeasurement, Access::Export),
+        (Action::PanUp, Access::View),
+        (Action::PanDown, Access::View),
+        (Action::PanLeft, Access::View),
+        (Action::PanRight, Access::View),
+        (Action::Follow, Access::View),
+        (Action::FlipSide, Access::View),
+        (Action::ToggleSlideshow, Access::View),
+        (Action::TogglePlayback, Access::View),
+        (Action::NextFrame, Access::View),
+        (Action::PrevFrame, Access::View),
+        (Action::RotateCw, Access::View),
+        (Action::RotateCcw, Access::View),
+        (Action::FlipHorizontal, Access::View),
+        (Action::FlipVertical, Access::View),
+        (Action::Delete, Access::Modify),
+        (Action::Move, Access::Modify),
+        (Action::Copy, Access::Modify),
+        (Action::ToggleInfo, Access::View),
+        (Action::ToggleHelp, Access::View),
+        (Action::ToggleLog, Access::View),
+    ];
+
+    #[test]
+    fn every_bound_key_is_in_the_action_table() {
+        let chars = (' '..='~').map(KeyCode::Char);
+        let named = [
+            KeyCode::Delete,
+            KeyCode::Down,
+            KeyCode::End,
+            KeyCode::Enter,
+            KeyCode::Esc,
+            KeyCode::Home,
+            KeyCode::Left,
+            KeyCode::PageDown,
+            KeyCode::PageUp,
+            KeyCode::Right,
+            KeyCode::Tab,
+            KeyCode::Up,
+        ];
+        for code in chars.chain(named) {
+            for mods in [KeyModifiers::NONE, KeyModifiers::CONTROL, KeyModifiers::SHIFT] {
+                if let Some(action) = action_for(&KeyEvent::new(code, mods)) {
+                    assert!(ACTIONS.iter().any(|&(a, _)| a == action), "{:?}", action);
+                }
+            }
+        }
     }
 
-    fn pan(&mut self, dx: f64, dy: f64) {
-        self.pan_x += dx;
-        self.pan_y += dy;
+    /// What a stream of key bytes asks for, as crossterm would report the keys.
+    fn actions(bytes: &[u8]) -> Vec<Option<Action>> {
+        crate::reply::keys(bytes).iter().map(action_for).collect()
     }
 
-    fn current_filename(&self) -> String {
-        if self.images.is_empty() {
-            return "(none)".into();
+    #[test]
+    fn keys_mean_the_same_in_legacy_and_kitty_encodings() {
+        // (legacy bytes, kitty keyboard protocol bytes, action)
+        let cases: &[(&[u8], &[u8], Option<Action>)] = &[
+            (b"q", b"\x1b[113u", Some(Action::Quit)),
+            (b"\x1b", b"\x1b[27u", Some(Action::Quit)),
+            (b"\x03", b"\x1b[99;5u", Some(Action::Quit)),
+            (b"G", b"\x1b[103;2u", Some(Action::Last)),
+            (b"g", b"\x1b[103u", Some(Action::First)),
+            (b"F", b"\x1b[102;2u", Some(Action::Follow)),
+            (b"R", b"\x1b[114;2u", Some(Action::RotateCcw)),
+            (b"\x12", b"\x1b[114;5u", Some(Action::Reload)),
+            (b"\x1b[1;5D", b"\x1b[1;5D", Some(Action::PanLeft)),
+            (b"\x1b[1;5A", b"\x1b[1;5A", Some(Action::PanUp)),
+            (b"+", b"\x1b[43;2u", Some(Action::ZoomIn)),
+            (b"=", b"\x1b[61u", Some(Action::ZoomIn)),
+            (b"_", b"\x1b[95;2u", Some(Action::ZoomOut)),
+            (b" ", b"\x1b[32;2u", Some(Action::TogglePlayback)),
+            (b"\t", b"\x1b[9u", Some(Action::FlipSide)),
+            (b"\r", b"\x1b[13u", Some(Action::Open)),
+            (b"\x1b[3~", b"\x1b[3~", Some(Action::Delete)),
+            (b"\x1b[H", b"\x1b[H", Some(Action::First)),
+            // Alt isn't bound to anything
+            (b"\x1bx", b"\x1b[120;3u", None),
+            (b"\x1b[1;3C", b"\x1b[1;3C", None),
+        ];
+        for (legacy, kitty, expected) in cases {
+            let what = String::from_utf8_lossy(kitty);
+            assert_eq!(actions(legacy), [*expected], "{:?}", String::from_utf8_lossy(legacy));
+            assert_eq!(actions(kitty), [*expected], "{:?}", what);
         }
-        self.images[self.index]
-            .file_name()
-            .unwrap_or_default()
-            .to_string_lossy()
-            .into()
+
+        // Whole streams in either encoding come out the same, except that a
+        // legacy Esc followed by another key reads as Alt with that key
+        let unambiguous = cases.iter().filter(|c| c.0 != b"\x1b");
+        let legacy: Vec<u8> = unambiguous.clone().flat_map(|c| c.0.iter().copied()).collect();
+        let kitty: Vec<u8> = unambiguous.flat_map(|c| c.1.iter().copied()).collect();
+        assert_eq!(actions(&legacy), actions(&kitty));
+        assert_eq!(actions(&kitty).len(), cases.len() - 1);
+        assert_eq!(actions(b"\x1bq"), [None]);
+        assert_eq!(actions(b"\x1b[27uq"), [Some(Action::Quit), Some(Action::Quit)]);
     }
 
-    fn image_info(&self) -> String {
-        if let Some(ref img) = self.current_image {
-            let (w, h) = img.dimensions();
-            format!("{}x{}", w, h)
-        } else {
-            String::new()
+    #[test]
+    fn kitty_encoding_tells_apart_keys_legacy_merges() {
+        // Legacy sends Ctrl+I as Tab and Ctrl+M as Enter; only the kitty
+        // encoding tells them apart, and they aren't bound
+        assert_eq!(actions(b"\t"), [Some(Action::FlipSide)]);
+        assert_eq!(actions(b"\x1b[105;5u"), [None]);
+        assert_eq!(actions(b"\x1b[109;5u"), [None]);
+        // Shift+Tab is never Tab
+        assert_eq!(actions(b"\x1b[Z"), [None]);
+        // Shift is folded into characters however it is reported
+        for key in [
+            KeyEvent::new(KeyCode::Char('G'), KeyModifiers::NONE),
+            KeyEvent::new(KeyCode::Char('G'), KeyModifiers::SHIFT),
+            KeyEvent::new(KeyCode::Char('g'), KeyModifiers::SHIFT),
+        ] {
+            assert_eq!(normalize_key(&key), (KeyCode::Char('G'), KeyModifiers::NONE));
+            assert_eq!(action_for(&key), Some(Action::Last));
         }
+        // Shift stays on keys that aren't characters
+        let shift_left = KeyEvent::new(KeyCode::Left, KeyModifiers::SHIFT);
+        assert_eq!(normalize_key(&shift_left), (KeyCode::Left, KeyModifiers::SHIFT));
+        let ctrl_shift = KeyModifiers::CONTROL | KeyModifiers::SHIFT;
+        assert_eq!(action_for(&KeyEvent::new(KeyCode::Char('g'), ctrl_shift)), None);
     }
 
-    /// Get the image view, applying zoom and pan via cropping.
-    fn get_view_image(&self) -> Option<DynamicImage> {
-        let img = self.current_image.as_ref()?;
+    #[test]
+    fn read_only_refuses_every_action_that_does_more_than_it_allows() {
+        for (action, access) in ACTIONS {
+            assert_eq!(action.access(), access, "{:?}", action);
+        }
+        let modes = [
+            (None, true, true),
+            (Some(ReadOnly::Normal), true, false),
+            (Some(ReadOnly::Strict), false, false),
+        ];
+        for (mode, export, modify) in modes {
+            for (action, access) in ACTIONS {
+                let mut app = browsing(2, 0);
+                app.read_only = mode;
+                let expected = match access {
+                    Access::View => true,
+                    Access::Export => export,
+                    Access::Modify => modify,
+                };
+                assert_eq!(app.permits(action), expected, "{:?} under {:?}", action, mode);
+                assert_eq!(app.error_message.is_some(), !expected, "{:?}", action);
+            }
+        }
+    }
 
-        if (self.zoom - 1.0).abs() < 0.01 && self.pan_x.abs() < 0.01 && self.pan_y.abs() < 0.01 {
-            return Some(img.clone());
+    #[test]
+    fn strict_read_only_leaves_the_clipboard_alone() {
+        let key = KeyEvent::new(KeyCode::Char('y'), KeyModifiers::NONE);
+        for (mode, copies) in [(Some(ReadOnly::Normal), true), (Some(ReadOnly::Strict), false)] {
+            let mut app = browsing(1, 0);
+            app.read_only = mode;
+            app.toggle_measure(None);
+            app.measure_key(&KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE), None);
+            assert_eq!(app.measure_key(&key, None), Some(Refresh::Status));
+            assert_eq!(app.clipboard.is_some(), copies, "{:?}", mode);
         }
+    }
 
-        let (w, h) = img.dimensions();
-        let view_w = ((w as f64 / self.zoom) as u32).max(1);
-        let view_h = ((h as f64 / self.zoom) as u32).max(1);
+    #[test]
+    fn home_paths_are_refused_without_home() {
+        let tmp = TestDir::new();
+        let file = tmp.file("a.png", b"pixels");
+        let mut app = browsing(1, 0);
+        app.images = Playlist::new(vec![file.clone()]);
+        assert!(app.persistence.home.is_none());
+        assert!(app.thumb_cache.is_none());
+        app.prompt = Some(Prompt::Destination {
+            op: Transfer::Move,
+            input: "~/sorted".into(),
+            file: app.current_target(),
+        });
+        let enter = KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE);
+        assert_eq!(app.prompt_key(&enter), Refresh::Status);
+        assert_eq!(app.error_message.as_deref(), Some("$HOME isn't set, so ~ can't be used"));
+        assert!(file.exists());
+    }
 
-        let center_x = (w as f64 / 2.0 + self.pan_x * w as f64).clamp(0.0, w as f64);
-        let center_y = (h as f64 / 2.0 + self.pan_y * h as f64).clamp(0.0, h as f64);
+    /// A 40x30 image of `fill`, with its outermost pixels set by `border`.
+    fn bordered(fill: [u8; 4], border: impl Fn(u32, u32) -> [u8; 4]) -> DynamicImage {
+        let img = image::RgbaImage::from_fn(40, 30, |x, y| {
+            let edge = x == 0 || y == 0 || x == 39 || y == 29;
+            image::Rgba(if edge { border(x, y) } else { fill })
+        });
+        DynamicImage::ImageRgba8(img)
+    }
 
-        let x = (center_x - view_w as f64 / 2.0)
-            .max(0.0)
-            .min((w.saturating_sub(view_w.min(w))) as f64) as u32;
-        let y = (center_y - view_h as f64 / 2.0)
-            .max(0.0)
-            .min((h.saturating_sub(view_h.min(h))) as f64) as u32;
+    const WHOLE: PixelRect = (0, 0, 40, 30);
 
-        let crop_w = view_w.min(w - x);
-        let crop_h = view_h.min(h - y);
+    #[test]
+    fn solid_border_is_matched() {
+        let img = bordered([255, 0, 0, 255], |_, _| [10, 20, 30, 255]);
+        assert_eq!(edge_color(&img, WHOLE), Some((10, 20, 30)));
+        // Only the border of the part in view counts
+        assert_eq!(edge_color(&img, (1, 1, 38, 28)), Some((255, 0, 0)));
+    }
 
-        if crop_w == 0 || crop_h == 0 {
-            return Some(img.clone());
-        }
+    #[test]
+    fn gradient_border_is_averaged_while_gentle() {
+        let gentle = bordered([0; 4], |x, _| [100 + x as u8, 100, 100, 255]);
+        let (r, g, b) = edge_color(&gentle, WHOLE).unwrap();
+        assert!((115..=125).contains(&r), "{}", r);
+        assert_eq!((g, b), (100, 100));
 
-        Some(img.crop_imm(x, y, crop_w, crop_h))
+        let steep = bordered([0; 4], |x, _| [(x * 6) as u8, 0, 0, 255]);
+        assert_eq!(edge_color(&steep, WHOLE), None);
     }
-}
 
-// ---------------------------------------------------------------------------
-// Drawing
-// ---------------------------------------------------------------------------
+    #[test]
+    fn noisy_border_is_not_matched() {
+        let mut seed = 0x2545_f491_u32;
+        let mut noise = || {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed as u8
+        };
+        let noisy = image::RgbaImage::from_fn(40, 30, |_, _| {
+            image::Rgba([noise(), noise(), noise(), 255])
+        });
+        assert_eq!(edge_color(&DynamicImage::ImageRgba8(noisy), WHOLE), None);
+    }
 
-fn draw(out: &mut impl Write, app: &App) -> io::Result<()> {
-    let (cols, rows) = terminal::size()?;
-    let (cell_w, cell_h) = get_cell_size();
+    #[test]
+    fn transparent_border_pixels_count_by_their_alpha() {
+        let clear = bordered([0; 4], |_, _| [255, 255, 255, 0]);
+        assert_eq!(edge_color(&clear, WHOLE), None);
+        // Hidden colours under alpha 0 don't pull the average
+        let mixed = bordered([0; 4], |x, _| {
+            if x % 2 == 0 {
+                [9, 9, 9, 255]
+            } else {
+                [255, 0, 0, 0]
+            }
+        });
+        assert_eq!(edge_color(&mixed, WHOLE), Some((9, 9, 9)));
+    }
 
-    // Clear screen and delete old kitty images
-    queue!(out, terminal::Clear(ClearType::All))?;
-    kitty_clear(out)?;
+    #[test]
+    fn letterbox_colors_parse() {
+        assert_eq!("#0a0B0c".parse(), Ok(Letterbox::Solid(10, 11, 12)));
+        assert_eq!("AUTO".parse(), Ok(Letterbox::Auto(None)));
+        assert_eq!("auto:#202020".parse(), Ok(Letterbox::Auto(Some((32, 32, 32)))));
+        for bad in ["", "auto:", "auto:#12345", "#1234567", "#gg0000", "solid:#000000"] {
+            assert!(bad.parse::<Letterbox>().is_err(), "{:?}", bad);
+        }
+    }
 
-    let image_rows = rows.saturating_sub(1); // reserve 1 row for status bar
+    #[test]
+    fn letterbox_colour_waits_for_the_view_to_settle() {
+        let renderer = render::renderer_for(
+            Protocol::Blocks,
+            LinkSpeed::Fast,
+            KittyCaps::MODERN,
+            Palette::VGA,
+            1.0,
+        );
+        let mut screen = Screen::new(renderer);
+        let img = bordered([200, 200, 200, 255], |_, _| [0, 0, 0, 255]);
+        let view = |rect| View {
+            rect,
+            scaling: render::Scaling {
+                max: 1.0,
+                stretch: false,
+            },
+            grid: false,
+        };
 
-    // Draw image
-    if let Some(view_img) = app.get_view_image() {
-        kitty_display(out, &view_img, cols, image_rows, cell_w, cell_h)?;
-    } else if let Some(ref err) = app.error_message {
-        let err_row = rows / 2;
-        let err_col = cols.saturating_sub(err.len() as u16) / 2;
-        queue!(
-            out,
-            cursor::MoveTo(err_col, err_row),
-            SetForegroundColor(style::Color::Red),
-        )?;
-        write!(out, "{}", err)?;
-        queue!(out, SetForegroundColor(style::Color::Reset))?;
+        assert_eq!(screen.edge_color(&img, 1, view(WHOLE)), Some((0, 0, 0)));
+        assert!(!screen.take_edge_moved());
+        // Panned in: the colour holds until the view settles
+        assert_eq!(screen.edge_color(&img, 1, view((1, 1, 20, 20))), Some((0, 0, 0)));
+        assert!(screen.take_edge_moved());
+        screen.settle_edge();
+        assert_eq!(screen.edge_color(&img, 1, view((1, 1, 20, 20))), Some((200, 200, 200)));
+        assert_eq!(screen.edge_color(&img, 1, view((1, 1, 20, 20))), Some((200, 200, 200)));
+        assert!(!screen.take_edge_moved());
+        // Another image is sampled straight away
+        assert_eq!(screen.edge_color(&img, 2, view(WHOLE)), Some((0, 0, 0)));
+        assert!(!screen.take_edge_moved());
     }
 
-    // Status bar
-    let filename = app.current_filename();
-    let counter = if app.images.is_empty() {
-        "0/0".into()
-    } else {
-        format!("{}/{}", app.index + 1, app.images.len())
-    };
-    let info = app.image_info();
-    let zoom_str = if (app.zoom - 1.0).abs() > 0.01 {
-        format!(" {:.0}%", app.zoom * 100.0)
-    } else {
-        String::new()
-    };
+    #[test]
+    fn boxes_too_small_for_their_border_are_left_out() {
+        let lines = [(style::Color::White, "text".to_string())];
+        for (w, h) in [(0, 0), (1, 5), (5, 1), (1, 1), (0, 3)] {
+            let mut out = Vec::new();
+            draw_panel_at(&mut out, (0, 0), (w, h), &lines).unwrap();
+            assert!(out.is_empty(), "{}x{}", w, h);
+            draw_tile_frame(&mut out, (0, 0, w, h), true).unwrap();
+            assert!(out.is_empty(), "{}x{}", w, h);
+        }
+
+        let mut out = Vec::new();
+        draw_panel_at(&mut out, (0, 0), (2, 2), &lines).unwrap();
+        let drawn = String::from_utf8(out).unwrap();
+        assert!(drawn.contains("┌┐") && drawn.contains("└┘"), "{:?}", drawn);
+        assert!(!drawn.contains("text"));
+    }
 
-    let left = format!(" {} {} {}", filename, info, zoom_str);
-    let right = format!("{} | q:quit ?:help ", counter);
+    #[test]
+    fn overlays_fit_the_narrowest_terminals() {
+        let mut log = SessionLog::new(None);
+        log.push(Severity::Error, "a message longer than the terminal is wide".into());
+        for cols in 0..4 {
+            for rows in 0..4 {
+                let mut out = Vec::new();
+                draw_log_overlay(&mut out, cols, rows, &log).unwrap();
+                draw_help_overlay(&mut out, cols, rows).unwrap();
+                draw_delete_overlay(&mut out, cols, rows, Path::new("a.png"), false).unwrap();
+                draw_tile_label(&mut out, (0, 0, cols, rows), Path::new("a.png")).unwrap();
+                // Nothing close to a full line of border for a box this small
+                assert!(out.len() < 4096, "{}x{}: {} bytes", cols, rows, out.len());
+            }
+        }
+    }
 
-    draw_status_bar(out, rows - 1, cols, &left, &right)?;
+    /// A GIF of `count` 4x4 frames, each a shade of red brighter than the last.
+    fn gif(count: u8) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        let mut encoder = image::codecs::gif::GifEncoder::new(&mut bytes);
+        let frames = (0..count).map(|i| {
+            let pixels = image::RgbaImage::from_pixel(4, 4, image::Rgba([i * 50, 0, 0, 255]));
+            let delay = image::Delay::from_saturating_duration(Duration::from_millis(70));
+            image::Frame::from_parts(pixels, 0, 0, delay)
+        });
+        encoder.encode_frames(frames).unwrap();
+        drop(encoder);
+        bytes
+    }
 
-    // Help overlay
-    if app.show_help {
-        draw_help_overlay(out, cols, rows)?;
+    #[test]
+    fn single_frame_gifs_are_stills() {
+        let Ok(Decoded::Still(img)) = decode_bytes_as_stored(&gif(1)) else {
+            panic!("not a still");
+        };
+        assert_eq!(img.dimensions(), (4, 4));
+        assert_eq!(img.get_pixel(0, 0).0, [0, 0, 0, 255]);
     }
 
-    // Hide cursor
-    queue!(out, cursor::Hide)?;
+    #[test]
+    fn animated_gifs_keep_every_frame() {
+        let Ok(Decoded::Animated(frames)) = decode_bytes_as_stored(&gif(3)) else {
+            panic!("not animated");
+        };
+        let reds: Vec<u8> = frames.iter().map(|(img, _)| img.get_pixel(0, 0)[0]).collect();
+        assert_eq!(reds, [0, 50, 100]);
+        assert!(frames.iter().all(|&(_, delay)| delay == Duration::from_millis(70)));
 
-    out.flush()?;
-    Ok(())
-}
+        let dir = TestDir::new();
+        let path = dir.file("two.gif", &gif(2));
+        let Ok(Decoded::Animated(frames)) = decode_builtin_as_stored(&path) else {
+            panic!("not animated");
+        };
+        assert_eq!(frames.len(), 2);
+    }
 
-// ---------------------------------------------------------------------------
-// Main
-// ---------------------------------------------------------------------------
+    #[test]
+    fn animation_cut_short_shows_its_first_frame() {
+        let mut bytes = gif(3);
+        // Keep the first frame whole, and lose the end of the file
+        bytes.truncate(bytes.len() - 20);
+        let Ok(Decoded::Still(img)) = decode_bytes_as_stored(&bytes) else {
+            panic!("not a still");
+        };
+        assert_eq!(img.dimensions(), (4, 4));
+    }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args = Args::parse();
+    const FRAME_DELAY: Duration = Duration::from_millis(50);
+    const SLOW_ENCODE: Duration = Duration::from_millis(25);
 
-    let browse_dir = if let Some(ref file) = args.file {
-        if file.is_dir() {
-            file.clone()
-        } else {
-            file.parent().unwrap_or(Path::new(".")).to_path_buf()
+    /// Takes `SLOW_ENCODE` to draw anything, and writes which frame it drew:
+    /// the red of its top left pixel.
+    struct SlowEncode;
+
+    impl Renderer for SlowEncode {
+        fn display(
+            &mut self,
+            out: &mut dyn Write,
+            img: &DynamicImage,
+            _area: CellRect,
+            _cell_size: (u16, u16),
+        ) -> io::Result<()> {
+            std::thread::sleep(SLOW_ENCODE);
+            write!(out, "[frame {}]", img.get_pixel(0, 0)[0])
         }
-    } else {
-        args.directory.clone()
-    };
 
-    let browse_dir = std::fs::canonicalize(&browse_dir).unwrap_or(browse_dir);
-    let images = collect_images(&browse_dir);
+        fn clear(&mut self, _out: &mut dyn Write) -> io::Result<()> {
+            Ok(())
+        }
 
-    let start_index = if let Some(ref file) = args.file {
-        if file.is_file() {
-            let canonical = std::fs::canonicalize(file).unwrap_or(file.clone());
-            images
-                .iter()
-                .position(|p| std::fs::canonicalize(p).unwrap_or(p.clone()) == canonical)
-                .unwrap_or(0)
-        } else {
-            0
+        fn frame_encoder(&self) -> Box<dyn Renderer + Send> {
+            Box::new(SlowEncode)
         }
-    } else {
-        0
-    };
+    }
 
-    // Setup terminal
-    enable_raw_mode()?;
-    let mut out = stdout();
-    execute!(
-        out,
-        terminal::EnterAlternateScreen,
-        cursor::Hide,
-    )?;
+    fn animated(count: usize) -> App {
+        let mut app = browsing(1, 0);
+        let frames: Vec<Frame> = (0..count)
+            .map(|i| {
+                let frame = image::RgbImage::from_pixel(8, 8, image::Rgb([i as u8, 0, 0]));
+                (DynamicImage::ImageRgb8(frame), FRAME_DELAY)
+            })
+            .collect();
+        app.current_image = Some(frames[0].0.clone());
+        app.animation = Some(Animation {
+            frames,
+            current: 0,
+            paused: false,
+            key: app.image_key,
+        });
+        app
+    }
 
-    let mut app = App::new(images, start_index);
+    /// Draw the image as the event loop does when a frame is due, returning
+    /// what was written and how long it took.
+    fn draw(screen: &mut Screen, app: &App) -> (String, Duration) {
+        let start = Instant::now();
+        let mut out = Vec::new();
+        screen.render_image(&mut out, app, 80, 24).unwrap();
+        (String::from_utf8_lossy(&out).into_owned(), start.elapsed())
+    }
 
-    // Initial draw
-    draw(&mut out, &app)?;
+    #[test]
+    fn animation_frames_are_drawn_before_they_are_due() {
+        let mut app = animated(50);
+        let mut screen = Screen::new(Box::new(SlowEncode));
+        let (first, _) = draw(&mut screen, &app);
+        assert!(first.contains("[frame 0]"));
 
-    // Event loop
-    loop {
-        if event::poll(Duration::from_millis(100))? {
-            match event::read()? {
-                Event::Key(key) => {
-                    if key.kind != KeyEventKind::Press {
-                        continue;
-                    }
+        let mut due = Instant::now() + FRAME_DELAY;
+        for i in 1..50 {
+            std::thread::sleep(due.saturating_duration_since(Instant::now()));
+            let tick = Instant::now();
+            assert_eq!(app.advance_frame(), Refresh::Image);
+            let (drawn, _) = draw(&mut screen, &app);
+            let latency = tick.elapsed();
 
-                    let mut needs_redraw = true;
+            assert!(drawn.contains(&format!("[frame {}]", i)), "{}: {}", i, drawn);
+            assert!(latency < FRAME_DELAY, "frame {} took {:?}", i, latency);
+            // Only writing is left for the tick
+            assert!(latency < SLOW_ENCODE, "frame {} drawn on its tick ({:?})", i, latency);
+            due += FRAME_DELAY;
+        }
+    }
 
-                    match key.code {
-                        KeyCode::Char('q') | KeyCode::Esc => break,
-                        KeyCode::Char('c')
-                            if key.modifiers.contains(KeyModifiers::CONTROL) =>
-                        {
-                            break
-                        }
+    #[test]
+    fn frames_drawn_ahead_are_dropped_when_the_view_changes() {
+        let mut app = animated(3);
+        let mut screen = Screen::new(Box::new(SlowEncode));
+        draw(&mut screen, &app);
+        assert!(screen.next_frame.is_some());
 
-                        KeyCode::Right | KeyCode::Char('l') => app.next(),
-                        KeyCode::Left | KeyCode::Char('h') => app.prev(),
-                        KeyCode::Home | KeyCode::Char('g') => app.first(),
-                        KeyCode::End => app.last(),
-                        KeyCode::Char('G') => app.last(),
+        app.zoom_in();
+        app.advance_frame();
+        let (drawn, took) = draw(&mut screen, &app);
+        assert!(drawn.contains("[frame 1]"), "{}", drawn);
+        assert!(took >= SLOW_ENCODE, "{:?}", took);
 
-                        KeyCode::Char('+') | KeyCode::Char('=') => app.zoom_in(),
-                        KeyCode::Char('-') | KeyCode::Char('_') => app.zoom_out(),
-                        KeyCode::Char('0') => app.zoom_reset(),
+        // Turning the image doesn't show a frame drawn the old way up
+        std::thread::sleep(FRAME_DELAY);
+        app.reorient(Orientation::ROTATE_CW);
+        app.advance_frame();
+        let (drawn, took) = draw(&mut screen, &app);
+        assert!(drawn.contains("[frame 2]"), "{}", drawn);
+        assert!(took >= SLOW_ENCODE, "{:?}", took);
 
-                        KeyCode::Char('w') => app.pan(0.0, -0.05),
-                        KeyCode::Char('s') => app.pan(0.0, 0.05),
-                        KeyCode::Char('a') => app.pan(-0.05, 0.0),
-                        KeyCode::Char('d') => app.pan(0.05, 0.0),
+        // Paused, there is no next frame to draw
+        app.toggle_playback();
+        draw(&mut screen, &app);
+        assert!(screen.next_frame.is_none());
+    }
 
-                        KeyCode::Char('?') => app.show_help = !app.show_help,
+    #[test]
+    fn normal_screen_is_left_with_the_drawing_above_the_prompt() {
+        let mut out = Vec::new();
+        leave_screen(&mut out, false, 30).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(!out.contains("\x1b[2J"), "cleared: {:?}", out);
+        assert!(out.ends_with("\x1b[30;1H\r\n"), "{:?}", out);
+        assert!(out.contains("\x1b[?7h"), "line wrap left off: {:?}", out);
 
-                        _ => needs_redraw = false,
-                    }
+        let mut out = Vec::new();
+        leave_screen(&mut out, true, 30).unwrap();
+        assert_eq!(out, b"\x1b[?1049l");
+    }
 
-                    if needs_redraw {
-                        draw(&mut out, &app)?;
-                    }
-                }
-                Event::Resize(_, _) => {
-                    draw(&mut out, &app)?;
-                }
-                _ => {}
+    /// Feed the recording in `text` through `app` the way the event loop
+    /// does, as fast as it will go. Returns whether it quit.
+    fn replay_into(app: &mut App, text: &str) -> bool {
+        let tmp = TestDir::new();
+        let recording = Recording::load(&tmp.file("session.tvrec", text.as_bytes())).unwrap();
+        assert_eq!(recording.differences(&app.images), None);
+        let mut replay = Replay::new(recording, 1.0);
+        let screen = Screen::new(Box::new(Placed));
+        let later = Instant::now() + Duration::from_secs(3600);
+        while !replay.is_finished() {
+            for timer in replay.timers(later) {
+                let _ = match timer {
+                    Timer::Slideshow => app.advance_slideshow(),
+                    Timer::Frame => Some(app.advance_frame()),
+                    _ => unreachable!("{:?} isn't replayed", timer),
+                };
+            }
+            let Some(Event::Key(key)) = replay.next_event(later) else {
+                continue;
+            };
+            let Some(action) = action_for(&key).filter(|&action| app.permits(action)) else {
+                continue;
+            };
+            if perform(app, action, &screen, (80, 24)).is_none() {
+                return true;
             }
         }
+        false
     }
 
-    // Cleanup: delete kitty images, restore terminal
-    kitty_clear(&mut out)?;
-    execute!(
-        out,
-        cursor::Show,
-        terminal::LeaveAlternateScreen,
-    )?;
-    disable_raw_mode()?;
+    #[test]
+    fn replaying_a_recording_ends_in_the_recorded_state() {
+        let mut app = browsing(3, 0);
+        let recording = "termview-recording 1
+size 80 24
+image 0.png
+image 1.png
+image 2.png
+events
+100 key Right
+200 key l
+300 key p
+400 timer Slideshow
+500 key p
+600 key +
+650 key +
+700 key -
+800 key Hyper-x
+";
+        assert!(!replay_into(&mut app, recording));
+        // Two steps on, then the slideshow looped round to the first
+        assert_eq!(app.index, 0);
+        assert!(app.slideshow.is_none());
+        // Navigating reset the zoom; the keys after it left one step in
+        assert_eq!(app.zoom, 1.25);
 
-    Ok(())
-}
\ No newline at end of file
+        // The same recording again gives the same result
+        let mut again = browsing(3, 0);
+        replay_into(&mut again, recording);
+        assert_eq!((again.index, again.zoom), (app.index, app.zoom));
+    }
+
+    #[test]
+    fn replaying_stops_at_a_recorded_quit() {
+        let mut app = browsing(3, 0);
+        let recording = "termview-recording 1
+size 80 24
+image 0.png
+image 1.png
+image 2.png
+events
+100 key End
+200 key q
+300 key Home
+";
+        assert!(replay_into(&mut app, recording));
+        assert_eq!(app.index, 2);
+    }
+}
@@ -0,0 +1,417 @@
+//! Measuring between two points of an image (`M`), for screenshots of UI
+//! elements: a crosshair is moved over the image, and two points placed
+//! with Enter give the distance between them, the size of the rectangle
+//! they span and its aspect ratio. Measurements stay drawn until cleared.
+//!
+//! Points are image pixels, so the numbers are the same at any zoom. They
+//! are drawn into the view after it has been scaled for the screen, where
+//! lines stay one screen pixel wide however far the image is enlarged.
+
+use std::path::PathBuf;
+
+use image::{Rgba, RgbaImage};
+
+use crate::render::{shade, PixelRect};
+
+/// A pixel of the image: (x, y).
+pub type Point = (u32, u32);
+
+/// Colour of finished measurements.
+const DONE_COLOR: Rgba<u8> = Rgba([255, 64, 160, 255]);
+/// Colour of the measurement being placed.
+const PENDING_COLOR: Rgba<u8> = Rgba([255, 210, 0, 255]);
+/// Screen pixels per image pixel from which the pixel under the crosshair
+/// is outlined as well.
+const CURSOR_BOX_SCALE: f64 = 4.0;
+/// Largest terms an aspect ratio is given in, e.g. `16:9`; others are
+/// written as a decimal, e.g. `1.873:1`.
+const MAX_RATIO_TERM: u32 = 32;
+
+/// Two points and what lies between them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Measurement {
+    pub from: Point,
+    pub to: Point,
+}
+
+impl Measurement {
+    /// Distance between the centres of the two pixels.
+    pub fn distance(&self) -> f64 {
+        let dx = self.from.0.abs_diff(self.to.0) as f64;
+        let dy = self.from.1.abs_diff(self.to.1) as f64;
+        dx.hypot(dy)
+    }
+
+    /// Size of the rectangle the points are corners of, counting both: a
+    /// point on the first and last pixel of a button gives its width.
+    pub fn size(&self) -> (u32, u32) {
+        (
+            self.from.0.abs_diff(self.to.0) + 1,
+            self.from.1.abs_diff(self.to.1) + 1,
+        )
+    }
+
+    /// The numbers as shown on the status bar and copied: e.g.
+    /// `141.4px 101×101 1:1`.
+    pub fn summary(&self) -> String {
+        let (w, h) = self.size();
+        format!("{:.1}px {}×{} {}", self.distance(), w, h, aspect_ratio(w, h))
+    }
+
+    /// The pixel rectangle spanned: (x, y, width, height).
+    fn rect(&self) -> PixelRect {
+        let (w, h) = self.size();
+        (self.from.0.min(self.to.0), self.from.1.min(self.to.1), w, h)
+    }
+}
+
+/// `w:h` in lowest terms if they are small, otherwise as a decimal.
+fn aspect_ratio(w: u32, h: u32) -> String {
+    let divisor = gcd(w, h);
+    let (a, b) = (w / divisor, h / divisor);
+    if a <= MAX_RATIO_TERM && b <= MAX_RATIO_TERM {
+        format!("{}:{}", a, b)
+    } else {
+        format!("{:.3}:1", w as f64 / h as f64)
+    }
+}
+
+fn gcd(a: u32, b: u32) -> u32 {
+    if b == 0 {
+        a.max(1)
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+/// Measurement mode and the measurements of the image on screen.
+#[derive(Debug, Clone)]
+pub struct Measure {
+    /// Whether keys go to the crosshair. Measurements are still drawn
+    /// after leaving, until cleared.
+    pub active: bool,
+    /// The image measured and its size; measurements don't carry over to
+    /// another image.
+    pub path: PathBuf,
+    pub size: (u32, u32),
+    pub cursor: Point,
+    /// First point of the measurement being placed.
+    pub anchor: Option<Point>,
+    pub done: Vec<Measurement>,
+    /// The numbers were copied since the last key.
+    pub copied: bool,
+}
+
+impl Measure {
+    /// Start measuring `path`, `size` pixels, with the crosshair at `cursor`.
+    pub fn new(path: PathBuf, size: (u32, u32), cursor: Point) -> Self {
+        let mut measure = Measure {
+            active: true,
+            path,
+            size,
+            cursor,
+            anchor: None,
+            done: Vec::new(),
+            copied: false,
+        };
+        measure.reset(size);
+        measure
+    }
+
+    /// Drop all points, for the image now being `size` pixels.
+    pub fn reset(&mut self, size: (u32, u32)) {
+        self.size = size;
+        self.anchor = None;
+        self.done.clear();
+        self.move_cursor(0, 0);
+    }
+
+    /// Move the crosshair, stopping at the edges of the image.
+    pub fn move_cursor(&mut self, dx: i64, dy: i64) {
+        let clamp = |v: u32, d: i64, len: u32| (v as i64 + d).clamp(0, len as i64 - 1) as u32;
+        self.cursor = (
+            clamp(self.cursor.0, dx, self.size.0.max(1)),
+            clamp(self.cursor.1, dy, self.size.1.max(1)),
+        );
+    }
+
+    /// Put a point at the crosshair: the first of a measurement, or the
+    /// second, which finishes it.
+    pub fn place(&mut self) {
+        match self.anchor.take() {
+            Some(from) => self.done.push(Measurement {
+                from,
+                to: self.cursor,
+            }),
+            None => self.anchor = Some(self.cursor),
+        }
+    }
+
+    /// Take back the point or measurement placed last.
+    pub fn undo(&mut self) {
+        if self.anchor.take().is_none() {
+            self.done.pop();
+        }
+    }
+
+    /// The measurement being placed, up to the crosshair, or else the one
+    /// finished last.
+    pub fn current(&self) -> Option<Measurement> {
+        match self.anchor {
+            Some(from) => Some(Measurement {
+                from,
+                to: self.cursor,
+            }),
+            None => self.done.last().copied(),
+        }
+    }
+
+    /// Whether there is anything to draw.
+    pub fn is_shown(&self) -> bool {
+        self.active || !self.done.is_empty()
+    }
+
+    /// Draw the measurements, and the crosshair while measuring, onto
+    /// `canvas`: the `view` rectangle of the image as scaled for the screen.
+    pub fn draw(&self, canvas: &mut RgbaImage, view: PixelRect) {
+        let map = Mapping::new(canvas, view);
+        for measurement in &self.done {
+            draw_measurement(canvas, &map, measurement, DONE_COLOR);
+        }
+        if !self.active {
+            return;
+        }
+        if let Some(from) = self.anchor {
+            let pending = Measurement {
+                from,
+                to: self.cursor,
+            };
+            draw_measurement(canvas, &map, &pending, PENDING_COLOR);
+        }
+
+        // A crosshair across the view, contrasting with what it crosses
+        let (cx, cy) = map.center(self.cursor);
+        let (w, h) = canvas.dimensions();
+        for x in 0..w {
+            shade_at(canvas, x as i64, cy);
+        }
+        for y in 0..h {
+            shade_at(canvas, cx, y as i64);
+        }
+        if map.scale.0.min(map.scale.1) >= CURSOR_BOX_SCALE {
+            let (x, y) = self.cursor;
+            draw_rect(canvas, &map, (x, y, 1, 1), PENDING_COLOR);
+        }
+    }
+}
+
+/// From image pixels to canvas pixels, for a view scaled onto a canvas.
+struct Mapping {
+    origin: (u32, u32),
+    /// Canvas pixels per image pixel.
+    scale: (f64, f64),
+}
+
+impl Mapping {
+    fn new(canvas: &RgbaImage, (x, y, w, h): PixelRect) -> Self {
+        let (cw, ch) = canvas.dimensions();
+        Mapping {
+            origin: (x, y),
+            scale: (cw as f64 / w.max(1) as f64, ch as f64 / h.max(1) as f64),
+        }
+    }
+
+    /// Where the point `offset` into image pixel `p` falls on the canvas:
+    /// 0 for its top left corner, 0.5 for its centre.
+    fn point(&self, (x, y): Point, offset: f64) -> (i64, i64) {
+        let at = |v: u32, origin: u32, scale: f64| {
+            ((v as f64 - origin as f64 + offset) * scale).floor() as i64
+        };
+        (at(x, self.origin.0, self.scale.0), at(y, self.origin.1, self.scale.1))
+    }
+
+    fn center(&self, p: Point) -> (i64, i64) {
+        self.point(p, 0.5)
+    }
+}
+
+/// The rectangle a measurement spans, and the line between its points.
+fn draw_measurement(canvas: &mut RgbaImage, map: &Mapping, m: &Measurement, color: Rgba<u8>) {
+    draw_rect(canvas, map, m.rect(), color);
+    draw_line(canvas, map.center(m.from), map.center(m.to), color);
+}
+
+/// Outline the image pixels `rect` along their outer edges.
+fn draw_rect(canvas: &mut RgbaImage, map: &Mapping, (x, y, w, h): PixelRect, color: Rgba<u8>) {
+    let (left, top) = map.point((x, y), 0.0);
+    let (right, bottom) = map.point((x + w, y + h), 0.0);
+    let (right, bottom) = (right - 1, bottom - 1);
+    draw_line(canvas, (left, top), (right, top), color);
+    draw_line(canvas, (left, bottom), (right, bottom), color);
+    draw_line(canvas, (left, top), (left, bottom), color);
+    draw_line(canvas, (right, top), (right, bottom), color);
+}
+
+/// Bresenham's line, clipped to the canvas.
+fn draw_line(canvas: &mut RgbaImage, (x0, y0): (i64, i64), (x1, y1): (i64, i64), color: Rgba<u8>) {
+    let (w, h) = canvas.dimensions();
+    let (dx, dy) = ((x1 - x0).abs(), -(y1 - y0).abs());
+    let (sx, sy) = ((x1 - x0).signum(), (y1 - y0).signum());
+    let (mut x, mut y, mut err) = (x0, y0, dx + dy);
+    loop {
+        if (0..w as i64).contains(&x) && (0..h as i64).contains(&y) {
+            canvas.put_pixel(x as u32, y as u32, color);
+        }
+        if (x, y) == (x1, y1) {
+            break;
+        }
+        let e2 = 2 * err;
+        if e2 >= dy {
+            err += dy;
+            x += sx;
+        }
+        if e2 <= dx {
+            err += dx;
+            y += sy;
+        }
+    }
+}
+
+fn shade_at(canvas: &mut RgbaImage, x: i64, y: i64) {
+    let (w, h) = canvas.dimensions();
+    if (0..w as i64).contains(&x) && (0..h as i64).contains(&y) {
+        shade(canvas.get_pixel_mut(x as u32, y as u32));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A measurement being placed from `from` with the crosshair at `to`.
+    fn measuring(size: (u32, u32), from: Point, to: Point) -> Measure {
+        let mut measure = Measure::new(PathBuf::from("shot.png"), size, from);
+        measure.place();
+        measure.cursor = to;
+        measure
+    }
+
+    #[test]
+    fn pixels_map_onto_the_canvas_at_any_zoom() {
+        // Whole image at 1:1, 4x and half size
+        for (canvas, corner, center) in [
+            ((100, 50), (10, 20), (10, 20)),
+            ((400, 200), (40, 80), (42, 82)),
+            ((50, 25), (5, 10), (5, 10)),
+        ] {
+            let map = Mapping::new(&RgbaImage::new(canvas.0, canvas.1), (0, 0, 100, 50));
+            assert_eq!(map.point((10, 20), 0.0), corner, "canvas {:?}", canvas);
+            assert_eq!(map.center((10, 20)), center, "canvas {:?}", canvas);
+        }
+        // Far corner of a halved image: its last pixel is the canvas's last
+        let map = Mapping::new(&RgbaImage::new(100, 50), (0, 0, 200, 100));
+        assert_eq!(map.point((199, 99), 0.0), (99, 49));
+        assert_eq!(map.point((200, 100), 0.0), (100, 50));
+    }
+
+    #[test]
+    fn pixels_map_from_the_panned_origin() {
+        // 4x, panned to (30, 10)
+        let map = Mapping::new(&RgbaImage::new(100, 48), (30, 10, 25, 12));
+        assert_eq!(map.point((30, 10), 0.0), (0, 0));
+        assert_eq!(map.center((40, 15)), (42, 22));
+        assert_eq!(map.point((55, 22), 0.0), (100, 48));
+        // Pixels panned out of view land off the canvas, to be clipped
+        assert_eq!(map.point((20, 5), 0.0), (-40, -20));
+
+        // Zoomed unevenly, as cells rarely divide a view exactly
+        let map = Mapping::new(&RgbaImage::new(90, 40), (7, 3, 60, 20));
+        assert_eq!(map.scale, (1.5, 2.0));
+        assert_eq!(map.center((10, 3)), (5, 1));
+    }
+
+    #[test]
+    fn the_crosshair_is_drawn_through_the_cursor_pixel() {
+        let mut measure = Measure::new(PathBuf::from("shot.png"), (200, 100), (40, 15));
+        let mut canvas = RgbaImage::new(100, 48);
+        measure.draw(&mut canvas, (30, 10, 25, 12));
+        let grey = Rgba([128, 128, 128, 255]);
+        assert_eq!(*canvas.get_pixel(0, 22), grey);
+        assert_eq!(*canvas.get_pixel(42, 47), grey);
+        // Enlarged enough that the pixel itself is outlined
+        assert_eq!(*canvas.get_pixel(40, 20), PENDING_COLOR);
+        assert_eq!(*canvas.get_pixel(43, 23), PENDING_COLOR);
+        assert_eq!(*canvas.get_pixel(41, 21), Rgba([0, 0, 0, 0]));
+
+        // At 1:1 only the lines are drawn
+        measure.cursor = (5, 5);
+        let mut canvas = RgbaImage::new(200, 100);
+        measure.draw(&mut canvas, (0, 0, 200, 100));
+        assert_eq!(*canvas.get_pixel(5, 99), grey);
+        assert_eq!(*canvas.get_pixel(199, 5), grey);
+        assert_eq!(*canvas.get_pixel(4, 4), Rgba([0, 0, 0, 0]));
+    }
+
+    #[test]
+    fn measurements_reaching_out_of_view_are_clipped() {
+        let mut measure = measuring((200, 100), (35, 12), (150, 90));
+        measure.place();
+        measure.active = false;
+        // 4x, panned to (30, 10): only the top left corner is in view
+        let mut canvas = RgbaImage::new(100, 48);
+        measure.draw(&mut canvas, (30, 10, 25, 12));
+        assert_eq!(*canvas.get_pixel(20, 8), DONE_COLOR);
+        assert_eq!(*canvas.get_pixel(99, 8), DONE_COLOR);
+        assert_eq!(*canvas.get_pixel(20, 47), DONE_COLOR);
+        assert_eq!(*canvas.get_pixel(19, 7), Rgba([0, 0, 0, 0]));
+
+        // Panned past it altogether, nothing is drawn
+        let mut canvas = RgbaImage::new(100, 48);
+        measure.draw(&mut canvas, (0, 0, 25, 12));
+        assert!(canvas.pixels().all(|p| *p == Rgba([0, 0, 0, 0])));
+    }
+
+    #[test]
+    fn the_cursor_stops_at_the_edges_of_the_image() {
+        // Started outside a smaller image, it is put on its last pixel
+        let mut measure = Measure::new(PathBuf::from("shot.png"), (10, 5), (50, 50));
+        assert_eq!(measure.cursor, (9, 4));
+        measure.move_cursor(-100, -3);
+        assert_eq!(measure.cursor, (0, 1));
+        measure.move_cursor(4, 10);
+        assert_eq!(measure.cursor, (4, 4));
+
+        // Another image drops the points and keeps the cursor on it
+        measure.place();
+        measure.reset((3, 3));
+        assert_eq!(measure.anchor, None);
+        assert_eq!(measure.cursor, (2, 2));
+
+        // An empty image has only the one place
+        measure.reset((0, 0));
+        measure.move_cursor(1, 1);
+        assert_eq!(measure.cursor, (0, 0));
+    }
+
+    #[test]
+    fn measurements_count_both_end_pixels() {
+        let m = Measurement {
+            from: (100, 100),
+            to: (0, 0),
+        };
+        assert_eq!(m.size(), (101, 101));
+        assert_eq!(m.rect(), (0, 0, 101, 101));
+        assert_eq!(m.summary(), "141.4px 101×101 1:1");
+
+        let m = Measurement {
+            from: (0, 0),
+            to: (1919, 1079),
+        };
+        assert_eq!(m.summary(), "2201.5px 1920×1080 16:9");
+        assert_eq!(aspect_ratio(1873, 1000), "1.873:1");
+        assert_eq!(aspect_ratio(0, 0), "0:0");
+
+        let measure = measuring((200, 100), (3, 4), (6, 8));
+        assert_eq!(measure.current().map(|m| m.distance()), Some(5.0));
+    }
+}
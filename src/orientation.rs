@@ -0,0 +1,132 @@
+//! Which way up an image is shown.
+//!
+//! Cameras and phones usually store a photo's pixels as the sensor saw them
+//! and record how to turn them upright in the EXIF orientation tag. The
+//! `image` crate ignores the tag, so it is read here and applied after
+//! decoding. The same type tracks the rotate/flip keys.
+
+use std::path::Path;
+
+use image::DynamicImage;
+
+use crate::exif::{self, Tiff};
+
+/// A rotation by quarter turns, optionally preceded by a left-right mirror.
+/// This covers all eight ways of putting a rectangle back in its frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Orientation {
+    /// Clockwise quarter turns, 0 to 3.
+    turns: u8,
+    /// Mirrored left-right before turning.
+    mirrored: bool,
+}
+
+impl Orientation {
+    pub const ROTATE_CW: Orientation = Orientation {
+        turns: 1,
+        mirrored: false,
+    };
+    pub const ROTATE_CCW: Orientation = Orientation {
+        turns: 3,
+        mirrored: false,
+    };
+    pub const FLIP_HORIZONTAL: Orientation = Orientation {
+        turns: 0,
+        mirrored: true,
+    };
+    /// Top-bottom mirror, which is a left-right mirror turned half way round.
+    pub const FLIP_VERTICAL: Orientation = Orientation {
+        turns: 2,
+        mirrored: true,
+    };
+
+    /// The transform that displays an image with EXIF orientation `tag`
+    /// upright. Unknown values are treated as already upright.
+    pub fn from_exif(tag: u16) -> Self {
+        let (turns, mirrored) = match tag {
+            2 => (0, true),
+            3 => (2, false),
+            4 => (2, true),
+            5 => (3, true),
+            6 => (1, false),
+            7 => (1, true),
+            8 => (3, false),
+            _ => (0, false),
+        };
+        Orientation { turns, mirrored }
+    }
+
+    pub fn is_identity(self) -> bool {
+        self == Orientation::default()
+    }
+
+    /// This transform followed by `next`.
+    pub fn then(self, next: Orientation) -> Orientation {
+        // A mirror reverses the direction of any turn made before it
+        let turns = if next.mirrored {
+            next.turns + 4 - self.turns
+        } else {
+            next.turns + self.turns
+        };
+        Orientation {
+            turns: turns % 4,
+            mirrored: self.mirrored != next.mirrored,
+        }
+    }
+
+    /// The transform that undoes this one.
+    pub fn inverse(self) -> Orientation {
+        if self.mirrored {
+            // Mirrored orientations are their own inverse
+            self
+        } else {
+            Orientation {
+                turns: (4 - self.turns) % 4,
+                mirrored: false,
+            }
+        }
+    }
+
+    pub fn apply(self, img: DynamicImage) -> DynamicImage {
+        let img = if self.mirrored { img.fliph() } else { img };
+        match self.turns {
+            1 => img.rotate90(),
+            2 => img.rotate180(),
+            3 => img.rotate270(),
+            _ => img,
+        }
+    }
+
+    /// Move a point given as an offset from the image centre, in fractions
+    /// of the image's width and height.
+    pub fn apply_to_point(self, (x, y): (f64, f64)) -> (f64, f64) {
+        let mut point = if self.mirrored { (-x, y) } else { (x, y) };
+        for _ in 0..self.turns {
+            point = (-point.1, point.0);
+        }
+        point
+    }
+
+    /// Short description for the status bar; empty when upright.
+    pub fn label(self) -> String {
+        match (self.turns, self.mirrored) {
+            (0, false) => String::new(),
+            (0, true) => "flip H".into(),
+            (2, true) => "flip V".into(),
+            (turns, false) => format!("rot {}", turns as u16 * 90),
+            (turns, true) => format!("rot {} flip H", turns as u16 * 90),
+        }
+    }
+}
+
+/// The orientation recorded in the EXIF data of the file at `path`, if any.
+pub fn read_exif(path: &Path) -> Option<Orientation> {
+    exif_orientation(&exif::read_head(path)?)
+}
+
+/// The orientation recorded in EXIF data at the start of a JPEG or TIFF file.
+pub fn exif_orientation(bytes: &[u8]) -> Option<Orientation> {
+    let tiff = Tiff::find(bytes)?;
+    let tag = tiff.number(tiff.first_ifd()?, exif::ORIENTATION)?;
+    Some(Orientation::from_exif(tag as u16))
+}
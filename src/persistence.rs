@@ -0,0 +1,334 @@
+//! Where termview keeps things between runs, worked out once at startup:
+//! the shared thumbnail cache, the download cache, the home trash and the
+//! `--log` file.
+//!
+//! In a container or a system service `$HOME` may be unset, or the
+//! filesystem read-only. A store that can't be used is disabled with the
+//! reason, and whatever depends on it carries on without it: thumbnails
+//! are only kept in memory, deleting needs `--no-trash`, and the session
+//! log stays in the `L` overlay. Code that would write somewhere asks here
+//! rather than reading the environment itself; `termview doctor` prints
+//! what was found.
+
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// One place termview writes to.
+#[derive(Debug, Clone)]
+pub struct Store {
+    /// Where it lives, if a location could be worked out at all.
+    pub path: Option<PathBuf>,
+    /// Why it can't be used, or `None` if it can.
+    pub disabled: Option<String>,
+}
+
+impl Store {
+    fn disabled(path: Option<PathBuf>, reason: impl Into<String>) -> Self {
+        Store {
+            path,
+            disabled: Some(reason.into()),
+        }
+    }
+
+    /// The location, if it can be written to.
+    pub fn usable(&self) -> Option<&Path> {
+        match self.disabled {
+            None => self.path.as_deref(),
+            Some(_) => None,
+        }
+    }
+
+    /// The location, or why there is none to use, for a failed operation.
+    pub fn require(&self) -> io::Result<&Path> {
+        self.usable().ok_or_else(|| {
+            let reason = self.disabled.as_deref().unwrap_or("no location");
+            io::Error::new(io::ErrorKind::Unsupported, reason.to_string())
+        })
+    }
+
+    /// A store at `path`, usable if it can be created or written there.
+    fn at(path: PathBuf) -> Self {
+        match writable(&path) {
+            Ok(()) => Store {
+                path: Some(path),
+                disabled: None,
+            },
+            Err(reason) => Store::disabled(Some(path), reason),
+        }
+    }
+
+    /// A store under an XDG base directory: `$var` if it is set to an
+    /// absolute path, as the spec asks, otherwise `fallback` below `home`.
+    fn xdg(env: &Env<'_>, var: &str, fallback: &str, name: &str, home: Option<&Path>) -> Self {
+        let base = env(var)
+            .filter(|d| Path::new(d).is_absolute())
+            .map(PathBuf::from)
+            .or_else(|| home.map(|h| h.join(fallback)));
+        match base {
+            Some(base) => Store::at(base.join(name)),
+            None => Store::disabled(None, format!("neither ${} nor $HOME is set", var)),
+        }
+    }
+}
+
+/// Looks up an environment variable.
+type Env<'a> = dyn Fn(&str) -> Option<OsString> + 'a;
+
+/// Everything termview may write to, as resolved for this run.
+#[derive(Debug, Clone)]
+pub struct Persistence {
+    /// `$HOME`, for `~` in typed paths and as the XDG fallback.
+    pub home: Option<PathBuf>,
+    /// The freedesktop thumbnail cache, shared with file managers.
+    pub thumbnails: Store,
+    /// Images downloaded from URL arguments, in builds that open them.
+    #[cfg_attr(not(feature = "fetch"), allow(dead_code))]
+    pub downloads: Store,
+    /// The home trash, where deleted images go.
+    pub trash: Store,
+    /// The `--log` file, if one was asked for.
+    pub log: Option<Store>,
+}
+
+impl Persistence {
+    /// Resolve every store from the environment. `thumbnails` is false
+    /// for `--no-thumbnail-cache` and `downloads` for `--no-cache`; `log`
+    /// is the `--log` file.
+    pub fn resolve(thumbnails: bool, downloads: bool, log: Option<&Path>) -> Self {
+        let env = |var: &str| std::env::var_os(var);
+        Persistence::resolve_from(&env, thumbnails, downloads, log)
+    }
+
+    /// `resolve`, reading variables through `env`.
+    fn resolve_from(env: &Env<'_>, thumbnails: bool, downloads: bool, log: Option<&Path>) -> Self {
+        let home = env("HOME")
+            .filter(|h| Path::new(h).is_absolute())
+            .map(PathBuf::from);
+        let thumbnails = if thumbnails {
+            Store::xdg(env, "XDG_CACHE_HOME", ".cache", "thumbnails", home.as_deref())
+        } else {
+            Store::disabled(None, "turned off with --no-thumbnail-cache")
+        };
+        let downloads = if downloads {
+            let name = "termview/downloads";
+            Store::xdg(env, "XDG_CACHE_HOME", ".cache", name, home.as_deref())
+        } else {
+            Store::disabled(None, "turned off with --no-cache")
+        };
+        Persistence {
+            thumbnails,
+            downloads,
+            trash: Store::xdg(env, "XDG_DATA_HOME", ".local/share", "Trash", home.as_deref()),
+            log: log.map(|path| Store::at(path.to_path_buf())),
+            home,
+        }
+    }
+
+    /// Nothing kept between runs, as when no setup has been done yet.
+    pub fn in_memory() -> Self {
+        let off = || Store::disabled(None, "not set up");
+        Persistence {
+            home: None,
+            thumbnails: off(),
+            downloads: off(),
+            trash: off(),
+            log: None,
+        }
+    }
+
+    /// One line per store, for `termview doctor`.
+    pub fn report(&self) -> Vec<(&'static str, String)> {
+        let mut lines = vec![(
+            "home",
+            match &self.home {
+                Some(home) => home.display().to_string(),
+                None => "unset (no ~ in typed paths)".into(),
+            },
+        )];
+        lines.push(("thumbnail cache", describe(&self.thumbnails)));
+        #[cfg(feature = "fetch")]
+        lines.push(("download cache", describe(&self.downloads)));
+        lines.push(("trash", describe(&self.trash)));
+        lines.push((
+            "log file",
+            match &self.log {
+                Some(log) => describe(log),
+                None => "none (--log not given)".into(),
+            },
+        ));
+        lines
+    }
+}
+
+fn describe(store: &Store) -> String {
+    match (&store.path, &store.disabled) {
+        (Some(path), None) => path.display().to_string(),
+        (Some(path), Some(reason)) => format!("{} (disabled: {})", path.display(), reason),
+        (None, Some(reason)) => format!("disabled: {}", reason),
+        (None, None) => "disabled".into(),
+    }
+}
+
+/// Whether `path` can be written, or created along with the folders it
+/// needs. Only looks: nothing is created until a store is first used.
+fn writable(path: &Path) -> Result<(), String> {
+    let existing = path
+        .ancestors()
+        .find(|p| p.exists())
+        .ok_or_else(|| format!("nothing of {} exists", path.display()))?;
+    if existing != path && !existing.is_dir() {
+        return Err(format!("{} isn't a folder", existing.display()));
+    }
+    match access_w(existing) {
+        Ok(()) => Ok(()),
+        Err(e) => Err(format!("{} isn't writable: {}", existing.display(), e)),
+    }
+}
+
+#[cfg(unix)]
+pub fn access_w(path: &Path) -> io::Result<()> {
+    use std::ffi::{CString, OsString};
+    use std::os::unix::ffi::OsStringExt;
+
+    let path = CString::new(OsString::from(path).into_vec())
+        .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+    // access() rather than the permission bits, so read-only mounts count
+    if unsafe { libc::access(path.as_ptr(), libc::W_OK) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(unix))]
+pub fn access_w(path: &Path) -> io::Result<()> {
+    match std::fs::metadata(path)?.permissions().readonly() {
+        false => Ok(()),
+        true => Err(io::Error::from(io::ErrorKind::PermissionDenied)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TestDir;
+    use crate::thumbcache::ThumbCache;
+
+    /// Resolve with only `vars` set.
+    fn resolved(vars: &[(&str, &Path)], log: Option<&Path>) -> Persistence {
+        let env = |var: &str| {
+            vars.iter()
+                .find(|(name, _)| *name == var)
+                .map(|(_, value)| value.as_os_str().to_os_string())
+        };
+        Persistence::resolve_from(&env, true, true, log)
+    }
+
+    #[test]
+    fn nothing_is_kept_without_home() {
+        let p = resolved(&[], None);
+        assert_eq!(p.home, None);
+        let stores = [
+            (&p.thumbnails, "XDG_CACHE_HOME"),
+            (&p.downloads, "XDG_CACHE_HOME"),
+            (&p.trash, "XDG_DATA_HOME"),
+        ];
+        for (store, var) in stores {
+            assert_eq!(store.path, None);
+            assert_eq!(store.usable(), None);
+            let reason = store.disabled.as_deref().unwrap();
+            assert!(reason.contains(var) && reason.contains("$HOME"), "{}", reason);
+            let e = store.require().unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::Unsupported);
+            assert_eq!(e.to_string(), reason);
+        }
+        assert!(ThumbCache::new(&p.thumbnails).is_none());
+
+        let report = p.report();
+        assert_eq!(report[0], ("home", "unset (no ~ in typed paths)".into()));
+        let (log, stores) = report[1..].split_last().unwrap();
+        assert!(stores.iter().all(|(_, line)| line.starts_with("disabled: ")));
+        assert_eq!(*log, ("log file", "none (--log not given)".into()));
+    }
+
+    #[test]
+    fn relative_paths_count_as_unset() {
+        let p = resolved(&[("HOME", Path::new("home")), ("XDG_CACHE_HOME", Path::new("c"))], None);
+        assert_eq!(p.home, None);
+        assert_eq!(p.thumbnails.usable(), None);
+    }
+
+    #[test]
+    fn stores_default_to_below_home() {
+        let tmp = TestDir::new();
+        let p = resolved(&[("HOME", tmp.path())], None);
+        assert_eq!(p.home.as_deref(), Some(tmp.path()));
+        // Not created yet, but creatable
+        let cache = tmp.path().join(".cache/thumbnails");
+        assert_eq!(p.thumbnails.usable(), Some(cache.as_path()));
+        let downloads = tmp.path().join(".cache/termview/downloads");
+        assert_eq!(p.downloads.usable(), Some(downloads.as_path()));
+        let trash = tmp.path().join(".local/share/Trash");
+        assert_eq!(p.trash.usable(), Some(trash.as_path()));
+        assert!(!tmp.path().join(".cache").exists());
+        assert!(ThumbCache::new(&p.thumbnails).is_some());
+    }
+
+    #[test]
+    fn xdg_variables_win_over_home() {
+        let tmp = TestDir::new();
+        let cache = tmp.dir("cache");
+        let p = resolved(&[("HOME", &tmp.path().join("home")), ("XDG_CACHE_HOME", &cache)], None);
+        assert_eq!(p.thumbnails.usable(), Some(cache.join("thumbnails").as_path()));
+    }
+
+    #[test]
+    fn a_file_in_the_way_disables_the_store() {
+        let tmp = TestDir::new();
+        let blocker = tmp.file("data", b"not a folder");
+        let p = resolved(&[("XDG_DATA_HOME", &blocker)], None);
+        assert_eq!(p.trash.usable(), None);
+        assert_eq!(p.trash.path, Some(blocker.join("Trash")));
+        let reason = p.trash.disabled.as_deref().unwrap();
+        assert!(reason.ends_with("isn't a folder"), "{}", reason);
+        assert!(describe(&p.trash).contains("(disabled: "));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn read_only_folders_disable_their_stores() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp = TestDir::new();
+        let ro = tmp.dir("ro");
+        std::fs::set_permissions(&ro, std::fs::Permissions::from_mode(0o555)).unwrap();
+        // Root may write anywhere, so there is nothing to refuse
+        if access_w(&ro).is_ok() {
+            return;
+        }
+        let log = ro.join("session.log");
+        let p = resolved(&[("XDG_CACHE_HOME", &ro), ("XDG_DATA_HOME", &ro)], Some(&log));
+        for store in [&p.thumbnails, &p.downloads, &p.trash, p.log.as_ref().unwrap()] {
+            assert_eq!(store.usable(), None);
+            assert!(store.disabled.as_deref().unwrap().contains("isn't writable"));
+        }
+        assert!(ThumbCache::new(&p.thumbnails).is_none());
+        assert!(std::fs::read_dir(&ro).unwrap().next().is_none());
+        std::fs::set_permissions(&ro, std::fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    #[test]
+    fn turned_off_cache_says_so() {
+        let tmp = TestDir::new();
+        let env = |_: &str| Some(tmp.path().as_os_str().to_os_string());
+        let p = Persistence::resolve_from(&env, false, false, None);
+        assert_eq!(p.thumbnails.usable(), None);
+        assert_eq!(
+            describe(&p.thumbnails),
+            "disabled: turned off with --no-thumbnail-cache"
+        );
+        assert_eq!(describe(&p.downloads), "disabled: turned off with --no-cache");
+        assert!(p.trash.usable().is_some());
+    }
+}
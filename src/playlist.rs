@@ -0,0 +1,249 @@
+//! The list of images being browsed, kept as small IDs into a table of
+//! paths.
+//!
+//! What is known about an image (so far, its grid thumbnail) lives in side
+//! maps keyed by ID and filled as images are visited, so memory grows with
+//! what was looked at rather than with the list. Rescans, filters and new
+//! orders only move IDs: each path keeps the ID it was first given, so an
+//! image still listed keeps its data, and one that goes and comes back
+//! (a file restored from the trash) finds it again.
+
+use std::collections::HashMap;
+use std::ops::Index;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// An image of the list, the same for as long as termview runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct EntryId(u32);
+
+/// Data kept about some of the images, by ID.
+pub type EntryMap<T> = HashMap<EntryId, T>;
+
+#[derive(Debug, Clone, Default)]
+pub struct Playlist {
+    /// The images in the order they are browsed.
+    order: Vec<EntryId>,
+    /// Every path given an ID, indexed by it. Shared with `ids`, so each
+    /// path is stored once.
+    paths: Vec<Arc<Path>>,
+    ids: HashMap<Arc<Path>, EntryId>,
+}
+
+impl Playlist {
+    pub fn new(paths: Vec<PathBuf>) -> Self {
+        let mut playlist = Playlist::default();
+        playlist.replace(paths);
+        playlist
+    }
+
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+
+    pub fn get(&self, index: usize) -> Option<&Path> {
+        self.order.get(index).map(|&id| self.path(id))
+    }
+
+    /// ID of the image at `index`.
+    pub fn id(&self, index: usize) -> Option<EntryId> {
+        self.order.get(index).copied()
+    }
+
+    /// ID `path` was given, whether or not it is still listed.
+    pub fn id_of(&self, path: &Path) -> Option<EntryId> {
+        self.ids.get(path).copied()
+    }
+
+    /// Path of an image, whether or not it is still listed.
+    pub fn path(&self, id: EntryId) -> &Path {
+        &self.paths[id.0 as usize]
+    }
+
+    /// Where the image is in the list.
+    pub fn index_of(&self, id: EntryId) -> Option<usize> {
+        self.order.iter().position(|&listed| listed == id)
+    }
+
+    /// Where `path` is in the list.
+    pub fn position(&self, path: &Path) -> Option<usize> {
+        self.index_of(self.id_of(path)?)
+    }
+
+    pub fn contains(&self, path: &Path) -> bool {
+        self.position(path).is_some()
+    }
+
+    pub fn iter(&self) -> impl ExactSizeIterator<Item = &Path> + '_ {
+        self.order.iter().map(|&id| self.path(id))
+    }
+
+    /// The paths in order, for work done away from the list (a rescan).
+    pub fn to_vec(&self) -> Vec<PathBuf> {
+        self.iter().map(Path::to_path_buf).collect()
+    }
+
+    /// Take the image at `index` off the list.
+    pub fn remove(&mut self, index: usize) -> EntryId {
+        self.order.remove(index)
+    }
+
+    /// List `paths` instead, in their order: the result of a rescan, a
+    /// filter or a new sort. Paths listed before keep their IDs.
+    pub fn replace(&mut self, paths: Vec<PathBuf>) {
+        self.order = paths.into_iter().map(|path| self.intern(path)).collect();
+    }
+
+    fn intern(&mut self, path: PathBuf) -> EntryId {
+        if let Some(&id) = self.ids.get(path.as_path()) {
+            return id;
+        }
+        let id = EntryId(u32::try_from(self.paths.len()).expect("over 4 billion paths"));
+        let path: Arc<Path> = path.into();
+        self.paths.push(Arc::clone(&path));
+        self.ids.insert(path, id);
+        id
+    }
+}
+
+impl Index<usize> for Playlist {
+    type Output = Path;
+
+    fn index(&self, index: usize) -> &Path {
+        self.path(self.order[index])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    fn paths(names: &[&str]) -> Vec<PathBuf> {
+        names.iter().map(PathBuf::from).collect()
+    }
+
+    fn listed(playlist: &Playlist) -> Vec<&str> {
+        playlist.iter().map(|p| p.to_str().unwrap()).collect()
+    }
+
+    /// Marks on `names`, as some feature would keep them.
+    fn marked(playlist: &Playlist, names: &[&str]) -> EntryMap<bool> {
+        names.iter().map(|n| (playlist.id_of(Path::new(n)).unwrap(), true)).collect()
+    }
+
+    fn marks_of<'a>(playlist: &'a Playlist, marks: &EntryMap<bool>) -> Vec<&'a str> {
+        let mut names: Vec<&str> = playlist
+            .iter()
+            .filter(|p| marks.contains_key(&playlist.id_of(p).unwrap()))
+            .map(|p| p.to_str().unwrap())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn images_are_found_by_index_path_and_id() {
+        let playlist = Playlist::new(paths(&["a.png", "b.png", "c.png"]));
+        assert_eq!(playlist.len(), 3);
+        assert_eq!(&playlist[1], Path::new("b.png"));
+        assert_eq!(playlist.get(3), None);
+        let id = playlist.id(2).unwrap();
+        assert_eq!(playlist.path(id), Path::new("c.png"));
+        assert_eq!(playlist.index_of(id), Some(2));
+        assert_eq!(playlist.position(Path::new("a.png")), Some(0));
+        assert!(!playlist.contains(Path::new("d.png")));
+        assert_eq!(playlist.to_vec(), paths(&["a.png", "b.png", "c.png"]));
+    }
+
+    #[test]
+    fn marks_survive_new_orders() {
+        let mut playlist = Playlist::new(paths(&["a.png", "b.png", "c.png", "d.png"]));
+        let marks = marked(&playlist, &["b.png", "d.png"]);
+        let before: Vec<EntryId> = (0..4).map(|i| playlist.id(i).unwrap()).collect();
+
+        // Sorted newest first, say
+        playlist.replace(paths(&["d.png", "c.png", "b.png", "a.png"]));
+        assert_eq!(listed(&playlist), ["d.png", "c.png", "b.png", "a.png"]);
+        assert_eq!(playlist.id(0), Some(before[3]));
+        assert_eq!(marks_of(&playlist, &marks), ["b.png", "d.png"]);
+    }
+
+    #[test]
+    fn marks_survive_filters_and_come_back_with_their_images() {
+        let mut playlist = Playlist::new(paths(&["a.png", "b.png", "c.png"]));
+        let marks = marked(&playlist, &["a.png", "c.png"]);
+
+        playlist.replace(paths(&["b.png", "c.png"]));
+        assert_eq!(marks_of(&playlist, &marks), ["c.png"]);
+        assert!(!playlist.contains(Path::new("a.png")));
+
+        // Filtered back in, or restored from the trash
+        playlist.replace(paths(&["a.png", "b.png", "c.png"]));
+        assert_eq!(marks_of(&playlist, &marks), ["a.png", "c.png"]);
+        assert_eq!(playlist.paths.len(), 3);
+    }
+
+    #[test]
+    fn marks_survive_incremental_rescans() {
+        let mut playlist = Playlist::new(paths(&["a.png", "c.png", "e.png"]));
+        let marks = marked(&playlist, &["c.png", "e.png"]);
+
+        let mut images = playlist.to_vec();
+        let changes = crate::listing::ListDiff {
+            added: paths(&["b.png", "d.png"]),
+            removed: paths(&["e.png"]),
+        };
+        crate::listing::apply(&mut images, &changes, Path::to_path_buf, false);
+        playlist.replace(images);
+        assert_eq!(listed(&playlist), ["a.png", "b.png", "c.png", "d.png"]);
+        assert_eq!(marks_of(&playlist, &marks), ["c.png"]);
+
+        // Taking one off by hand leaves the rest
+        let removed = playlist.remove(2);
+        assert!(marks.contains_key(&removed));
+        assert_eq!(marks_of(&playlist, &marks), Vec::<&str>::new());
+        assert_eq!(playlist.position(Path::new("d.png")), Some(2));
+    }
+
+    /// Library-sized list: a new order and a rescan each take well under a
+    /// second. `cargo test --release playlist -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn benchmark_reordering_a_large_list() {
+        const COUNT: usize = 100_000;
+        let names: Vec<PathBuf> = (0..COUNT)
+            .map(|i| PathBuf::from(format!("/photos/{:03}/IMG_{:06}.jpg", i % 500, i)))
+            .collect();
+
+        let start = Instant::now();
+        let mut playlist = Playlist::new(names.clone());
+        eprintln!("listed {} in {:?}", COUNT, start.elapsed());
+        let marks: EntryMap<bool> =
+            (0..COUNT).step_by(7).map(|i| (playlist.id(i).unwrap(), true)).collect();
+
+        let start = Instant::now();
+        let mut reversed = playlist.to_vec();
+        reversed.sort_by_cached_key(|p| std::cmp::Reverse(p.file_name().map(|n| n.to_owned())));
+        playlist.replace(reversed);
+        let sorted = start.elapsed();
+        eprintln!("sorted in {:?}", sorted);
+
+        let start = Instant::now();
+        let mut rescanned = names;
+        rescanned.retain(|p| !p.to_string_lossy().ends_with("5.jpg"));
+        playlist.replace(rescanned);
+        let filtered = start.elapsed();
+        eprintln!("filtered in {:?}", filtered);
+
+        assert!(sorted.as_secs_f64() < 1.0, "{:?}", sorted);
+        assert!(filtered.as_secs_f64() < 1.0, "{:?}", filtered);
+        let kept = (0..COUNT).step_by(7).filter(|i| i % 10 != 5).count();
+        let still = playlist.iter().filter(|p| marks.contains_key(&playlist.id_of(p).unwrap()));
+        assert_eq!(still.count(), kept);
+    }
+}
@@ -0,0 +1,147 @@
+//! Decoding the neighbours of the displayed image ahead of time.
+//!
+//! A worker thread decodes whatever paths it was last asked for into a small
+//! LRU cache. Asking again replaces the previous request, so skipping quickly
+//! through a directory doesn't queue up decodes of images already passed; at
+//! most the file in progress is finished. Entries remember the file's
+//! modification time and are ignored if the file changed since.
+
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use crate::{Decoded, LoadError, Loader};
+
+type LoadResult = Result<Decoded, LoadError>;
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Decoded images by path, least recently used first.
+struct Cache {
+    entries: Vec<(PathBuf, Option<SystemTime>, LoadResult)>,
+    capacity: usize,
+}
+
+impl Cache {
+    fn contains(&self, path: &Path) -> bool {
+        self.entries.iter().any(|(p, _, _)| p == path)
+    }
+
+    fn insert(&mut self, path: PathBuf, stamp: Option<SystemTime>, result: LoadResult) {
+        self.entries.retain(|(p, _, _)| *p != path);
+        if self.entries.len() >= self.capacity {
+            let _evicted = self.entries.remove(0);
+        }
+        self.entries.push((path, stamp, result));
+    }
+
+    /// Remove and return the entry for `path`, if it is still current.
+    fn take(&mut self, path: &Path) -> Option<LoadResult> {
+        let i = self.entries.iter().position(|(p, _, _)| p == path)?;
+        let (_, stamp, result) = self.entries.remove(i);
+        (stamp.is_some() && stamp == modified(path)).then_some(result)
+    }
+}
+
+struct State {
+    cache: Cache,
+    /// The file the worker is decoding right now.
+    in_flight: Option<PathBuf>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+}
+
+/// What the preloader has for a path.
+pub enum Preloaded {
+    Ready(LoadResult),
+    /// The worker is decoding it right now; ask again shortly rather than
+    /// decoding it twice.
+    Decoding,
+    Missing,
+}
+
+pub struct Preloader {
+    shared: Arc<Shared>,
+    requests: Sender<Vec<PathBuf>>,
+}
+
+impl Preloader {
+    /// Start a worker that keeps up to `capacity` decoded images.
+    pub fn new(loader: Loader, capacity: usize) -> Self {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                cache: Cache {
+                    entries: Vec::new(),
+                    capacity: capacity.max(1),
+                },
+                in_flight: None,
+            }),
+        });
+        let (requests, rx) = mpsc::channel();
+        let worker_shared = Arc::clone(&shared);
+        std::thread::spawn(move || work(&worker_shared, &loader, rx));
+        Preloader { shared, requests }
+    }
+
+    /// Decode `paths` in the background, in order, replacing any earlier request.
+    pub fn prefetch(&self, paths: Vec<PathBuf>) {
+        let _ = self.requests.send(paths);
+    }
+
+    /// Take the decoded image for `path` out of the cache.
+    pub fn take(&self, path: &Path) -> Preloaded {
+        let mut state = self.shared.state.lock().unwrap();
+        if let Some(result) = state.cache.take(path) {
+            return Preloaded::Ready(result);
+        }
+        match state.in_flight.as_deref() == Some(path) {
+            true => Preloaded::Decoding,
+            false => Preloaded::Missing,
+        }
+    }
+
+    /// Hand back an image that is no longer displayed, so returning to it is instant.
+    pub fn put(&self, path: PathBuf, decoded: Decoded) {
+        let stamp = modified(&path);
+        let mut state = self.shared.state.lock().unwrap();
+        state.cache.insert(path, stamp, Ok(decoded));
+    }
+}
+
+/// Worker loop: decode the latest request's paths that aren't cached yet,
+/// switching to a newer request as soon as one arrives.
+fn work(shared: &Shared, loader: &Loader, rx: Receiver<Vec<PathBuf>>) {
+    // Ends when the Preloader (and with it the sender) is dropped
+    while let Ok(mut targets) = rx.recv() {
+        let mut next = 0;
+        while next < targets.len() {
+            if let Some(newer) = rx.try_iter().last() {
+                targets = newer;
+                next = 0;
+                continue;
+            }
+            let path = targets[next].clone();
+            next += 1;
+
+            {
+                let mut state = shared.state.lock().unwrap();
+                if state.cache.contains(&path) {
+                    continue;
+                }
+                state.in_flight = Some(path.clone());
+            }
+
+            let stamp = modified(&path);
+            let result = loader.load(&path);
+
+            let mut state = shared.state.lock().unwrap();
+            state.in_flight = None;
+            state.cache.insert(path, stamp, result);
+        }
+    }
+}
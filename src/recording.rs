@@ -0,0 +1,556 @@
+//! Recording input to a file (`--record-input`) and feeding it back through
+//! the event loop (`--replay`), so an interaction that goes wrong can be
+//! reproduced exactly.
+//!
+//! A recording is a text file. A header gives the terminal size and the
+//! image list it was made against, then each line after `events` is one
+//! input with the milliseconds since the event loop started:
+//!
+//! ```text
+//! termview-recording 1
+//! size 100 30
+//! image ./a.png
+//! image ./b.png
+//! events
+//! 850 key Right
+//! 1320 key C-r
+//! 2004 resize 80 24
+//! 2100 timer Frame
+//! ```
+//!
+//! Keys are written as the character itself, `U+XXXX` for blanks and
+//! control characters, or a name such as `PageDown` or `F5`, prefixed with
+//! `C-`, `A-` and `S-` for Ctrl, Alt and Shift. Lines a version doesn't
+//! understand are skipped, so recordings outlive small format additions.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crossterm::event::{Event, KeyCode, KeyEvent, KeyModifiers};
+
+use crate::playlist::Playlist;
+use crate::scheduler::Timer;
+
+const MAGIC: &str = "termview-recording";
+const VERSION: u32 = 1;
+
+/// One recorded input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Input {
+    /// A key press, as reduced by `normalize_key`.
+    Key(KeyCode, KeyModifiers),
+    Resize(u16, u16),
+    /// A timer that changes the view on a clock; see `replayed`.
+    Timer(Timer),
+}
+
+impl Input {
+    /// The terminal event a key press or resize stands for.
+    pub fn to_event(self) -> Option<Event> {
+        match self {
+            Input::Key(code, mods) => Some(Event::Key(KeyEvent::new(code, mods))),
+            Input::Resize(cols, rows) => Some(Event::Resize(cols, rows)),
+            Input::Timer(_) => None,
+        }
+    }
+
+    fn to_line(self) -> Option<String> {
+        Some(match self {
+            Input::Key(code, mods) => format!("key {}", key_name(code, mods)?),
+            Input::Resize(cols, rows) => format!("resize {} {}", cols, rows),
+            Input::Timer(timer) => format!("timer {:?}", timer),
+        })
+    }
+
+    fn parse(line: &str) -> Option<Input> {
+        let mut words = line.split(' ');
+        let input = match words.next()? {
+            "key" => {
+                let (code, mods) = parse_key(words.next()?)?;
+                Input::Key(code, mods)
+            }
+            "resize" => Input::Resize(words.next()?.parse().ok()?, words.next()?.parse().ok()?),
+            "timer" => Input::Timer(parse_timer(words.next()?)?),
+            _ => return None,
+        };
+        words.next().is_none().then_some(input)
+    }
+}
+
+/// Whether `timer` is taken from the recording during a replay. Timers that
+/// change the view on a clock are; those that look at the filesystem run live
+/// instead, since they act on what is on disk at the time.
+pub fn replayed(timer: Timer) -> bool {
+    match timer {
+        Timer::Frame | Timer::Slideshow => true,
+        Timer::FollowScan
+        | Timer::WaitScan
+        | Timer::Decode
+        | Timer::Watch
+        | Timer::WatchSettle
+        | Timer::Resize
+        | Timer::ListNotice
+        | Timer::Letterbox => false,
+    }
+}
+
+fn parse_timer(name: &str) -> Option<Timer> {
+    Some(match name {
+        "Frame" => Timer::Frame,
+        "Slideshow" => Timer::Slideshow,
+        _ => return None,
+    })
+}
+
+/// Writes inputs to a recording as they happen. Each line is flushed at
+/// once, so a recording ending in a crash is complete up to it.
+pub struct Recorder {
+    file: BufWriter<File>,
+    start: Instant,
+}
+
+impl Recorder {
+    /// Write the header to `file` and start the clock.
+    pub fn new(file: File, size: (u16, u16), images: &Playlist) -> io::Result<Self> {
+        let mut file = BufWriter::new(file);
+        writeln!(file, "{} {}", MAGIC, VERSION)?;
+        writeln!(file, "size {} {}", size.0, size.1)?;
+        for image in images.iter() {
+            writeln!(file, "image {}", image.display())?;
+        }
+        writeln!(file, "events")?;
+        file.flush()?;
+        Ok(Recorder {
+            file,
+            start: Instant::now(),
+        })
+    }
+
+    pub fn record(&mut self, input: Input) -> io::Result<()> {
+        let Some(line) = input.to_line() else {
+            return Ok(());
+        };
+        writeln!(self.file, "{} {}", self.start.elapsed().as_millis(), line)?;
+        self.file.flush()
+    }
+}
+
+/// A recording read back from its file.
+pub struct Recording {
+    /// Terminal size when recording started.
+    pub size: Option<(u16, u16)>,
+    pub images: Vec<PathBuf>,
+    events: Vec<(Duration, Input)>,
+    /// Event lines that couldn't be read, e.g. keys added in a later version.
+    pub skipped: usize,
+}
+
+impl Recording {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+        let mut lines = BufReader::new(File::open(path)?).lines();
+
+        let header = lines.next().transpose()?.unwrap_or_default();
+        let version = header
+            .strip_prefix(MAGIC)
+            .and_then(|v| v.trim().parse::<u32>().ok())
+            .ok_or_else(|| invalid("not a termview recording"))?;
+        if version > VERSION {
+            return Err(invalid("recorded by a newer version of termview"));
+        }
+
+        let mut recording = Recording {
+            size: None,
+            images: Vec::new(),
+            events: Vec::new(),
+            skipped: 0,
+        };
+        let mut in_events = false;
+        for line in lines {
+            let line = line?;
+            if in_events {
+                let event = line.split_once(' ').and_then(|(ms, input)| {
+                    Some((Duration::from_millis(ms.parse().ok()?), Input::parse(input)?))
+                });
+                match event {
+                    Some(event) => recording.events.push(event),
+                    None if line.is_empty() => {}
+                    None => recording.skipped += 1,
+                }
+                continue;
+            }
+            match line.split_once(' ') {
+                Some(("size", size)) => {
+                    recording.size = size
+                        .split_once(' ')
+                        .and_then(|(c, r)| Some((c.parse().ok()?, r.parse().ok()?)));
+                }
+                Some(("image", path)) => recording.images.push(PathBuf::from(path)),
+                _ if line == "events" => in_events = true,
+                // Header lines from a later version
+                _ => {}
+            }
+        }
+        // Lines are written in order, but don't trust a hand-edited file to be
+        recording.events.sort_by_key(|(at, _)| *at);
+        Ok(recording)
+    }
+
+    /// How `images` differs from the list the recording was made against,
+    /// or `None` if they are the same.
+    pub fn differences(&self, images: &Playlist) -> Option<String> {
+        if self.images.len() != images.len() {
+            return Some(format!(
+                "recorded with {} images, replaying with {}",
+                self.images.len(),
+                images.len()
+            ));
+        }
+        let (index, (recorded, now)) = self
+            .images
+            .iter()
+            .zip(images.iter())
+            .enumerate()
+            .find(|(_, (recorded, now))| recorded != now)?;
+        Some(format!(
+            "image {} was {}, now {}",
+            index + 1,
+            recorded.display(),
+            now.display()
+        ))
+    }
+
+    /// Whatever may make replaying against `images` in a terminal of `size`
+    /// go differently from the recording, to warn about.
+    pub fn problems(&self, images: &Playlist, size: (u16, u16)) -> Vec<String> {
+        let mut problems = Vec::new();
+        problems.extend(self.differences(images));
+        if let Some((cols, rows)) = self.size.filter(|&recorded| recorded != size) {
+            problems.push(format!("recorded in a {}x{} terminal", cols, rows));
+        }
+        if self.skipped > 0 {
+            problems.push(format!("{} inputs not understood", self.skipped));
+        }
+        problems
+    }
+}
+
+/// Feeds a recording back, each input when it is due.
+pub struct Replay {
+    events: VecDeque<(Duration, Input)>,
+    start: Instant,
+    /// How much faster than recorded to play (`--replay-speed`).
+    speed: f64,
+}
+
+impl Replay {
+    /// Start playing `recording` now.
+    pub fn new(recording: Recording, speed: f64) -> Self {
+        Replay {
+            events: recording.events.into(),
+            start: Instant::now(),
+            speed,
+        }
+    }
+
+    fn due(&self, now: Instant) -> Option<&Input> {
+        let (at, input) = self.events.front()?;
+        let at = self.start + at.div_f64(self.speed);
+        (at <= now).then_some(input)
+    }
+
+    /// How long until the next input is due, `Duration::ZERO` if one is.
+    pub fn timeout(&self, now: Instant) -> Option<Duration> {
+        let (at, _) = self.events.front()?;
+        Some((self.start + at.div_f64(self.speed)).saturating_duration_since(now))
+    }
+
+    /// The next key press or resize, if it is due and nothing comes before it.
+    pub fn next_event(&mut self, now: Instant) -> Option<Event> {
+        let event = self.due(now)?.to_event()?;
+        self.events.pop_front();
+        Some(event)
+    }
+
+    /// The timers due before the next key press or resize.
+    pub fn timers(&mut self, now: Instant) -> Vec<Timer> {
+        let mut timers = Vec::new();
+        while let Some(&Input::Timer(timer)) = self.due(now) {
+            timers.push(timer);
+            self.events.pop_front();
+        }
+        timers
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.events.is_empty()
+    }
+}
+
+/// A key as written in a recording, or `None` for keys termview never binds.
+fn key_name(code: KeyCode, mods: KeyModifiers) -> Option<String> {
+    let key = match code {
+        KeyCode::Char(c) if c.is_whitespace() || c.is_control() => format!("U+{:04X}", c as u32),
+        KeyCode::Char(c) => c.to_string(),
+        KeyCode::F(n) => format!("F{}", n),
+        code => NAMED_KEYS.iter().find(|(_, k)| *k == code)?.0.to_string(),
+    };
+    let mut name = String::new();
+    for (prefix, modifier) in MODIFIERS {
+        if mods.contains(modifier) {
+            name.push_str(prefix);
+        }
+    }
+    name.push_str(&key);
+    Some(name)
+}
+
+fn parse_key(mut name: &str) -> Option<(KeyCode, KeyModifiers)> {
+    let mut mods = KeyModifiers::NONE;
+    // `C--` is Ctrl with the minus key, so a prefix is only taken if a key follows
+    'prefixes: loop {
+        for (prefix, modifier) in MODIFIERS {
+            if let Some(rest) = name.strip_prefix(prefix).filter(|rest| !rest.is_empty()) {
+                mods |= modifier;
+                name = rest;
+                continue 'prefixes;
+            }
+        }
+        break;
+    }
+
+    let mut chars = name.chars();
+    let code = match (chars.next()?, chars.next()) {
+        (c, None) => KeyCode::Char(c),
+        _ => {
+            if let Some(hex) = name.strip_prefix("U+") {
+                KeyCode::Char(char::from_u32(u32::from_str_radix(hex, 16).ok()?)?)
+            } else if let Some(n) = name.strip_prefix('F').and_then(|n| n.parse().ok()) {
+                KeyCode::F(n)
+            } else {
+                NAMED_KEYS.iter().find(|(n, _)| *n == name)?.1
+            }
+        }
+    };
+    Some((code, mods))
+}
+
+const MODIFIERS: [(&str, KeyModifiers); 3] = [
+    ("C-", KeyModifiers::CONTROL),
+    ("A-", KeyModifiers::ALT),
+    ("S-", KeyModifiers::SHIFT),
+];
+
+const NAMED_KEYS: &[(&str, KeyCode)] = &[
+    ("Left", KeyCode::Left),
+    ("Right", KeyCode::Right),
+    ("Up", KeyCode::Up),
+    ("Down", KeyCode::Down),
+    ("Home", KeyCode::Home),
+    ("End", KeyCode::End),
+    ("PageUp", KeyCode::PageUp),
+    ("PageDown", KeyCode::PageDown),
+    ("Enter", KeyCode::Enter),
+    ("Esc", KeyCode::Esc),
+    ("Tab", KeyCode::Tab),
+    ("BackTab", KeyCode::BackTab),
+    ("Backspace", KeyCode::Backspace),
+    ("Delete", KeyCode::Delete),
+    ("Insert", KeyCode::Insert),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TestDir;
+
+    const RECORDED: &str = "termview-recording 1
+size 100 30
+image ./a.png
+image ./b.png
+events
+850 key Right
+1320 key C-r
+2004 resize 80 24
+2100 timer Frame
+2200 key U+0020
+2300 key C--
+2400 key S-BackTab
+2500 key A-F5
+";
+
+    fn load(tmp: &TestDir, text: &str) -> io::Result<Recording> {
+        Recording::load(&tmp.file("session.tvrec", text.as_bytes()))
+    }
+
+    /// Long after every input is due.
+    fn later() -> Instant {
+        Instant::now() + Duration::from_secs(3600)
+    }
+
+    fn key(code: KeyCode, mods: KeyModifiers) -> Input {
+        Input::Key(code, mods)
+    }
+
+    #[test]
+    fn recordings_are_read_back_in_full() {
+        let tmp = TestDir::new();
+        let recording = load(&tmp, RECORDED).unwrap();
+        assert_eq!(recording.size, Some((100, 30)));
+        assert_eq!(recording.images, [PathBuf::from("./a.png"), PathBuf::from("./b.png")]);
+        assert_eq!(recording.skipped, 0);
+        let ms = |ms| Duration::from_millis(ms);
+        assert_eq!(
+            recording.events,
+            [
+                (ms(850), key(KeyCode::Right, KeyModifiers::NONE)),
+                (ms(1320), key(KeyCode::Char('r'), KeyModifiers::CONTROL)),
+                (ms(2004), Input::Resize(80, 24)),
+                (ms(2100), Input::Timer(Timer::Frame)),
+                (ms(2200), key(KeyCode::Char(' '), KeyModifiers::NONE)),
+                (ms(2300), key(KeyCode::Char('-'), KeyModifiers::CONTROL)),
+                (ms(2400), key(KeyCode::BackTab, KeyModifiers::SHIFT)),
+                (ms(2500), key(KeyCode::F(5), KeyModifiers::ALT)),
+            ]
+        );
+    }
+
+    #[test]
+    fn what_is_recorded_reads_back_the_same() {
+        let tmp = TestDir::new();
+        let path = tmp.path().join("session.tvrec");
+        let images = [PathBuf::from("a.png"), PathBuf::from("sub dir/b.png")];
+        let inputs = [
+            key(KeyCode::Char('+'), KeyModifiers::NONE),
+            key(KeyCode::Char('\t'), KeyModifiers::NONE),
+            key(KeyCode::PageDown, KeyModifiers::CONTROL | KeyModifiers::SHIFT),
+            Input::Resize(120, 40),
+            Input::Timer(Timer::Slideshow),
+        ];
+        let listed = Playlist::new(images.to_vec());
+        let mut recorder = Recorder::new(File::create(&path).unwrap(), (90, 25), &listed).unwrap();
+        for input in inputs {
+            recorder.record(input).unwrap();
+        }
+        // Keys nothing is bound to aren't written
+        recorder.record(key(KeyCode::CapsLock, KeyModifiers::NONE)).unwrap();
+        drop(recorder);
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert!(text.starts_with("termview-recording 1\nsize 90 25\nimage a.png\n"));
+        let recording = Recording::load(&path).unwrap();
+        assert_eq!(recording.size, Some((90, 25)));
+        assert_eq!(recording.images, images);
+        assert_eq!(recording.skipped, 0);
+        let read: Vec<Input> = recording.events.iter().map(|&(_, input)| input).collect();
+        assert_eq!(read, inputs);
+        assert!(recording.events.windows(2).all(|w| w[0].0 <= w[1].0));
+    }
+
+    #[test]
+    fn other_files_and_later_versions_are_refused() {
+        let tmp = TestDir::new();
+        let others = [
+            "",
+            "size 100 30\nevents\n",
+            "termview-recording\n",
+            "termview-recording x\n",
+        ];
+        for text in others {
+            let e = load(&tmp, text).err().unwrap();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+            assert_eq!(e.to_string(), "not a termview recording");
+        }
+        let e = load(&tmp, "termview-recording 2\nevents\n").err().unwrap();
+        assert_eq!(e.to_string(), "recorded by a newer version of termview");
+        // Older versions and this one are read
+        assert!(load(&tmp, "termview-recording 0\nevents\n").is_ok());
+    }
+
+    #[test]
+    fn lines_that_cant_be_read_are_skipped() {
+        let tmp = TestDir::new();
+        let text = "termview-recording 1
+size 100 30
+colour-depth 24
+events
+100 key Right
+soon key Left
+200 key Hyper-x
+300 scroll 1 2
+400 resize 80
+500 resize 80 24 1
+600 timer Watch
+
+700 key Left
+";
+        let recording = load(&tmp, text).unwrap();
+        // The unknown header line is ignored rather than counted
+        assert_eq!(recording.skipped, 6);
+        let read: Vec<Input> = recording.events.iter().map(|&(_, input)| input).collect();
+        assert_eq!(
+            read,
+            [key(KeyCode::Right, KeyModifiers::NONE), key(KeyCode::Left, KeyModifiers::NONE)]
+        );
+    }
+
+    #[test]
+    fn events_are_replayed_in_time_order() {
+        let tmp = TestDir::new();
+        let text = "termview-recording 1\nevents\n300 key b\n100 key a\n200 timer Frame\n";
+        let mut replay = Replay::new(load(&tmp, text).unwrap(), 1.0);
+        // Nothing is due at the start
+        assert_eq!(replay.next_event(Instant::now()), None);
+        assert!(replay.timeout(Instant::now()).unwrap() > Duration::from_millis(50));
+
+        let a = Event::Key(KeyEvent::new(KeyCode::Char('a'), KeyModifiers::NONE));
+        assert_eq!(replay.next_event(later()), Some(a));
+        // A timer waits for its turn; the key after it waits for the timer
+        assert_eq!(replay.next_event(later()), None);
+        assert_eq!(replay.timers(later()), [Timer::Frame]);
+        assert!(replay.next_event(later()).is_some());
+        assert!(replay.is_finished());
+        assert_eq!(replay.timeout(later()), None);
+    }
+
+    #[test]
+    fn a_changed_image_list_is_described() {
+        let tmp = TestDir::new();
+        let recording = load(&tmp, RECORDED).unwrap();
+        let same = Playlist::new(vec!["./a.png".into(), "./b.png".into()]);
+        assert_eq!(recording.differences(&same), None);
+        assert_eq!(
+            recording.differences(&Playlist::new(vec!["./a.png".into()])),
+            Some("recorded with 2 images, replaying with 1".into())
+        );
+        let renamed = Playlist::new(vec!["./a.png".into(), "./c.png".into()]);
+        assert_eq!(
+            recording.differences(&renamed),
+            Some("image 2 was ./b.png, now ./c.png".into())
+        );
+    }
+
+    #[test]
+    fn a_different_terminal_size_is_warned_about() {
+        let tmp = TestDir::new();
+        let recording = load(&tmp, RECORDED).unwrap();
+        let images = Playlist::new(recording.images.clone());
+        assert!(recording.problems(&images, (100, 30)).is_empty());
+        assert_eq!(
+            recording.problems(&images, (80, 24)),
+            ["recorded in a 100x30 terminal"]
+        );
+
+        // Without a size line there is nothing to compare
+        let mut text = RECORDED.replace("size 100 30\n", "");
+        text.push_str("900 key Hyper-x\n");
+        let recording = load(&tmp, &text).unwrap();
+        assert_eq!(
+            recording.problems(&Playlist::new(vec!["./a.png".into()]), (80, 24)),
+            ["recorded with 2 images, replaying with 1", "1 inputs not understood"]
+        );
+    }
+}
@@ -0,0 +1,2227 @@
+//! Image output backends.
+//!
+//! The kitty graphics protocol gives native pixels and is preferred. Sixel
+//! covers terminals (and tmux builds) without it, and unicode half-blocks
+//! with truecolor work nearly everywhere as a last resort. Everything else
+//! (status bar, overlays) is plain text and draws the same over all three.
+
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use base64::Engine;
+use crossterm::{
+    cursor, event,
+    style::{self, SetBackgroundColor, SetForegroundColor},
+    QueueableCommand,
+};
+use image::imageops::FilterType;
+use image::{DynamicImage, GenericImageView, RgbaImage};
+
+use crate::reply::{Reply, ReplyParser};
+use crate::CellRect;
+
+/// A rectangle of image pixels: (x, y, width, height).
+pub type PixelRect = (u32, u32, u32, u32);
+
+/// How an image is scaled into the cells it is drawn in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Scaling {
+    /// Largest magnification allowed.
+    pub max: f64,
+    /// Scale width and height separately to fill the area, ignoring the
+    /// aspect ratio.
+    pub stretch: bool,
+}
+
+impl Scaling {
+    /// Shrink to fit, never enlarging.
+    pub const FIT: Scaling = Scaling {
+        max: 1.0,
+        stretch: false,
+    };
+}
+
+/// The part of an image to draw, and how to scale it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct View {
+    pub rect: PixelRect,
+    pub scaling: Scaling,
+    /// Outline each image pixel, for views enlarged far enough to see them.
+    pub grid: bool,
+}
+
+/// Which image output backend to use (`--protocol`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Protocol {
+    /// Query the terminal and pick the best supported backend
+    Auto,
+    /// Kitty graphics protocol (native pixels)
+    Kitty,
+    /// DEC sixel graphics (256-colour palette)
+    Sixel,
+    /// Unicode half-blocks with truecolor (two pixels per cell)
+    Blocks,
+    /// Unicode half-blocks in the terminal's 16 colours, dithered, for
+    /// consoles without truecolor
+    Blocks16,
+}
+
+/// How fast the connection to the terminal is (`--link-speed`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum LinkSpeed {
+    /// Measure how fast images are written and adapt
+    Auto,
+    /// Always send full-resolution images
+    Fast,
+    /// Always send reduced, compressed images
+    Slow,
+}
+
+/// Something that can put an image on screen.
+pub trait Renderer {
+    /// Draw `img` scaled to fit and centered within the `area` cell rectangle.
+    fn display(
+        &mut self,
+        out: &mut dyn Write,
+        img: &DynamicImage,
+        area: CellRect,
+        cell_size: (u16, u16),
+    ) -> io::Result<()>;
+
+    /// Draw the `view.rect` rectangle of `img` (in its pixels), scaled into
+    /// `area` as `view.scaling` says. `key` changes whenever the pixels of
+    /// `img` do, which lets a backend keep `img` on the terminal side and only
+    /// move the view.
+    fn display_view(
+        &mut self,
+        out: &mut dyn Write,
+        img: &DynamicImage,
+        view: View,
+        _key: u64,
+        area: CellRect,
+        cell_size: (u16, u16),
+    ) -> io::Result<()> {
+        self.display(out, &view_pixels(img, view, area, cell_size), area, cell_size)
+    }
+
+    /// Remove anything drawn that clearing the cells underneath won't erase.
+    fn clear(&mut self, out: &mut dyn Write) -> io::Result<()>;
+
+    /// Everything drawn was deleted behind the backend's back, as when the
+    /// terminal was handed back during a suspend, so none of it can be reused.
+    fn forget(&mut self) {}
+
+    /// How much detail images are sent with and why, for backends that adapt
+    /// it to the link.
+    fn quality(&self) -> Option<&'static str> {
+        None
+    }
+
+    /// A renderer with the same settings, to draw animation frames ahead of
+    /// time on another thread into bytes written out when each is due. It
+    /// leaves nothing in the terminal for the next frame to build on.
+    fn frame_encoder(&self) -> Box<dyn Renderer + Send>;
+}
+
+pub fn renderer_for(
+    protocol: Protocol,
+    link: LinkSpeed,
+    caps: KittyCaps,
+    palette: Palette,
+    gamma: f64,
+) -> Box<dyn Renderer> {
+    match protocol {
+        Protocol::Kitty | Protocol::Auto => Box::new(Kitty {
+            caps,
+            link,
+            throughput: Throughput::default(),
+            retained: None,
+            input_pending,
+        }),
+        Protocol::Sixel => Box::new(Sixel),
+        Protocol::Blocks => Box::new(Blocks { gamma }),
+        Protocol::Blocks16 => Box::new(Blocks16 {
+            quantizer: Quantizer::new(palette),
+            gamma,
+            drawn: None,
+        }),
+    }
+}
+
+/// Where an `img_w` x `img_h` pixel image lands when fitted into `area`:
+/// its size in pixels after scaling, and the cells it covers, centered.
+pub fn fit_image(
+    img_w: u32,
+    img_h: u32,
+    area: CellRect,
+    cell_width_px: u16,
+    cell_height_px: u16,
+) -> ((u32, u32), CellRect) {
+    fit_scaled(img_w, img_h, area, (cell_width_px, cell_height_px), Scaling::FIT)
+}
+
+/// Like `fit_image`, but scaling as `scaling` says.
+pub fn fit_scaled(
+    img_w: u32,
+    img_h: u32,
+    area: CellRect,
+    (cell_width_px, cell_height_px): (u16, u16),
+    scaling: Scaling,
+) -> ((u32, u32), CellRect) {
+    let (area_col, area_row, cols, rows) = area;
+    let avail_px_w = cols as u32 * cell_width_px as u32;
+    let avail_px_h = rows as u32 * cell_height_px as u32;
+
+    // Scale to fit while preserving aspect ratio, unless stretching
+    let fit_x = avail_px_w as f64 / img_w.max(1) as f64;
+    let fit_y = avail_px_h as f64 / img_h.max(1) as f64;
+    let (scale_x, scale_y) = if scaling.stretch {
+        (fit_x, fit_y)
+    } else {
+        let scale = fit_x.min(fit_y).min(scaling.max);
+        (scale, scale)
+    };
+
+    // Rounded, so a view that fills the area exactly isn't a pixel short on
+    // one axis and not the other
+    let disp_w = ((img_w as f64 * scale_x).round() as u32).max(1);
+    let disp_h = ((img_h as f64 * scale_y).round() as u32).max(1);
+
+    // Center the image: compute the column/row offset
+    let img_cols = disp_w.div_ceil(cell_width_px as u32).min(cols as u32);
+    let img_rows = disp_h.div_ceil(cell_height_px as u32).min(rows as u32);
+    let col_offset = (cols as u32).saturating_sub(img_cols) / 2;
+    let row_offset = (rows as u32).saturating_sub(img_rows) / 2;
+
+    (
+        (disp_w, disp_h),
+        (
+            area_col + col_offset as u16,
+            area_row + row_offset as u16,
+            img_cols as u16,
+            img_rows as u16,
+        ),
+    )
+}
+
+/// The pixels of `view` for `display` to fit into `area`: resized here if the
+/// view is enlarged, since `display` only ever shrinks, and cropped otherwise.
+fn view_pixels<'a>(
+    img: &'a DynamicImage,
+    view: View,
+    area: CellRect,
+    cell_size: (u16, u16),
+) -> Cow<'a, DynamicImage> {
+    let (x, y, w, h) = view.rect;
+    if view.scaling != Scaling::FIT {
+        Cow::Owned(scaled_view(img, view, area, cell_size))
+    } else if (x, y, w, h) == (0, 0, img.width(), img.height()) {
+        Cow::Borrowed(img)
+    } else {
+        Cow::Owned(img.crop_imm(x, y, w, h))
+    }
+}
+
+/// The `view` of `img` resized to the size it is shown at in `area`, for a
+/// backend to draw as it is, or for drawing over before `Renderer::display`.
+pub fn scaled_view(img: &DynamicImage, view: View, area: CellRect, cell_size: (u16, u16)) -> DynamicImage {
+    let (x, y, w, h) = view.rect;
+    let ((disp_w, disp_h), _) = fit_scaled(w, h, area, cell_size, view.scaling);
+    if view.grid {
+        return DynamicImage::ImageRgba8(gridded(&img.crop_imm(x, y, w, h), disp_w, disp_h));
+    }
+    resize_to(&img.crop_imm(x, y, w, h), disp_w, disp_h)
+}
+
+/// `img` enlarged to `w` x `h` by repeating pixels, with the first row and
+/// column of each of its pixels shaded to outline it. Pixels and lines come
+/// from the same integer mapping, so however the scale divides, every line
+/// falls on a pixel boundary and none drift across the image.
+fn gridded(img: &DynamicImage, w: u32, h: u32) -> RgbaImage {
+    let src = img.to_rgba8();
+    let (src_w, src_h) = src.dimensions();
+    let col = |x: u32| (x as u64 * src_w as u64 / w as u64) as u32;
+    let row = |y: u32| (y as u64 * src_h as u64 / h as u64) as u32;
+    RgbaImage::from_fn(w, h, |x, y| {
+        let (sx, sy) = (col(x), row(y));
+        let mut px = *src.get_pixel(sx, sy);
+        let edge = x == 0 || y == 0 || col(x - 1) != sx || row(y - 1) != sy;
+        if edge {
+            shade(&mut px);
+        }
+        px
+    })
+}
+
+/// Make a grid line out of a pixel: darker if it is light, lighter if it is
+/// dark, and grey over transparency, so the line shows against anything.
+pub fn shade(px: &mut image::Rgba<u8>) {
+    if px[3] < ALPHA_THRESHOLD {
+        *px = image::Rgba([128, 128, 128, 255]);
+        return;
+    }
+    let luma = (px[0] as u32 * 299 + px[1] as u32 * 587 + px[2] as u32 * 114) / 1000;
+    for c in &mut px.0[..3] {
+        let v = *c as u32;
+        *c = if luma >= 128 {
+            v - v * 3 / 8
+        } else {
+            v + (255 - v) * 3 / 8
+        } as u8;
+    }
+}
+
+fn resize_to(img: &DynamicImage, w: u32, h: u32) -> DynamicImage {
+    if (w, h) != img.dimensions() {
+        img.resize_exact(w, h, FilterType::Lanczos3)
+    } else {
+        img.clone()
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Link speed
+// ---------------------------------------------------------------------------
+
+/// Writes smaller than this mostly land in kernel and network buffers, so
+/// timing them says little about the link.
+const MIN_SAMPLE_BYTES: usize = 256 * 1024;
+
+/// Below this rate a full-resolution image takes noticeably long to arrive.
+const SLOW_LINK_BYTES_PER_SEC: f64 = 4.0 * 1024.0 * 1024.0;
+
+/// Recent image transmissions: how many bytes, and how long writing them took.
+#[derive(Debug, Default)]
+struct Throughput {
+    samples: VecDeque<(usize, Duration)>,
+}
+
+impl Throughput {
+    const MAX_SAMPLES: usize = 8;
+
+    fn record(&mut self, bytes: usize, elapsed: Duration) {
+        if bytes < MIN_SAMPLE_BYTES {
+            return;
+        }
+        if self.samples.len() == Self::MAX_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((bytes, elapsed));
+    }
+
+    /// Average rate over the recorded transmissions, if there are any.
+    fn bytes_per_sec(&self) -> Option<f64> {
+        let bytes: usize = self.samples.iter().map(|(b, _)| b).sum();
+        let secs: f64 = self.samples.iter().map(|(_, t)| t.as_secs_f64()).sum();
+        (bytes > 0).then(|| bytes as f64 / secs.max(1e-6))
+    }
+}
+
+/// How much image data to send.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Quality {
+    /// Every pixel of the fitted image, uncompressed.
+    Full,
+    /// Half the fitted resolution, zlib-compressed, scaled up by the terminal.
+    Reduced,
+}
+
+impl Quality {
+    /// What sending at this quality means, given the link setting it was
+    /// chosen under.
+    fn describe(self, link: LinkSpeed) -> &'static str {
+        match (self, link) {
+            (Quality::Full, LinkSpeed::Auto) => "full resolution",
+            (Quality::Full, _) => "full resolution (--link-speed fast)",
+            (Quality::Reduced, LinkSpeed::Auto) => {
+                "half resolution, compressed: the link measured under 4 MB/s"
+            }
+            (Quality::Reduced, _) => "half resolution, compressed (--link-speed slow)",
+        }
+    }
+}
+
+/// Pick the quality for the next image from the link setting and the rate
+/// measured so far (`None` until something big enough has been sent).
+fn choose_quality(link: LinkSpeed, measured: Option<f64>) -> Quality {
+    match link {
+        LinkSpeed::Fast => Quality::Full,
+        LinkSpeed::Slow => Quality::Reduced,
+        LinkSpeed::Auto => match measured {
+            Some(rate) if rate < SLOW_LINK_BYTES_PER_SEC => Quality::Reduced,
+            _ => Quality::Full,
+        },
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Detection
+// ---------------------------------------------------------------------------
+
+/// Which kitty graphics keys to use (`--kitty-quirks`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum KittyQuirks {
+    /// Go by the terminal's name and version (XTVERSION)
+    Auto,
+    /// Only the basic transmit-and-display commands, for older or partial
+    /// implementations that print errors or garbage for newer keys
+    Legacy,
+    /// Everything termview knows how to use
+    Strict,
+}
+
+/// The kitty graphics keys beyond plain transmit-and-display that the
+/// terminal is trusted with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KittyCaps {
+    /// Image and placement ids (`i=`, `p=`, `a=t`, `a=p`), so an image can
+    /// stay in the terminal and be placed again. Commands with an id make
+    /// the terminal reply, so these come with `q=2` to silence it.
+    pub placements: bool,
+    /// `o=z` zlib-compressed payloads.
+    pub compression: bool,
+}
+
+impl KittyCaps {
+    pub const MODERN: KittyCaps = KittyCaps {
+        placements: true,
+        compression: true,
+    };
+    pub const LEGACY: KittyCaps = KittyCaps {
+        placements: false,
+        compression: false,
+    };
+
+    pub fn name(self) -> &'static str {
+        if self == KittyCaps::MODERN {
+            "full"
+        } else {
+            "legacy subset"
+        }
+    }
+}
+
+/// A `major.minor.patch` version number.
+type Version = (u32, u32, u32);
+
+/// What termview knows about kitty graphics support by the name terminals
+/// report to XTVERSION: the first version that handles every key it uses,
+/// or `None` for implementations that only manage the basics. Terminals not
+/// listed get the full set, since they already passed the detection query.
+const KITTY_SUPPORT: &[(&str, Option<Version>)] = &[
+    ("kitty", Some((0, 20, 0))),
+    ("WezTerm", Some((0, 0, 0))),
+    ("ghostty", Some((0, 0, 0))),
+    ("Konsole", None),
+];
+
+/// Decide which keys to use, from `--kitty-quirks` and the XTVERSION reply.
+pub fn kitty_caps(quirks: KittyQuirks, terminal: Option<&str>) -> KittyCaps {
+    match quirks {
+        KittyQuirks::Legacy => return KittyCaps::LEGACY,
+        KittyQuirks::Strict => return KittyCaps::MODERN,
+        KittyQuirks::Auto => {}
+    }
+    let Some(terminal) = terminal else {
+        // Windows Terminal doesn't answer XTVERSION, and some builds choke on newer keys
+        return if std::env::var_os("WT_SESSION").is_some() {
+            KittyCaps::LEGACY
+        } else {
+            KittyCaps::MODERN
+        };
+    };
+
+    // Replies look like "kitty(0.35.2)" or "WezTerm 20240203-110809-5046fc22"
+    let name_end = terminal
+        .find(['(', ' '])
+        .unwrap_or(terminal.len());
+    let (name, rest) = terminal.split_at(name_end);
+    let version = parse_version(rest.trim_start_matches([' ', '(']));
+    match KITTY_SUPPORT.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)) {
+        Some((_, None)) => KittyCaps::LEGACY,
+        Some((_, Some(since))) if version.is_some_and(|v| v < *since) => KittyCaps::LEGACY,
+        _ => KittyCaps::MODERN,
+    }
+}
+
+/// `major.minor.patch` from the start of `s`; missing parts count as 0.
+fn parse_version(s: &str) -> Option<Version> {
+    let end = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let mut parts = s[..end].split('.').map(|p| p.parse::<u32>().ok());
+    let major = parts.next()??;
+    let minor = parts.next().flatten().unwrap_or(0);
+    let patch = parts.next().flatten().unwrap_or(0);
+    Some((major, minor, patch))
+}
+
+/// Ask the terminal for its name and version (XTVERSION). Must be called in
+/// raw mode. `None` if it doesn't say.
+pub fn terminal_version() -> Option<String> {
+    #[cfg(unix)]
+    {
+        // XTVERSION, then DA1 so terminals that ignore it still end the wait
+        let replies = query_tty(b"\x1b[>0q\x1b[c", std::time::Duration::from_millis(500))?;
+        replies.into_iter().find_map(|r| match r {
+            Reply::TerminalVersion(version) => Some(version),
+            _ => None,
+        })
+    }
+    #[cfg(not(unix))]
+    None
+}
+
+/// Work out which backend the terminal supports. Must be called in raw mode.
+///
+/// Sends a kitty graphics query followed by a primary device attributes
+/// request. Every terminal answers DA1, so its arrival ends the wait; a kitty
+/// `OK` before it means kitty graphics work, and a `4` among the DA1
+/// attributes means sixel does.
+pub fn detect() -> Protocol {
+    #[cfg(unix)]
+    {
+        // 1x1 RGB query image, a=q: only report whether it would be accepted
+        const QUERY: &[u8] = b"\x1b_Gi=31,s=1,v=1,a=q,t=d,f=24;AAAA\x1b\\\x1b[c";
+        if let Some(replies) = query_tty(QUERY, std::time::Duration::from_millis(500)) {
+            return protocol_from_replies(&replies);
+        }
+    }
+
+    // No answer: go by what the environment says
+    let term = std::env::var("TERM").unwrap_or_default();
+    let term_program = std::env::var("TERM_PROGRAM").unwrap_or_default();
+    if term.contains("kitty")
+        || term.contains("foot")
+        || term.contains("ghostty")
+        || term_program == "WezTerm"
+        || std::env::var_os("WT_SESSION").is_some()
+    {
+        Protocol::Kitty
+    } else {
+        blocks_for_env()
+    }
+}
+
+/// Half-blocks in truecolor, or in 16 colours on terminals known to have no
+/// more: the Linux console and anything whose terminfo name says so.
+fn blocks_for_env() -> Protocol {
+    let term = std::env::var("TERM").unwrap_or_default();
+    let colorterm = std::env::var("COLORTERM").unwrap_or_default();
+    let truecolor = colorterm == "truecolor" || colorterm == "24bit";
+    if !truecolor && (term == "linux" || term.ends_with("-16color")) {
+        Protocol::Blocks16
+    } else {
+        Protocol::Blocks
+    }
+}
+
+/// Pick a backend from what the terminal sent back to the detection query.
+fn protocol_from_replies(replies: &[Reply]) -> Protocol {
+    let kitty = replies.iter().any(|r| {
+        matches!(r, Reply::Graphics { id: Some(31), message } if message == "OK")
+    });
+    if kitty {
+        return Protocol::Kitty;
+    }
+
+    let sixel = replies
+        .iter()
+        .any(|r| matches!(r, Reply::DeviceAttributes(attrs) if attrs.contains(&4)));
+    if sixel {
+        Protocol::Sixel
+    } else {
+        blocks_for_env()
+    }
+}
+
+/// Ask the terminal for the size of a cell in pixels: directly (`CSI 16 t`),
+/// or as the size of its text area (`CSI 14 t`) split over the `cols` x
+/// `rows` grid. `None` if it answers neither within `timeout`.
+pub fn query_cell_size(grid: (u16, u16), timeout: std::time::Duration) -> Option<(u16, u16)> {
+    #[cfg(unix)]
+    {
+        let replies = query_tty(b"\x1b[16t\x1b[14t\x1b[c", timeout)?;
+        cell_size_from_replies(&replies, grid)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (grid, timeout);
+        None
+    }
+}
+
+/// Ask the terminal for its 16 ANSI colours (`OSC 4 ; n ; ?`). Colours it
+/// doesn't report keep their `Palette::VGA` value; `None` if it reports none.
+pub fn query_palette(timeout: std::time::Duration) -> Option<Palette> {
+    #[cfg(unix)]
+    {
+        let mut query: Vec<u8> = (0..16)
+            .flat_map(|i| format!("\x1b]4;{};?\x1b\\", i).into_bytes())
+            .collect();
+        query.extend_from_slice(b"\x1b[c");
+        let replies = query_tty(&query, timeout)?;
+        palette_from_replies(&replies)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = timeout;
+        None
+    }
+}
+
+/// Fill in a palette from what the terminal sent back to `query_palette`.
+#[cfg_attr(not(unix), allow(dead_code))]
+fn palette_from_replies(replies: &[Reply]) -> Option<Palette> {
+    let mut palette = Palette::VGA;
+    let mut reported = false;
+    for reply in replies {
+        if let Reply::PaletteColor { index, color } = *reply {
+            if let Some(entry) = palette.0.get_mut(index as usize) {
+                *entry = color;
+                reported = true;
+            }
+        }
+    }
+    reported.then_some(palette)
+}
+
+/// Work out the cell size from what the terminal sent back to `query_cell_size`.
+#[cfg_attr(not(unix), allow(dead_code))]
+fn cell_size_from_replies(replies: &[Reply], (cols, rows): (u16, u16)) -> Option<(u16, u16)> {
+    let cell = replies.iter().find_map(|r| match *r {
+        Reply::CellSize { width, height } => Some((width, height)),
+        _ => None,
+    });
+    let from_window = || {
+        replies.iter().find_map(|r| match *r {
+            Reply::WindowSize { width, height } if cols > 0 && rows > 0 => {
+                Some((width / cols, height / rows))
+            }
+            _ => None,
+        })
+    };
+    cell.or_else(from_window).filter(|&(w, h)| w > 0 && h > 0)
+}
+
+/// Write `query` to the terminal and collect the replies up to and including
+/// the DA1 response, or `None` if it doesn't arrive within `timeout`.
+///
+/// Keys typed while waiting are read from the tty directly rather than
+/// through crossterm, so they are kept for `typed_ahead` instead of being
+/// returned.
+#[cfg(unix)]
+fn query_tty(query: &[u8], timeout: std::time::Duration) -> Option<Vec<Reply>> {
+    use std::io::Read;
+    use std::os::unix::io::AsRawFd;
+    use std::time::Instant;
+
+    let mut tty = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")
+        .ok()?;
+    tty.write_all(query).ok()?;
+    tty.flush().ok()?;
+
+    let deadline = Instant::now() + timeout;
+    let mut parser = ReplyParser::new();
+    let mut replies = Vec::new();
+    let mut buf = [0u8; 256];
+    let answered = loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            break false;
+        }
+        let mut fds = libc::pollfd {
+            fd: tty.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let ready = unsafe { libc::poll(&mut fds, 1, left.as_millis() as libc::c_int) };
+        if ready <= 0 {
+            break false;
+        }
+        let n = match tty.read(&mut buf) {
+            Ok(n) if n > 0 => n,
+            _ => break false,
+        };
+        for reply in parser.feed(&buf[..n]) {
+            match reply {
+                Reply::Input(bytes) => lock_typed_ahead().extend(bytes),
+                reply => replies.push(reply),
+            }
+        }
+        if replies.iter().any(|r| matches!(r, Reply::DeviceAttributes(_))) {
+            break true;
+        }
+    };
+    lock_typed_ahead().extend(parser.finish());
+    answered.then_some(replies)
+}
+
+/// Input read by `query_tty`, in the order it was typed.
+static TYPED_AHEAD: Mutex<Vec<u8>> = Mutex::new(Vec::new());
+
+fn lock_typed_ahead() -> MutexGuard<'static, Vec<u8>> {
+    TYPED_AHEAD.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// The keys pressed while waiting for replies to queries, for the event
+/// loop to handle before any read since.
+pub fn typed_ahead() -> Vec<event::KeyEvent> {
+    crate::reply::keys(&std::mem::take(&mut *lock_typed_ahead()))
+}
+
+/// Block until the terminal has processed everything written to it so far,
+/// by sending a device attributes request and waiting for the answer.
+/// Gives up after `timeout` when the terminal doesn't answer.
+pub fn wait_for_terminal(timeout: std::time::Duration) {
+    #[cfg(unix)]
+    {
+        let _ = query_tty(b"\x1b[c", timeout);
+    }
+    #[cfg(not(unix))]
+    let _ = timeout;
+}
+
+/// Put `text` on the clipboard through the terminal (OSC 52), which works
+/// over SSH. Terminals that don't support it, or have it turned off,
+/// ignore the request.
+pub fn copy_to_clipboard(out: &mut dyn Write, text: &str) -> io::Result<()> {
+    let b64 = base64::engine::general_purpose::STANDARD.encode(text);
+    write!(out, "\x1b]52;c;{}\x1b\\", b64)
+}
+
+// ---------------------------------------------------------------------------
+// Kitty graphics protocol
+// ---------------------------------------------------------------------------
+
+/// One kitty graphics command, `ESC _ G keys ; payload ESC \`, built from
+/// the keys in the order they are given. Keys the terminal isn't trusted
+/// with by its `KittyCaps` are left out, so the same code serves both sets.
+struct GraphicsCommand<'a> {
+    caps: KittyCaps,
+    keys: Vec<(char, String)>,
+    payload: Option<&'a str>,
+}
+
+impl<'a> GraphicsCommand<'a> {
+    fn new(caps: KittyCaps) -> Self {
+        GraphicsCommand {
+            caps,
+            keys: Vec::new(),
+            payload: None,
+        }
+    }
+
+    fn key(mut self, key: char, value: impl std::fmt::Display) -> Self {
+        self.keys.push((key, value.to_string()));
+        self
+    }
+
+    /// An image (`i=`) or placement (`p=`) id, if ids are supported.
+    fn id(self, key: char, id: u32) -> Self {
+        if self.caps.placements {
+            self.key(key, id)
+        } else {
+            self
+        }
+    }
+
+    /// `q=2`: no replies. Only sent with placements, the commands that get any.
+    fn quiet(self) -> Self {
+        if self.caps.placements {
+            self.key('q', 2)
+        } else {
+            self
+        }
+    }
+
+    /// `o=z`: the payload is zlib-compressed, if compression is supported.
+    fn compressed(self) -> Self {
+        if self.caps.compression {
+            self.key('o', 'z')
+        } else {
+            self
+        }
+    }
+
+    fn payload(mut self, payload: &'a str) -> Self {
+        self.payload = Some(payload);
+        self
+    }
+
+    fn write(&self, out: &mut dyn Write) -> io::Result<()> {
+        write!(out, "{}", self)
+    }
+}
+
+impl std::fmt::Display for GraphicsCommand<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("\x1b_G")?;
+        for (i, (key, value)) in self.keys.iter().enumerate() {
+            let sep = if i == 0 { "" } else { "," };
+            write!(f, "{}{}={}", sep, key, value)?;
+        }
+        if let Some(payload) = self.payload {
+            write!(f, ";{}", payload)?;
+        }
+        f.write_str("\x1b\\")
+    }
+}
+
+/// Delete all kitty graphics placements from the screen and free their data.
+pub fn kitty_clear(out: &mut dyn Write) -> io::Result<()> {
+    // a=d (delete), d=A (all placements, freeing the images)
+    GraphicsCommand::new(KittyCaps::LEGACY).key('a', 'd').key('d', 'A').write(out)
+}
+
+/// Id of the image shown in the single-image view. Transmitting with the same
+/// id replaces the previous image, so switching files never leaves old pixel
+/// data behind in the terminal.
+const VIEW_IMAGE_ID: u32 = 1;
+
+/// Don't keep images larger than this many times the area they are shown in
+/// on the terminal; deep zooms into huge images send just the visible crop.
+const MAX_RETAINED_AREA_RATIO: u64 = 4;
+
+/// How far from the view's scale, as a share of its size, a placement may
+/// be drawn on either axis before the view is scaled here instead: a pixel
+/// across a 200 pixel button. The terminal filling whole cells would
+/// otherwise stretch an axis by up to a cell.
+const MAX_PLACEMENT_DISTORTION: f64 = 0.005;
+
+/// Set when termview has been told to exit by a signal, so a transfer in
+/// progress stops at its next check for input rather than holding up the exit.
+pub static TERMINATING: AtomicBool = AtomicBool::new(false);
+
+/// Image data written between checks for input. Over a slow link a large
+/// image takes seconds to send; a key pressed meanwhile abandons the rest at
+/// the next check, so this bounds how long the key waits.
+const TRANSMIT_BATCH: usize = 16 * 1024;
+
+/// Whether a transfer should stop: a key is waiting, or a signal said to exit.
+fn input_pending() -> io::Result<bool> {
+    Ok(TERMINATING.load(Ordering::SeqCst) || event::poll(Duration::ZERO)?)
+}
+
+/// The image held in the terminal under `VIEW_IMAGE_ID`.
+#[derive(Debug, Clone, Copy)]
+struct Retained {
+    /// `key` passed to `display_view` for the source image.
+    key: u64,
+    /// Size of the source image, and of the copy transmitted.
+    source: (u32, u32),
+    sent: (u32, u32),
+}
+
+pub struct Kitty {
+    caps: KittyCaps,
+    link: LinkSpeed,
+    throughput: Throughput,
+    retained: Option<Retained>,
+    /// Checked between batches of a transfer; `input_pending` outside tests.
+    input_pending: fn() -> io::Result<bool>,
+}
+
+impl Kitty {
+    /// Send `rgba` pixels of size `w` x `h`, chunked into 4096-byte base64
+    /// payloads. `command` holds the first chunk's leading keys, and `id`
+    /// is the image id it gives, if any.
+    ///
+    /// If input arrives part way through, the transfer is closed, what was
+    /// sent is deleted, and an `Interrupted` error is returned so the caller
+    /// can handle the input first and send the image again later.
+    fn transmit(
+        &mut self,
+        out: &mut dyn Write,
+        rgba: RgbaImage,
+        quality: Quality,
+        command: GraphicsCommand,
+        id: Option<u32>,
+    ) -> io::Result<()> {
+        let (w, h) = rgba.dimensions();
+        let compress = quality == Quality::Reduced && self.caps.compression;
+        let payload = if compress {
+            zlib_compress(rgba.as_raw())?
+        } else {
+            rgba.into_raw()
+        };
+
+        let b64 = base64::engine::general_purpose::STANDARD.encode(payload);
+        let chunks: Vec<&str> = b64
+            .as_bytes()
+            .chunks(4096)
+            .map(|c| std::str::from_utf8(c).unwrap())
+            .collect();
+
+        let started = Instant::now();
+        let mut batch = 0;
+        // f=32 (RGBA), s=width, v=height
+        let mut first = Some(command.key('f', 32).key('s', w).key('v', h));
+        for (i, chunk) in chunks.iter().enumerate() {
+            let is_last = i == chunks.len() - 1;
+            let more = if is_last { 0 } else { 1 };
+
+            let command = match first.take() {
+                Some(command) if compress => command.compressed(),
+                Some(command) => command,
+                None => GraphicsCommand::new(self.caps),
+            };
+            command.key('m', more).payload(chunk).write(out)?;
+
+            batch += chunk.len();
+            if batch >= TRANSMIT_BATCH && !is_last {
+                out.flush()?;
+                batch = 0;
+                if (self.input_pending)()? {
+                    // An empty last chunk ends the transfer short, which the terminal discards
+                    GraphicsCommand::new(self.caps).key('m', 0).quiet().payload("").write(out)?;
+                    if let Some(id) = id {
+                        let delete = GraphicsCommand::new(self.caps).key('a', 'd').key('d', 'I');
+                        delete.id('i', id).quiet().write(out)?;
+                    }
+                    out.flush()?;
+                    return Err(io::ErrorKind::Interrupted.into());
+                }
+            }
+        }
+        out.flush()?;
+        self.throughput.record(b64.len(), started.elapsed());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl Kitty {
+    /// A renderer on a fast link that never finds input waiting.
+    pub fn for_tests(caps: KittyCaps) -> Kitty {
+        Kitty {
+            caps,
+            link: LinkSpeed::Fast,
+            throughput: Throughput::default(),
+            retained: None,
+            input_pending: || Ok(false),
+        }
+    }
+}
+
+impl Renderer for Kitty {
+    /// The image is transmitted and placed in one go. Over a slow link it is
+    /// sent at half resolution and zlib-compressed, and the terminal scales
+    /// it up to the same cells.
+    fn display(
+        &mut self,
+        out: &mut dyn Write,
+        img: &DynamicImage,
+        area: CellRect,
+        (cell_width_px, cell_height_px): (u16, u16),
+    ) -> io::Result<()> {
+        let (img_w, img_h) = img.dimensions();
+        let ((disp_w, disp_h), (col, row, cols, rows)) =
+            fit_image(img_w, img_h, area, cell_width_px, cell_height_px);
+
+        let quality = choose_quality(self.link, self.throughput.bytes_per_sec());
+        // a=T (transmit and display); c/r stretch a reduced image over the
+        // cells the full one would cover
+        let command = GraphicsCommand::new(self.caps).key('a', 'T');
+        let (rgba, command) = match quality {
+            Quality::Full => (resize_to(img, disp_w, disp_h).to_rgba8(), command),
+            Quality::Reduced => {
+                // Padded out to the cells' shape, so it is doubled evenly
+                // rather than stretched more on one axis to fill them
+                let half = |v: u32| (v / 2).max(1);
+                let cells_w = cols as u32 * cell_width_px as u32;
+                let cells_h = rows as u32 * cell_height_px as u32;
+                let mut padded = RgbaImage::new(half(cells_w), half(cells_h));
+                let reduced = resize_to(img, half(disp_w), half(disp_h)).to_rgba8();
+                image::imageops::replace(&mut padded, &reduced, 0, 0);
+                (padded, command.key('c', cols).key('r', rows))
+            }
+        };
+
+        out.queue(cursor::MoveTo(col, row))?;
+        self.transmit(out, rgba, quality, command, None)
+    }
+
+    /// The whole image is kept in the terminal under an id, at the resolution
+    /// the view needs (with headroom for zooming in), and zoom and pan only
+    /// move the placement's source rectangle. Pixels are sent again when the
+    /// image changes or a deeper zoom needs more of them.
+    fn display_view(
+        &mut self,
+        out: &mut dyn Write,
+        img: &DynamicImage,
+        view: View,
+        key: u64,
+        area: CellRect,
+        (cell_width_px, cell_height_px): (u16, u16),
+    ) -> io::Result<()> {
+        let cell_size = (cell_width_px, cell_height_px);
+        let (_, _, vw, vh) = view.rect;
+        // The grid is drawn into the pixels, so the terminal can't do the enlarging
+        if !self.caps.placements || view.grid {
+            let scaled = scaled_view(img, view, area, cell_size);
+            return self.display(out, &scaled, area, cell_size);
+        }
+        let (img_w, img_h) = img.dimensions();
+        let ((disp_w, disp_h), (col, row, cols, rows)) =
+            fit_scaled(vw, vh, area, cell_size, view.scaling);
+
+        // Beyond one sent pixel per image pixel the terminal does the enlarging
+        let quality = choose_quality(self.link, self.throughput.bytes_per_sec());
+        let mut needed = (disp_w as f64 / vw.max(1) as f64)
+            .max(disp_h as f64 / vh.max(1) as f64)
+            .min(1.0);
+        if quality == Quality::Reduced {
+            needed /= 2.0;
+        }
+
+        let reusable = self.retained.filter(|r| {
+            r.key == key && r.source == (img_w, img_h) && r.sent.0 as f64 >= img_w as f64 * needed
+        });
+        let sent = match reusable {
+            Some(retained) => retained.sent,
+            None => {
+                // Twice the needed resolution covers the next few zoom steps
+                let scale = (needed * 2.0).min(1.0);
+                let send_w = ((img_w as f64 * scale).round() as u32).max(1);
+                let send_h = ((img_h as f64 * scale).round() as u32).max(1);
+                let area_px =
+                    (cols as u64 * cell_width_px as u64) * (rows as u64 * cell_height_px as u64);
+                if send_w as u64 * send_h as u64 > area_px * MAX_RETAINED_AREA_RATIO {
+                    self.retained = None;
+                    let scaled = scaled_view(img, view, area, cell_size);
+                    return self.display(out, &scaled, area, cell_size);
+                }
+                (send_w, send_h)
+            }
+        };
+
+        // The terminal stretches the source rectangle over whole cells, which
+        // only keeps the view's proportions if the rectangle has theirs too
+        let Some((x, y, w, h)) = placement_source(
+            view.rect,
+            (disp_w, disp_h),
+            (cols, rows),
+            cell_size,
+            (img_w, img_h),
+            sent,
+        ) else {
+            let scaled = scaled_view(img, view, area, cell_size);
+            return self.display(out, &scaled, area, cell_size);
+        };
+
+        if reusable.is_none() {
+            // a=t (transmit only), q=2 (no replies)
+            let rgba = resize_to(img, sent.0, sent.1).to_rgba8();
+            let command = GraphicsCommand::new(self.caps).key('a', 't');
+            let command = command.id('i', VIEW_IMAGE_ID).quiet();
+            // Sending under the id replaces what was kept, even if it is cut short
+            self.retained = None;
+            self.transmit(out, rgba, quality, command, Some(VIEW_IMAGE_ID))?;
+            self.retained = Some(Retained {
+                key,
+                source: (img_w, img_h),
+                sent,
+            });
+        }
+
+        // a=p (place), p=1: placing again replaces the previous placement
+        out.queue(cursor::MoveTo(col, row))?;
+        let place = GraphicsCommand::new(self.caps).key('a', 'p');
+        let place = place.id('i', VIEW_IMAGE_ID).id('p', 1);
+        let place = place.key('x', x).key('y', y).key('w', w).key('h', h);
+        place.key('c', cols).key('r', rows).quiet().write(out)
+    }
+
+    fn forget(&mut self) {
+        self.retained = None;
+    }
+
+    fn clear(&mut self, out: &mut dyn Write) -> io::Result<()> {
+        if !self.caps.placements {
+            return kitty_clear(out);
+        }
+        // d=a: remove placements but keep image data, so the retained view
+        // image can be placed again without resending it
+        GraphicsCommand::new(self.caps).key('a', 'd').key('d', 'a').quiet().write(out)
+    }
+
+    fn quality(&self) -> Option<&'static str> {
+        let quality = choose_quality(self.link, self.throughput.bytes_per_sec());
+        Some(quality.describe(self.link))
+    }
+
+    /// Frames are sent whole with `a=T`, at the quality chosen for the link now.
+    fn frame_encoder(&self) -> Box<dyn Renderer + Send> {
+        let link = match choose_quality(self.link, self.throughput.bytes_per_sec()) {
+            Quality::Full => LinkSpeed::Fast,
+            Quality::Reduced => LinkSpeed::Slow,
+        };
+        Box::new(Kitty {
+            caps: KittyCaps {
+                placements: false,
+                ..self.caps
+            },
+            link,
+            throughput: Throughput::default(),
+            retained: None,
+            input_pending: || Ok(false),
+        })
+    }
+}
+
+/// The rectangle of a `sent`-sized copy of an `image`-sized image to place
+/// over `cells` so that `view`, drawn `disp` screen pixels large, comes out
+/// at its own scale on both axes. The rectangle takes the cells' shape by
+/// reaching past the view into the image around it. `None` if the image
+/// runs out first, or whole sent pixels can't come within
+/// `MAX_PLACEMENT_DISTORTION` of that shape, as when a few image pixels are
+/// enlarged over the whole area.
+fn placement_source(
+    view: PixelRect,
+    disp: (u32, u32),
+    cells: (u16, u16),
+    (cell_width_px, cell_height_px): (u16, u16),
+    image: (u32, u32),
+    sent: (u32, u32),
+) -> Option<PixelRect> {
+    let (vx, vy, vw, vh) = view;
+    let cells_w = cells.0 as u32 * cell_width_px as u32;
+    let cells_h = cells.1 as u32 * cell_height_px as u32;
+    let (x, w) = placement_span(vx, vw, disp.0, cells_w, image.0, sent.0)?;
+    let (y, h) = placement_span(vy, vh, disp.1, cells_h, image.1, sent.1)?;
+    Some((x, y, w, h))
+}
+
+/// One axis of `placement_source`: a view `len` image pixels long from
+/// `start`, drawn `disp` screen pixels long, placed over `cells` screen pixels.
+fn placement_span(start: u32, len: u32, disp: u32, cells: u32, image: u32, sent: u32) -> Option<(u32, u32)> {
+    // Screen pixels per sent pixel
+    let scale = disp as f64 * image as f64 / (len.max(1) as f64 * sent as f64);
+    let size = ((cells as f64 / scale).round() as u32).clamp(1, sent);
+    let tolerance = (cells as f64 * MAX_PLACEMENT_DISTORTION).max(1.0);
+    if (size as f64 * scale - cells as f64).abs() > tolerance {
+        return None;
+    }
+    let from = (start as u64 * sent as u64 / image as u64) as u32;
+    Some((from.min(sent - size), size))
+}
+
+fn zlib_compress(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::fast());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+// ---------------------------------------------------------------------------
+// Sixel
+// ---------------------------------------------------------------------------
+
+/// Pixels with less alpha than this are left unpainted (transparent).
+const ALPHA_THRESHOLD: u8 = 128;
+
+pub struct Sixel;
+
+impl Renderer for Sixel {
+    fn display(
+        &mut self,
+        out: &mut dyn Write,
+        img: &DynamicImage,
+        area: CellRect,
+        (cell_width_px, cell_height_px): (u16, u16),
+    ) -> io::Result<()> {
+        let (img_w, img_h) = img.dimensions();
+        let ((disp_w, disp_h), (col, row, _, _)) =
+            fit_image(img_w, img_h, area, cell_width_px, cell_height_px);
+
+        let rgba = resize_to(img, disp_w, disp_h).to_rgba8();
+        out.queue(cursor::MoveTo(col, row))?;
+        out.write_all(&encode_sixel(&rgba))
+    }
+
+    fn clear(&mut self, _out: &mut dyn Write) -> io::Result<()> {
+        // Sixel pixels live in the cells; blanking the cells removes them
+        Ok(())
+    }
+
+    fn frame_encoder(&self) -> Box<dyn Renderer + Send> {
+        Box::new(Sixel)
+    }
+}
+
+/// Encode `img` as a sixel sequence with a 256-colour palette fitted to it.
+fn encode_sixel(img: &RgbaImage) -> Vec<u8> {
+    let (w, h) = img.dimensions();
+    let quant = color_quant::NeuQuant::new(10, 256, img.as_raw());
+    let palette = quant.color_map_rgb();
+    let indices: Vec<Option<u8>> = img
+        .pixels()
+        .map(|p| (p[3] >= ALPHA_THRESHOLD).then(|| quant.index_of(&p.0) as u8))
+        .collect();
+
+    // P2=1: pixels we don't paint keep the background
+    let mut s = format!("\x1bP0;1;0q\"1;1;{};{}", w, h).into_bytes();
+    for (i, rgb) in palette.chunks(3).enumerate() {
+        let pct = |c: u8| c as u32 * 100 / 255;
+        s.extend(format!("#{};2;{};{};{}", i, pct(rgb[0]), pct(rgb[1]), pct(rgb[2])).bytes());
+    }
+
+    for band in (0..h).step_by(6) {
+        let band_h = (h - band).min(6);
+        let mut colors: Vec<u8> = (band..band + band_h)
+            .flat_map(|y| (0..w).map(move |x| (x, y)))
+            .filter_map(|(x, y)| indices[(y * w + x) as usize])
+            .collect();
+        colors.sort_unstable();
+        colors.dedup();
+
+        for (n, &color) in colors.iter().enumerate() {
+            if n > 0 {
+                s.push(b'$'); // back to the start of the band for the next colour
+            }
+            s.extend(format!("#{}", color).bytes());
+
+            let mut run_char = 0u8;
+            let mut run_len = 0u32;
+            for x in 0..w {
+                let mut bits = 0u8;
+                for dy in 0..band_h {
+                    if indices[((band + dy) * w + x) as usize] == Some(color) {
+                        bits |= 1 << dy;
+                    }
+                }
+                let c = b'?' + bits;
+                if c == run_char {
+                    run_len += 1;
+                } else {
+                    push_sixel_run(&mut s, run_char, run_len);
+                    run_char = c;
+                    run_len = 1;
+                }
+            }
+            push_sixel_run(&mut s, run_char, run_len);
+        }
+        s.push(b'-'); // next band
+    }
+
+    s.extend_from_slice(b"\x1b\\");
+    s
+}
+
+fn push_sixel_run(s: &mut Vec<u8>, c: u8, len: u32) {
+    match len {
+        0 => {}
+        1..=3 => s.extend(std::iter::repeat_n(c, len as usize)),
+        _ => {
+            s.extend(format!("!{}", len).bytes());
+            s.push(c);
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Half-blocks
+// ---------------------------------------------------------------------------
+
+pub struct Blocks {
+    /// `--fallback-gamma`.
+    gamma: f64,
+}
+
+impl Renderer for Blocks {
+    /// Each cell shows two vertically stacked pixels: the upper half block
+    /// `▀` in the foreground colour over the background colour.
+    fn display(
+        &mut self,
+        out: &mut dyn Write,
+        img: &DynamicImage,
+        area: CellRect,
+        cell_size: (u16, u16),
+    ) -> io::Result<()> {
+        let Some((rgba, cells)) = half_block_pixels(img, area, cell_size, self.gamma) else {
+            return Ok(());
+        };
+        draw_half_blocks(out, cells, |x, y| {
+            let p = rgba.get_pixel(x, y);
+            (p[3] >= ALPHA_THRESHOLD).then_some(style::Color::Rgb {
+                r: p[0],
+                g: p[1],
+                b: p[2],
+            })
+        })
+    }
+
+    fn clear(&mut self, _out: &mut dyn Write) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn frame_encoder(&self) -> Box<dyn Renderer + Send> {
+        Box::new(Blocks { gamma: self.gamma })
+    }
+}
+
+/// `img` fitted into `area` at one pixel per column and two per row, and the
+/// cells it covers, with `gamma` applied. `None` if it covers none.
+fn half_block_pixels(
+    img: &DynamicImage,
+    area: CellRect,
+    (cell_width_px, cell_height_px): (u16, u16),
+    gamma: f64,
+) -> Option<(RgbaImage, CellRect)> {
+    let (img_w, img_h) = img.dimensions();
+    let (_, cells) = fit_image(img_w, img_h, area, cell_width_px, cell_height_px);
+    let (_, _, cols, rows) = cells;
+    if cols == 0 || rows == 0 {
+        return None;
+    }
+    let (w, h) = (cols as u32, rows as u32 * 2);
+    let mut rgba = if w >= img_w && h >= img_h {
+        resize_to(img, w, h).to_rgba8()
+    } else {
+        match img.as_rgba8() {
+            Some(rgba) => shrink_linear(rgba, w, h),
+            None => shrink_linear(&img.to_rgba8(), w, h),
+        }
+    };
+    adjust_gamma(&mut rgba, gamma);
+    Some((rgba, cells))
+}
+
+fn srgb_to_linear(v: f32) -> f32 {
+    if v <= 0.04045 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(v: f32) -> f32 {
+    if v <= 0.003_130_8 {
+        v * 12.92
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// `src` shrunk to `w` x `h` by averaging each block of pixels in linear
+/// light, which is how the eye mixes pixels too small to tell apart, so
+/// the result is as bright as the image looks drawn in full. Averaging the
+/// stored sRGB values, as resizing filters do, darkens fine detail. Colours
+/// are weighted by alpha, so transparent pixels don't darken their block.
+fn shrink_linear(src: &RgbaImage, w: u32, h: u32) -> RgbaImage {
+    let linear: [f32; 256] = std::array::from_fn(|v| srgb_to_linear(v as f32 / 255.0));
+    let (src_w, src_h) = src.dimensions();
+    // Source pixels `start..end` along an axis for pixel `i` of `n`; at
+    // least one, for the axis being enlarged
+    let block = |i: u32, n: u32, len: u32| {
+        let start = (i as u64 * len as u64 / n as u64) as u32;
+        let end = ((i as u64 + 1) * len as u64 / n as u64) as u32;
+        (start.min(len - 1), end.clamp(start + 1, len))
+    };
+    RgbaImage::from_fn(w, h, |x, y| {
+        let (x0, x1) = block(x, w, src_w);
+        let (y0, y1) = block(y, h, src_h);
+        let mut sum = [0.0f32; 3];
+        let mut alpha = 0.0f32;
+        for sy in y0..y1 {
+            for sx in x0..x1 {
+                let p = src.get_pixel(sx, sy);
+                let a = p[3] as f32 / 255.0;
+                for (c, total) in sum.iter_mut().enumerate() {
+                    *total += linear[p[c] as usize] * a;
+                }
+                alpha += a;
+            }
+        }
+        if alpha == 0.0 {
+            return image::Rgba([0, 0, 0, 0]);
+        }
+        let count = ((x1 - x0) * (y1 - y0)) as f32;
+        let encode = |v: f32| (linear_to_srgb(v / alpha) * 255.0).round().clamp(0.0, 255.0) as u8;
+        let a = (alpha / count * 255.0).round() as u8;
+        image::Rgba([encode(sum[0]), encode(sum[1]), encode(sum[2]), a])
+    })
+}
+
+/// Brighten the colours of `rgba` for a `gamma` above 1, or darken them
+/// below 1, for terminals that show colours darker or lighter than the
+/// graphics protocols do.
+fn adjust_gamma(rgba: &mut RgbaImage, gamma: f64) {
+    if gamma == 1.0 {
+        return;
+    }
+    let table: [u8; 256] = std::array::from_fn(|v| {
+        ((v as f64 / 255.0).powf(1.0 / gamma) * 255.0).round() as u8
+    });
+    for p in rgba.pixels_mut() {
+        for c in 0..3 {
+            p[c] = table[p[c] as usize];
+        }
+    }
+}
+
+/// Fill `cells` with half blocks. `color(x, y)` is the colour of the pixel
+/// in column `x` and half-row `y`, or `None` to leave it unpainted.
+fn draw_half_blocks(
+    out: &mut dyn Write,
+    (col, row, cols, rows): CellRect,
+    color: impl Fn(u32, u32) -> Option<style::Color>,
+) -> io::Result<()> {
+    // Only emit colour changes, which are most of the output otherwise
+    let mut fg = None;
+    let mut bg = None;
+    for r in 0..rows {
+        out.queue(cursor::MoveTo(col, row + r))?;
+        for c in 0..cols {
+            let top = color(c as u32, r as u32 * 2);
+            let bottom = color(c as u32, r as u32 * 2 + 1);
+            let (glyph, want_fg, want_bg) = match (top, bottom) {
+                (Some(top), bottom) => ("▀", Some(top), bottom.unwrap_or(style::Color::Reset)),
+                (None, Some(bottom)) => ("▄", Some(bottom), style::Color::Reset),
+                (None, None) => (" ", None, style::Color::Reset),
+            };
+            if let Some(want) = want_fg.filter(|&c| Some(c) != fg) {
+                set_color(out, want, true)?;
+                fg = Some(want);
+            }
+            if Some(want_bg) != bg {
+                set_color(out, want_bg, false)?;
+                bg = Some(want_bg);
+            }
+            write!(out, "{}", glyph)?;
+        }
+    }
+
+    out.queue(SetForegroundColor(style::Color::Reset))?
+        .queue(SetBackgroundColor(style::Color::Reset))?;
+    Ok(())
+}
+
+/// Set the foreground or background colour. The first 16 indexed colours use
+/// their original SGR codes, which consoles without 256 colours understand,
+/// rather than crossterm's `38;5;n` form.
+fn set_color(out: &mut dyn Write, color: style::Color, foreground: bool) -> io::Result<()> {
+    match color {
+        style::Color::AnsiValue(i) if i < 16 => {
+            let base = match (foreground, i < 8) {
+                (true, true) => 30,
+                (true, false) => 90 - 8,
+                (false, true) => 40,
+                (false, false) => 100 - 8,
+            };
+            write!(out, "\x1b[{}m", base + i as u16)
+        }
+        color if foreground => out.queue(SetForegroundColor(color)).map(|_| ()),
+        color => out.queue(SetBackgroundColor(color)).map(|_| ()),
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Half-blocks in 16 colours
+// ---------------------------------------------------------------------------
+
+/// The 16 ANSI colours as a terminal shows them, in SGR order: black, red,
+/// green, yellow, blue, magenta, cyan and white, then their bright versions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Palette(pub [(u8, u8, u8); 16]);
+
+impl Palette {
+    /// The standard VGA colours, which the Linux console starts out with.
+    pub const VGA: Palette = Palette([
+        (0x00, 0x00, 0x00),
+        (0xaa, 0x00, 0x00),
+        (0x00, 0xaa, 0x00),
+        (0xaa, 0x55, 0x00),
+        (0x00, 0x00, 0xaa),
+        (0xaa, 0x00, 0xaa),
+        (0x00, 0xaa, 0xaa),
+        (0xaa, 0xaa, 0xaa),
+        (0x55, 0x55, 0x55),
+        (0xff, 0x55, 0x55),
+        (0x55, 0xff, 0x55),
+        (0xff, 0xff, 0x55),
+        (0x55, 0x55, 0xff),
+        (0xff, 0x55, 0xff),
+        (0x55, 0xff, 0xff),
+        (0xff, 0xff, 0xff),
+    ]);
+}
+
+impl std::str::FromStr for Palette {
+    type Err = String;
+
+    /// Comma-separated `#rrggbb` colours: all 16, or the first 8 with the
+    /// bright ones left at their VGA values.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let colors = s
+            .split(',')
+            .map(|c| parse_hex_color(c.trim()).ok_or_else(|| format!("invalid colour '{}'", c)))
+            .collect::<Result<Vec<_>, _>>()?;
+        if colors.len() != 8 && colors.len() != 16 {
+            return Err(format!("expected 8 or 16 colours, got {}", colors.len()));
+        }
+        let mut palette = Palette::VGA;
+        palette.0[..colors.len()].copy_from_slice(&colors);
+        Ok(palette)
+    }
+}
+
+/// `#rrggbb`, with or without the `#`.
+fn parse_hex_color(s: &str) -> Option<(u8, u8, u8)> {
+    let hex = s.strip_prefix('#').unwrap_or(s);
+    if hex.len() != 6 || !hex.is_ascii() {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+    Some((channel(0)?, channel(2)?, channel(4)?))
+}
+
+/// Bits per channel of the quantizer's lookup table.
+const LUT_BITS: u32 = 5;
+
+/// Maps colours to palette entries. The nearest entry for every colour is
+/// worked out once, at `LUT_BITS` per channel, so dithering costs a table
+/// read per pixel.
+#[derive(Clone)]
+struct Quantizer {
+    palette: Palette,
+    lut: Vec<u8>,
+}
+
+impl Quantizer {
+    fn new(palette: Palette) -> Self {
+        let levels = 1u32 << LUT_BITS;
+        let step = 256 / levels;
+        // Each table entry stands for the colours around the middle of its step
+        let centre = |v: u32| (v * step + step / 2) as u8;
+        let mut lut = Vec::with_capacity((levels * levels * levels) as usize);
+        for r in 0..levels {
+            for g in 0..levels {
+                for b in 0..levels {
+                    lut.push(nearest_entry(&palette, (centre(r), centre(g), centre(b))));
+                }
+            }
+        }
+        Quantizer { palette, lut }
+    }
+
+    fn nearest(&self, [r, g, b]: [f32; 3]) -> u8 {
+        let level = |c: f32| (c as u32 >> (8 - LUT_BITS)) as usize;
+        let levels = 1usize << LUT_BITS;
+        self.lut[(level(r) * levels + level(g)) * levels + level(b)]
+    }
+
+    /// Palette entries for the pixels of `rgba`, row by row, dithered with
+    /// Floyd–Steinberg error diffusion. Transparent pixels are `None` and
+    /// pass no error on.
+    fn dither(&self, rgba: &RgbaImage) -> Vec<Option<u8>> {
+        let (w, h) = rgba.dimensions();
+        let mut indices = Vec::with_capacity((w * h) as usize);
+        // Error carried into this row and the next, offset by one so the
+        // neighbours of the first and last pixels need no bounds checks
+        let mut error = vec![[0.0f32; 3]; w as usize + 2];
+        let mut next = vec![[0.0f32; 3]; w as usize + 2];
+        for y in 0..h {
+            for x in 0..w {
+                let p = rgba.get_pixel(x, y);
+                if p[3] < ALPHA_THRESHOLD {
+                    indices.push(None);
+                    continue;
+                }
+                let i = x as usize + 1;
+                let want: [f32; 3] =
+                    std::array::from_fn(|c| (p[c] as f32 + error[i][c]).clamp(0.0, 255.0));
+                let index = self.nearest(want);
+                let (r, g, b) = self.palette.0[index as usize];
+                let got = [r as f32, g as f32, b as f32];
+                for c in 0..3 {
+                    let e = want[c] - got[c];
+                    error[i + 1][c] += e * 7.0 / 16.0;
+                    next[i - 1][c] += e * 3.0 / 16.0;
+                    next[i][c] += e * 5.0 / 16.0;
+                    next[i + 1][c] += e / 16.0;
+                }
+                indices.push(Some(index));
+            }
+            std::mem::swap(&mut error, &mut next);
+            next.iter_mut().for_each(|e| *e = [0.0; 3]);
+        }
+        indices
+    }
+}
+
+/// The palette entry closest to `color`, weighting green most and red
+/// least as the eye does.
+fn nearest_entry(palette: &Palette, (r, g, b): (u8, u8, u8)) -> u8 {
+    let distance = |&(pr, pg, pb): &(u8, u8, u8)| {
+        let d = |a: u8, b: u8| (a as i32 - b as i32).pow(2);
+        2 * d(r, pr) + 4 * d(g, pg) + 3 * d(b, pb)
+    };
+    (0..16u8)
+        .min_by_key(|&i| distance(&palette.0[i as usize]))
+        .unwrap_or(0)
+}
+
+/// What a view was drawn from and into: the image key, the view, the area
+/// and the cell size. Pixels drawn for an equal one can be used again.
+pub type DrawnView = (u64, View, CellRect, (u16, u16));
+
+/// Half-blocks in the 16 ANSI colours, dithered against the colours the
+/// terminal really shows for them. Consoles often differ from the standard
+/// values, and going by those instead looks poor.
+pub struct Blocks16 {
+    quantizer: Quantizer,
+    /// `--fallback-gamma`.
+    gamma: f64,
+    /// The last view drawn, the cells it covered and their palette entries,
+    /// so drawing it again (e.g. after an overlay closes) skips resizing and
+    /// dithering.
+    drawn: Option<(DrawnView, CellRect, Vec<Option<u8>>)>,
+}
+
+/// Fill `cells` with half blocks in the palette entries `indices`, one per
+/// pixel, row by row.
+fn draw_indexed(out: &mut dyn Write, cells: CellRect, indices: &[Option<u8>]) -> io::Result<()> {
+    let cols = cells.2 as usize;
+    draw_half_blocks(out, cells, |x, y| {
+        indices[y as usize * cols + x as usize].map(style::Color::AnsiValue)
+    })
+}
+
+impl Renderer for Blocks16 {
+    fn display(
+        &mut self,
+        out: &mut dyn Write,
+        img: &DynamicImage,
+        area: CellRect,
+        cell_size: (u16, u16),
+    ) -> io::Result<()> {
+        let Some((rgba, cells)) = half_block_pixels(img, area, cell_size, self.gamma) else {
+            return Ok(());
+        };
+        draw_indexed(out, cells, &self.quantizer.dither(&rgba))
+    }
+
+    fn display_view(
+        &mut self,
+        out: &mut dyn Write,
+        img: &DynamicImage,
+        view: View,
+        key: u64,
+        area: CellRect,
+        cell_size: (u16, u16),
+    ) -> io::Result<()> {
+        let id = (key, view, area, cell_size);
+        if !matches!(self.drawn, Some((ref drawn, ..)) if *drawn == id) {
+            let pixels = view_pixels(img, view, area, cell_size);
+            let Some((rgba, cells)) = half_block_pixels(&pixels, area, cell_size, self.gamma) else {
+                self.drawn = None;
+                return Ok(());
+            };
+            self.drawn = Some((id, cells, self.quantizer.dither(&rgba)));
+        }
+        let (_, cells, indices) = self.drawn.as_ref().expect("drawn above");
+        draw_indexed(out, *cells, indices)
+    }
+
+    fn clear(&mut self, _out: &mut dyn Write) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn frame_encoder(&self) -> Box<dyn Renderer + Send> {
+        Box::new(Blocks16 {
+            quantizer: self.quantizer.clone(),
+            gamma: self.gamma,
+            drawn: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kitty(caps: KittyCaps) -> Kitty {
+        Kitty::for_tests(caps)
+    }
+
+    fn written(f: impl FnOnce(&mut Vec<u8>) -> io::Result<()>) -> String {
+        let mut out = Vec::new();
+        f(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    fn place(caps: KittyCaps) -> String {
+        let place = GraphicsCommand::new(caps).key('a', 'p').id('i', 1).id('p', 1);
+        place.key('c', 4).key('r', 2).quiet().to_string()
+    }
+
+    fn abort(caps: KittyCaps) -> String {
+        GraphicsCommand::new(caps).key('m', 0).quiet().payload("").to_string()
+    }
+
+    #[test]
+    fn modern_commands() {
+        let caps = KittyCaps::MODERN;
+        assert_eq!(place(caps), "\x1b_Ga=p,i=1,p=1,c=4,r=2,q=2\x1b\\");
+        assert_eq!(abort(caps), "\x1b_Gm=0,q=2;\x1b\\");
+        assert_eq!(written(|out| kitty(caps).clear(out)), "\x1b_Ga=d,d=a,q=2\x1b\\");
+
+        let pixel = RgbaImage::new(1, 1);
+        let command = GraphicsCommand::new(caps).key('a', 't').id('i', 1).quiet();
+        let sent = written(|out| kitty(caps).transmit(out, pixel, Quality::Full, command, Some(1)));
+        assert_eq!(sent, "\x1b_Ga=t,i=1,q=2,f=32,s=1,v=1,m=0;AAAAAA==\x1b\\");
+
+        let pixel = RgbaImage::new(1, 1);
+        let command = GraphicsCommand::new(caps).key('a', 'T');
+        let sent = written(|out| kitty(caps).transmit(out, pixel, Quality::Reduced, command, None));
+        assert!(sent.starts_with("\x1b_Ga=T,f=32,s=1,v=1,o=z,m=0;"), "{:?}", sent);
+    }
+
+    #[test]
+    fn legacy_commands_leave_out_newer_keys() {
+        let caps = KittyCaps::LEGACY;
+        assert_eq!(place(caps), "\x1b_Ga=p,c=4,r=2\x1b\\");
+        assert_eq!(abort(caps), "\x1b_Gm=0;\x1b\\");
+        assert_eq!(written(|out| kitty(caps).clear(out)), "\x1b_Ga=d,d=A\x1b\\");
+
+        let pixel = RgbaImage::new(1, 1);
+        let command = GraphicsCommand::new(caps).key('a', 'T').key('c', 4).key('r', 2);
+        let sent = written(|out| kitty(caps).transmit(out, pixel, Quality::Reduced, command, None));
+        assert_eq!(sent, "\x1b_Ga=T,c=4,r=2,f=32,s=1,v=1,m=0;AAAAAA==\x1b\\");
+    }
+
+    #[test]
+    fn later_chunks_carry_only_more() {
+        let pixels = RgbaImage::new(32, 32);
+        let command = GraphicsCommand::new(KittyCaps::MODERN).key('a', 'T');
+        let sent = written(|out| {
+            kitty(KittyCaps::MODERN).transmit(out, pixels, Quality::Full, command, None)
+        });
+        let chunks: Vec<&str> = sent.split("\x1b\\").filter(|c| !c.is_empty()).collect();
+        assert_eq!(chunks.len(), 2);
+        assert!(chunks[0].starts_with("\x1b_Ga=T,f=32,s=32,v=32,m=1;"));
+        assert!(chunks[1].starts_with("\x1b_Gm=0;"));
+    }
+
+    #[test]
+    fn placements_keep_the_view_scale() {
+        // Cells 10x20 pixels, over a 400x300 image
+        let place = |view, disp, cells, sent| {
+            placement_source(view, disp, cells, (10, 20), (400, 300), sent)
+        };
+        // The view fills its cells exactly: placed as it is
+        let source = place((0, 0, 400, 300), (800, 600), (80, 30), (400, 300));
+        assert_eq!(source, Some((0, 0, 400, 300)));
+        // From a half-size copy the same view is half the sent pixels
+        let source = place((100, 50, 200, 150), (800, 600), (80, 30), (200, 150));
+        assert_eq!(source, Some((50, 25, 100, 75)));
+        // Cells wider than the view take in more of the image to the side
+        let source = place((100, 0, 200, 300), (400, 600), (41, 30), (400, 300));
+        assert_eq!(source, Some((100, 0, 205, 300)));
+        // Three pixels over 61 cells can't be drawn square by the terminal
+        let source = placement_source((0, 0, 3, 3), (600, 600), (61, 30), (10, 20), (3, 3), (3, 3));
+        assert_eq!(source, None);
+    }
+
+    /// Relative luminance of an sRGB colour, in linear light.
+    fn luminance(p: &image::Rgba<u8>) -> f32 {
+        let l = |c: u8| srgb_to_linear(c as f32 / 255.0);
+        0.2126 * l(p[0]) + 0.7152 * l(p[1]) + 0.0722 * l(p[2])
+    }
+
+    /// How far, on average, the luminance of each half-block pixel is from
+    /// that of the 4x4 block of `img` it stands for, which is what the eye
+    /// sees of those pixels drawn through a graphics protocol.
+    fn luminance_error(img: &RgbaImage, blocks: &RgbaImage) -> f32 {
+        let mut error = 0.0;
+        for (x, y, p) in blocks.enumerate_pixels() {
+            let mut seen = 0.0;
+            for sy in y * 4..y * 4 + 4 {
+                for sx in x * 4..x * 4 + 4 {
+                    seen += luminance(img.get_pixel(sx, sy)) / 16.0;
+                }
+            }
+            error += (luminance(p) - seen).abs();
+        }
+        error / (blocks.width() * blocks.height()) as f32
+    }
+
+    /// A ramp from black to white, drawn in a fine checkerboard of two grays
+    /// either side of it, as dithered images and fine texture are.
+    fn gray_ramp() -> RgbaImage {
+        RgbaImage::from_fn(256, 64, |x, y| {
+            let v = x as i32 + if (x + y) % 2 == 0 { 64 } else { -64 };
+            let v = v.clamp(0, 255) as u8;
+            image::Rgba([v, v, v, 255])
+        })
+    }
+
+    #[test]
+    fn half_blocks_keep_the_luminance_of_the_image() {
+        let ramp = gray_ramp();
+        let img = DynamicImage::ImageRgba8(ramp.clone());
+        // 64x8 cells of 4x8 pixels, so each half block stands for 4x4 pixels
+        let (blocks, cells) = half_block_pixels(&img, (0, 0, 64, 8), (4, 8), 1.0).unwrap();
+        assert_eq!(cells, (0, 0, 64, 8));
+        let error = luminance_error(&ramp, &blocks);
+        assert!(error < 0.005, "error {}", error);
+
+        // Averaged as stored, the same ramp comes out visibly darker
+        let srgb = resize_to(&img, 64, 16).to_rgba8();
+        let error = luminance_error(&ramp, &srgb);
+        assert!(error > 0.02, "error {}", error);
+    }
+
+    #[test]
+    fn transparent_pixels_do_not_darken_their_block() {
+        let img = RgbaImage::from_fn(4, 4, |x, _| {
+            if x < 2 {
+                image::Rgba([255, 255, 255, 255])
+            } else {
+                image::Rgba([0, 0, 0, 0])
+            }
+        });
+        let shrunk = shrink_linear(&img, 1, 1);
+        assert_eq!(shrunk.get_pixel(0, 0).0, [255, 255, 255, 128]);
+    }
+
+    #[test]
+    fn fallback_gamma_brightens_and_darkens() {
+        let gray = |gamma| {
+            let mut img = RgbaImage::from_pixel(1, 1, image::Rgba([128, 128, 128, 255]));
+            adjust_gamma(&mut img, gamma);
+            img.get_pixel(0, 0)[0]
+        };
+        assert_eq!(gray(1.0), 128);
+        assert!(gray(2.0) > 170);
+        assert!(gray(0.5) < 80);
+        let mut img = RgbaImage::from_pixel(1, 1, image::Rgba([0, 255, 0, 7]));
+        adjust_gamma(&mut img, 3.0);
+        assert_eq!(img.get_pixel(0, 0).0, [0, 255, 0, 7]);
+    }
+
+    /// A terminal link of `BYTES_PER_SEC`, on which a key is pressed once
+    /// `PRESS_AFTER` bytes have gone through.
+    struct Throttled {
+        out: Vec<u8>,
+    }
+
+    const BYTES_PER_SEC: u64 = 512 * 1024;
+    const PRESS_AFTER: usize = 64 * 1024;
+
+    thread_local! {
+        static PRESSED: std::cell::Cell<Option<Instant>> = const { std::cell::Cell::new(None) };
+    }
+
+    impl Write for Throttled {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            std::thread::sleep(Duration::from_micros(buf.len() as u64 * 1_000_000 / BYTES_PER_SEC));
+            self.out.extend_from_slice(buf);
+            if self.out.len() >= PRESS_AFTER && PRESSED.get().is_none() {
+                PRESSED.set(Some(Instant::now()));
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Transmit a multi-megabyte image over the slow link, pressing a key
+    /// part way; returns how long the key waited, and what was written.
+    fn interrupted_transfer(caps: KittyCaps) -> (Duration, String) {
+        PRESSED.set(None);
+        let mut kitty = kitty(caps);
+        kitty.input_pending = || Ok(PRESSED.get().is_some());
+        let mut link = Throttled { out: Vec::new() };
+        let pixels = RgbaImage::new(1000, 1000);
+        // Images are only kept under an id where ids are supported
+        let (command, id) = if caps.placements {
+            (GraphicsCommand::new(caps).key('a', 't').id('i', 1).quiet(), Some(1))
+        } else {
+            (GraphicsCommand::new(caps).key('a', 'T'), None)
+        };
+        let result = kitty.transmit(&mut link, pixels, Quality::Full, command, id);
+        let waited = PRESSED.get().expect("the key was never pressed").elapsed();
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::Interrupted);
+        (waited, String::from_utf8(link.out).unwrap())
+    }
+
+    #[test]
+    fn transfer_stops_soon_after_a_key_on_a_slow_link() {
+        let (waited, sent) = interrupted_transfer(KittyCaps::MODERN);
+        assert!(waited < Duration::from_millis(100), "the key waited {:?}", waited);
+        assert!(sent.len() < 256 * 1024, "{} bytes sent", sent.len());
+        assert!(sent.ends_with("\x1b_Gm=0,q=2;\x1b\\\x1b_Ga=d,d=I,i=1,q=2\x1b\\"));
+    }
+
+    #[test]
+    fn legacy_transfer_is_ended_with_a_bare_chunk() {
+        let (waited, sent) = interrupted_transfer(KittyCaps::LEGACY);
+        assert!(waited < Duration::from_millis(100), "the key waited {:?}", waited);
+        assert!(sent.ends_with("\x1b_Gm=0;\x1b\\"));
+        assert!(!sent.contains("q=2"));
+    }
+
+    #[test]
+    fn quirks_flag_overrides_the_terminal() {
+        for terminal in [None, Some("kitty(0.35.2)"), Some("Konsole 23.08.1")] {
+            assert_eq!(kitty_caps(KittyQuirks::Legacy, terminal), KittyCaps::LEGACY);
+            assert_eq!(kitty_caps(KittyQuirks::Strict, terminal), KittyCaps::MODERN);
+        }
+    }
+
+    #[test]
+    fn caps_follow_the_terminal_version() {
+        let cases = [
+            ("kitty(0.35.2)", KittyCaps::MODERN),
+            ("kitty(0.20.0)", KittyCaps::MODERN),
+            ("kitty(0.19.3)", KittyCaps::LEGACY),
+            ("kitty(0.19)", KittyCaps::LEGACY),
+            ("KITTY(0.14.6)", KittyCaps::LEGACY),
+            // A version that can't be read doesn't count against it
+            ("kitty", KittyCaps::MODERN),
+            ("kitty(dev)", KittyCaps::MODERN),
+            ("WezTerm 20240203-110809-5046fc22", KittyCaps::MODERN),
+            ("ghostty 1.0.1", KittyCaps::MODERN),
+            ("Konsole 23.08.1", KittyCaps::LEGACY),
+            ("konsole", KittyCaps::LEGACY),
+            // Not listed: it passed the detection query, so it gets everything
+            ("foot(1.16.2)", KittyCaps::MODERN),
+            ("", KittyCaps::MODERN),
+        ];
+        for (terminal, caps) in cases {
+            assert_eq!(kitty_caps(KittyQuirks::Auto, Some(terminal)), caps, "{:?}", terminal);
+        }
+    }
+
+    #[test]
+    fn silent_terminals_get_everything_unless_windows_terminal() {
+        let expected = if std::env::var_os("WT_SESSION").is_some() {
+            KittyCaps::LEGACY
+        } else {
+            KittyCaps::MODERN
+        };
+        assert_eq!(kitty_caps(KittyQuirks::Auto, None), expected);
+    }
+
+    #[test]
+    fn versions_parse_from_the_start() {
+        assert_eq!(parse_version("0.35.2)"), Some((0, 35, 2)));
+        assert_eq!(parse_version("20240203-110809-5046fc22"), Some((20240203, 0, 0)));
+        assert_eq!(parse_version("1.2"), Some((1, 2, 0)));
+        assert_eq!(parse_version("3"), Some((3, 0, 0)));
+        assert_eq!(parse_version("1..4"), Some((1, 0, 4)));
+        assert_eq!(parse_version("v1.2"), None);
+        assert_eq!(parse_version(""), None);
+    }
+
+    #[test]
+    fn cell_size_comes_from_the_cell_reply_before_the_window() {
+        let cell = Reply::CellSize { width: 9, height: 18 };
+        let window = Reply::WindowSize { width: 800, height: 600 };
+        let da = Reply::DeviceAttributes(vec![62]);
+        let typed = Reply::Input(b"q".to_vec());
+        let cases = [
+            (vec![cell.clone(), window.clone(), da.clone()], Some((9, 18))),
+            (vec![window.clone(), cell.clone()], Some((9, 18))),
+            // Whole cells only: the remainder is padding
+            (vec![typed.clone(), window.clone(), da.clone()], Some((10, 25))),
+            (vec![da.clone()], None),
+            (vec![typed.clone(), da.clone()], None),
+            (vec![Reply::WindowSize { width: 40, height: 12 }], None),
+        ];
+        for (replies, expected) in cases {
+            assert_eq!(cell_size_from_replies(&replies, (80, 24)), expected, "{:?}", replies);
+        }
+        assert_eq!(cell_size_from_replies(std::slice::from_ref(&window), (0, 24)), None);
+        assert_eq!(cell_size_from_replies(&[window, cell], (0, 0)), Some((9, 18)));
+    }
+
+    /// Throughput after sending `count` images of `bytes` each at `rate`
+    /// bytes a second.
+    fn measured(samples: &[(usize, f64, usize)]) -> Throughput {
+        let mut throughput = Throughput::default();
+        for &(bytes, rate, count) in samples {
+            for _ in 0..count {
+                throughput.record(bytes, Duration::from_secs_f64(bytes as f64 / rate));
+            }
+        }
+        throughput
+    }
+
+    const KB: f64 = 1024.0;
+    const MB: f64 = 1024.0 * 1024.0;
+
+    #[test]
+    fn quality_follows_the_measured_rate() {
+        let image = 4 * 1024 * 1024;
+        let cases = [
+            (64.0 * KB, Quality::Reduced),
+            (MB, Quality::Reduced),
+            (3.9 * MB, Quality::Reduced),
+            (4.1 * MB, Quality::Full),
+            (100.0 * MB, Quality::Full),
+            (2048.0 * MB, Quality::Full),
+        ];
+        for (rate, quality) in cases {
+            let measured = measured(&[(image, rate, 3)]).bytes_per_sec();
+            assert_eq!(choose_quality(LinkSpeed::Auto, measured), quality, "{} B/s", rate);
+            assert_eq!(choose_quality(LinkSpeed::Fast, measured), Quality::Full);
+            assert_eq!(choose_quality(LinkSpeed::Slow, measured), Quality::Reduced);
+        }
+    }
+
+    #[test]
+    fn quality_is_full_until_something_big_is_timed() {
+        assert_eq!(choose_quality(LinkSpeed::Auto, None), Quality::Full);
+        // Small writes finish in the buffers, however slow the link
+        let small = measured(&[(64 * 1024, 10.0 * KB, 8)]);
+        assert_eq!(small.bytes_per_sec(), None);
+        assert_eq!(choose_quality(LinkSpeed::Auto, small.bytes_per_sec()), Quality::Full);
+    }
+
+    #[test]
+    fn quality_recovers_once_recent_images_arrive_fast() {
+        let image = 1024 * 1024;
+        let slow = measured(&[(image, 512.0 * KB, 8), (image, 50.0 * MB, 1)]);
+        assert_eq!(choose_quality(LinkSpeed::Auto, slow.bytes_per_sec()), Quality::Reduced);
+        // Only the last few transmissions count
+        let recovered = measured(&[(image, 512.0 * KB, 8), (image, 50.0 * MB, 8)]);
+        assert_eq!(choose_quality(LinkSpeed::Auto, recovered.bytes_per_sec()), Quality::Full);
+        // A big image weighs more than a small one
+        let mixed = measured(&[(20 * image, 50.0 * MB, 1), (image, 512.0 * KB, 1)]);
+        assert!(mixed.bytes_per_sec().unwrap() > 4.0 * MB);
+    }
+
+    #[test]
+    fn quality_is_reported_as_it_changes() {
+        let mut kitty = kitty(KittyCaps::MODERN);
+        kitty.link = LinkSpeed::Auto;
+        assert_eq!(kitty.quality(), Some("full resolution"));
+        kitty.throughput = measured(&[(1024 * 1024, 256.0 * KB, 2)]);
+        let reduced = kitty.quality().unwrap();
+        assert!(reduced.starts_with("half resolution, compressed: "), "{}", reduced);
+        kitty.link = LinkSpeed::Fast;
+        assert_eq!(kitty.quality(), Some("full resolution (--link-speed fast)"));
+        assert_eq!(Blocks { gamma: 1.0 }.quality(), None);
+    }
+
+    /// A console whose first eight colours are the saturated primaries.
+    const PRIMARIES: &str = "#000000,#ff0000,#00ff00,#ffff00,#0000ff,#ff00ff,#00ffff,#ffffff";
+
+    #[test]
+    fn palettes_parse_with_or_without_the_bright_half() {
+        let bright = PRIMARIES;
+        let palette: Palette = bright.parse().unwrap();
+        assert_eq!(palette.0[1], (0xff, 0x00, 0x00));
+        assert_eq!(palette.0[7], (0xff, 0xff, 0xff));
+        assert_eq!(palette.0[8..], Palette::VGA.0[8..]);
+
+        let all = [bright, "808080, ff8080,80ff80,FFFF80,8080ff,ff80ff,80ffff,fefefe"].join(",");
+        let palette: Palette = all.parse().unwrap();
+        assert_eq!(palette.0[8], (0x80, 0x80, 0x80));
+        assert_eq!(palette.0[11], (0xff, 0xff, 0x80));
+        assert_eq!(palette.0[15], (0xfe, 0xfe, 0xfe));
+
+        let errors = [
+            ("", "invalid colour ''"),
+            ("#000000,#ffffff", "expected 8 or 16 colours, got 2"),
+            (&[bright, "#000000"].join(","), "expected 8 or 16 colours, got 9"),
+            (&bright.replace("#000000", "#00000"), "invalid colour '#00000'"),
+            (&bright.replace("#000000", "#gg0000"), "invalid colour '#gg0000'"),
+            (&bright.replace("#000000", "#00000é"), "invalid colour '#00000é'"),
+            (&bright.replace("#ffffff", "#fff"), "invalid colour '#fff'"),
+        ];
+        for (text, error) in errors {
+            assert_eq!(text.parse::<Palette>(), Err(error.to_string()), "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn reported_colours_replace_only_their_own_entries() {
+        let replies = [
+            Reply::PaletteColor { index: 1, color: (0xc0, 0x10, 0x10) },
+            Reply::PaletteColor { index: 200, color: (1, 2, 3) },
+            Reply::DeviceAttributes(vec![62]),
+        ];
+        let palette = palette_from_replies(&replies).unwrap();
+        assert_eq!(palette.0[1], (0xc0, 0x10, 0x10));
+        assert_eq!(palette.0[0], Palette::VGA.0[0]);
+        assert_eq!(palette.0[2..], Palette::VGA.0[2..]);
+
+        // A terminal that answers only the attributes query has no palette to give
+        assert_eq!(palette_from_replies(&replies[1..]), None);
+        assert_eq!(palette_from_replies(&[]), None);
+    }
+
+    #[test]
+    fn palette_colours_map_to_themselves() {
+        for palette in [Palette::VGA, PRIMARIES.parse().unwrap()] {
+            let quantizer = Quantizer::new(palette);
+            for (i, &(r, g, b)) in palette.0.iter().enumerate() {
+                // Entries that repeat an earlier colour map to the first of them
+                let first = palette.0.iter().position(|&c| c == (r, g, b)).unwrap();
+                assert_eq!(nearest_entry(&palette, (r, g, b)), first as u8);
+                let rgba = RgbaImage::from_pixel(4, 4, image::Rgba([r, g, b, 255]));
+                assert!(quantizer.dither(&rgba).iter().all(|&e| e == Some(first as u8)), "{}", i);
+            }
+        }
+    }
+
+    /// Average colour of the palette entries picked for a whole image.
+    fn mean_colour(palette: &Palette, indices: &[Option<u8>]) -> [f64; 3] {
+        let picked: Vec<(u8, u8, u8)> =
+            indices.iter().flatten().map(|&i| palette.0[i as usize]).collect();
+        let n = picked.len() as f64;
+        [
+            picked.iter().map(|c| c.0 as f64).sum::<f64>() / n,
+            picked.iter().map(|c| c.1 as f64).sum::<f64>() / n,
+            picked.iter().map(|c| c.2 as f64).sum::<f64>() / n,
+        ]
+    }
+
+    #[test]
+    fn dithering_keeps_the_average_colour() {
+        let quantizer = Quantizer::new(Palette::VGA);
+        for colour in [[128, 128, 128], [200, 40, 90], [30, 150, 150], [250, 250, 240]] {
+            let [r, g, b] = colour;
+            let rgba = RgbaImage::from_pixel(32, 32, image::Rgba([r, g, b, 255]));
+            let indices = quantizer.dither(&rgba);
+            let mean = mean_colour(&Palette::VGA, &indices);
+            for c in 0..3 {
+                let off = (mean[c] - colour[c] as f64).abs();
+                assert!(off < 6.0, "{:?} came out {:?}", colour, mean);
+            }
+            // A colour between entries needs more than one of them
+            assert!(indices.iter().any(|&e| e != indices[0]), "{:?}", colour);
+        }
+    }
+
+    #[test]
+    fn transparent_pixels_are_left_out_and_pass_no_error_on() {
+        let quantizer = Quantizer::new(Palette::VGA);
+        let rgba = RgbaImage::from_fn(8, 1, |x, _| {
+            let alpha = if x % 2 == 0 { 255 } else { 0 };
+            image::Rgba([0, 0, 0, alpha])
+        });
+        let indices = quantizer.dither(&rgba);
+        assert_eq!(indices, [Some(0), None, Some(0), None, Some(0), None, Some(0), None]);
+
+        let mut noisy = RgbaImage::from_pixel(3, 1, image::Rgba([0, 0, 0, 255]));
+        noisy.put_pixel(0, 0, image::Rgba([250, 250, 250, 10]));
+        assert_eq!(quantizer.dither(&noisy), [None, Some(0), Some(0)]);
+    }
+
+    /// A colourful test card: hues across, light to dark down.
+    fn test_card() -> RgbaImage {
+        RgbaImage::from_fn(48, 24, |x, y| {
+            let hue = x as f64 / 48.0 * 6.0;
+            let level = 1.0 - y as f64 / 24.0;
+            let channel = |offset: f64| {
+                let d = ((hue - offset).rem_euclid(6.0) - 3.0).abs();
+                ((d - 1.0).clamp(0.0, 1.0) * level * 255.0) as u8
+            };
+            image::Rgba([channel(0.0), channel(2.0), channel(4.0), 255])
+        })
+    }
+
+    #[test]
+    fn custom_palette_changes_the_dithering() {
+        let card = test_card();
+        let vga = Quantizer::new(Palette::VGA).dither(&card);
+        let primaries: Palette = PRIMARIES.parse().unwrap();
+        let custom = Quantizer::new(primaries).dither(&card);
+        assert_ne!(vga, custom);
+
+        // Each comes out closer to the card with the palette it was made for
+        let error = |palette: &Palette, indices: &[Option<u8>]| {
+            let mut total = 0.0;
+            for (pixel, index) in card.pixels().zip(indices) {
+                let (r, g, b) = palette.0[index.unwrap() as usize];
+                let d = |a: u8, b: u8| (a as f64 - b as f64).abs();
+                total += d(pixel[0], r) + d(pixel[1], g) + d(pixel[2], b);
+            }
+            total
+        };
+        assert!(error(&primaries, &custom) < error(&primaries, &vga));
+        assert!(error(&Palette::VGA, &vga) < error(&Palette::VGA, &custom));
+    }
+
+    #[test]
+    fn same_view_is_dithered_once() {
+        let mut blocks = Blocks16 {
+            quantizer: Quantizer::new(Palette::VGA),
+            gamma: 1.0,
+            drawn: None,
+        };
+        let img = DynamicImage::ImageRgba8(test_card());
+        let view = View { rect: (0, 0, 48, 24), scaling: Scaling::FIT, grid: false };
+        let area = (0, 0, 12, 6);
+        let first = written(|out| blocks.display_view(out, &img, view, 7, area, (8, 16)));
+        assert!(!first.is_empty());
+        let drawn = blocks.drawn.as_ref().map(|d| d.0);
+        assert_eq!(drawn, Some((7, view, area, (8, 16))));
+
+        // Changing the cached entries shows they're used as they are
+        if let Some((_, _, indices)) = blocks.drawn.as_mut() {
+            indices.iter_mut().for_each(|i| *i = Some(9));
+        }
+        let again = written(|out| blocks.display_view(out, &img, view, 7, area, (8, 16)));
+        assert_ne!(again, first);
+        let other = written(|out| blocks.display_view(out, &img, view, 8, area, (8, 16)));
+        assert_eq!(other, first);
+    }
+
+    /// Where `gridded` should start each of `src` image pixels spread over
+    /// `len` screen pixels: the first screen pixel mapped to each.
+    fn line_starts(src: u32, len: u32) -> Vec<u32> {
+        (0..src).map(|i| (i * len).div_ceil(src)).collect()
+    }
+
+    #[test]
+    fn grid_lines_start_every_image_pixel_at_any_scale() {
+        const LIGHT: image::Rgba<u8> = image::Rgba([220, 220, 220, 255]);
+        let mut line = LIGHT;
+        shade(&mut line);
+        for (src, len) in [(4, 16), (4, 37), (7, 56), (7, 60), (16, 100), (3, 1000)] {
+            let img = DynamicImage::ImageRgba8(RgbaImage::from_pixel(src, src + 1, LIGHT));
+            let out = gridded(&img, len, len + 3);
+            let cols = line_starts(src, len);
+            let rows = line_starts(src + 1, len + 3);
+            // A row and a column that aren't lines themselves
+            let (y, x) = (rows[1] - 1, cols[1] - 1);
+            let shaded_cols: Vec<u32> =
+                (0..len).filter(|&c| *out.get_pixel(c, y) == line).collect();
+            let shaded_rows: Vec<u32> =
+                (0..len + 3).filter(|&r| *out.get_pixel(x, r) == line).collect();
+            assert_eq!(shaded_cols, cols, "{} pixels over {}", src, len);
+            assert_eq!(shaded_rows, rows, "{} pixels over {}", src + 1, len + 3);
+            // Everything else keeps its colour
+            let plain = out.pixels().filter(|&&px| px == LIGHT).count() as u32;
+            assert_eq!(plain, (len - src) * (len + 3 - src - 1));
+        }
+    }
+
+    #[test]
+    fn grid_lines_contrast_with_their_pixel() {
+        let mut light = image::Rgba([200, 180, 160, 255]);
+        shade(&mut light);
+        assert_eq!(light.0, [125, 113, 100, 255]);
+        let mut dark = image::Rgba([0, 40, 80, 255]);
+        shade(&mut dark);
+        assert_eq!(dark.0, [95, 120, 145, 255]);
+        let mut clear = image::Rgba([255, 255, 255, 0]);
+        shade(&mut clear);
+        assert_eq!(clear.0, [128, 128, 128, 255]);
+    }
+}
@@ -0,0 +1,665 @@
+//! Parsing what the terminal sends back to queries.
+//!
+//! Replies arrive on the same stream as keystrokes. They can be split across
+//! reads, mangled by multiplexers, or have keys typed in the middle of them.
+//! `ReplyParser` is fed bytes as they are read. Each recognised reply comes
+//! out as a `Reply` whose parameters have been checked. Everything else comes
+//! out as `Reply::Input`, byte for byte and in order, so no input is lost or
+//! repeated. That includes garbage, sequences nobody asked for, and replies
+//! with out-of-range values.
+
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+
+/// Longest escape sequence waited for. Anything longer is treated as input,
+/// so a stray ESC can't swallow everything typed after it.
+const MAX_SEQUENCE_LEN: usize = 1024;
+
+/// Largest cell size, in pixels, taken as genuine.
+const MAX_CELL_PX: u16 = 1024;
+
+const ESC: u8 = 0x1b;
+const BEL: u8 = 0x07;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reply {
+    /// Primary device attributes (`CSI ? Ps ; ... c`).
+    DeviceAttributes(Vec<u16>),
+    /// Kitty graphics protocol response: `OK` or an error message.
+    Graphics { id: Option<u32>, message: String },
+    /// Size of a cell in pixels (`CSI 6 ; height ; width t`).
+    CellSize { width: u16, height: u16 },
+    /// Size of the text area in pixels (`CSI 4 ; height ; width t`).
+    WindowSize { width: u16, height: u16 },
+    /// Cursor position, 1-based (`CSI row ; col R`).
+    CursorPosition { row: u16, col: u16 },
+    /// Background colour (`OSC 11 ; rgb:R/G/B`), scaled to 8 bits per channel.
+    Background(u8, u8, u8),
+    /// One of the 256 indexed colours (`OSC 4 ; index ; rgb:R/G/B`), scaled
+    /// the same way.
+    PaletteColor { index: u8, color: (u8, u8, u8) },
+    /// Terminal name and version (XTVERSION, `DCS > | text ST`).
+    TerminalVersion(String),
+    /// Bytes that aren't a reply, such as keys typed while waiting for one.
+    Input(Vec<u8>),
+}
+
+/// What the bytes at the start of the buffer turned out to be.
+enum Scan {
+    /// A complete sequence of this many bytes, which may or may not be a reply.
+    Sequence(usize, Option<Reply>),
+    /// The start of what may be a sequence; more bytes are needed.
+    Partial,
+    /// Not a sequence: the ESC is just input.
+    NotSequence,
+}
+
+#[derive(Debug, Default)]
+pub struct ReplyParser {
+    /// Bytes of a sequence that hasn't finished arriving.
+    pending: Vec<u8>,
+}
+
+impl ReplyParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Consume `bytes`, returning the replies and input completed by them.
+    pub fn feed(&mut self, bytes: &[u8]) -> Vec<Reply> {
+        self.pending.extend_from_slice(bytes);
+        let buf = std::mem::take(&mut self.pending);
+        let mut out = Vec::new();
+        let mut pos = 0;
+        while pos < buf.len() {
+            if buf[pos] != ESC {
+                let end = buf[pos..]
+                    .iter()
+                    .position(|&b| b == ESC)
+                    .map_or(buf.len(), |i| pos + i);
+                push_input(&mut out, &buf[pos..end]);
+                pos = end;
+                continue;
+            }
+            // Looking no further than the longest sequence keeps the result
+            // the same however the bytes were split across reads
+            let window = &buf[pos..buf.len().min(pos + MAX_SEQUENCE_LEN)];
+            match scan(window) {
+                Scan::Sequence(len, Some(reply)) => {
+                    out.push(reply);
+                    pos += len;
+                }
+                Scan::Sequence(len, None) => {
+                    push_input(&mut out, &buf[pos..pos + len]);
+                    pos += len;
+                }
+                Scan::Partial if window.len() < MAX_SEQUENCE_LEN => {
+                    self.pending = buf[pos..].to_vec();
+                    return out;
+                }
+                Scan::Partial | Scan::NotSequence => {
+                    push_input(&mut out, &buf[pos..pos + 1]);
+                    pos += 1;
+                }
+            }
+        }
+        out
+    }
+
+    /// The bytes of a sequence cut off by the end of the reading, such as a
+    /// lone ESC, which can only have been input.
+    pub fn finish(self) -> Vec<u8> {
+        self.pending
+    }
+}
+
+/// Append input bytes, merging with input just before them.
+fn push_input(out: &mut Vec<Reply>, bytes: &[u8]) {
+    if let Some(Reply::Input(last)) = out.last_mut() {
+        last.extend_from_slice(bytes);
+    } else {
+        out.push(Reply::Input(bytes.to_vec()));
+    }
+}
+
+/// Look at the sequence starting with the ESC at `buf[0]`.
+fn scan(buf: &[u8]) -> Scan {
+    match buf.get(1) {
+        None => Scan::Partial,
+        Some(b'[') => scan_csi(buf),
+        Some(b']') => scan_string(buf, true, parse_osc),
+        Some(b'_') => scan_string(buf, false, parse_apc),
+        Some(b'P') => scan_string(buf, false, parse_dcs),
+        Some(_) => Scan::NotSequence,
+    }
+}
+
+/// `ESC [`, parameter and intermediate bytes, then one final byte.
+fn scan_csi(buf: &[u8]) -> Scan {
+    for (i, &b) in buf.iter().enumerate().skip(2) {
+        match b {
+            0x20..=0x3f => continue,
+            0x40..=0x7e => {
+                let body = std::str::from_utf8(&buf[2..i]).unwrap_or("");
+                return Scan::Sequence(i + 1, parse_csi(body, b));
+            }
+            _ => return Scan::NotSequence,
+        }
+    }
+    Scan::Partial
+}
+
+/// A control string (`ESC ]`, `ESC _` or `ESC P`) ended by ST (`ESC \`), or by BEL
+/// where `bel_ends` (OSC replies use either).
+fn scan_string(buf: &[u8], bel_ends: bool, parse: fn(&str) -> Option<Reply>) -> Scan {
+    let mut i = 2;
+    while i < buf.len() {
+        let end = match buf[i] {
+            BEL if bel_ends => Some((i, i + 1)),
+            ESC => match buf.get(i + 1) {
+                Some(b'\\') => Some((i, i + 2)),
+                Some(_) => return Scan::NotSequence,
+                None => return Scan::Partial,
+            },
+            _ => None,
+        };
+        if let Some((content_end, len)) = end {
+            let content = std::str::from_utf8(&buf[2..content_end]).ok();
+            return Scan::Sequence(len, content.and_then(parse));
+        }
+        i += 1;
+    }
+    Scan::Partial
+}
+
+/// Numeric parameters separated by `;`, or `None` if any is malformed.
+fn numbers(params: &str) -> Option<Vec<u16>> {
+    params.split(';').map(|p| p.parse().ok()).collect()
+}
+
+fn parse_csi(body: &str, last: u8) -> Option<Reply> {
+    match last {
+        b'c' => {
+            let attrs = numbers(body.strip_prefix('?')?)?;
+            Some(Reply::DeviceAttributes(attrs))
+        }
+        b't' => {
+            let (kind, height, width) = match numbers(body)?[..] {
+                [kind, height, width] if height > 0 && width > 0 => (kind, height, width),
+                _ => return None,
+            };
+            match kind {
+                6 if width <= MAX_CELL_PX && height <= MAX_CELL_PX => {
+                    Some(Reply::CellSize { width, height })
+                }
+                4 => Some(Reply::WindowSize { width, height }),
+                _ => None,
+            }
+        }
+        b'R' => match numbers(body)?[..] {
+            [row, col] if row > 0 && col > 0 => Some(Reply::CursorPosition { row, col }),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// `11;COLOR` or `4;INDEX;COLOR`.
+fn parse_osc(content: &str) -> Option<Reply> {
+    if let Some(spec) = content.strip_prefix("11;") {
+        let (r, g, b) = parse_rgb(spec)?;
+        return Some(Reply::Background(r, g, b));
+    }
+    let (index, spec) = content.strip_prefix("4;")?.split_once(';')?;
+    Some(Reply::PaletteColor {
+        index: index.parse().ok()?,
+        color: parse_rgb(spec)?,
+    })
+}
+
+/// `rgb:RRRR/GGGG/BBBB`, with 1 to 4 hex digits per channel.
+fn parse_rgb(spec: &str) -> Option<(u8, u8, u8)> {
+    let spec = spec.strip_prefix("rgb:")?;
+    let mut channels = spec.split('/').map(|c| {
+        if c.is_empty() || c.len() > 4 {
+            return None;
+        }
+        let value = u32::from_str_radix(c, 16).ok()?;
+        let max = (1u32 << (4 * c.len())) - 1;
+        Some((value * 255 / max) as u8)
+    });
+    let color = (channels.next()??, channels.next()??, channels.next()??);
+    if channels.next().is_some() {
+        return None;
+    }
+    Some(color)
+}
+
+/// `G` followed by `key=value,...;message`.
+fn parse_apc(content: &str) -> Option<Reply> {
+    let (keys, message) = content.strip_prefix('G')?.split_once(';')?;
+    let id = keys
+        .split(',')
+        .find_map(|kv| kv.strip_prefix("i="))
+        .and_then(|v| v.parse().ok());
+    Some(Reply::Graphics {
+        id,
+        message: message.to_string(),
+    })
+}
+
+/// `>|` followed by the terminal's name and version.
+fn parse_dcs(content: &str) -> Option<Reply> {
+    let version = content.strip_prefix(">|")?;
+    (!version.is_empty()).then(|| Reply::TerminalVersion(version.to_string()))
+}
+
+/// The key presses in input read together with replies, as crossterm would
+/// report them: text, control characters, Alt as a leading ESC, the CSI and
+/// SS3 sequences of special keys, and the kitty keyboard protocol's
+/// `CSI code ; modifiers u`. Anything else is dropped.
+pub fn keys(input: &[u8]) -> Vec<KeyEvent> {
+    let mut keys = Vec::new();
+    let mut rest = input;
+    while !rest.is_empty() {
+        let (len, key) = match rest {
+            [ESC] => (1, Some(KeyEvent::new(KeyCode::Esc, KeyModifiers::NONE))),
+            [ESC, b'[', ..] => csi_key(rest),
+            [ESC, b'O', last, ..] => (3, ss3_key(*last)),
+            [ESC, ..] => {
+                let (len, key) = plain_key(&rest[1..]);
+                (len + 1, key.map(|k| KeyEvent::new(k.code, k.modifiers | KeyModifiers::ALT)))
+            }
+            _ => plain_key(rest),
+        };
+        keys.extend(key);
+        rest = &rest[len.min(rest.len())..];
+    }
+    keys
+}
+
+/// A key that is a single character, and its length in bytes.
+fn plain_key(input: &[u8]) -> (usize, Option<KeyEvent>) {
+    let key = |code| Some(KeyEvent::new(code, KeyModifiers::NONE));
+    match input[0] {
+        b'\r' | b'\n' => (1, key(KeyCode::Enter)),
+        b'\t' => (1, key(KeyCode::Tab)),
+        0x7f | 0x08 => (1, key(KeyCode::Backspace)),
+        0 => (1, Some(KeyEvent::new(KeyCode::Char(' '), KeyModifiers::CONTROL))),
+        b @ 1..=26 => {
+            let c = (b'a' + b - 1) as char;
+            (1, Some(KeyEvent::new(KeyCode::Char(c), KeyModifiers::CONTROL)))
+        }
+        27..=0x1f => (1, None),
+        first => {
+            let len = match first {
+                0xc0..=0xdf => 2,
+                0xe0..=0xef => 3,
+                0xf0..=0xf7 => 4,
+                _ => 1,
+            };
+            let c = input
+                .get(..len)
+                .and_then(|b| std::str::from_utf8(b).ok())
+                .and_then(|s| s.chars().next());
+            match c {
+                Some(c) if c.is_uppercase() => {
+                    (len, Some(KeyEvent::new(KeyCode::Char(c), KeyModifiers::SHIFT)))
+                }
+                Some(c) => (len, key(KeyCode::Char(c))),
+                None => (1, None),
+            }
+        }
+    }
+}
+
+/// `ESC O` and a final byte: cursor keys in application mode, and F1-F4.
+fn ss3_key(last: u8) -> Option<KeyEvent> {
+    let code = match last {
+        b'P'..=b'S' => KeyCode::F(last - b'P' + 1),
+        _ => cursor_key(last)?,
+    };
+    Some(KeyEvent::new(code, KeyModifiers::NONE))
+}
+
+fn cursor_key(last: u8) -> Option<KeyCode> {
+    Some(match last {
+        b'A' => KeyCode::Up,
+        b'B' => KeyCode::Down,
+        b'C' => KeyCode::Right,
+        b'D' => KeyCode::Left,
+        b'H' => KeyCode::Home,
+        b'F' => KeyCode::End,
+        _ => return None,
+    })
+}
+
+/// A key sent as `ESC [`, numbers, and a final byte, and its length in bytes.
+fn csi_key(input: &[u8]) -> (usize, Option<KeyEvent>) {
+    let Some(end) = input[2..].iter().position(|b| !(0x20..=0x3f).contains(b)) else {
+        return (input.len(), None);
+    };
+    let end = end + 2;
+    let len = end + 1;
+    let last = input[end];
+    if !(0x40..=0x7e).contains(&last) {
+        return (end, None);
+    }
+    // Each number may have `:`-separated alternatives; the first is the key
+    let params = std::str::from_utf8(&input[2..end]).unwrap_or("");
+    let mut numbers = params.split(';').map(|p| p.split(':').next().unwrap_or("").parse().ok());
+    let number: Option<u32> = numbers.next().flatten();
+    let modifiers = match numbers.next().flatten() {
+        Some(m @ 1..) => {
+            let bits = m - 1;
+            let mut modifiers = KeyModifiers::NONE;
+            for (bit, modifier) in [
+                (1, KeyModifiers::SHIFT),
+                (2, KeyModifiers::ALT),
+                (4, KeyModifiers::CONTROL),
+            ] {
+                if bits & bit != 0 {
+                    modifiers |= modifier;
+                }
+            }
+            modifiers
+        }
+        _ => KeyModifiers::NONE,
+    };
+    let code = match (last, number) {
+        (b'Z', _) => Some(KeyCode::BackTab),
+        (b'P'..=b'S', _) => Some(KeyCode::F(last - b'P' + 1)),
+        (b'~', Some(n)) => tilde_key(n),
+        (b'u', Some(n)) => match n {
+            27 => Some(KeyCode::Esc),
+            13 => Some(KeyCode::Enter),
+            9 => Some(KeyCode::Tab),
+            127 => Some(KeyCode::Backspace),
+            _ => char::from_u32(n).filter(|c| !c.is_control()).map(KeyCode::Char),
+        },
+        _ => cursor_key(last),
+    };
+    (len, code.map(|code| KeyEvent::new(code, modifiers)))
+}
+
+/// The key of `CSI number ~`.
+fn tilde_key(number: u32) -> Option<KeyCode> {
+    Some(match number {
+        1 | 7 => KeyCode::Home,
+        2 => KeyCode::Insert,
+        3 => KeyCode::Delete,
+        4 | 8 => KeyCode::End,
+        5 => KeyCode::PageUp,
+        6 => KeyCode::PageDown,
+        11..=15 => KeyCode::F((number - 10) as u8),
+        17..=21 => KeyCode::F((number - 11) as u8),
+        23 | 24 => KeyCode::F((number - 12) as u8),
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// xorshift, so failures can be reproduced from the seed.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+    }
+
+    /// Replies a terminal sends, with what they parse to.
+    fn replies() -> Vec<(&'static [u8], Reply)> {
+        vec![
+            (b"\x1b[?62;4;22c", Reply::DeviceAttributes(vec![62, 4, 22])),
+            (b"\x1b[6;20;10t", Reply::CellSize { width: 10, height: 20 }),
+            (b"\x1b[4;600;800t", Reply::WindowSize { width: 800, height: 600 }),
+            (b"\x1b[12;40R", Reply::CursorPosition { row: 12, col: 40 }),
+            (b"\x1b]11;rgb:ffff/8080/0000\x07", Reply::Background(255, 128, 0)),
+            (
+                b"\x1b]4;3;rgb:00/ff/00\x1b\\",
+                Reply::PaletteColor {
+                    index: 3,
+                    color: (0, 255, 0),
+                },
+            ),
+            (
+                b"\x1b_Gi=31;OK\x1b\\",
+                Reply::Graphics {
+                    id: Some(31),
+                    message: "OK".into(),
+                },
+            ),
+            (b"\x1bP>|kitty(0.35.2)\x1b\\", Reply::TerminalVersion("kitty(0.35.2)".into())),
+        ]
+    }
+
+    /// Input a user may type: text, keys sent as sequences, and ESC itself.
+    const TYPED: &[&[u8]] = &[
+        b"q",
+        b"hello",
+        b"\x1b[A",
+        b"\x1b[5~",
+        b"\x1b[27u",
+        b"\xc3\xa9",
+        b"\x1b",
+    ];
+
+    /// Garbage: out-of-range values, sequences nobody asked for, cut-off ones.
+    const GARBAGE: &[&[u8]] = &[
+        b"\x1b[6;0;10t",
+        b"\x1b[6;5000;10t",
+        b"\x1b[?c",
+        b"\x1b[0;5R",
+        b"\x1b]11;rgb:zz/00/00\x07",
+        b"\x1b_Gno separator\x1b\\",
+        b"\x1b[12",
+        b"\x1bP>|\x1b\\",
+        b"\x1b]4;300;rgb:0/0/0\x1b\\",
+        b"\xff\x00\x7f",
+    ];
+
+    /// Feed `bytes` in pieces of random sizes, merging the input that comes out.
+    fn feed_split(bytes: &[u8], rng: &mut Rng) -> (Vec<Reply>, Vec<u8>) {
+        let mut parser = ReplyParser::new();
+        let mut out = Vec::new();
+        let mut rest = bytes;
+        while !rest.is_empty() {
+            let n = 1 + rng.below(rest.len().min(40));
+            for reply in parser.feed(&rest[..n]) {
+                match reply {
+                    Reply::Input(bytes) => push_input(&mut out, &bytes),
+                    reply => out.push(reply),
+                }
+            }
+            rest = &rest[n..];
+        }
+        (out, parser.finish())
+    }
+
+    #[test]
+    fn replies_and_typed_input_are_separated_however_they_are_split() {
+        let replies = replies();
+        for seed in 1..500 {
+            let mut rng = Rng(seed);
+            let mut stream = Vec::new();
+            let mut expected = Vec::new();
+            for _ in 0..rng.below(12) {
+                if rng.below(2) == 0 {
+                    let (bytes, reply) = &replies[rng.below(replies.len())];
+                    stream.extend_from_slice(bytes);
+                    expected.push(reply.clone());
+                } else {
+                    // A lone ESC is only input once something that can't
+                    // continue a sequence follows it
+                    let typed = TYPED[rng.below(TYPED.len() - 1)];
+                    stream.extend_from_slice(typed);
+                    push_input(&mut expected, typed);
+                }
+            }
+            let (out, left) = feed_split(&stream, &mut rng);
+            assert_eq!(out, expected, "seed {}", seed);
+            assert!(left.is_empty(), "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn garbage_comes_out_as_input_byte_for_byte() {
+        for seed in 1..500 {
+            let mut rng = Rng(seed);
+            let mut stream = Vec::new();
+            for _ in 0..1 + rng.below(8) {
+                let pieces = if rng.below(2) == 0 { GARBAGE } else { TYPED };
+                stream.extend_from_slice(pieces[rng.below(pieces.len())]);
+            }
+            let (out, left) = feed_split(&stream, &mut rng);
+            let mut input = Vec::new();
+            for reply in out {
+                match reply {
+                    Reply::Input(bytes) => input.extend(bytes),
+                    reply => panic!("seed {}: {:?} out of garbage", seed, reply),
+                }
+            }
+            input.extend(left);
+            assert_eq!(input, stream, "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn random_bytes_never_lose_input() {
+        for seed in 1..200 {
+            let mut rng = Rng(seed);
+            let len = rng.below(3000);
+            // Mostly the bytes sequences are made of, so they get far into them
+            let alphabet = b"\x1b[]_P;?0123456789tRcG=\\\x07>|:rgb/ ";
+            let stream: Vec<u8> = (0..len)
+                .map(|_| match rng.below(8) {
+                    0 => rng.next() as u8,
+                    _ => alphabet[rng.below(alphabet.len())],
+                })
+                .collect();
+            let whole = {
+                let mut parser = ReplyParser::new();
+                let mut out = parser.feed(&stream);
+                out.push(Reply::Input(parser.finish()));
+                out
+            };
+            let (split, left) = feed_split(&stream, &mut rng);
+            let input = |replies: &[Reply]| -> Vec<u8> {
+                replies
+                    .iter()
+                    .filter_map(|r| match r {
+                        Reply::Input(bytes) => Some(bytes.clone()),
+                        _ => None,
+                    })
+                    .flatten()
+                    .collect()
+            };
+            let mut split_input = input(&split);
+            split_input.extend(left);
+            assert_eq!(input(&whole), split_input, "seed {}", seed);
+            // Replies are never made up: each is a sequence present in the stream
+            let replies = split.iter().filter(|r| !matches!(r, Reply::Input(_))).count();
+            assert!(replies <= stream.iter().filter(|&&b| b == ESC).count(), "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn long_unterminated_sequences_are_input() {
+        let mut stream = b"\x1b]11;".to_vec();
+        stream.extend(std::iter::repeat_n(b'x', MAX_SEQUENCE_LEN));
+        stream.extend_from_slice(b"\x1b[?1c");
+        let mut parser = ReplyParser::new();
+        let out = parser.feed(&stream);
+        assert_eq!(out[0], Reply::Input(stream[..stream.len() - 5].to_vec()));
+        assert_eq!(out[1], Reply::DeviceAttributes(vec![1]));
+    }
+
+    #[test]
+    fn cut_off_sequence_is_left_for_finish() {
+        let mut parser = ReplyParser::new();
+        assert_eq!(parser.feed(b"j\x1b"), vec![Reply::Input(b"j".to_vec())]);
+        assert_eq!(parser.finish(), b"\x1b");
+    }
+
+    #[test]
+    fn size_replies_parse_and_malformed_ones_are_input() {
+        let cell = |width, height| Some(Reply::CellSize { width, height });
+        let window = |width, height| Some(Reply::WindowSize { width, height });
+        let cases: &[(&[u8], Option<Reply>)] = &[
+            (b"\x1b[6;16;8t", cell(8, 16)),
+            (b"\x1b[6;1024;1024t", cell(1024, 1024)),
+            (b"\x1b[4;1080;1920t", window(1920, 1080)),
+            (b"\x1b[4;65535;65535t", window(65535, 65535)),
+            // Malformed or not asked for
+            (b"\x1b[6;16t", None),
+            (b"\x1b[6;16;8;2t", None),
+            (b"\x1b[6;;8t", None),
+            (b"\x1b[6;16;0t", None),
+            (b"\x1b[4;0;1920t", None),
+            (b"\x1b[6;16;-8t", None),
+            (b"\x1b[6;16; 8t", None),
+            (b"\x1b[6;1025;8t", None),
+            (b"\x1b[4;65536;1920t", None),
+            (b"\x1b[8;24;80t", None),
+            (b"\x1b[t", None),
+            (b"\x1b[?6;16;8t", None),
+        ];
+        for (bytes, expected) in cases {
+            let mut parser = ReplyParser::new();
+            let out = parser.feed(bytes);
+            let what = String::from_utf8_lossy(bytes);
+            match expected {
+                Some(reply) => assert_eq!(out, std::slice::from_ref(reply), "{:?}", what),
+                None => assert_eq!(out, [Reply::Input(bytes.to_vec())], "{:?}", what),
+            }
+            assert!(parser.finish().is_empty(), "{:?}", what);
+        }
+    }
+
+    fn key(code: KeyCode, modifiers: KeyModifiers) -> KeyEvent {
+        KeyEvent::new(code, modifiers)
+    }
+
+    #[test]
+    fn typed_keys_decode_as_crossterm_reports_them() {
+        let none = KeyModifiers::NONE;
+        let cases: &[(&[u8], Vec<KeyEvent>)] = &[
+            (b"lq", vec![key(KeyCode::Char('l'), none), key(KeyCode::Char('q'), none)]),
+            (b"R", vec![key(KeyCode::Char('R'), KeyModifiers::SHIFT)]),
+            ("é".as_bytes(), vec![key(KeyCode::Char('é'), none)]),
+            (b"\r\t\x7f", vec![
+                key(KeyCode::Enter, none),
+                key(KeyCode::Tab, none),
+                key(KeyCode::Backspace, none),
+            ]),
+            (b"\x03", vec![key(KeyCode::Char('c'), KeyModifiers::CONTROL)]),
+            (b"\x1b", vec![key(KeyCode::Esc, none)]),
+            (b"\x1bx", vec![key(KeyCode::Char('x'), KeyModifiers::ALT)]),
+            (b"\x1b[D\x1bOA", vec![key(KeyCode::Left, none), key(KeyCode::Up, none)]),
+            (b"\x1b[1;5C", vec![key(KeyCode::Right, KeyModifiers::CONTROL)]),
+            (b"\x1b[6~\x1b[3~", vec![key(KeyCode::PageDown, none), key(KeyCode::Delete, none)]),
+            (b"\x1b[15~\x1bOP", vec![key(KeyCode::F(5), none), key(KeyCode::F(1), none)]),
+            (b"\x1b[27u", vec![key(KeyCode::Esc, none)]),
+            (b"\x1b[114;5u", vec![key(KeyCode::Char('r'), KeyModifiers::CONTROL)]),
+            (b"\x1b[97:65;2u", vec![key(KeyCode::Char('a'), KeyModifiers::SHIFT)]),
+            (b"\x1b[Z", vec![key(KeyCode::BackTab, none)]),
+            // Not keys: dropped without taking what follows with them
+            (b"\x1b[?1;2cq", vec![key(KeyCode::Char('q'), none)]),
+            (b"\x1b[12", vec![]),
+            (b"\x1c\xff", vec![]),
+        ];
+        for (bytes, expected) in cases {
+            assert_eq!(&keys(bytes), expected, "{:?}", String::from_utf8_lossy(bytes));
+        }
+    }
+}
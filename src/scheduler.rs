@@ -0,0 +1,222 @@
+//! Timers for the main loop.
+//!
+//! Features register deadlines here instead of checking the clock on every
+//! pass through the event loop. The loop sleeps in `event::poll` until the
+//! earliest deadline (or input arrives), then dispatches whatever expired.
+
+use std::time::{Duration, Instant};
+
+/// Everything that can wake the main loop without input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timer {
+    /// Rescan the directory in follow mode.
+    FollowScan,
+    /// Rescan an empty directory for a first image (`--wait-for-images`).
+    WaitScan,
+    /// Show the next frame of an animation.
+    Frame,
+    /// See whether a decode running in the background has finished.
+    Decode,
+    /// Move on to the next image of a slideshow.
+    Slideshow,
+    /// Read what the directory watcher has seen.
+    Watch,
+    /// Act on watched changes once writes have stopped for a moment.
+    WatchSettle,
+    /// Redraw for a new terminal size once resizing has stopped for a moment.
+    Resize,
+    /// Take the note on how the image list changed off the status bar.
+    ListNotice,
+    /// Match the letterbox to the image's edges once the view stops moving.
+    Letterbox,
+}
+
+#[derive(Debug)]
+struct Entry {
+    timer: Timer,
+    deadline: Instant,
+    /// Re-arm with this period after firing.
+    every: Option<Duration>,
+}
+
+/// A set of pending timers, at most one per `Timer` kind.
+#[derive(Debug, Default)]
+pub struct Scheduler {
+    entries: Vec<Entry>,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Scheduler::default()
+    }
+
+    /// Fire `timer` every `period`, starting one period from now.
+    pub fn every(&mut self, timer: Timer, period: Duration) {
+        self.insert(timer, Instant::now() + period, Some(period));
+    }
+
+    /// Fire `timer` once, `delay` from now.
+    pub fn after(&mut self, timer: Timer, delay: Duration) {
+        self.insert(timer, Instant::now() + delay, None);
+    }
+
+    /// Fire `timer` once, at `deadline`.
+    pub fn at(&mut self, timer: Timer, deadline: Instant) {
+        self.insert(timer, deadline, None);
+    }
+
+    pub fn is_pending(&self, timer: Timer) -> bool {
+        self.entries.iter().any(|e| e.timer == timer)
+    }
+
+    pub fn cancel(&mut self, timer: Timer) {
+        self.entries.retain(|e| e.timer != timer);
+    }
+
+    /// Re-scheduling a pending timer moves it rather than adding a second
+    /// one, so repeated requests coalesce.
+    fn insert(&mut self, timer: Timer, deadline: Instant, every: Option<Duration>) {
+        self.cancel(timer);
+        self.entries.push(Entry {
+            timer,
+            deadline,
+            every,
+        });
+    }
+
+    /// How long the loop may wait for input before a timer is due, capped at
+    /// `max` so the loop still wakes occasionally when nothing is scheduled.
+    pub fn timeout(&self, now: Instant, max: Duration) -> Duration {
+        self.entries
+            .iter()
+            .map(|e| e.deadline.saturating_duration_since(now))
+            .min()
+            .map_or(max, |d| d.min(max))
+    }
+
+    /// Remove and return the timers due at `now`, earliest first. Recurring
+    /// timers are re-armed one period after their deadline, skipping periods
+    /// that were missed entirely rather than firing them all at once.
+    pub fn expired(&mut self, now: Instant) -> Vec<Timer> {
+        let mut due: Vec<(Instant, Timer)> = Vec::new();
+
+        self.entries.retain_mut(|e| {
+            if e.deadline > now {
+                return true;
+            }
+            due.push((e.deadline, e.timer));
+            match e.every {
+                Some(period) => {
+                    e.deadline += period;
+                    if e.deadline <= now {
+                        e.deadline = now + period;
+                    }
+                    true
+                }
+                None => false,
+            }
+        });
+
+        due.sort_by_key(|(deadline, _)| *deadline);
+        due.into_iter().map(|(_, timer)| timer).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MS: Duration = Duration::from_millis(1);
+
+    #[test]
+    fn expired_timers_come_out_earliest_first() {
+        let now = Instant::now();
+        let mut timers = Scheduler::new();
+        timers.at(Timer::Slideshow, now + 30 * MS);
+        timers.at(Timer::Frame, now + 10 * MS);
+        timers.at(Timer::Watch, now + 20 * MS);
+        timers.at(Timer::Resize, now + 50 * MS);
+
+        assert!(timers.expired(now).is_empty());
+        assert_eq!(timers.expired(now + 10 * MS), [Timer::Frame]);
+        let due = timers.expired(now + 40 * MS);
+        assert_eq!(due, [Timer::Watch, Timer::Slideshow]);
+        assert!(timers.expired(now + 40 * MS).is_empty());
+        assert!(timers.is_pending(Timer::Resize));
+        assert!(!timers.is_pending(Timer::Frame));
+    }
+
+    #[test]
+    fn scheduling_again_moves_the_timer() {
+        let now = Instant::now();
+        let mut timers = Scheduler::new();
+        // A burst of resize events leaves one redraw, after the last of them
+        for i in 0..5 {
+            timers.at(Timer::Resize, now + (100 + i * 10) * MS);
+        }
+        assert!(timers.expired(now + 100 * MS).is_empty());
+        assert_eq!(timers.expired(now + 140 * MS), [Timer::Resize]);
+        assert!(timers.expired(now + 1000 * MS).is_empty());
+
+        // Moving it earlier works too
+        timers.at(Timer::Watch, now + 500 * MS);
+        timers.at(Timer::Watch, now + 5 * MS);
+        assert_eq!(timers.expired(now + 5 * MS), [Timer::Watch]);
+
+        // So does making a recurring timer a one-off
+        timers.every(Timer::Frame, 10 * MS);
+        timers.at(Timer::Frame, now + 20 * MS);
+        assert_eq!(timers.expired(now + 20 * MS), [Timer::Frame]);
+        assert!(!timers.is_pending(Timer::Frame));
+    }
+
+    #[test]
+    fn cancelled_timers_never_fire() {
+        let now = Instant::now();
+        let mut timers = Scheduler::new();
+        timers.at(Timer::Slideshow, now + 10 * MS);
+        timers.every(Timer::Watch, 10 * MS);
+        timers.cancel(Timer::Slideshow);
+        timers.cancel(Timer::Watch);
+        timers.cancel(Timer::Frame);
+        assert!(!timers.is_pending(Timer::Slideshow));
+        assert!(timers.expired(now + 10_000 * MS).is_empty());
+        assert_eq!(timers.timeout(now, 5000 * MS), 5000 * MS);
+    }
+
+    #[test]
+    fn recurring_timers_skip_missed_periods() {
+        let mut timers = Scheduler::new();
+        let start = Instant::now();
+        timers.every(Timer::Watch, 100 * MS);
+        assert!(timers.expired(start + 99 * MS).is_empty());
+
+        // Noticed late: the next deadline is still a period after the one
+        // missed, not a period after it was noticed
+        let late = Instant::now() + 100 * MS + 30 * MS;
+        assert_eq!(timers.expired(late), [Timer::Watch]);
+        let next = timers.timeout(late, Duration::from_secs(5));
+        assert!(next < 100 * MS && next >= 40 * MS, "{:?}", next);
+
+        // Asleep for many periods: it fires once, then a period from now
+        let much_later = late + 1000 * MS;
+        assert_eq!(timers.expired(much_later), [Timer::Watch]);
+        assert_eq!(timers.timeout(much_later, Duration::from_secs(5)), 100 * MS);
+    }
+
+    #[test]
+    fn idle_loop_sleeps_until_the_next_deadline_or_the_cap() {
+        let now = Instant::now();
+        let mut timers = Scheduler::new();
+        let cap = Duration::from_secs(5);
+        assert_eq!(timers.timeout(now, cap), cap);
+
+        timers.at(Timer::ListNotice, now + 4000 * MS);
+        assert_eq!(timers.timeout(now, cap), 4000 * MS);
+        timers.at(Timer::Letterbox, now + 150 * MS);
+        assert_eq!(timers.timeout(now, cap), 150 * MS);
+        assert_eq!(timers.timeout(now, 50 * MS), 50 * MS);
+        // Overdue timers mean no waiting at all
+        assert_eq!(timers.timeout(now + 200 * MS, cap), Duration::ZERO);
+    }
+}
@@ -0,0 +1,413 @@
+//! `termview serve`: a tiny read-only HTTP endpoint for peeking at the
+//! browsed images from another device.
+//!
+//! Only GET is accepted, and images are addressed by their index in the
+//! list, so nothing outside the list can ever be served.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use image::DynamicImage;
+
+/// Makes the thumbnail of an image as the grid shows it: from the shared
+/// thumbnail cache, or decoded as the viewer shows it and saved there.
+pub type Thumbnail = Box<dyn Fn(&Path) -> Result<DynamicImage, String> + Send + Sync>;
+
+/// What one client may take up, so a slow, stuck or hostile one can't tie
+/// up the server or its memory.
+#[derive(Debug, Clone, Copy)]
+struct Limits {
+    /// Longest wait for a request to arrive, or for a response to be taken.
+    timeout: Duration,
+    /// Longest request line and headers together.
+    head_bytes: u64,
+    /// Connections handled at once; more are turned away.
+    connections: usize,
+}
+
+impl Limits {
+    const DEFAULT: Limits = Limits {
+        timeout: Duration::from_secs(10),
+        head_bytes: 16 * 1024,
+        connections: 32,
+    };
+}
+
+struct State {
+    images: Vec<PathBuf>,
+    thumbnail: Thumbnail,
+    limits: Limits,
+    /// Connections being handled.
+    active: AtomicUsize,
+}
+
+/// Serve `images` on `addr` until the process is killed, making thumbnails
+/// with `thumbnail`.
+pub fn serve(addr: &str, images: Vec<PathBuf>, thumbnail: Thumbnail) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    eprintln!(
+        "termview: serving {} images on http://{}/list",
+        images.len(),
+        listener.local_addr()?
+    );
+    serve_on(listener, images, thumbnail, Limits::DEFAULT)
+}
+
+fn serve_on(
+    listener: TcpListener,
+    images: Vec<PathBuf>,
+    thumbnail: Thumbnail,
+    limits: Limits,
+) -> io::Result<()> {
+    let state = Arc::new(State {
+        images,
+        thumbnail,
+        limits,
+        active: AtomicUsize::new(0),
+    });
+
+    for stream in listener.incoming() {
+        let Ok(mut stream) = stream else { continue };
+        if stream.set_read_timeout(Some(limits.timeout)).is_err()
+            || stream.set_write_timeout(Some(limits.timeout)).is_err()
+        {
+            continue;
+        }
+        if state.active.fetch_add(1, Ordering::SeqCst) >= limits.connections {
+            state.active.fetch_sub(1, Ordering::SeqCst);
+            let _ = respond(&mut stream, "503 Service Unavailable", "text/plain", b"busy");
+            continue;
+        }
+        let connection = Connection(Arc::clone(&state));
+        std::thread::spawn(move || {
+            let _ = handle(stream, &connection.0);
+        });
+    }
+    Ok(())
+}
+
+/// Counts as an active connection until dropped, even by a panic.
+struct Connection(Arc<State>);
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn handle(mut stream: TcpStream, state: &State) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?.take(state.limits.head_bytes));
+    let mut request_line = String::new();
+    let mut fits = head_line(&mut reader, &mut request_line)?;
+
+    // Drain the headers; nothing in them changes the response
+    let mut line = String::new();
+    while fits && request_line.ends_with('\n') {
+        fits = head_line(&mut reader, &mut line)?;
+        if line.trim_end().is_empty() {
+            break;
+        }
+    }
+    if !fits {
+        let status = "431 Request Header Fields Too Large";
+        return respond(&mut stream, status, "text/plain", b"request too large");
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return respond(&mut stream, "400 Bad Request", "text/plain", b"bad request");
+    };
+    if method != "GET" {
+        return respond(&mut stream, "405 Method Not Allowed", "text/plain", b"GET only");
+    }
+
+    let path = target.split('?').next().unwrap_or("");
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match segments.as_slice() {
+        ["list"] => {
+            let body = list_json(&state.images);
+            respond(&mut stream, "200 OK", "application/json", body.as_bytes())
+        }
+        ["thumb", index] => match lookup(state, index) {
+            Some(path) => match thumbnail(state, path) {
+                Ok(jpeg) => respond(&mut stream, "200 OK", "image/jpeg", &jpeg),
+                Err(e) => respond(
+                    &mut stream,
+                    "500 Internal Server Error",
+                    "text/plain",
+                    e.as_bytes(),
+                ),
+            },
+            None => not_found(&mut stream),
+        },
+        ["image", index] => match lookup(state, index) {
+            Some(path) => match std::fs::read(path) {
+                Ok(bytes) => respond(&mut stream, "200 OK", content_type(path), &bytes),
+                Err(_) => not_found(&mut stream),
+            },
+            None => not_found(&mut stream),
+        },
+        _ => not_found(&mut stream),
+    }
+}
+
+/// Read one line of the request head into `line`, or return false if the
+/// head runs past its limit first.
+fn head_line(reader: &mut BufReader<io::Take<TcpStream>>, line: &mut String) -> io::Result<bool> {
+    line.clear();
+    reader.read_line(line)?;
+    Ok(line.ends_with('\n') || reader.get_ref().limit() > 0)
+}
+
+fn lookup<'a>(state: &'a State, index: &str) -> Option<&'a Path> {
+    let i: usize = index.parse().ok()?;
+    state.images.get(i).map(|p| p.as_path())
+}
+
+/// The thumbnail of `path` as a JPEG.
+fn thumbnail(state: &State, path: &Path) -> Result<Vec<u8>, String> {
+    let mut jpeg = Vec::new();
+    (state.thumbnail)(path)?
+        .to_rgb8()
+        .write_to(&mut io::Cursor::new(&mut jpeg), image::ImageOutputFormat::Jpeg(85))
+        .map_err(|e| e.to_string())?;
+    Ok(jpeg)
+}
+
+/// JSON array describing each image. Dimensions come from the file header,
+/// so listing doesn't decode anything.
+fn list_json(images: &[PathBuf]) -> String {
+    let entries: Vec<String> = images
+        .iter()
+        .enumerate()
+        .map(|(i, path)| {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            let size = std::fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+            let dims = match image::image_dimensions(path) {
+                Ok((w, h)) => format!(",\"width\":{},\"height\":{}", w, h),
+                Err(_) => String::new(),
+            };
+            format!(
+                "{{\"index\":{},\"name\":\"{}\",\"size\":{}{}}}",
+                i,
+                json_escape(&name),
+                size,
+                dims
+            )
+        })
+        .collect();
+    format!("[{}]", entries.join(","))
+}
+
+fn json_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn content_type(path: &Path) -> &'static str {
+    let ext = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase());
+    match ext.as_deref() {
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("bmp") => "image/bmp",
+        Some("tif" | "tiff") => "image/tiff",
+        _ => "application/octet-stream",
+    }
+}
+
+fn not_found(stream: &mut TcpStream) -> io::Result<()> {
+    respond(stream, "404 Not Found", "text/plain", b"not found")
+}
+
+fn respond(stream: &mut TcpStream, status: &str, content_type: &str, body: &[u8]) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    )?;
+    stream.write_all(body)?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TestDir;
+    use image::{GenericImageView, Rgb, RgbImage};
+    use std::net::SocketAddr;
+    use std::time::Instant;
+
+    const LIMITS: Limits = Limits {
+        timeout: Duration::from_millis(300),
+        head_bytes: 1024,
+        connections: 4,
+    };
+
+    /// Serve `images` on a free port, with thumbnails a quarter turn and an
+    /// eighth the size of the image, counting those made.
+    fn server(images: Vec<PathBuf>, limits: Limits) -> (SocketAddr, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let made = Arc::new(AtomicUsize::new(0));
+        let counted = Arc::clone(&made);
+        let thumbnail: Thumbnail = Box::new(move |path: &Path| {
+            counted.fetch_add(1, Ordering::SeqCst);
+            let img = image::open(path).map_err(|e| e.to_string())?;
+            Ok(img.rotate90().thumbnail(img.height() / 8, img.width() / 8))
+        });
+        std::thread::spawn(move || serve_on(listener, images, thumbnail, limits));
+        (addr, made)
+    }
+
+    fn images(dir: &TestDir, count: usize) -> Vec<PathBuf> {
+        (0..count)
+            .map(|i| {
+                let path = dir.path().join(format!("{}.png", i));
+                RgbImage::from_pixel(400, 200, Rgb([i as u8, 0, 0])).save(&path).unwrap();
+                path
+            })
+            .collect()
+    }
+
+    /// Send `request` and read the whole response.
+    fn send(addr: SocketAddr, request: &[u8]) -> Vec<u8> {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        // The server may answer and close before taking all of an oversized head
+        let _ = stream.write_all(request);
+        let mut response = Vec::new();
+        let _ = stream.read_to_end(&mut response);
+        response
+    }
+
+    fn get(addr: SocketAddr, path: &str) -> Vec<u8> {
+        send(addr, format!("GET {} HTTP/1.1\r\nHost: test\r\n\r\n", path).as_bytes())
+    }
+
+    fn status(response: &[u8]) -> String {
+        let text = String::from_utf8_lossy(response);
+        text.lines().next().unwrap_or("").to_string()
+    }
+
+    fn body(response: &[u8]) -> &[u8] {
+        let end = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        &response[end + 4..]
+    }
+
+    #[test]
+    fn lists_the_images() {
+        let dir = TestDir::new();
+        let (addr, _) = server(images(&dir, 2), LIMITS);
+        let response = get(addr, "/list");
+        assert_eq!(status(&response), "HTTP/1.1 200 OK");
+        let list = String::from_utf8_lossy(body(&response)).to_string();
+        assert!(list.contains("\"name\":\"1.png\""), "{}", list);
+        assert!(list.contains("\"width\":400,\"height\":200"), "{}", list);
+        assert_eq!(status(&get(addr, "/image/2")), "HTTP/1.1 404 Not Found");
+    }
+
+    #[test]
+    fn oversized_heads_are_refused() {
+        let dir = TestDir::new();
+        let (addr, _) = server(images(&dir, 1), LIMITS);
+
+        let long_line = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(4096));
+        let response = send(addr, long_line.as_bytes());
+        assert_eq!(status(&response), "HTTP/1.1 431 Request Header Fields Too Large");
+
+        let mut many_headers = b"GET /list HTTP/1.1\r\n".to_vec();
+        for i in 0..200 {
+            many_headers.extend(format!("X-Header-{}: value\r\n", i).bytes());
+        }
+        many_headers.extend(b"\r\n");
+        let response = send(addr, &many_headers);
+        assert_eq!(status(&response), "HTTP/1.1 431 Request Header Fields Too Large");
+
+        // A head just inside the limit is still answered
+        let mut fits = b"GET /list HTTP/1.1\r\n".to_vec();
+        fits.extend(format!("X-Pad: {}\r\n\r\n", "a".repeat(900)).bytes());
+        assert_eq!(status(&send(addr, &fits)), "HTTP/1.1 200 OK");
+    }
+
+    #[test]
+    fn silent_clients_are_dropped() {
+        let dir = TestDir::new();
+        let (addr, _) = server(images(&dir, 1), LIMITS);
+
+        let start = Instant::now();
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream.write_all(b"GET /list HTTP/1.1\r\n").unwrap();
+        let mut response = Vec::new();
+        let _ = stream.read_to_end(&mut response);
+
+        let waited = start.elapsed();
+        assert!(response.is_empty());
+        assert!(waited >= LIMITS.timeout, "{:?}", waited);
+        assert!(waited < Duration::from_secs(2), "{:?}", waited);
+    }
+
+    #[test]
+    fn connections_over_the_limit_are_turned_away() {
+        let dir = TestDir::new();
+        let limits = Limits { connections: 2, ..LIMITS };
+        let (addr, _) = server(images(&dir, 1), limits);
+
+        let silent: Vec<TcpStream> = (0..2).map(|_| TcpStream::connect(addr).unwrap()).collect();
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(status(&get(addr, "/list")), "HTTP/1.1 503 Service Unavailable");
+
+        // Their slots come back once they time out
+        std::thread::sleep(LIMITS.timeout + Duration::from_millis(200));
+        assert_eq!(status(&get(addr, "/list")), "HTTP/1.1 200 OK");
+        drop(silent);
+    }
+
+    #[test]
+    fn thumbnails_are_served_as_made() {
+        let dir = TestDir::new();
+        let (addr, made) = server(images(&dir, 1), LIMITS);
+
+        let response = get(addr, "/thumb/0");
+        assert_eq!(status(&response), "HTTP/1.1 200 OK");
+        assert!(String::from_utf8_lossy(&response).contains("Content-Type: image/jpeg"));
+        let thumb = image::load_from_memory(body(&response)).unwrap();
+        assert_eq!(thumb.dimensions(), (25, 50));
+        assert_eq!(made.load(Ordering::SeqCst), 1);
+
+        // Kept by the thumbnail cache, not here
+        assert_eq!(status(&get(addr, "/thumb/0")), "HTTP/1.1 200 OK");
+        assert_eq!(made.load(Ordering::SeqCst), 2);
+        assert_eq!(status(&get(addr, "/thumb/1")), "HTTP/1.1 404 Not Found");
+    }
+
+    #[test]
+    fn thumbnails_that_cant_be_made_are_errors() {
+        let dir = TestDir::new();
+        let broken = dir.file("broken.png", b"not a png");
+        let (addr, _) = server(vec![broken], LIMITS);
+
+        let response = get(addr, "/thumb/0");
+        assert_eq!(status(&response), "HTTP/1.1 500 Internal Server Error");
+        assert!(!body(&response).is_empty());
+    }
+}
@@ -0,0 +1,182 @@
+//! Record of what happened during this run: images that failed to decode,
+//! thumbnails that couldn't be made, and changes to the image list seen while
+//! following a directory. Errors only stay on screen until the next image is
+//! shown; the log keeps them so a long pass can be reviewed afterwards with
+//! `L`. With `--log FILE` every entry is also appended to FILE as it happens.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::Write;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Entries kept for the overlay; older ones are dropped (but stay in the file).
+pub const CAPACITY: usize = 500;
+
+/// How long quitting waits for lines still on their way to the log file.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
+impl Severity {
+    pub fn label(self) -> &'static str {
+        match self {
+            Severity::Info => "info",
+            Severity::Warning => "warn",
+            Severity::Error => "error",
+        }
+    }
+}
+
+pub struct Entry {
+    pub time: SystemTime,
+    pub severity: Severity,
+    pub message: String,
+}
+
+pub struct SessionLog {
+    entries: VecDeque<Entry>,
+    /// Entries ever logged, including ones dropped from the front.
+    total: usize,
+    /// Errors ever logged.
+    errors: usize,
+    file: Option<Writer>,
+    /// Whether the overlay is open.
+    pub visible: bool,
+    /// How many of the newest entries are scrolled out of view below.
+    scroll: usize,
+}
+
+/// Appends lines to the log file from a thread of its own, so a slow disk or
+/// network mount doesn't hold up the viewer.
+struct Writer {
+    lines: Sender<String>,
+    /// Signalled once every line sent has been written.
+    done: Receiver<()>,
+}
+
+impl Writer {
+    fn spawn(mut file: File) -> Self {
+        let (lines, rx) = mpsc::channel::<String>();
+        let (done_tx, done) = mpsc::channel();
+        std::thread::spawn(move || {
+            for line in rx {
+                // A full disk shouldn't take the viewer down with it
+                let _ = writeln!(file, "{}", line);
+            }
+            let _ = done_tx.send(());
+        });
+        Writer { lines, done }
+    }
+}
+
+impl SessionLog {
+    pub fn new(file: Option<File>) -> Self {
+        SessionLog {
+            entries: VecDeque::new(),
+            total: 0,
+            errors: 0,
+            file: file.map(Writer::spawn),
+            visible: false,
+            scroll: 0,
+        }
+    }
+
+    pub fn push(&mut self, severity: Severity, message: String) {
+        let entry = Entry {
+            time: SystemTime::now(),
+            severity,
+            message,
+        };
+        if let Some(ref file) = self.file {
+            let _ = file.lines.send(format!(
+                "{} {:<5} {}",
+                format_time(entry.time, true),
+                severity.label(),
+                entry.message
+            ));
+        }
+        if self.entries.len() >= CAPACITY {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+        self.total += 1;
+        if severity == Severity::Error {
+            self.errors += 1;
+        }
+        // Keep a scrolled-back view on the same entries
+        if self.scroll > 0 {
+            self.scroll = (self.scroll + 1).min(self.entries.len() - 1);
+        }
+    }
+
+    pub fn total(&self) -> usize {
+        self.total
+    }
+
+    pub fn errors(&self) -> usize {
+        self.errors
+    }
+
+    pub fn scroll(&self) -> usize {
+        self.scroll
+    }
+
+    /// Scroll by `delta` entries; positive moves back towards older ones.
+    /// Returns whether the view moved.
+    pub fn scroll_by(&mut self, delta: isize) -> bool {
+        let max = self.entries.len().saturating_sub(1) as isize;
+        let scroll = (self.scroll as isize + delta).clamp(0, max) as usize;
+        let moved = scroll != self.scroll;
+        self.scroll = scroll;
+        moved
+    }
+
+    /// Entries from oldest up to the last one in view.
+    pub fn in_view(&self) -> impl DoubleEndedIterator<Item = &Entry> {
+        self.entries.range(..self.entries.len() - self.scroll)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl Drop for SessionLog {
+    /// Give the last lines a moment to reach the file, without hanging on
+    /// quit if it has stopped responding.
+    fn drop(&mut self) {
+        if let Some(Writer { lines, done }) = self.file.take() {
+            drop(lines);
+            let _ = done.recv_timeout(FLUSH_TIMEOUT);
+        }
+    }
+}
+
+/// Local time of day as `HH:MM:SS`, prefixed with the date if `with_date`.
+pub fn format_time(time: SystemTime, with_date: bool) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0) as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    // SAFETY: both pointers are valid for the duration of the call
+    unsafe { libc::localtime_r(&secs, &mut tm) };
+    let clock = format!("{:02}:{:02}:{:02}", tm.tm_hour, tm.tm_min, tm.tm_sec);
+    if with_date {
+        format!(
+            "{}-{:02}-{:02} {}",
+            tm.tm_year + 1900,
+            tm.tm_mon + 1,
+            tm.tm_mday,
+            clock
+        )
+    } else {
+        clock
+    }
+}
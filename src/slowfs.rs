@@ -0,0 +1,104 @@
+//! Filesystem work that may hang.
+//!
+//! On a network mount with an unresponsive server a single `stat` or
+//! `read_dir` can block for half a minute or more. Work like the directory
+//! rescans of follow mode runs on a thread of its own here, so the event loop
+//! keeps handling keys and redrawing while it waits. A thread stuck in a
+//! syscall can't be interrupted: once it has run for too long its result is
+//! abandoned and the thread left to finish (or not) in the background, and
+//! quitting doesn't wait for it.
+
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+/// How long an operation runs before it is reported as slow.
+pub const SLOW_AFTER: Duration = Duration::from_secs(2);
+
+/// How long an operation may run before its result is abandoned.
+pub const GIVE_UP_AFTER: Duration = Duration::from_secs(60);
+
+/// Work running on its own thread.
+pub struct Task<T> {
+    rx: Receiver<T>,
+    started: Instant,
+}
+
+/// Where a `Task` has got to.
+pub enum Progress<T> {
+    Done(T),
+    /// Still running, for less than `SLOW_AFTER`.
+    Running,
+    /// Still running, for longer than `SLOW_AFTER`.
+    Slow,
+    /// Ran past `GIVE_UP_AFTER`, or the thread panicked. No result will come.
+    Abandoned,
+}
+
+impl<T: Send + 'static> Task<T> {
+    pub fn spawn(work: impl FnOnce() -> T + Send + 'static) -> Self {
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            // The receiver is gone if the task was abandoned
+            let _ = tx.send(work());
+        });
+        Task {
+            rx,
+            started: Instant::now(),
+        }
+    }
+
+    /// Wait up to `timeout` for the result.
+    pub fn wait(&self, timeout: Duration) -> Progress<T> {
+        match self.rx.recv_timeout(timeout) {
+            Ok(result) => Progress::Done(result),
+            Err(RecvTimeoutError::Disconnected) => Progress::Abandoned,
+            Err(RecvTimeoutError::Timeout) => match self.started.elapsed() {
+                elapsed if elapsed >= GIVE_UP_AFTER => Progress::Abandoned,
+                elapsed if elapsed >= SLOW_AFTER => Progress::Slow,
+                _ => Progress::Running,
+            },
+        }
+    }
+}
+
+/// Run `work` on its own thread, giving up on it after `timeout`.
+pub fn within<T: Send + 'static>(
+    timeout: Duration,
+    work: impl FnOnce() -> T + Send + 'static,
+) -> Option<T> {
+    Task::spawn(work).rx.recv_timeout(timeout).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn results_are_waited_for_up_to_the_timeout() {
+        let task = Task::spawn(|| 7);
+        assert!(matches!(task.wait(Duration::from_secs(5)), Progress::Done(7)));
+
+        let (release, held) = mpsc::channel::<()>();
+        let task = Task::spawn(move || held.recv().is_ok());
+        let started = Instant::now();
+        assert!(matches!(task.wait(Duration::from_millis(20)), Progress::Running));
+        assert!(started.elapsed() < Duration::from_secs(1));
+        release.send(()).unwrap();
+        assert!(matches!(task.wait(Duration::from_secs(5)), Progress::Done(true)));
+    }
+
+    #[test]
+    fn a_panicked_task_is_abandoned() {
+        let task: Task<()> = Task::spawn(|| panic!("stuck"));
+        assert!(matches!(task.wait(Duration::from_secs(5)), Progress::Abandoned));
+    }
+
+    #[test]
+    fn hung_work_is_left_behind() {
+        let (_release, held) = mpsc::channel::<()>();
+        let started = Instant::now();
+        assert_eq!(within(Duration::from_millis(50), move || held.recv().ok()), None);
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(within(Duration::from_secs(5), || 3), Some(3));
+    }
+}
@@ -0,0 +1,559 @@
+//! The freedesktop.org thumbnail cache (`~/.cache/thumbnails`), shared with
+//! file managers and other image viewers.
+//!
+//! Thumbnails made by other programs are used rather than decoding the image
+//! again, and the grid's thumbnails are saved there for them in turn. Each is
+//! a PNG named after the MD5 of the file's URI, no larger than its size
+//! bucket, carrying the URI and the file's modification time and size in
+//! text chunks so a changed file is noticed. Images that can't be decoded
+//! get an empty marker under `fail/`, so they aren't tried on every run.
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use image::DynamicImage;
+
+use crate::fileops::url_encode;
+use crate::persistence::Store;
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// Size buckets from the spec, by the longest side of the thumbnails in them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bucket {
+    Normal,
+    Large,
+}
+
+impl Bucket {
+    /// The smallest bucket holding thumbnails at least `px` pixels across.
+    pub fn for_size(px: u32) -> Bucket {
+        if px <= Bucket::Normal.max_px() {
+            Bucket::Normal
+        } else {
+            Bucket::Large
+        }
+    }
+
+    pub fn max_px(self) -> u32 {
+        match self {
+            Bucket::Normal => 128,
+            Bucket::Large => 256,
+        }
+    }
+
+    fn dir_name(self) -> &'static str {
+        match self {
+            Bucket::Normal => "normal",
+            Bucket::Large => "large",
+        }
+    }
+}
+
+/// What the cache holds for a file.
+pub enum Cached {
+    Thumb(DynamicImage),
+    /// Thumbnailing the file as it is now failed before.
+    Failed,
+    Missing,
+}
+
+/// What a thumbnail's text chunks must say for it to be current.
+struct Stamp {
+    uri: String,
+    mtime: u64,
+    size: u64,
+}
+
+impl Stamp {
+    fn of(path: &Path) -> io::Result<Stamp> {
+        let path = fs::canonicalize(path)?;
+        let meta = fs::metadata(&path)?;
+        let mtime = meta
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        Ok(Stamp {
+            uri: format!("file://{}", url_encode(&path)),
+            mtime,
+            size: meta.len(),
+        })
+    }
+
+    /// Whether a thumbnail with these text chunks was made from this file.
+    /// `Thumb::Size` is optional in the spec, so only a mismatch counts.
+    fn matches(&self, chunks: &[(String, String)]) -> bool {
+        let get = |key: &str| chunks.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str());
+        get("Thumb::URI") == Some(self.uri.as_str())
+            && get("Thumb::MTime").and_then(|m| m.parse().ok()) == Some(self.mtime)
+            && get("Thumb::Size").is_none_or(|s| s.parse().ok() == Some(self.size))
+    }
+
+    fn chunks(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("Thumb::URI", self.uri.clone()),
+            ("Thumb::MTime", self.mtime.to_string()),
+            ("Thumb::Size", self.size.to_string()),
+            ("Software", format!("termview {}", env!("CARGO_PKG_VERSION"))),
+        ]
+    }
+
+    /// Name of the thumbnail file: the MD5 of the URI in hex.
+    fn file_name(&self) -> String {
+        let digest = md5(self.uri.as_bytes());
+        let hex: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+        hex + ".png"
+    }
+}
+
+pub struct ThumbCache {
+    root: PathBuf,
+}
+
+impl ThumbCache {
+    /// The cache in the store `Persistence` resolved for it, if usable.
+    pub fn new(store: &Store) -> Option<ThumbCache> {
+        Some(ThumbCache {
+            root: store.usable()?.to_path_buf(),
+        })
+    }
+
+    fn fail_dir(&self) -> PathBuf {
+        self.root
+            .join("fail")
+            .join(format!("termview-{}", env!("CARGO_PKG_VERSION")))
+    }
+
+    /// Whether `path` may be cached at all: thumbnails of the cache's own
+    /// files would only breed more of them.
+    fn covers(&self, path: &Path) -> bool {
+        !path.starts_with(&self.root)
+    }
+
+    /// The cached thumbnail of `path` in `bucket`, if it is up to date.
+    pub fn get(&self, path: &Path, bucket: Bucket) -> Cached {
+        if !self.covers(path) {
+            return Cached::Missing;
+        }
+        let Ok(stamp) = Stamp::of(path) else {
+            return Cached::Missing;
+        };
+        let name = stamp.file_name();
+
+        let thumb = self.root.join(bucket.dir_name()).join(&name);
+        if let Ok(png) = fs::read(&thumb) {
+            if stamp.matches(&text_chunks(&png)) {
+                let img = image::load_from_memory_with_format(&png, image::ImageFormat::Png);
+                if let Ok(img) = img {
+                    return Cached::Thumb(img);
+                }
+            }
+        }
+
+        match fs::read(self.fail_dir().join(&name)) {
+            Ok(png) if stamp.matches(&text_chunks(&png)) => Cached::Failed,
+            _ => Cached::Missing,
+        }
+    }
+
+    /// Save a thumbnail of `path` in `bucket`, made from the decoded `img`.
+    pub fn put(&self, path: &Path, bucket: Bucket, img: &DynamicImage) -> io::Result<()> {
+        if !self.covers(path) {
+            return Ok(());
+        }
+        let stamp = Stamp::of(path)?;
+        let max = bucket.max_px();
+        // Shrunk to fit the bucket, never enlarged
+        let thumb = if img.width() > max || img.height() > max {
+            img.thumbnail(max, max)
+        } else {
+            img.clone()
+        };
+        let dir = self.root.join(bucket.dir_name());
+        write_png(&dir, &stamp.file_name(), &thumb, &stamp.chunks())
+    }
+
+    /// Record that `path` couldn't be thumbnailed as it is now.
+    pub fn mark_failed(&self, path: &Path) -> io::Result<()> {
+        if !self.covers(path) {
+            return Ok(());
+        }
+        let stamp = Stamp::of(path)?;
+        let empty = DynamicImage::new_rgba8(1, 1);
+        write_png(&self.fail_dir(), &stamp.file_name(), &empty, &stamp.chunks())
+    }
+}
+
+/// Write `img` as `dir/name` with `chunks` as tEXt chunks, so that other
+/// programs never read half a thumbnail, and only the user can read it.
+fn write_png(
+    dir: &Path,
+    name: &str,
+    img: &DynamicImage,
+    chunks: &[(&str, String)],
+) -> io::Result<()> {
+    create_private_dir(dir)?;
+
+    let mut png = Vec::new();
+    img.write_to(&mut io::Cursor::new(&mut png), image::ImageFormat::Png)
+        .map_err(io::Error::other)?;
+    let png = with_text_chunks(&png, chunks)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed PNG"))?;
+    write_private(dir, name, &png)
+}
+
+/// Write `bytes` as `dir/name`, readable by the user only. The file is
+/// written under a temporary name and renamed into place, so no reader
+/// ever sees half of it.
+pub fn write_private(dir: &Path, name: &str, bytes: &[u8]) -> io::Result<()> {
+    let temp = dir.join(format!(".{}.termview-{}", name, std::process::id()));
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let written = options.open(&temp).and_then(|mut file| file.write_all(bytes));
+    match written.and_then(|_| fs::rename(&temp, dir.join(name))) {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            let _ = fs::remove_file(&temp);
+            Err(e)
+        }
+    }
+}
+
+/// Create `dir` and its parents, with the new ones readable by the user only.
+pub fn create_private_dir(dir: &Path) -> io::Result<()> {
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+    builder.create(dir)
+}
+
+/// The chunks of a PNG file as (type, data), or `None` if it isn't one.
+fn png_chunks(png: &[u8]) -> Option<Vec<([u8; 4], &[u8])>> {
+    let mut rest = png.strip_prefix(PNG_SIGNATURE)?;
+    let mut chunks = Vec::new();
+    while !rest.is_empty() {
+        let len = u32::from_be_bytes(rest.get(..4)?.try_into().ok()?) as usize;
+        let kind: [u8; 4] = rest.get(4..8)?.try_into().ok()?;
+        let data = rest.get(8..8 + len)?;
+        chunks.push((kind, data));
+        // Skip the CRC
+        rest = rest.get(12 + len..)?;
+    }
+    Some(chunks)
+}
+
+/// The keyword and text of each uncompressed text chunk (tEXt, and iTXt
+/// without compression) in a PNG file.
+fn text_chunks(png: &[u8]) -> Vec<(String, String)> {
+    let latin1 = |bytes: &[u8]| bytes.iter().map(|&b| b as char).collect::<String>();
+    let mut texts = Vec::new();
+    for (kind, data) in png_chunks(png).unwrap_or_default() {
+        let Some(nul) = data.iter().position(|&b| b == 0) else {
+            continue;
+        };
+        let (keyword, rest) = (latin1(&data[..nul]), &data[nul + 1..]);
+        match &kind {
+            b"tEXt" => texts.push((keyword, latin1(rest))),
+            // Compression flag and method, then language and translated
+            // keyword, each ended by a NUL
+            b"iTXt" if rest.first() == Some(&0) => {
+                let mut fields = rest.get(2..).unwrap_or_default().splitn(3, |&b| b == 0);
+                if let Some(text) = fields.nth(2) {
+                    texts.push((keyword, String::from_utf8_lossy(text).into_owned()));
+                }
+            }
+            _ => {}
+        }
+    }
+    texts
+}
+
+/// `png` with a tEXt chunk added after the header for each of `chunks`.
+fn with_text_chunks(png: &[u8], chunks: &[(&str, String)]) -> Option<Vec<u8>> {
+    let mut out = PNG_SIGNATURE.to_vec();
+    for (kind, data) in png_chunks(png)? {
+        push_chunk(&mut out, kind, data);
+        if &kind == b"IHDR" {
+            for (keyword, text) in chunks {
+                let data = [keyword.as_bytes(), b"\0", text.as_bytes()].concat();
+                push_chunk(&mut out, *b"tEXt", &data);
+            }
+        }
+    }
+    Some(out)
+}
+
+fn push_chunk(out: &mut Vec<u8>, kind: [u8; 4], data: &[u8]) {
+    let mut crc = flate2::Crc::new();
+    crc.update(&kind);
+    crc.update(data);
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    out.extend_from_slice(&kind);
+    out.extend_from_slice(data);
+    out.extend_from_slice(&crc.sum().to_be_bytes());
+}
+
+/// MD5 digest of `data` (RFC 1321). Only used to name cache files, as the
+/// thumbnail spec requires; it isn't a security measure here.
+pub fn md5(data: &[u8]) -> [u8; 16] {
+    const SHIFTS: [u32; 64] = [
+        7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 5, 9, 14, 20, 5, 9, 14, 20, 5,
+        9, 14, 20, 5, 9, 14, 20, 4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 6, 10,
+        15, 21, 6, 10, 15, 21, 6, 10, 15, 21, 6, 10, 15, 21,
+    ];
+    let constants: Vec<u32> = (0..64)
+        .map(|i| ((i as f64 + 1.0).sin().abs() * 4294967296.0) as u32)
+        .collect();
+
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64).wrapping_mul(8)).to_le_bytes());
+
+    let mut state: [u32; 4] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476];
+    for block in message.chunks(64) {
+        let words: Vec<u32> = block
+            .chunks(4)
+            .map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]))
+            .collect();
+        let [mut a, mut b, mut c, mut d] = state;
+        for i in 0..64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+            let rotated = a
+                .wrapping_add(f)
+                .wrapping_add(constants[i])
+                .wrapping_add(words[g])
+                .rotate_left(SHIFTS[i]);
+            (a, b, c, d) = (d, b.wrapping_add(rotated), b, c);
+        }
+        for (s, v) in state.iter_mut().zip([a, b, c, d]) {
+            *s = s.wrapping_add(v);
+        }
+    }
+
+    let mut digest = [0u8; 16];
+    for (i, word) in state.iter().enumerate() {
+        digest[i * 4..i * 4 + 4].copy_from_slice(&word.to_le_bytes());
+    }
+    digest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TestDir;
+    use image::GenericImageView;
+    use std::time::{Duration, SystemTime};
+
+    fn cache_in(tmp: &TestDir) -> ThumbCache {
+        let store = Store {
+            path: Some(tmp.path().join("thumbnails")),
+            disabled: None,
+        };
+        ThumbCache::new(&store).unwrap()
+    }
+
+    /// A PNG of `w` x `h` pixels at `name`, last modified at `secs`.
+    fn photo(tmp: &TestDir, name: &str, (w, h): (u32, u32), secs: u64) -> PathBuf {
+        let mut png = Vec::new();
+        DynamicImage::new_rgb8(w, h)
+            .write_to(&mut io::Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+        let path = tmp.file(name, &png);
+        let then = UNIX_EPOCH + Duration::from_secs(secs);
+        fs::File::options().write(true).open(&path).unwrap().set_modified(then).unwrap();
+        path
+    }
+
+    fn hex(digest: [u8; 16]) -> String {
+        digest.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn md5_matches_the_reference_digests() {
+        assert_eq!(hex(md5(b"")), "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(hex(md5(b"abc")), "900150983cd24fb0d6963f7d28e17f72");
+        // Longer than one block
+        assert_eq!(hex(md5(&[b'a'; 100])), "36a92cc94a9e0fa21f625f8bfb007adf");
+        // The example from the thumbnail spec
+        let stamp = Stamp {
+            uri: "file:///home/jens/photos/me.png".into(),
+            mtime: 0,
+            size: 0,
+        };
+        assert_eq!(stamp.file_name(), "c6ee772d9e49320e97ec29a7eb5b1697.png");
+    }
+
+    #[test]
+    fn thumbnails_fit_their_bucket_keeping_proportions() {
+        let tmp = TestDir::new();
+        let cache = cache_in(&tmp);
+        assert_eq!(Bucket::for_size(100), Bucket::Normal);
+        assert_eq!(Bucket::for_size(128), Bucket::Normal);
+        assert_eq!(Bucket::for_size(129), Bucket::Large);
+        let cases = [
+            ((1000, 500), Bucket::Normal, (128, 64)),
+            ((1000, 500), Bucket::Large, (256, 128)),
+            ((300, 900), Bucket::Normal, (43, 128)),
+            // Never enlarged
+            ((50, 30), Bucket::Large, (50, 30)),
+        ];
+        for (i, (size, bucket, expected)) in cases.into_iter().enumerate() {
+            let path = photo(&tmp, &format!("{}.png", i), size, 1_000_000_000);
+            let img = image::open(&path).unwrap();
+            cache.put(&path, bucket, &img).unwrap();
+            match cache.get(&path, bucket) {
+                Cached::Thumb(thumb) => assert_eq!(thumb.dimensions(), expected, "{:?}", size),
+                _ => panic!("no thumbnail of {:?}", size),
+            }
+        }
+    }
+
+    #[test]
+    fn thumbnails_carry_the_required_chunks() {
+        let tmp = TestDir::new();
+        let cache = cache_in(&tmp);
+        let path = photo(&tmp, "my photo.png", (20, 10), 1_234_567_890);
+        cache.put(&path, Bucket::Normal, &image::open(&path).unwrap()).unwrap();
+
+        let uri = format!("file://{}", tmp.path().join("my%20photo.png").display());
+        let name = format!("{}.png", hex(md5(uri.as_bytes())));
+        let thumb = tmp.path().join("thumbnails/normal").join(name);
+        let chunks = text_chunks(&fs::read(&thumb).unwrap());
+        let get = |key: &str| chunks.iter().find(|(k, _)| k == key).map(|(_, v)| v.clone());
+        assert_eq!(get("Thumb::URI"), Some(uri));
+        assert_eq!(get("Thumb::MTime").as_deref(), Some("1234567890"));
+        let size = fs::metadata(&path).unwrap().len().to_string();
+        assert_eq!(get("Thumb::Size"), Some(size));
+        assert!(get("Software").unwrap().starts_with("termview "));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn thumbnails_are_private_and_written_whole() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp = TestDir::new();
+        let cache = cache_in(&tmp);
+        let path = photo(&tmp, "a.png", (20, 10), 1_000_000_000);
+        cache.put(&path, Bucket::Large, &image::open(&path).unwrap()).unwrap();
+        cache.mark_failed(&path).unwrap();
+
+        let mode = |p: &Path| fs::metadata(p).unwrap().permissions().mode() & 0o777;
+        let root = tmp.path().join("thumbnails");
+        assert_eq!(mode(&root), 0o700);
+        for dir in [root.join("large"), cache.fail_dir()] {
+            assert_eq!(mode(&dir), 0o700);
+            let files: Vec<_> = fs::read_dir(&dir).unwrap().map(|e| e.unwrap().path()).collect();
+            // Only the finished thumbnail: no temporary file is left behind
+            assert_eq!(files.len(), 1, "{:?}", files);
+            assert_eq!(mode(&files[0]), 0o600);
+            assert!(!files[0].file_name().unwrap().to_string_lossy().starts_with('.'));
+        }
+    }
+
+    #[test]
+    fn changed_files_need_new_thumbnails() {
+        let tmp = TestDir::new();
+        let cache = cache_in(&tmp);
+        let path = photo(&tmp, "a.png", (20, 10), 1_000_000_000);
+        cache.put(&path, Bucket::Normal, &image::open(&path).unwrap()).unwrap();
+        assert!(matches!(cache.get(&path, Bucket::Normal), Cached::Thumb(_)));
+        // Other buckets are separate
+        assert!(matches!(cache.get(&path, Bucket::Large), Cached::Missing));
+
+        let later = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_100);
+        fs::File::options().write(true).open(&path).unwrap().set_modified(later).unwrap();
+        assert!(matches!(cache.get(&path, Bucket::Normal), Cached::Missing));
+    }
+
+    #[test]
+    fn failures_are_remembered_until_the_file_changes() {
+        let tmp = TestDir::new();
+        let cache = cache_in(&tmp);
+        let path = tmp.file("broken.png", b"not a png");
+        assert!(matches!(cache.get(&path, Bucket::Normal), Cached::Missing));
+        cache.mark_failed(&path).unwrap();
+        assert!(matches!(cache.get(&path, Bucket::Normal), Cached::Failed));
+        assert!(matches!(cache.get(&path, Bucket::Large), Cached::Failed));
+        let version = format!("termview-{}", env!("CARGO_PKG_VERSION"));
+        assert!(cache.fail_dir().ends_with(Path::new("fail").join(version)));
+
+        fs::write(&path, b"still not a png, but longer").unwrap();
+        assert!(matches!(cache.get(&path, Bucket::Normal), Cached::Missing));
+    }
+
+    #[test]
+    fn cache_files_are_not_cached() {
+        let tmp = TestDir::new();
+        let cache = cache_in(&tmp);
+        let path = photo(&tmp, "thumbnails/normal/x.png", (20, 10), 1_000_000_000);
+        cache.put(&path, Bucket::Normal, &image::open(&path).unwrap()).unwrap();
+        cache.mark_failed(&path).unwrap();
+        assert_eq!(fs::read_dir(path.parent().unwrap()).unwrap().count(), 1);
+        assert!(!cache.fail_dir().exists());
+    }
+
+    /// A thumbnail as another program writes one: its text after the image
+    /// data rather than the header, one of them as iTXt, and extra keys.
+    fn foreign_thumbnail(uri: &str, mtime: u64, img: &DynamicImage) -> Vec<u8> {
+        let mut png = Vec::new();
+        img.write_to(&mut io::Cursor::new(&mut png), image::ImageFormat::Png).unwrap();
+        let mut out = PNG_SIGNATURE.to_vec();
+        for (kind, data) in png_chunks(&png).unwrap() {
+            if &kind == b"IEND" {
+                let itxt = [b"Thumb::URI\0\0\0\0\0".as_slice(), uri.as_bytes()].concat();
+                push_chunk(&mut out, *b"iTXt", &itxt);
+                for text in [
+                    format!("Thumb::MTime\0{}", mtime),
+                    "Thumb::Mimetype\0image/png".into(),
+                    "Software\0GNOME::ThumbnailFactory".into(),
+                ] {
+                    push_chunk(&mut out, *b"tEXt", text.as_bytes());
+                }
+            }
+            push_chunk(&mut out, kind, data);
+        }
+        out
+    }
+
+    #[test]
+    fn thumbnails_from_other_programs_are_reused() {
+        let tmp = TestDir::new();
+        let cache = cache_in(&tmp);
+        let path = photo(&tmp, "a.png", (400, 300), 1_500_000_000);
+        let uri = format!("file://{}", path.display());
+        let magenta = image::RgbImage::from_pixel(128, 96, image::Rgb([255, 0, 255]));
+        let theirs = DynamicImage::ImageRgb8(magenta);
+        let png = foreign_thumbnail(&uri, 1_500_000_000, &theirs);
+        let name = format!("{}.png", hex(md5(uri.as_bytes())));
+        tmp.file(&format!("thumbnails/normal/{}", name), &png);
+
+        match cache.get(&path, Bucket::Normal) {
+            Cached::Thumb(thumb) => assert_eq!(thumb.to_rgb8(), theirs.to_rgb8()),
+            _ => panic!("the other program's thumbnail wasn't used"),
+        }
+        // Made from an older version of the file: not used
+        let stale = foreign_thumbnail(&uri, 1_400_000_000, &theirs);
+        tmp.file(&format!("thumbnails/normal/{}", name), &stale);
+        assert!(matches!(cache.get(&path, Bucket::Normal), Cached::Missing));
+    }
+}
@@ -0,0 +1,246 @@
+//! Downloads of URL arguments kept between runs, so opening the same URL
+//! again (from a script that redraws a chart, say) only asks the server
+//! whether it changed, and still works while the network is down.
+//!
+//! Each URL has two files, named after the MD5 of the URL: `<key>` holds
+//! what was downloaded and `<key>.meta` the URL and the validators the
+//! server sent with it (ETag and Last-Modified). A download's modification
+//! time is when it was last used; once the cache grows past its limit the
+//! least recently used go first.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::persistence::Store;
+use crate::thumbcache::{create_private_dir, md5, write_private};
+
+/// What a server sent to tell whether a later copy is the same.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+/// A download as kept in the cache.
+pub struct Entry {
+    pub bytes: Vec<u8>,
+    pub validators: Validators,
+}
+
+pub struct DownloadCache {
+    root: PathBuf,
+    /// Total size of the downloads kept, past which the oldest are removed.
+    max_bytes: u64,
+}
+
+impl DownloadCache {
+    /// The cache in the store `Persistence` resolved for it, if usable.
+    pub fn new(store: &Store, max_bytes: u64) -> Option<DownloadCache> {
+        Some(DownloadCache {
+            root: store.usable()?.to_path_buf(),
+            max_bytes,
+        })
+    }
+
+    /// The kept download of `url`, if there is one.
+    pub fn get(&self, url: &str) -> Option<Entry> {
+        let key = key(url);
+        let meta = fs::read_to_string(self.root.join(format!("{}.meta", key))).ok()?;
+        let mut fields = meta.lines().filter_map(|line| line.split_once(' '));
+        // Guards against the (unlikely) clash of two URLs' keys
+        if fields.next() != Some(("url", url)) {
+            return None;
+        }
+        let mut validators = Validators::default();
+        for (name, value) in fields {
+            match name {
+                "etag" => validators.etag = Some(value.to_string()),
+                "last-modified" => validators.last_modified = Some(value.to_string()),
+                _ => {}
+            }
+        }
+        let bytes = fs::read(self.root.join(&key)).ok()?;
+        Some(Entry { bytes, validators })
+    }
+
+    /// Keep `bytes` as the download of `url`, then make room for it.
+    pub fn put(&self, url: &str, validators: &Validators, bytes: &[u8]) -> io::Result<()> {
+        create_private_dir(&self.root)?;
+        let key = key(url);
+        let mut meta = format!("url {}\n", url);
+        if let Some(ref etag) = validators.etag {
+            meta += &format!("etag {}\n", etag);
+        }
+        if let Some(ref modified) = validators.last_modified {
+            meta += &format!("last-modified {}\n", modified);
+        }
+        write_private(&self.root, &format!("{}.meta", key), meta.as_bytes())?;
+        write_private(&self.root, &key, bytes)?;
+        self.evict()
+    }
+
+    /// Note that the download of `url` was just used, so it goes last.
+    pub fn touch(&self, url: &str) {
+        let path = self.root.join(key(url));
+        if let Ok(file) = fs::File::options().write(true).open(path) {
+            let _ = file.set_modified(SystemTime::now());
+        }
+    }
+
+    /// Remove the least recently used downloads until the rest fit.
+    fn evict(&self) -> io::Result<()> {
+        let mut kept = Vec::new();
+        for entry in fs::read_dir(&self.root)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            // Skip the validators and half-written files
+            if name.starts_with('.') || name.ends_with(".meta") {
+                continue;
+            }
+            let meta = entry.metadata()?;
+            let used = meta.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            kept.push((used, meta.len(), entry.path()));
+        }
+        kept.sort();
+
+        let mut total: u64 = kept.iter().map(|(_, len, _)| len).sum();
+        for (_, len, path) in kept {
+            if total <= self.max_bytes {
+                break;
+            }
+            remove(&path)?;
+            total -= len;
+        }
+        Ok(())
+    }
+}
+
+/// Remove a download and its validators.
+fn remove(path: &Path) -> io::Result<()> {
+    let mut meta = path.as_os_str().to_os_string();
+    meta.push(".meta");
+    match fs::remove_file(meta) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    fs::remove_file(path)
+}
+
+/// Name of the files kept for `url`: the MD5 of the URL in hex.
+pub fn key(url: &str) -> String {
+    md5(url.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TestDir;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn cache_in(tmp: &TestDir, max_bytes: u64) -> DownloadCache {
+        let store = Store {
+            path: Some(tmp.path().join("downloads")),
+            disabled: None,
+        };
+        DownloadCache::new(&store, max_bytes).unwrap()
+    }
+
+    /// Make the download of `url` look last used at `secs`.
+    fn used_at(cache: &DownloadCache, url: &str, secs: u64) {
+        let file = fs::File::options().write(true).open(cache.root.join(key(url)));
+        let then = UNIX_EPOCH + Duration::from_secs(secs);
+        file.unwrap().set_modified(then).unwrap();
+    }
+
+    #[test]
+    fn keys_are_the_md5_of_the_url() {
+        assert_eq!(key(""), "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(key("https://example.com/graph.png").len(), 32);
+        assert_ne!(key("https://example.com/a.png"), key("https://example.com/b.png"));
+        // The URL as given: a query string is a different download
+        assert_ne!(key("http://x/a.png"), key("http://x/a.png?v=2"));
+    }
+
+    #[test]
+    fn downloads_come_back_with_their_validators() {
+        let tmp = TestDir::new();
+        let cache = cache_in(&tmp, 1 << 20);
+        let url = "https://example.com/graph.png";
+        assert!(cache.get(url).is_none());
+
+        let validators = Validators {
+            etag: Some("\"abc\"".into()),
+            last_modified: Some("Wed, 21 Oct 2015 07:28:00 GMT".into()),
+        };
+        cache.put(url, &validators, b"png bytes").unwrap();
+        let entry = cache.get(url).unwrap();
+        assert_eq!(entry.bytes, b"png bytes");
+        assert_eq!(entry.validators, validators);
+
+        // Replaced whole, including validators the server stopped sending
+        cache.put(url, &Validators::default(), b"new").unwrap();
+        let entry = cache.get(url).unwrap();
+        assert_eq!(entry.bytes, b"new");
+        assert_eq!(entry.validators, Validators::default());
+        assert!(cache.get("https://example.com/other.png").is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn downloads_are_private_and_written_whole() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp = TestDir::new();
+        let cache = cache_in(&tmp, 1 << 20);
+        cache.put("http://x/a.png", &Validators::default(), b"a").unwrap();
+        let mode = |p: &Path| fs::metadata(p).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&cache.root), 0o700);
+        let names: Vec<_> = fs::read_dir(&cache.root)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        assert_eq!(names.len(), 2, "{:?}", names);
+        assert!(names.iter().all(|n| !n.starts_with('.')));
+        assert_eq!(mode(&cache.root.join(key("http://x/a.png"))), 0o600);
+    }
+
+    #[test]
+    fn the_least_recently_used_are_evicted_first() {
+        let tmp = TestDir::new();
+        let cache = cache_in(&tmp, 25);
+        for (n, url) in ["http://x/1", "http://x/2", "http://x/3"].iter().enumerate() {
+            cache.put(url, &Validators::default(), &[0; 10]).unwrap();
+            used_at(&cache, url, 1000 + n as u64);
+        }
+        // The third put went over 25 bytes and took out the oldest
+        assert!(cache.get("http://x/1").is_none());
+        assert!(!cache.root.join(format!("{}.meta", key("http://x/1"))).exists());
+
+        // Using 2 makes 3 the oldest, so it goes when 4 arrives
+        cache.touch("http://x/2");
+        cache.put("http://x/4", &Validators::default(), &[0; 10]).unwrap();
+        assert!(cache.get("http://x/2").is_some());
+        assert!(cache.get("http://x/3").is_none());
+        assert!(cache.get("http://x/4").is_some());
+    }
+
+    #[test]
+    fn a_download_larger_than_the_cache_isnt_kept() {
+        let tmp = TestDir::new();
+        let cache = cache_in(&tmp, 5);
+        cache.put("http://x/big", &Validators::default(), &[0; 10]).unwrap();
+        assert!(cache.get("http://x/big").is_none());
+    }
+
+    #[test]
+    fn a_disabled_store_has_no_cache() {
+        let store = Store {
+            path: None,
+            disabled: Some("turned off with --no-cache".into()),
+        };
+        assert!(DownloadCache::new(&store, 1).is_none());
+    }
+}
@@ -0,0 +1,177 @@
+//! Which pixels of an image are in view at a zoom and pan.
+//!
+//! At any zoom and pan the view keeps to this contract:
+//!
+//! - One scale applies to both axes: an image pixel is drawn `scale` screen
+//!   pixels wide and `scale` tall, so the region shown keeps the proportions
+//!   it has in the image. Only the stretch fit mode gives each axis its own
+//!   scale, to fill the area on purpose.
+//! - The crop lies inside the image and is at least one pixel each way.
+//! - The crop drawn at that scale fits in the area, so nothing downstream
+//!   shrinks it again to make it fit. Only whole image pixels are shown; the
+//!   part of a pixel that would be cut off at an edge is left as margin.
+//! - Zoomed so far that a single pixel is larger than the area, that one
+//!   pixel is shown as large as fits, still square.
+//!
+//! Each axis is worked out on its own from its share of the area and the
+//! same scale, so a wide image zoomed until only its height overflows keeps
+//! its full width and its proportions.
+
+use crate::render::PixelRect;
+
+/// Slack for `area / scale` landing a hair under a whole number, as it does
+/// when the scale was worked out from the area in the first place.
+const EPSILON: f64 = 1e-6;
+
+/// The part of an `image`-sized image in view in an `area` of screen pixels,
+/// with `scale` screen pixels per image pixel on each axis, and its centre
+/// moved from the image's by `pan`, in fractions of the image size.
+pub fn crop(image: (u32, u32), area: (f64, f64), scale: (f64, f64), pan: (f64, f64)) -> PixelRect {
+    let (x, w) = span(image.0, area.0, scale.0, pan.0);
+    let (y, h) = span(image.1, area.1, scale.1, pan.1);
+    (x, y, w, h)
+}
+
+/// Start and length of the view along one axis of `len` image pixels.
+fn span(len: u32, area: f64, scale: f64, pan: f64) -> (u32, u32) {
+    let len = len.max(1);
+    // Rounding up would leave the view too big for the area at this scale
+    let fits = (area / scale + EPSILON).floor();
+    let visible = if fits.is_finite() {
+        (fits as u32).clamp(1, len)
+    } else {
+        len
+    };
+
+    let center = (len as f64 / 2.0 + pan * len as f64).clamp(0.0, len as f64);
+    let start = (center - visible as f64 / 2.0)
+        .round()
+        .clamp(0.0, (len - visible) as f64);
+    (start as u32, visible)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::{fit_scaled, Scaling};
+
+    /// xorshift, so failures can be reproduced from the seed.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn range(&mut self, low: u32, high: u32) -> u32 {
+            low + (self.next() % (high - low + 1) as u64) as u32
+        }
+
+        /// Uniform in `low..high`.
+        fn float(&mut self, low: f64, high: f64) -> f64 {
+            low + (self.next() >> 11) as f64 / (1u64 << 53) as f64 * (high - low)
+        }
+    }
+
+    #[test]
+    fn regressions() {
+        // An exact fit shows the whole image
+        assert_eq!(crop((400, 300), (800.0, 600.0), (2.0, 2.0), (0.0, 0.0)), (0, 0, 400, 300));
+        // area / scale lands a hair off 3 when the scale came from the area
+        assert_eq!(crop((3, 1), (100.0, 100.0), (100.0 / 3.0, 100.0 / 3.0), (0.0, 0.0)).2, 3);
+        // A wide image zoomed until only its height overflows keeps its width
+        let scale = 1200.0 / 3000.0;
+        let rect = crop((3000, 1400), (1200.0, 400.0), (scale, scale), (0.0, 0.0));
+        assert_eq!(rect, (0, 200, 3000, 1000));
+        // The part of a pixel that doesn't fit is margin, not squeezed in
+        assert_eq!(crop((100, 100), (250.0, 250.0), (3.0, 3.0), (0.0, 0.0)), (9, 9, 83, 83));
+        // Panned past the edge, the view stops at it
+        let rect = crop((400, 300), (400.0, 300.0), (2.0, 2.0), (10.0, 10.0));
+        assert_eq!(rect, (200, 150, 200, 150));
+        let rect = crop((400, 300), (400.0, 300.0), (2.0, 2.0), (-10.0, -10.0));
+        assert_eq!(rect, (0, 0, 200, 150));
+        // One pixel larger than the area: that pixel alone
+        assert_eq!(crop((10, 10), (50.0, 50.0), (100.0, 100.0), (0.0, 0.0)), (5, 5, 1, 1));
+    }
+
+    #[test]
+    fn extreme_values_stay_in_the_image() {
+        let scales = [0.0, -1.0, 1e-300, 1e300, f64::INFINITY, f64::NAN, f64::MIN_POSITIVE];
+        let pans = [0.0, -1e300, 1e300, f64::INFINITY, f64::NEG_INFINITY, f64::NAN];
+        for image in [(1, 1), (1, 70000), (u32::MAX, 3)] {
+            for &scale in &scales {
+                for &pan in &pans {
+                    let (x, y, w, h) = crop(image, (100.0, 0.5), (scale, scale), (pan, -pan));
+                    assert!(w >= 1 && h >= 1, "{:?} {} {}", image, scale, pan);
+                    assert!(x as u64 + w as u64 <= image.0 as u64, "{:?} {} {}", image, scale, pan);
+                    assert!(y as u64 + h as u64 <= image.1 as u64, "{:?} {} {}", image, scale, pan);
+                }
+            }
+        }
+    }
+
+    /// For random images, areas, fit modes, zooms from 1/1000 to 100000 and
+    /// pans, the crop keeps the contract in the module doc, and is drawn at
+    /// the scale asked for.
+    #[test]
+    fn crops_keep_the_contract() {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        for case in 0..200_000 {
+            let image = (rng.range(1, 5000), rng.range(1, 5000));
+            let cell = (rng.range(1, 30) as u16, rng.range(1, 60) as u16);
+            let cells = (rng.range(1, 300) as u16, rng.range(1, 100) as u16);
+            let area = (
+                cells.0 as f64 * cell.0 as f64,
+                cells.1 as f64 * cell.1 as f64,
+            );
+            let stretch = rng.range(0, 9) == 0;
+            let fit = (area.0 / image.0 as f64, area.1 / image.1 as f64);
+            let zoom = 10f64.powf(rng.float(-3.0, 5.0));
+            let scale = if stretch {
+                (fit.0 * zoom, fit.1 * zoom)
+            } else {
+                let scale = fit.0.min(fit.1) * zoom;
+                (scale, scale)
+            };
+            let pan = (rng.float(-1.0, 1.0), rng.float(-1.0, 1.0));
+            let context = format!(
+                "case {}: image {:?} area {:?} scale {:?} pan {:?}",
+                case, image, area, scale, pan
+            );
+
+            let (x, y, w, h) = crop(image, area, scale, pan);
+            for (start, len, size, area, scale) in
+                [(x, w, image.0, area.0, scale.0), (y, h, image.1, area.1, scale.1)]
+            {
+                assert!(len >= 1 && start + len <= size, "{}: in the image", context);
+                // Within the EPSILON image pixels of slack
+                if len > 1 {
+                    assert!((len as f64 - EPSILON) * scale <= area, "{}: fits", context);
+                }
+                // As large as fits: one pixel more would overflow
+                if len < size {
+                    assert!(((len + 1) as f64 - EPSILON) * scale > area, "{}: fills", context);
+                }
+            }
+
+            let scaling = Scaling {
+                max: scale.0.max(scale.1),
+                stretch,
+            };
+            let ((disp_w, disp_h), _) = fit_scaled(w, h, (0, 0, cells.0, cells.1), cell, scaling);
+            if w == 1 && h == 1 && !stretch {
+                assert_eq!(disp_w, disp_h, "{}: a lone pixel stays square", context);
+            } else if !stretch && (w > 1 || w as f64 * scale.0 <= area.0)
+                && (h > 1 || h as f64 * scale.1 <= area.1)
+            {
+                // Anything drawn is at least a pixel
+                let off_w = (disp_w as f64 - (w as f64 * scale.0).max(1.0)).abs();
+                let off_h = (disp_h as f64 - (h as f64 * scale.1).max(1.0)).abs();
+                assert!(off_w <= 0.5 && off_h <= 0.5, "{}: drawn {}x{}", context, disp_w, disp_h);
+            }
+        }
+    }
+}
@@ -0,0 +1,148 @@
+//! Noticing changes to the browsed directory as they happen.
+//!
+//! On Linux this uses inotify directly through libc. The watch descriptor is
+//! non-blocking and read from the event loop on a short timer, so no other
+//! thread is involved. Elsewhere watching isn't available and `Watcher::new`
+//! fails; follow mode's rescans still work there.
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// What happened to a file in a watched directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    /// Its contents were written.
+    Written,
+    /// It appeared, disappeared or was renamed. Renaming a finished file over
+    /// an old one counts as this rather than `Written`.
+    Listing,
+}
+
+#[cfg(target_os = "linux")]
+pub struct Watcher {
+    fd: std::os::fd::OwnedFd,
+    /// Watched directory for each watch descriptor.
+    dirs: std::collections::HashMap<libc::c_int, PathBuf>,
+}
+
+#[cfg(target_os = "linux")]
+impl Watcher {
+    pub fn new() -> io::Result<Self> {
+        use std::os::fd::FromRawFd;
+
+        // SAFETY: plain syscall; the descriptor is owned from here on
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Watcher {
+            fd: unsafe { std::os::fd::OwnedFd::from_raw_fd(fd) },
+            dirs: std::collections::HashMap::new(),
+        })
+    }
+
+    /// Report changes to files directly inside `dir`. Paths are reported
+    /// joined onto `dir` as given.
+    pub fn watch(&mut self, dir: &Path) -> io::Result<()> {
+        use std::os::fd::AsRawFd;
+        use std::os::unix::ffi::OsStrExt;
+
+        let c_path = std::ffi::CString::new(dir.as_os_str().as_bytes())
+            .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+        let mask = libc::IN_CLOSE_WRITE
+            | libc::IN_MODIFY
+            | libc::IN_CREATE
+            | libc::IN_DELETE
+            | libc::IN_MOVED_FROM
+            | libc::IN_MOVED_TO
+            | libc::IN_ONLYDIR;
+        // SAFETY: the path is a valid C string for the duration of the call
+        let wd = unsafe { libc::inotify_add_watch(self.fd.as_raw_fd(), c_path.as_ptr(), mask) };
+        if wd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        self.dirs.insert(wd, dir.to_path_buf());
+        Ok(())
+    }
+
+    /// Everything that happened since the last call, oldest first. If the
+    /// kernel's queue overflowed, each watched directory is reported as a
+    /// `Listing` change of its own, meaning "look again at everything".
+    pub fn drain(&mut self) -> Vec<(PathBuf, Change)> {
+        use std::os::fd::AsRawFd;
+        use std::os::unix::ffi::OsStrExt;
+
+        /// Size of `struct inotify_event` without its name.
+        const HEADER: usize = 16;
+
+        let mut changes = Vec::new();
+        let mut buf = [0u8; 4096];
+        loop {
+            // SAFETY: reading into a buffer of the length given
+            let n = unsafe {
+                libc::read(self.fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len())
+            };
+            if n <= 0 {
+                // EAGAIN: nothing more queued
+                return changes;
+            }
+
+            let events = &buf[..n as usize];
+            let mut pos = 0;
+            while pos + HEADER <= events.len() {
+                let field = |at: usize| {
+                    let bytes = [events[at], events[at + 1], events[at + 2], events[at + 3]];
+                    u32::from_ne_bytes(bytes)
+                };
+                let wd = field(pos) as libc::c_int;
+                let mask = field(pos + 4);
+                let len = field(pos + 12) as usize;
+                let name = events.get(pos + HEADER..pos + HEADER + len).unwrap_or(&[]);
+                let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+                pos += HEADER + len;
+
+                if mask & libc::IN_Q_OVERFLOW != 0 {
+                    let dirs = self.dirs.values().cloned();
+                    changes.extend(dirs.map(|dir| (dir, Change::Listing)));
+                    continue;
+                }
+                let Some(dir) = self.dirs.get(&wd) else {
+                    continue;
+                };
+                if name.is_empty() {
+                    continue;
+                }
+                let path = dir.join(std::ffi::OsStr::from_bytes(name));
+                let listing =
+                    libc::IN_CREATE | libc::IN_DELETE | libc::IN_MOVED_FROM | libc::IN_MOVED_TO;
+                let change = if mask & listing != 0 {
+                    Change::Listing
+                } else {
+                    Change::Written
+                };
+                changes.push((path, change));
+            }
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub struct Watcher;
+
+#[cfg(not(target_os = "linux"))]
+impl Watcher {
+    pub fn new() -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "watching directories is only supported on Linux",
+        ))
+    }
+
+    pub fn watch(&mut self, _dir: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub fn drain(&mut self) -> Vec<(PathBuf, Change)> {
+        Vec::new()
+    }
+}
@@ -0,0 +1,230 @@
+//! Runs termview in a pseudo-terminal, through util-linux `script`, and
+//! checks what it leaves behind in the terminal. Skipped where `script`
+//! isn't installed.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// A folder of a few images large enough that sending one takes many
+/// chunks, removed again when dropped.
+struct Images(PathBuf);
+
+impl Images {
+    fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("termview-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for n in 0..3u32 {
+            let img = image::RgbImage::from_fn(800, 600, |x, y| {
+                let v = x.wrapping_mul(31) ^ y.wrapping_mul(17) ^ n.wrapping_mul(101);
+                image::Rgb([v as u8, (v >> 3) as u8, (v >> 6) as u8])
+            });
+            img.save(dir.join(format!("{}.png", n))).unwrap();
+        }
+        Images(dir)
+    }
+
+    fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for Images {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Run termview with `args` in a 120x30 pseudo-terminal, sending it SIGTERM
+/// after `secs` seconds. Returns its exit status and everything it wrote.
+fn run_until_terminated(args: &str, secs: f32) -> Option<(Option<i32>, Vec<u8>)> {
+    run_in_pty(&format!(
+        "timeout --preserve-status -s TERM {} {} {}",
+        secs,
+        env!("CARGO_BIN_EXE_termview"),
+        args
+    ))
+}
+
+/// Run the shell `command` in a 120x30 pseudo-terminal, where `$TERMVIEW`
+/// is the viewer. Returns its exit status and everything written.
+fn run_in_pty(command: &str) -> Option<(Option<i32>, Vec<u8>)> {
+    let command = format!("stty rows 30 cols 120; {}", command);
+    let output = Command::new("script")
+        .args(["-qec", &command, "/dev/null"])
+        .env("TERMVIEW", env!("CARGO_BIN_EXE_termview"))
+        .stdin(Stdio::null())
+        .output()
+        .ok()?;
+    Some((output.status.code(), output.stdout))
+}
+
+/// The keys of each kitty graphics command in `out`, in order, and whether
+/// every command that was started was also terminated.
+fn graphics_commands(out: &[u8]) -> (Vec<String>, bool) {
+    let mut commands = Vec::new();
+    let mut rest = out;
+    while let Some(start) = find(rest, b"\x1b_G") {
+        rest = &rest[start + 3..];
+        let Some(end) = find(rest, b"\x1b\\") else {
+            return (commands, false);
+        };
+        let command = &rest[..end];
+        let keys = command.split(|&b| b == b';').next().unwrap_or_default();
+        commands.push(String::from_utf8_lossy(keys).into_owned());
+        rest = &rest[end + 2..];
+    }
+    (commands, true)
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+fn find_last(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).rposition(|w| w == needle)
+}
+
+fn key<'a>(command: &'a str, name: &str) -> Option<&'a str> {
+    command
+        .split(',')
+        .find_map(|kv| kv.strip_prefix(name)?.strip_prefix('='))
+}
+
+#[test]
+fn terminated_viewer_deletes_its_images_and_restores_the_terminal() {
+    let images = Images::new("signal");
+    let args = format!(
+        "--protocol kitty --kitty-quirks strict --cell-size 9x19 --slideshow=0.2 {}",
+        images.path().display()
+    );
+    let Some((status, out)) = run_until_terminated(&args, 5.0) else {
+        eprintln!("skipped: `script` isn't available");
+        return;
+    };
+    assert_eq!(status, Some(128 + 15), "exit status");
+
+    let (commands, terminated) = graphics_commands(&out);
+    assert!(terminated, "a graphics command was cut off");
+    let sends = |c: &&String| matches!(key(c, "a"), Some("T") | Some("t"));
+    let transmitted = commands.iter().filter(sends).count();
+    assert!(transmitted > 0, "nothing was transmitted");
+
+    // Each transfer is finished, or closed with an empty last chunk, before
+    // the next command, and every image sent is deleted at the end
+    let mut open = false;
+    let mut deleted = Vec::new();
+    for command in &commands {
+        let more = key(command, "m") == Some("1");
+        if key(command, "a").is_some() {
+            assert!(!open, "{} in the middle of a transfer", command);
+        }
+        open = more;
+        if sends(&command) {
+            deleted.retain(|&id| Some(id) != key(command, "i"));
+            deleted.push(key(command, "i").unwrap_or("*"));
+        }
+        if key(command, "a") == Some("d") {
+            match (key(command, "d"), key(command, "i")) {
+                (Some("A"), _) => deleted.clear(),
+                (Some("I"), Some(id)) => deleted.retain(|&sent| sent != id),
+                _ => {}
+            }
+        }
+    }
+    assert!(!open, "the last transfer was left open");
+    assert!(deleted.is_empty(), "not deleted: {:?}", deleted);
+
+    // The images go before the screen is handed back
+    let cleared = find(&out, b"\x1b_Ga=d,d=A\x1b\\").expect("no final delete");
+    let left = find(&out[cleared..], b"\x1b[?1049l").expect("alternate screen not left");
+    assert!(find(&out[cleared + left..], b"\x1b[?25h").is_some(), "cursor not shown");
+}
+
+/// The last position the cursor was moved to before `at` in `out`, 1-based.
+fn cursor_before(out: &[u8], at: usize) -> Option<(u16, u16)> {
+    let text = String::from_utf8_lossy(&out[..at]);
+    let start = text.rfind("\x1b[")?;
+    let (row, col) = text[start + 2..].split_once('H')?.0.split_once(';')?;
+    Some((row.parse().ok()?, col.parse().ok()?))
+}
+
+#[test]
+fn placement_follows_resizes_made_while_sending() {
+    let images = Images::new("resize");
+    // Two seconds of resizes, spanning the first transfers, ending at 70x24.
+    // The markers show in the output where they started and stopped
+    let command = format!(
+        "$TERMVIEW --protocol kitty --kitty-quirks strict --cell-size 9x19 {} & pid=$!; \
+         sleep 1; printf '<resizing>'; n=0; while [ $n -lt 20 ]; do \
+         stty cols 100 rows 28; sleep 0.05; stty cols 120 rows 30; sleep 0.05; \
+         n=$((n + 1)); done; stty cols 70 rows 24; printf '<resized>'; \
+         sleep 3; kill -TERM $pid; wait $pid",
+        images.path().join("0.png").display()
+    );
+    let Some((_, out)) = run_in_pty(&command) else {
+        eprintln!("skipped: `script` isn't available");
+        return;
+    };
+    let started = find(&out, b"<resizing>").expect("resizes never started");
+    let resized = find(&out, b"<resized>").expect("resizes never finished");
+    let (during, _) = graphics_commands(&out[started..resized]);
+    let sends = |c: &&String| matches!(key(c, "a"), Some("T") | Some("t"));
+    assert!(during.iter().any(|c| sends(&c)), "nothing was sent while resizing");
+
+    let (after, terminated) = graphics_commands(&out[resized..]);
+    assert!(terminated, "a graphics command was cut off");
+    let place = after.iter().rev().find(|c| key(c, "a") == Some("p"));
+    let place = place.expect("not placed after the last resize");
+    // 800x600 fitted to the 70x23 cells above the status bar, at 9x19 each
+    assert_eq!((key(place, "c"), key(place, "r")), (Some("65"), Some("23")));
+    // Centred across the 70 columns
+    let placed = resized + find_last(&out[resized..], b"\x1b_Ga=p").unwrap();
+    assert_eq!(cursor_before(&out, placed), Some((1, 3)));
+    // The status bar drawn with it is on the last row of the final size
+    assert!(find(&out[placed..], b"\x1b[24;1H").is_some(), "no status bar on row 24");
+    assert!(find(&out[placed..], b"\x1b[30;1H").is_none(), "status bar for an old size");
+}
+
+#[test]
+fn a_stopped_viewer_hands_the_terminal_back_until_continued() {
+    let images = Images::new("suspend");
+    // Stopped once the keyboard protocol probe has given up, as a stop signal
+    // before the viewer handles it is dropped in an orphaned process group.
+    // The shell reads the terminal's modes after the stop, which waits for
+    // the first image to finish sending.
+    let command = format!(
+        "$TERMVIEW --protocol kitty --kitty-quirks strict --cell-size 9x19 {} & pid=$!; \
+         sleep 4; kill -TSTP $pid; \
+         for i in $(seq 50); do \
+         [ \"$(ps -o stat= -p $pid | cut -c1)\" = T ] && break; sleep 0.2; done; \
+         printf '<stopped %s %s>' \"$(ps -o stat= -p $pid | cut -c1)\" \
+         \"$(stty -a | grep -o -- '-*icanon' | head -1)\"; \
+         kill -CONT $pid; sleep 1.5; printf '<continued>'; \
+         kill -TERM $pid; wait $pid",
+        images.path().join("0.png").display()
+    );
+    let Some((status, out)) = run_in_pty(&command) else {
+        eprintln!("skipped: `script` isn't available");
+        return;
+    };
+    assert_eq!(status, Some(128 + 15), "exit status");
+    let stopped = find(&out, b"<stopped ").expect("never stopped");
+    let continued = find(&out, b"<continued>").expect("never continued");
+    let text = String::from_utf8_lossy(&out[stopped..]);
+    // Stopped, with the terminal back in cooked mode for the shell
+    assert!(text.starts_with("<stopped T icanon>"), "{}", &text[..30]);
+
+    // Before stopping: images deleted, alternate screen left, cursor shown
+    let before = &out[..stopped];
+    let cleared = find_last(before, b"\x1b_Ga=d,d=A\x1b\\").expect("images not deleted");
+    let left = find(&before[cleared..], b"\x1b[?1049l").expect("alternate screen not left");
+    assert!(find(&before[cleared + left..], b"\x1b[?25h").is_some(), "cursor not shown");
+
+    // After continuing: taken over again and the image sent anew
+    let resumed = &out[stopped..continued];
+    let entered = find(resumed, b"\x1b[?1049h").expect("alternate screen not entered again");
+    let (commands, _) = graphics_commands(&resumed[entered..]);
+    let sends = |c: &String| matches!(key(c, "a"), Some("T") | Some("t"));
+    assert!(commands.iter().any(sends), "image not sent again: {:?}", commands);
+}